@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ailang::{Lexer, Parser, AiCompiler};
+
+// Neither parsing nor compiling should ever panic, whatever the script looks like.
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let mut parser = Parser::new(Lexer::new(source));
+    let _ = parser.parse_safe();
+
+    let _ = AiCompiler::new().compile(source);
+});
@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 use pastey::paste;
-use crate::token::{Literal as LexLiteral, Token};
+use crate::token::{Literal as LexLiteral, Token, TokenType};
 
 
 macro_rules! expr {
@@ -27,6 +27,14 @@ macro_rules! expr {
                 }
                 }
             }
+
+            fn rewrite_self(self, rewriter: &mut impl ExprRewriter<'a>) -> Expr<'a> {
+                paste! {
+                match self {
+                    $(Expr::$ty(e) => rewriter.[<rewrite_ $ty:lower _expr>](e)),+
+                }
+                }
+            }
         }
 
         paste! {
@@ -39,6 +47,15 @@ macro_rules! expr {
             pub trait ExprVisitorMut<'a, R> {
                 $(fn [< visit_ $ty:lower _expr >](&mut self, expr: &$ty<'a>) -> R;)*
             }
+
+            /// A pass that transforms expressions. Each method is called with a node whose
+            /// children have already been rewritten, and returns the node to replace it with. By
+            /// default, nodes are left as they are.
+            pub trait ExprRewriter<'a> {
+                $(fn [< rewrite_ $ty:lower _expr >](&mut self, expr: $ty<'a>) -> Expr<'a> {
+                    Expr::$ty(expr)
+                })*
+            }
         }
     }
 }
@@ -50,6 +67,9 @@ expr! {
     Logical,
     Unary,
     Variable,
+    Call,
+    List,
+    Index,
 }
 
 impl<'a> Expr<'a> {
@@ -76,6 +96,72 @@ impl<'a> Expr<'a> {
     pub fn variable(name: Token<'a>) -> Box<Expr<'a>> {
         Box::new(Expr::Variable(Variable {name}))
     }
+
+    pub fn call(callee: Token<'a>, args: Vec<Expr<'a>>) -> Box<Expr<'a>> {
+        Box::new(Expr::Call(Call {callee, args, method: false}))
+    }
+
+    pub fn method_call(callee: Token<'a>, receiver: Expr<'a>, mut args: Vec<Expr<'a>>) -> Box<Expr<'a>> {
+        args.insert(0, receiver);
+        Box::new(Expr::Call(Call {callee, args, method: true}))
+    }
+
+    pub fn list(bracket: Token<'a>, elements: Vec<Expr<'a>>) -> Box<Expr<'a>> {
+        Box::new(Expr::List(List {bracket, elements}))
+    }
+
+    pub fn index(target: Box<Expr<'a>>, bracket: Token<'a>, index: Box<Expr<'a>>) -> Box<Expr<'a>> {
+        Box::new(Expr::Index(Index {target, bracket, index}))
+    }
+
+    /// The byte offset the expression starts at in the source, as far as its tokens tell. Literals
+    /// don't keep theirs, so an expression that starts with one gives its operator's instead, or
+    /// nothing at all.
+    pub fn start(&self) -> Option<usize> {
+        match self {
+            Expr::Binary(Binary {left, op, ..}) | Expr::Logical(Logical {left, op, ..}) => left.start().or(Some(op.start)),
+            Expr::Grouping(Grouping {expression, ..}) => expression.start(),
+            Expr::Literal(_) => None,
+            Expr::Unary(Unary {op, ..}) => Some(op.start),
+            Expr::Variable(Variable {name}) => Some(name.start),
+            Expr::Call(Call {callee, args, method}) => {
+                if *method {args[0].start().or(Some(callee.start))} else {Some(callee.start)}
+            }
+            Expr::List(List {bracket, ..}) => Some(bracket.start),
+            Expr::Index(Index {target, bracket, ..}) => target.start().or(Some(bracket.start)),
+        }
+    }
+
+    /// Rebuilds the expression bottom-up, passing each node to `rewriter`.
+    pub fn rewrite(self, rewriter: &mut impl ExprRewriter<'a>) -> Expr<'a> {
+        let boxed = |e: Box<Expr<'a>>, r: &mut _| Box::new(e.rewrite(r));
+        let expr = match self {
+            Expr::Binary(Binary {left, op, right}) => {
+                let left = boxed(left, rewriter);
+                Expr::Binary(Binary {left, op, right: boxed(right, rewriter)})
+            }
+            Expr::Grouping(Grouping {expression, abs}) => {
+                Expr::Grouping(Grouping {expression: boxed(expression, rewriter), abs})
+            }
+            Expr::Logical(Logical {left, op, right}) => {
+                let left = boxed(left, rewriter);
+                Expr::Logical(Logical {left, op, right: boxed(right, rewriter)})
+            }
+            Expr::Unary(Unary {op, right}) => Expr::Unary(Unary {op, right: boxed(right, rewriter)}),
+            Expr::Call(Call {callee, args, method}) => {
+                Expr::Call(Call {callee, args: args.into_iter().map(|a| a.rewrite(rewriter)).collect(), method})
+            }
+            Expr::List(List {bracket, elements}) => {
+                Expr::List(List {bracket, elements: elements.into_iter().map(|e| e.rewrite(rewriter)).collect()})
+            }
+            Expr::Index(Index {target, bracket, index}) => {
+                let target = boxed(target, rewriter);
+                Expr::Index(Index {target, bracket, index: boxed(index, rewriter)})
+            }
+            expr @ (Expr::Literal(_) | Expr::Variable(_)) => expr,
+        };
+        expr.rewrite_self(rewriter)
+    }
 }
 
 pub struct Binary<'a> {
@@ -108,6 +194,26 @@ pub struct Variable<'a> {
     pub name: Token<'a>,
 }
 
+// A function-style call inside an expression, e.g. `normalize_angle($a)`
+pub struct Call<'a> {
+    pub callee: Token<'a>,
+    pub args: Vec<Expr<'a>>,
+    pub method: bool, // written as a method on its first argument, e.g. `$a.abs()`
+}
+
+// A list literal, e.g. `[1, 2, $x]`
+pub struct List<'a> {
+    pub bracket: Token<'a>,
+    pub elements: Vec<Expr<'a>>,
+}
+
+// An element of a list, or a character of a string, e.g. `$waypoints[2]`
+pub struct Index<'a> {
+    pub target: Box<Expr<'a>>,
+    pub bracket: Token<'a>,
+    pub index: Box<Expr<'a>>,
+}
+
 macro_rules! stmt {
     ($($ty: ident),*$(,)?) => {
         #[allow(dead_code)]
@@ -132,6 +238,14 @@ macro_rules! stmt {
                 }
                 }
             }
+
+            fn rewrite_self(self, rewriter: &mut impl StmtRewriter<'a>) -> Stmt<'a> {
+                paste! {
+                match self {
+                    $(Stmt::$ty(e) => rewriter.[<rewrite_ $ty:lower _stmt>](e)),+
+                }
+                }
+            }
         }
 
         paste! {
@@ -144,6 +258,14 @@ macro_rules! stmt {
             pub trait StmtVisitorMut<'a, R> {
                 $(fn [< visit_ $ty:lower _stmt >](&mut self, stmt: &$ty<'a>) -> R;)*
             }
+
+            /// The statement counterpart to `ExprRewriter`. Expressions inside statements are
+            /// rewritten before the statements containing them.
+            pub trait StmtRewriter<'a>: ExprRewriter<'a> {
+                $(fn [< rewrite_ $ty:lower _stmt >](&mut self, stmt: $ty<'a>) -> Stmt<'a> {
+                    Stmt::$ty(stmt)
+                })*
+            }
         }
     }
 }
@@ -153,26 +275,50 @@ stmt! {
     Use,
     If,
     While,
+    Repeat,
+    DoWhile,
+    ForEach,
+    ForRange,
+    Conditional,
     Exec,
     Parallel,
+    Spawn,
     Var,
+    Capture,
     Return,
+    Halt,
     Yield,
     Break,
+    Continue,
 }
 
 impl<'a> Stmt<'a> {
-    pub fn group(name: Token<'a>, params: Vec<Token<'a>>, statements: Vec<Stmt<'a>>) -> Stmt<'a> {
-        Stmt::Group(Group {name, params, statements})
+    pub fn group(name: Token<'a>, params: Vec<GroupParam<'a>>, statements: Vec<Stmt<'a>>, attributes: Vec<Attribute<'a>>) -> Stmt<'a> {
+        Stmt::Group(Group {name, params, statements, attributes})
     }
     pub fn r#use(name: Token<'a>) -> Stmt<'a> {
         Stmt::Use(Use{name})
     }
-    pub fn r#if(condition: Box<Expr<'a>>, invert: bool, then_branch: Vec<Stmt<'a>>, else_branch: Vec<Stmt<'a>>) -> Stmt<'a> {
-        Stmt::If(If{condition, invert, then_branch, else_branch})
+    pub fn r#if(keyword: Token<'a>, condition: Box<Expr<'a>>, invert: bool, then_branch: Vec<Stmt<'a>>, else_branch: Vec<Stmt<'a>>) -> Stmt<'a> {
+        Stmt::If(If{keyword, condition, invert, then_branch, else_branch})
+    }
+    pub fn r#while(label: Option<Token<'a>>, keyword: Token<'a>, condition: Box<Expr<'a>>, invert: bool, body: Vec<Stmt<'a>>, timeout: Option<LoopTimeout<'a>>) -> Stmt<'a> {
+        Stmt::While(While{label, keyword, condition, invert, body, timeout})
+    }
+    pub fn repeat(label: Option<Token<'a>>, count: Box<Expr<'a>>, body: Vec<Stmt<'a>>) -> Stmt<'a> {
+        Stmt::Repeat(Repeat{label, count, body})
+    }
+    pub fn do_while(label: Option<Token<'a>>, body: Vec<Stmt<'a>>, condition: Box<Expr<'a>>, invert: bool) -> Stmt<'a> {
+        Stmt::DoWhile(DoWhile{label, body, condition, invert})
     }
-    pub fn r#while(condition: Box<Expr<'a>>, invert: bool, body: Vec<Stmt<'a>>) -> Stmt<'a> {
-        Stmt::While(While{condition, invert, body})
+    pub fn for_each(label: Option<Token<'a>>, var: Token<'a>, iterable: Box<Expr<'a>>, body: Vec<Stmt<'a>>) -> Stmt<'a> {
+        Stmt::ForEach(ForEach{label, var, iterable, body})
+    }
+    pub fn for_range(label: Option<Token<'a>>, var: Token<'a>, start: Box<Expr<'a>>, end: Box<Expr<'a>>, body: Vec<Stmt<'a>>) -> Stmt<'a> {
+        Stmt::ForRange(ForRange{label, var, start, end, body})
+    }
+    pub fn conditional(flag: Token<'a>, then_branch: Vec<Stmt<'a>>, else_branch: Vec<Stmt<'a>>) -> Stmt<'a> {
+        Stmt::Conditional(Conditional{flag, then_branch, else_branch})
     }
     pub fn exec(name: Token<'a>, args: Vec<Arg<'a>>) -> Stmt<'a> {
         Stmt::Exec(Exec{name, args})
@@ -180,20 +326,154 @@ impl<'a> Stmt<'a> {
     pub fn parallel(calls: Vec<Exec<'a>>, race: bool) -> Stmt<'a> {
         Stmt::Parallel(Parallel{calls, race})
     }
+    pub fn spawn(tok: Token<'a>, call: Exec<'a>) -> Stmt<'a> {
+        Stmt::Spawn(Spawn{tok, call})
+    }
     pub fn var(name: Token<'a>, value: Box<Expr<'a>>) -> Stmt<'a> {
         Stmt::Var(Var{name, value})
     }
+    pub fn capture(name: Token<'a>, call: Exec<'a>) -> Stmt<'a> {
+        Stmt::Capture(Capture{name, call})
+    }
     pub fn r#return(tok: Token<'a>) -> Stmt<'a> {
         Stmt::Return(Return{tok})
     }
+    pub fn halt(tok: Token<'a>) -> Stmt<'a> {
+        Stmt::Halt(Halt{tok})
+    }
     pub fn r#yield(tok: Token<'a>) -> Stmt<'a> {
         Stmt::Yield(Yield{tok})
     }
-    pub fn r#break(tok: Token<'a>) -> Stmt<'a> {
-        Stmt::Break(Break{tok})
+    pub fn r#break(tok: Token<'a>, label: Option<Token<'a>>) -> Stmt<'a> {
+        Stmt::Break(Break{tok, label})
+    }
+    pub fn r#continue(tok: Token<'a>, label: Option<Token<'a>>) -> Stmt<'a> {
+        Stmt::Continue(Continue{tok, label})
+    }
+
+    /// The byte offset the statement starts at in the source, as far as its tokens tell. See
+    /// `Expr::start`.
+    pub fn start(&self) -> Option<usize> {
+        let labelled = |label: &Option<Token<'a>>, rest: Option<usize>| label.as_ref().map(|l| l.start).or(rest);
+        match self {
+            Stmt::Group(s) => Some(s.name.start),
+            Stmt::Use(s) => Some(s.name.start),
+            Stmt::If(s) => Some(s.keyword.start),
+            Stmt::While(s) => labelled(&s.label, Some(s.keyword.start)),
+            Stmt::Repeat(s) => labelled(&s.label, s.count.start()),
+            Stmt::DoWhile(s) => labelled(&s.label, s.body.first().and_then(|b| b.start()).or(s.condition.start())),
+            Stmt::ForEach(s) => labelled(&s.label, Some(s.var.start)),
+            Stmt::ForRange(s) => labelled(&s.label, Some(s.var.start)),
+            Stmt::Conditional(s) => Some(s.flag.start),
+            Stmt::Exec(s) => Some(s.name.start),
+            Stmt::Parallel(s) => s.calls.first().map(|c| c.name.start),
+            Stmt::Spawn(s) => Some(s.tok.start),
+            Stmt::Var(s) => Some(s.name.start),
+            Stmt::Capture(s) => Some(s.name.start),
+            Stmt::Return(s) => Some(s.tok.start),
+            Stmt::Halt(s) => Some(s.tok.start),
+            Stmt::Yield(s) => Some(s.tok.start),
+            Stmt::Break(s) => Some(s.tok.start),
+            Stmt::Continue(s) => Some(s.tok.start),
+        }
+    }
+
+    /// Rebuilds the statement bottom-up, passing each statement and expression to `rewriter`.
+    pub fn rewrite(self, rewriter: &mut impl StmtRewriter<'a>) -> Stmt<'a> {
+        let stmt = match self {
+            Stmt::Group(Group {name, params, statements, attributes}) => {
+                Stmt::Group(Group {name, params, statements: rewrite(statements, rewriter), attributes})
+            }
+            Stmt::If(If {keyword, condition, invert, then_branch, else_branch}) => Stmt::If(If {
+                keyword,
+                condition: Box::new(condition.rewrite(rewriter)),
+                invert,
+                then_branch: rewrite(then_branch, rewriter),
+                else_branch: rewrite(else_branch, rewriter),
+            }),
+            Stmt::While(While {label, keyword, condition, invert, body, timeout}) => Stmt::While(While {
+                label,
+                keyword,
+                condition: Box::new(condition.rewrite(rewriter)),
+                invert,
+                body: rewrite(body, rewriter),
+                timeout: timeout.map(|t| LoopTimeout {
+                    duration: t.duration,
+                    else_body: rewrite(t.else_body, rewriter),
+                }),
+            }),
+            Stmt::Repeat(Repeat {label, count, body}) => Stmt::Repeat(Repeat {
+                label,
+                count: Box::new(count.rewrite(rewriter)),
+                body: rewrite(body, rewriter),
+            }),
+            Stmt::DoWhile(DoWhile {label, body, condition, invert}) => Stmt::DoWhile(DoWhile {
+                label,
+                body: rewrite(body, rewriter),
+                condition: Box::new(condition.rewrite(rewriter)),
+                invert,
+            }),
+            Stmt::ForEach(ForEach {label, var, iterable, body}) => Stmt::ForEach(ForEach {
+                label,
+                var,
+                iterable: Box::new(iterable.rewrite(rewriter)),
+                body: rewrite(body, rewriter),
+            }),
+            Stmt::ForRange(ForRange {label, var, start, end, body}) => Stmt::ForRange(ForRange {
+                label,
+                var,
+                start: Box::new(start.rewrite(rewriter)),
+                end: Box::new(end.rewrite(rewriter)),
+                body: rewrite(body, rewriter),
+            }),
+            Stmt::Conditional(Conditional {flag, then_branch, else_branch}) => Stmt::Conditional(Conditional {
+                flag,
+                then_branch: rewrite(then_branch, rewriter),
+                else_branch: rewrite(else_branch, rewriter),
+            }),
+            Stmt::Exec(exec) => Stmt::Exec(exec.rewrite_args(rewriter)),
+            Stmt::Parallel(Parallel {calls, race}) => Stmt::Parallel(Parallel {
+                calls: calls.into_iter().map(|c| c.rewrite_args(rewriter)).collect(),
+                race,
+            }),
+            Stmt::Var(Var {name, value}) => Stmt::Var(Var {name, value: Box::new(value.rewrite(rewriter))}),
+            Stmt::Capture(Capture {name, call}) => Stmt::Capture(Capture {name, call: call.rewrite_args(rewriter)}),
+            Stmt::Spawn(Spawn {tok, call}) => Stmt::Spawn(Spawn {tok, call: call.rewrite_args(rewriter)}),
+            stmt @ (Stmt::Use(_) | Stmt::Return(_) | Stmt::Halt(_) | Stmt::Yield(_) | Stmt::Break(_) | Stmt::Continue(_)) => stmt,
+        };
+        stmt.rewrite_self(rewriter)
+    }
+}
+
+/// Runs a rewriting pass over a whole program.
+pub fn rewrite<'a>(program: Vec<Stmt<'a>>, rewriter: &mut impl StmtRewriter<'a>) -> Vec<Stmt<'a>> {
+    program.into_iter().map(|stmt| stmt.rewrite(rewriter)).collect()
+}
+
+/// An example pass, which simplifies `-(-x)` to `x`.
+pub struct FoldDoubleNegation;
+
+impl<'a> ExprRewriter<'a> for FoldDoubleNegation {
+    fn rewrite_unary_expr(&mut self, expr: Unary<'a>) -> Expr<'a> {
+        let mut inner = &*expr.right;
+        while let Expr::Grouping(Grouping {expression, abs: false}) = inner {
+            inner = expression;
+        }
+        if expr.op.ty != TokenType::Minus || !matches!(inner, Expr::Unary(u) if u.op.ty == TokenType::Minus) {
+            return Expr::Unary(expr);
+        }
+
+        let mut inner = *expr.right;
+        while let Expr::Grouping(Grouping {expression, abs: false}) = inner {
+            inner = *expression;
+        }
+        let Expr::Unary(Unary {right, ..}) = inner else {unreachable!()};
+        *right
     }
 }
 
+impl<'a> StmtRewriter<'a> for FoldDoubleNegation {}
+
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum GroupKind {
     Sequence,
@@ -204,8 +484,22 @@ pub enum GroupKind {
 pub struct Group<'a> {
     // pub kind: GroupKind,
     pub name: Token<'a>,
-    pub params: Vec<Token<'a>>,
+    pub params: Vec<GroupParam<'a>>,
     pub statements: Vec<Stmt<'a>>,
+    pub attributes: Vec<Attribute<'a>>,
+}
+
+/// An attribute like `#[timeout(5s)]` attached to the declaration that follows it. The arguments
+/// are kept as tokens, since what they mean depends on the attribute.
+pub struct Attribute<'a> {
+    pub name: Token<'a>,
+    pub args: Vec<Token<'a>>,
+}
+
+pub struct GroupParam<'a> {
+    pub name: Token<'a>, // either a word or an identifier
+    // Whether changes to the parameter are written back to the caller's variable on return.
+    pub inout: bool,
 }
 
 pub struct Use<'a> {
@@ -213,6 +507,7 @@ pub struct Use<'a> {
 }
 
 pub struct If<'a> {
+    pub keyword: Token<'a>, // `if` or `unless`
     pub condition: Box<Expr<'a>>,
     pub invert: bool,
     pub then_branch: Vec<Stmt<'a>>,
@@ -222,9 +517,63 @@ pub struct If<'a> {
 }
 
 pub struct While<'a> {
+    pub label: Option<Token<'a>>,
+    pub keyword: Token<'a>, // `while` or `until`
     pub condition: Box<Expr<'a>>,
     pub invert: bool,
     pub body: Vec<Stmt<'a>>,
+    pub timeout: Option<LoopTimeout<'a>>,
+}
+
+/// The `timeout 3s { ... }` part of a loop like `until $done timeout 3s { ... } else { ... }`.
+pub struct LoopTimeout<'a> {
+    pub duration: Vec<Token<'a>>,
+    // Runs only if the loop gave up because it ran out of time
+    pub else_body: Vec<Stmt<'a>>,
+}
+
+/// `repeat 3 { ... }`, which runs the body a fixed number of times. The count is evaluated once,
+/// before the first iteration, and anything after the decimal point is ignored.
+pub struct Repeat<'a> {
+    pub label: Option<Token<'a>>,
+    pub count: Box<Expr<'a>>,
+    pub body: Vec<Stmt<'a>>,
+}
+
+/// `for $x in $list { ... }`, which runs the body once for each element of a list, or each
+/// character of a string.
+pub struct ForEach<'a> {
+    pub label: Option<Token<'a>>,
+    pub var: Token<'a>,
+    pub iterable: Box<Expr<'a>>,
+    pub body: Vec<Stmt<'a>>,
+}
+
+/// `for $i in 0..$n { ... }`, which counts from `start` up to but not including `end`. Both are
+/// evaluated once, before the first iteration.
+pub struct ForRange<'a> {
+    pub label: Option<Token<'a>>,
+    pub var: Token<'a>,
+    pub start: Box<Expr<'a>>,
+    pub end: Box<Expr<'a>>,
+    pub body: Vec<Stmt<'a>>,
+}
+
+/// `#if FLAG { ... } #else { ... } #endif`, where the compiler only compiles the branch picked by
+/// whether `flag` is defined.
+pub struct Conditional<'a> {
+    pub flag: Token<'a>,
+    pub then_branch: Vec<Stmt<'a>>,
+    pub else_branch: Vec<Stmt<'a>>,
+}
+
+/// `do { ... } while $x;`, which checks its condition after the body, so the body always runs at
+/// least once. `invert` is set for `do { ... } until $x;`.
+pub struct DoWhile<'a> {
+    pub label: Option<Token<'a>>,
+    pub body: Vec<Stmt<'a>>,
+    pub condition: Box<Expr<'a>>,
+    pub invert: bool,
 }
 
 pub enum Arg<'a> {
@@ -249,6 +598,16 @@ pub struct Exec<'a> {
     pub args: Vec<Arg<'a>>
 }
 
+impl<'a> Exec<'a> {
+    fn rewrite_args(self, rewriter: &mut impl StmtRewriter<'a>) -> Exec<'a> {
+        let args = self.args.into_iter().map(|arg| match arg {
+            Arg::Value(v) => Arg::Value(Box::new(v.rewrite(rewriter))),
+            word => word,
+        }).collect();
+        Exec {name: self.name, args}
+    }
+}
+
 pub struct Parallel<'a> {
     pub calls: Vec<Exec<'a>>,
     pub race: bool,
@@ -259,14 +618,39 @@ pub struct Var<'a> {
     pub value: Box<Expr<'a>>,
 }
 
+/// Starts a group in the background, as in `spawn blink 3;`, without waiting for it to finish.
+pub struct Spawn<'a> {
+    pub tok: Token<'a>,
+    pub call: Exec<'a>,
+}
+
+/// Assigns the result of a call, as in `$d = measure;`.
+pub struct Capture<'a> {
+    pub name: Token<'a>,
+    pub call: Exec<'a>,
+}
+
 pub struct Return<'a> {
     pub tok: Token<'a>,
 }
 
+/// Stops the whole program, including anything running alongside it in parallel.
+pub struct Halt<'a> {
+    pub tok: Token<'a>,
+}
+
 pub struct Yield<'a> {
     pub tok: Token<'a>,
 }
 
 pub struct Break<'a> {
     pub tok: Token<'a>,
+    // Names the loop to break out of. Otherwise, it's the innermost one.
+    pub label: Option<Token<'a>>,
+}
+
+pub struct Continue<'a> {
+    pub tok: Token<'a>,
+    // Names the loop to go on to the next iteration of. Otherwise, it's the innermost one.
+    pub label: Option<Token<'a>>,
 }
@@ -0,0 +1,363 @@
+use crate::compiler::{Op, Value, Program, AngleRange, NumberFormat, DebugSymbols};
+use crate::error::Error;
+use itertools::Itertools;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Layout: the magic bytes and a little-endian u16 version, followed by the constants pool, the
+// code, the group timeouts, and the line table, each prefixed by their length as a u32. Timeouts
+// are the group's name followed by the duration as a u64 of seconds and a u32 of nanoseconds. Line
+// table entries are an address followed by a line. Ops are their opcode (the `Op` discriminant)
+// followed by their operands. Numbers are little-endian, usizes and isizes are 64 bits, and
+// strings are UTF-8 prefixed by their length as a u32.
+const MAGIC: &[u8; 4] = b"AIBC";
+
+/// The version of the binary format written by `Program::to_bytes`. This only changes when
+/// programs written by an older version can no longer be read correctly.
+pub const BYTECODE_VERSION: u16 = 3;
+
+const NUMBER: u8 = 0;
+const STRING: u8 = 1;
+const BOOL: u8 = 2;
+const NIL: u8 = 3;
+const LIST: u8 = 4;
+
+fn opcode(op: &Op) -> u8 {
+    // Safe since `Op` is `repr(u8)`, which puts the discriminant first.
+    unsafe { *(op as *const Op as *const u8) }
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, b: u8) {
+        self.bytes.push(b);
+    }
+
+    fn u32(&mut self, n: usize) {
+        self.bytes.extend((n as u32).to_le_bytes());
+    }
+
+    fn usize(&mut self, n: usize) {
+        self.bytes.extend((n as u64).to_le_bytes());
+    }
+
+    fn isize(&mut self, n: isize) {
+        self.bytes.extend((n as i64).to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.u32(s.len());
+        self.bytes.extend(s.as_bytes());
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Number(n) => {
+                self.u8(NUMBER);
+                self.bytes.extend(n.to_le_bytes());
+            }
+            Value::String(s) => {
+                self.u8(STRING);
+                self.string(s);
+            }
+            Value::Bool(b) => {
+                self.u8(BOOL);
+                self.u8(*b as u8);
+            }
+            Value::List(l) => {
+                self.u8(LIST);
+                self.u32(l.len());
+                for v in l.iter() {
+                    self.value(v);
+                }
+            }
+            Value::Nil => self.u8(NIL),
+        }
+    }
+
+    fn duration(&mut self, d: &Duration) {
+        self.bytes.extend(d.as_secs().to_le_bytes());
+        self.bytes.extend(d.subsec_nanos().to_le_bytes());
+    }
+
+    fn calls(&mut self, calls: &[(String, usize)]) {
+        self.u32(calls.len());
+        for (name, arity) in calls {
+            self.string(name);
+            self.usize(*arity);
+        }
+    }
+
+    fn op(&mut self, op: &Op) {
+        self.u8(opcode(op));
+        use Op::*;
+        match op {
+            Load(a) | Store(a) | Const(a) | MakeList(a) | InitCounter(a) | Line(a) => self.usize(*a),
+            Get(s) | Set(s) | Label(s) => self.string(s),
+            Push(v) => self.value(v),
+            NormalizeAngle(r) => self.u8(*r as u8),
+            Format(n) => self.u8(*n as u8),
+            Jump(a) | JumpUnless(a) | JumpIf(a) | JumpUnlessEq(a) | JumpUnlessNe(a) |
+            JumpUnlessLt(a) | JumpUnlessLe(a) | JumpUnlessGt(a) | JumpUnlessGe(a) | Checkpoint(a) => self.isize(*a),
+            Call(name, arity) | CallValue(name, arity) | Spawn(name, arity) => {
+                self.string(name);
+                self.usize(*arity);
+            }
+            CallParallel(calls) | CallRace(calls) => self.calls(calls),
+            DecCounterJumpNonZero(a, j) => {
+                self.usize(*a);
+                self.isize(*j);
+            }
+            Pop | Dup | Add | Sub | Mul | Div | Mod | Exp | Neg | Abs | Round | Index | Len | Select | And | Or | Not | Xor |
+            Eq | Ne | Lt | Le | Gt | Ge | Yield | Statement | Return | Halt | Sort | Now => {}
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::InvalidBytecode(msg.into())
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| invalid("Unexpected end of bytecode"))?;
+        let out = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        // `take` guarantees the length
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<usize, Error> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+
+    fn usize(&mut self) -> Result<usize, Error> {
+        usize::try_from(u64::from_le_bytes(self.array()?)).map_err(|_| invalid("Index too large for this platform"))
+    }
+
+    fn isize(&mut self) -> Result<isize, Error> {
+        isize::try_from(i64::from_le_bytes(self.array()?)).map_err(|_| invalid("Jump too large for this platform"))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid("String is not valid UTF-8"))
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        Ok(match self.u8()? {
+            NUMBER => Value::Number(f64::from_le_bytes(self.array()?)),
+            STRING => Value::String(self.string()?),
+            BOOL => Value::Bool(self.u8()? != 0),
+            NIL => Value::Nil,
+            LIST => {
+                let len = self.u32()?;
+                let mut l = Vec::new();
+                for _ in 0..len {
+                    l.push(self.value()?);
+                }
+                Value::List(l)
+            }
+            t => return Err(invalid(&format!("Unknown value type {}", t))),
+        })
+    }
+
+    fn duration(&mut self) -> Result<Duration, Error> {
+        let secs = u64::from_le_bytes(self.array()?);
+        let nanos = u32::from_le_bytes(self.array()?);
+        if nanos >= 1_000_000_000 {
+            return Err(invalid("Invalid duration"));
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+
+    fn calls(&mut self) -> Result<Vec<(String, usize)>, Error> {
+        let len = self.u32()?;
+        let mut calls = Vec::new();
+        for _ in 0..len {
+            calls.push((self.string()?, self.usize()?));
+        }
+        Ok(calls)
+    }
+
+    fn angle_range(&mut self) -> Result<AngleRange, Error> {
+        Ok(match self.u8()? {
+            0 => AngleRange::SignedDegrees,
+            1 => AngleRange::UnsignedDegrees,
+            2 => AngleRange::SignedRadians,
+            3 => AngleRange::UnsignedRadians,
+            r => return Err(invalid(&format!("Unknown angle range {}", r))),
+        })
+    }
+
+    fn number_format(&mut self) -> Result<NumberFormat, Error> {
+        Ok(match self.u8()? {
+            0 => NumberFormat::Auto,
+            1 => NumberFormat::Fixed,
+            2 => NumberFormat::Scientific,
+            n => return Err(invalid(&format!("Unknown number format {}", n))),
+        })
+    }
+
+    fn op(&mut self) -> Result<Op, Error> {
+        use Op::*;
+        Ok(match self.u8()? {
+            0 => Load(self.usize()?),
+            1 => Store(self.usize()?),
+            2 => Get(self.string()?),
+            3 => Set(self.string()?),
+            4 => Push(self.value()?),
+            5 => Const(self.usize()?),
+            6 => Pop,
+            7 => Dup,
+            8 => Add,
+            9 => Sub,
+            10 => Mul,
+            11 => Div,
+            12 => Mod,
+            13 => Exp,
+            14 => Neg,
+            15 => Abs,
+            16 => NormalizeAngle(self.angle_range()?),
+            17 => And,
+            18 => Or,
+            19 => Not,
+            20 => Xor,
+            21 => Eq,
+            22 => Ne,
+            23 => Lt,
+            24 => Le,
+            25 => Gt,
+            26 => Ge,
+            27 => Jump(self.isize()?),
+            28 => JumpUnless(self.isize()?),
+            29 => JumpIf(self.isize()?),
+            30 => Label(self.string()?),
+            31 => Call(self.string()?, self.usize()?),
+            32 => CallParallel(self.calls()?),
+            33 => CallRace(self.calls()?),
+            34 => Yield,
+            35 => Return,
+            36 => JumpUnlessEq(self.isize()?),
+            37 => JumpUnlessNe(self.isize()?),
+            38 => JumpUnlessLt(self.isize()?),
+            39 => JumpUnlessLe(self.isize()?),
+            40 => JumpUnlessGt(self.isize()?),
+            41 => JumpUnlessGe(self.isize()?),
+            42 => MakeList(self.usize()?),
+            43 => Sort,
+            44 => Now,
+            45 => InitCounter(self.usize()?),
+            46 => DecCounterJumpNonZero(self.usize()?, self.isize()?),
+            47 => CallValue(self.string()?, self.usize()?),
+            48 => Round,
+            49 => Halt,
+            50 => Spawn(self.string()?, self.usize()?),
+            51 => Index,
+            52 => Len,
+            53 => Select,
+            54 => Format(self.number_format()?),
+            55 => Statement,
+            56 => Checkpoint(self.isize()?),
+            57 => Line(self.usize()?),
+            op => return Err(invalid(&format!("Unknown opcode {}", op))),
+        })
+    }
+}
+
+impl Program {
+    /// Serializes the program's code and constants. Callables and properties aren't included, so
+    /// they need to be registered again with the interpreter that runs the deserialized program.
+    /// Debug symbols and group info aren't included either.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer {bytes: Vec::new()};
+        w.bytes.extend(MAGIC);
+        w.bytes.extend(BYTECODE_VERSION.to_le_bytes());
+        w.u32(self.constants.len());
+        for constant in self.constants.iter() {
+            w.value(constant);
+        }
+        w.u32(self.code.len());
+        for op in self.code.iter() {
+            w.op(op);
+        }
+        w.u32(self.timeouts.len());
+        // sorted so the same program always serializes the same way
+        for (name, timeout) in self.timeouts.iter().sorted() {
+            w.string(name);
+            w.duration(timeout);
+        }
+        w.u32(self.lines.len());
+        for (addr, line) in self.lines.iter() {
+            w.usize(*addr);
+            w.usize(*line);
+        }
+        w.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, Error> {
+        let mut r = Reader {bytes, pos: 0};
+        if r.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(invalid("Not an Ai program"));
+        }
+        let version = r.u16()?;
+        if version != BYTECODE_VERSION {
+            return Err(Error::UnsupportedBytecodeVersion(version));
+        }
+
+        let mut constants = Vec::new();
+        for _ in 0..r.u32()? {
+            constants.push(r.value()?);
+        }
+        let mut code = Vec::new();
+        for _ in 0..r.u32()? {
+            code.push(r.op()?);
+        }
+        let mut timeouts = HashMap::new();
+        for _ in 0..r.u32()? {
+            timeouts.insert(r.string()?, r.duration()?);
+        }
+        let mut lines = Vec::new();
+        for _ in 0..r.u32()? {
+            lines.push((r.usize()?, r.usize()?));
+        }
+        if r.pos != bytes.len() {
+            return Err(invalid("Trailing bytes after program"));
+        }
+
+        Ok(Program {
+            code,
+            constants,
+            timeouts,
+            symbols: DebugSymbols::default(),
+            group_info: HashMap::new(),
+            groups: None,
+            callables: HashMap::new(),
+            props: HashMap::new(),
+            lines,
+        })
+    }
+}
@@ -1,21 +1,27 @@
 #![allow(dead_code)]
 use std::fmt::{Display, Formatter, Result as FmtResult};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, BTreeMap};
 use unicode_segmentation::{UnicodeSegmentation};
 use itertools::Itertools;
 
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
-use crate::token::{TokenType};
+use crate::token::{Token, TokenType};
 use crate::token::{Literal as LexLiteral};
 use crate::ast::{Arg as AstArg, *};
-use crate::error::Error;
+use crate::error::{Error, Warning};
+use crate::interpreter::Interpreter;
+use crate::units::{Unit, Dimension};
+use crate::lexer::is_keyword;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Number(f64),
     String(String),
     Bool(bool),
+    List(Vec<Value>),
     Nil,
 }
 
@@ -25,6 +31,7 @@ impl Value {
             Value::Number(n) => *n != 0.0,
             Value::String(s) => s.len() > 0,
             Value::Bool(b) => *b,
+            Value::List(l) => !l.is_empty(),
             Value::Nil => false,
         }
     }
@@ -36,6 +43,35 @@ impl Value {
     pub fn is_str(&self) -> bool {
         if let Value::String(_) = self {true} else {false}
     }
+
+    /// The name of the value's type, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "a number",
+            Value::String(_) => "a string",
+            Value::Bool(_) => "a boolean",
+            Value::List(_) => "a list",
+            Value::Nil => "nil",
+        }
+    }
+
+    // The element at `index`, counting characters rather than bytes in strings. `None` if it's out
+    // of range, or the value can't be indexed.
+    pub(crate) fn element(&self, index: usize) -> Option<Value> {
+        match self {
+            Value::String(s) => s.chars().nth(index).map(|c| Value::String(c.into())),
+            Value::List(l) => l.get(index).cloned(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn len(&self) -> Option<usize> {
+        match self {
+            Value::String(s) => Some(s.chars().count()),
+            Value::List(l) => Some(l.len()),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Value {
@@ -44,17 +80,32 @@ impl Display for Value {
             Value::Number(n) => write!(f, "{}", n),
             Value::String(s) => write!(f, "\"{}\"", s),
             Value::Bool(b) => write!(f, "{}", b),
+            Value::List(l) => write!(f, "[{}]", l.iter().join(", ")),
             Value::Nil => write!(f, "nil"),
         }
     }
 }
 
+macro_rules! parse_string {
+    ($value:expr, $op:expr) => {
+        {
+            if !$value.starts_with("\"") {
+                Err(Error::IRParse{line: 0, msg: format!("'{}' must take a string", $op)})
+            } else if $value.len() < 2 || !$value.ends_with("\"") {
+                Err(Error::IRParse{line: 0, msg: format!("Unterminated string in '{}'", $op)})
+            } else {
+                Ok($value[1..$value.len()-1].to_string())
+            }
+        }
+    }
+}
+
 impl std::str::FromStr for Value {
     type Err = Error;
     fn from_str(value: &str) -> Result<Value, Error> {
-        if value.as_bytes()[0] == b'"' {
-            Ok(Value::String(value.to_string()))
-        } else if value.as_bytes()[0].is_ascii_digit() {
+        if value.starts_with('"') {
+            parse_string!(value, "string").map(Value::String)
+        } else if value.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
             value.parse().map(|n| Value::Number(n)).map_err(|_| Error::IRParse {
                 line: 0,
                 msg: "Invalid word, expected number".into(),
@@ -72,59 +123,460 @@ impl std::str::FromStr for Value {
     }
 }
 
-#[derive(Clone)]
+// The discriminants are the opcodes used in the binary format (see `bytecode.rs`), so existing ones
+// must never change. New ops get the next unused number.
+#[derive(Clone, PartialEq)]
+#[repr(u8)]
 pub enum Op {
     // Use(String),
 
-    Load(usize), // relative index of the variable on the stack
-    Store(usize),
-    Get(String),
-    Set(String),
-    Push(Value),
-    Pop,
-    Dup,
-
-    Add,
-    Sub,
-    Mul,
-    Div,
-    Mod,
-    Exp,
-    Neg,
-    Abs,
-
-    And,
-    Or,
-    Not,
-    Xor,
-
-    Eq,
-    Ne,
-    Lt,
-    Le,
-    Gt,
-    Ge,
-
-    Jump(isize), // absolute position
-    JumpUnless(isize),
-    JumpIf(isize),
-
-    Label(String),
-    Call(String, usize),
-    CallParallel(Vec<(String, usize)>), // first usize is reverse offset. Will always be reverse
-    CallRace(Vec<(String, usize)>),
+    Load(usize) = 0, // relative index of the variable on the stack
+    Store(usize) = 1,
+    Get(String) = 2,
+    Set(String) = 3,
+    Push(Value) = 4,
+    Const(usize) = 5, // index into the program's constants
+    Pop = 6,
+    Dup = 7,
+
+    Add = 8,
+    Sub = 9,
+    Mul = 10,
+    Div = 11,
+    Mod = 12,
+    Exp = 13,
+    Neg = 14,
+    Abs = 15,
+    NormalizeAngle(AngleRange) = 16,
+
+    And = 17,
+    Or = 18,
+    Not = 19,
+    Xor = 20,
+
+    Eq = 21,
+    Ne = 22,
+    Lt = 23,
+    Le = 24,
+    Gt = 25,
+    Ge = 26,
+
+    Jump(isize) = 27, // absolute position
+    JumpUnless(isize) = 28,
+    JumpIf(isize) = 29,
+
+    Label(String) = 30,
+    Call(String, usize) = 31,
+    CallParallel(Vec<(String, usize)>) = 32, // runs the groups side by side, each with its own stack, until they've all returned
+    CallRace(Vec<(String, usize)>) = 33, // like CallParallel, but the first group to return stops the rest
     // StartPara(usize, usize), // call count, total arg count
-    Yield,
-    Return,
+    Yield = 34, // ends the current step, resuming at the next op on the following one
+    Return = 35,
+
+    // A comparison fused with the `JumpUnless` after it. See `fuse_compare_jumps`.
+    JumpUnlessEq(isize) = 36,
+    JumpUnlessNe(isize) = 37,
+    JumpUnlessLt(isize) = 38,
+    JumpUnlessLe(isize) = 39,
+    JumpUnlessGt(isize) = 40,
+    JumpUnlessGe(isize) = 41,
+
+    MakeList(usize) = 42, // element count
+    Sort = 43,
+    Now = 44, // pushes the interpreter clock's time in seconds
+
+    // Counted loops. The counter lives in a local slot, and is always a whole, non-negative number.
+    InitCounter(usize) = 45, // pops the iteration count into the slot
+    DecCounterJumpNonZero(usize, isize) = 46, // slot, jump taken (after decrementing) unless it's 0
+
+    CallValue(String, usize) = 47, // a `Call` that pushes the callable's result
+
+    Round = 48, // to the nearest whole number, with halves rounded away from zero
+    Halt = 49, // stops the program, cancelling everything running in parallel
+    Spawn(String, usize) = 50, // starts a group in the background, without waiting for it
+
+    Index = 51, // pops the index, then the list or string, and pushes the element
+    Len = 52, // the number of elements in a list, or characters in a string
+    Select = 53, // pops the value for false, the value for true, then the condition, and pushes one
+    Format(NumberFormat) = 54, // turns a number into a string
+    Statement = 55, // marks the start of a statement, for `Interpreter::set_yield_per_statement`
+    Checkpoint(isize) = 56, // in a best-effort group, where to carry on from if the next statement fails
+    Line(usize) = 57, // where a line's code starts. Only used while compiling, for `Program::lines`
+}
+
+/// The range angles are wrapped into by `normalize_angle`.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[repr(u8)]
+pub enum AngleRange {
+    #[default]
+    SignedDegrees = 0, // [-180, 180)
+    UnsignedDegrees = 1, // [0, 360)
+    SignedRadians = 2, // [-pi, pi)
+    UnsignedRadians = 3, // [0, 2pi)
+}
+
+impl AngleRange {
+    pub fn normalize(&self, angle: f64) -> f64 {
+        use std::f64::consts::TAU;
+        let (lower, span) = match self {
+            AngleRange::SignedDegrees => (-180.0, 360.0),
+            AngleRange::UnsignedDegrees => (0.0, 360.0),
+            AngleRange::SignedRadians => (-TAU / 2.0, TAU),
+            AngleRange::UnsignedRadians => (0.0, TAU),
+        };
+        let wrapped = (angle - lower).rem_euclid(span) + lower;
+        // rem_euclid can round up to `span` itself for tiny negative inputs
+        if wrapped >= lower + span {lower} else {wrapped}
+    }
+}
+
+impl Display for AngleRange {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            AngleRange::SignedDegrees => write!(f, "signed_degrees"),
+            AngleRange::UnsignedDegrees => write!(f, "unsigned_degrees"),
+            AngleRange::SignedRadians => write!(f, "signed_radians"),
+            AngleRange::UnsignedRadians => write!(f, "unsigned_radians"),
+        }
+    }
+}
+
+impl std::str::FromStr for AngleRange {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<AngleRange, Error> {
+        match value {
+            "signed_degrees" => Ok(AngleRange::SignedDegrees),
+            "unsigned_degrees" => Ok(AngleRange::UnsignedDegrees),
+            "signed_radians" => Ok(AngleRange::SignedRadians),
+            "unsigned_radians" => Ok(AngleRange::UnsignedRadians),
+            _ => Err(Error::IRParse {
+                line: 0,
+                msg: format!("Invalid angle range: '{}'", value),
+            }),
+        }
+    }
+}
+
+/// How `format` writes numbers out as text.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[repr(u8)]
+pub enum NumberFormat {
+    /// Plain decimal, unless the number is very large or very small.
+    Auto = 0,
+    /// Always plain decimal, e.g. `100000000000000000000`.
+    #[default]
+    Fixed = 1,
+    /// Always scientific notation, e.g. `1e20`.
+    Scientific = 2,
+}
+
+impl NumberFormat {
+    pub fn format(&self, n: f64) -> String {
+        let scientific = match self {
+            NumberFormat::Auto => n != 0.0 && n.is_finite() && (n.abs() >= 1e15 || n.abs() < 1e-6),
+            NumberFormat::Fixed => false,
+            NumberFormat::Scientific => true,
+        };
+        if scientific {format!("{:e}", n)} else {format!("{}", n)}
+    }
+}
+
+impl Display for NumberFormat {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            NumberFormat::Auto => write!(f, "auto"),
+            NumberFormat::Fixed => write!(f, "fixed"),
+            NumberFormat::Scientific => write!(f, "scientific"),
+        }
+    }
+}
+
+impl std::str::FromStr for NumberFormat {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<NumberFormat, Error> {
+        match value {
+            "auto" => Ok(NumberFormat::Auto),
+            "fixed" => Ok(NumberFormat::Fixed),
+            "scientific" => Ok(NumberFormat::Scientific),
+            _ => Err(Error::IRParse {
+                line: 0,
+                msg: format!("Invalid number format: '{}'", value),
+            }),
+        }
+    }
+}
+
+/// Broad kinds of operations, used to restrict what a program is allowed to do. See
+/// `Program::check_ops`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OpCategory {
+    Stack, // variables and literals
+    Arithmetic,
+    Logic,
+    Comparison,
+    ControlFlow, // jumps, yields, and calls to groups
+    PropertyRead,
+    PropertyWrite,
+    Call, // calls to registered callables
+    Concurrency, // parallel and race calls
+}
+
+impl OpCategory {
+    pub fn all() -> HashSet<OpCategory> {
+        use OpCategory::*;
+        [Stack, Arithmetic, Logic, Comparison, ControlFlow, PropertyRead, PropertyWrite, Call, Concurrency].into()
+    }
+}
+
+impl Display for OpCategory {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            OpCategory::Stack => write!(f, "stack"),
+            OpCategory::Arithmetic => write!(f, "arithmetic"),
+            OpCategory::Logic => write!(f, "logic"),
+            OpCategory::Comparison => write!(f, "comparison"),
+            OpCategory::ControlFlow => write!(f, "control_flow"),
+            OpCategory::PropertyRead => write!(f, "property_read"),
+            OpCategory::PropertyWrite => write!(f, "property_write"),
+            OpCategory::Call => write!(f, "call"),
+            OpCategory::Concurrency => write!(f, "concurrency"),
+        }
+    }
 }
 
 impl Op {
+    // `Call` is `OpCategory::Call` here, since telling calls to groups apart needs the rest of the
+    // program.
+    fn category(&self) -> OpCategory {
+        use Op::*;
+        match self {
+            Load(_) | Store(_) | Push(_) | Const(_) | Pop | Dup | MakeList(_) | InitCounter(_) => OpCategory::Stack,
+            Add | Sub | Mul | Div | Mod | Exp | Neg | Abs | Round | NormalizeAngle(_) | Format(_) | Sort | Index | Len => OpCategory::Arithmetic,
+            And | Or | Not | Xor | Select => OpCategory::Logic,
+            // Fused jumps are filed under their comparison, since control flow can't be denied
+            // without breaking nearly every program anyway.
+            Eq | Ne | Lt | Le | Gt | Ge | JumpUnlessEq(_) | JumpUnlessNe(_) | JumpUnlessLt(_) |
+            JumpUnlessLe(_) | JumpUnlessGt(_) | JumpUnlessGe(_) => OpCategory::Comparison,
+            Jump(_) | JumpUnless(_) | JumpIf(_) | Label(_) | Yield | Statement | Return | Halt |
+            DecCounterJumpNonZero(_, _) | Checkpoint(_) | Line(_) => OpCategory::ControlFlow,
+            Get(_) | Now => OpCategory::PropertyRead,
+            Set(_) => OpCategory::PropertyWrite,
+            Call(_, _) | CallValue(_, _) => OpCategory::Call,
+            CallParallel(_) | CallRace(_) | Spawn(_, _) => OpCategory::Concurrency,
+        }
+    }
+
     pub fn is_call(&self) -> bool {
         match self {
-            Op::Call(_, _) | Op::CallParallel(_) | Op::CallRace(_) => true,
+            Op::Call(_, _) | Op::CallValue(_, _) | Op::CallParallel(_) | Op::CallRace(_) | Op::Spawn(_, _) => true,
             _ => false,
         }
     }
+
+    pub(crate) fn jump_offset(&self) -> Option<isize> {
+        use Op::*;
+        match self {
+            Jump(a) | JumpUnless(a) | JumpIf(a) | JumpUnlessEq(a) | JumpUnlessNe(a) |
+            JumpUnlessLt(a) | JumpUnlessLe(a) | JumpUnlessGt(a) | JumpUnlessGe(a) |
+            DecCounterJumpNonZero(_, a) | Checkpoint(a) => Some(*a),
+            _ => None,
+        }
+    }
+
+    fn set_jump_offset(&mut self, offset: isize) {
+        use Op::*;
+        match self {
+            Jump(a) | JumpUnless(a) | JumpIf(a) | JumpUnlessEq(a) | JumpUnlessNe(a) |
+            JumpUnlessLt(a) | JumpUnlessLe(a) | JumpUnlessGt(a) | JumpUnlessGe(a) |
+            DecCounterJumpNonZero(_, a) | Checkpoint(a) => *a = offset,
+            _ => {}
+        }
+    }
+
+    fn fused_with_jump_unless(&self, offset: isize) -> Option<Op> {
+        use Op::*;
+        Some(match self {
+            Eq => JumpUnlessEq(offset),
+            Ne => JumpUnlessNe(offset),
+            Lt => JumpUnlessLt(offset),
+            Le => JumpUnlessLe(offset),
+            Gt => JumpUnlessGt(offset),
+            Ge => JumpUnlessGe(offset),
+            _ => return None,
+        })
+    }
+}
+
+// Replaces each comparison directly followed by a `JumpUnless` with a fused op, which saves
+// pushing and popping the boolean in between. This works on the fully laid out program, since
+// removing ops means every jump across them has to be shortened.
+fn fuse_compare_jumps(code: Vec<Op>) -> Vec<Op> {
+    let targets: HashSet<usize> = code.iter().enumerate()
+        .filter_map(|(i, op)| op.jump_offset().map(|a| i.wrapping_add_signed(a)))
+        .collect();
+
+    // Where each old address ends up, plus one past the end for jumps to the end of the program
+    let mut new_addr = Vec::with_capacity(code.len() + 1);
+    let mut fused: Vec<(usize, Op)> = Vec::with_capacity(code.len()); // old address, op
+    let mut ops = code.into_iter().enumerate().peekable();
+    while let Some((i, op)) = ops.next() {
+        new_addr.push(fused.len());
+        // Nothing can jump into the middle of the pair
+        if let Some((j, Op::JumpUnless(a))) = ops.peek() && !targets.contains(j) && let Some(f) = op.fused_with_jump_unless(*a) {
+            new_addr.push(fused.len());
+            fused.push((*j, f));
+            ops.next();
+            continue;
+        }
+        fused.push((i, op));
+    }
+    new_addr.push(fused.len());
+
+    fused.into_iter().enumerate().map(|(addr, (old, mut op))| {
+        if let Some(a) = op.jump_offset() && let Some(target) = new_addr.get(old.wrapping_add_signed(a)) {
+            op.set_jump_offset(*target as isize - addr as isize);
+        }
+        op
+    }).collect()
+}
+
+// Takes the `Line` markers back out of the fully laid out program, returning where each line's code
+// starts. Jumps to a marker go to whatever follows it.
+fn strip_line_markers(code: Vec<Op>) -> (Vec<Op>, Vec<(usize, usize)>) {
+    let mut new_addr = Vec::with_capacity(code.len() + 1);
+    let mut lines: Vec<(usize, usize)> = Vec::new();
+    let mut kept: Vec<(usize, Op)> = Vec::with_capacity(code.len()); // old address, op
+    for (i, op) in code.into_iter().enumerate() {
+        new_addr.push(kept.len());
+        let Op::Line(line) = op else {
+            kept.push((i, op));
+            continue;
+        };
+        // Where markers meet, like a `do` loop's and its first statement's, the innermost wins
+        match lines.last_mut() {
+            Some(last) if last.0 == kept.len() => last.1 = line,
+            Some(last) if last.1 == line => {}
+            _ => lines.push((kept.len(), line)),
+        }
+    }
+    new_addr.push(kept.len());
+
+    let code = kept.into_iter().enumerate().map(|(addr, (old, mut op))| {
+        if let Some(a) = op.jump_offset() && let Some(target) = new_addr.get(old.wrapping_add_signed(a)) {
+            op.set_jump_offset(*target as isize - addr as isize);
+        }
+        op
+    }).collect();
+    (code, lines)
+}
+
+// The local slots `code` might load before anything's been stored in them, on some path through it,
+// each with the address of the first such load. The first `initialized` slots are parameters, which
+// always hold a value. Jumps out of `code` are treated as leaving it.
+fn possibly_uninitialized(code: &[Op], initialized: usize) -> BTreeMap<usize, usize> {
+    // Which slots are definitely stored on entry to each op, or None if it hasn't been reached yet
+    let mut stored: Vec<Option<Vec<bool>>> = vec![None; code.len()];
+    let mut read = BTreeMap::new();
+    let locals = code.iter().filter_map(|op| match op {
+        Op::Load(a) | Op::Store(a) | Op::InitCounter(a) => Some(a + 1),
+        _ => None,
+    }).max().unwrap_or(0).max(initialized);
+    if code.is_empty() {
+        return read;
+    }
+    stored[0] = Some((0..locals).map(|slot| slot < initialized).collect());
+    let mut work = vec![0];
+    while let Some(i) = work.pop() {
+        let mut state = stored[i].clone().unwrap();
+        let op = &code[i];
+        match op {
+            Op::Load(a) if !state[*a] => {
+                let first = read.entry(*a).or_insert(i);
+                *first = (*first).min(i);
+            }
+            Op::Store(a) | Op::InitCounter(a) => state[*a] = true,
+            _ => {}
+        }
+        let mut next = Vec::new();
+        if !matches!(op, Op::Jump(_) | Op::Return | Op::Halt) {
+            next.push(i + 1);
+        }
+        if let Some(offset) = op.jump_offset() {
+            next.push(i.wrapping_add_signed(offset));
+        }
+        for n in next.into_iter().filter(|n| *n < code.len()) {
+            let changed = match &mut stored[n] {
+                Some(before) => {
+                    let mut changed = false;
+                    for (b, s) in before.iter_mut().zip(state.iter()) {
+                        changed |= *b && !s;
+                        *b &= s;
+                    }
+                    changed
+                }
+                slot => {
+                    *slot = Some(state.clone());
+                    true
+                }
+            };
+            if changed {
+                work.push(n);
+            }
+        }
+    }
+    read
+}
+
+// Turns each call a group makes to itself as its very last action into a jump back to its start,
+// so the call reuses the group's frame. `code` is the whole group, laid out as its label, `locals`
+// pushes reserving its locals, the body, and the pops and return that clean up its `params` and
+// locals. The arguments are moved into the parameters' slots, and the locals are reset, just as
+// they would be for a fresh call.
+fn eliminate_tail_calls(code: Vec<Op>, name: &str, params: usize, locals: usize) -> Vec<Op> {
+    let epilogue = code.len() - 1 - (params + locals);
+    let start = 1 + locals;
+    // Only jumps forward can be on the way out, since anything else would run more code
+    let is_tail = |mut i: usize| loop {
+        match code.get(i) {
+            _ if i == epilogue => return true,
+            Some(Op::Jump(a)) if *a > 0 => i = i.wrapping_add_signed(*a),
+            _ => return false,
+        }
+    };
+    let tails: HashSet<usize> = code.iter().enumerate()
+        .filter(|(i, op)| matches!(op, Op::Call(called, arity) if called == name && *arity == params) && is_tail(i + 1))
+        .map(|(i, _)| i)
+        .collect();
+    if tails.is_empty() {
+        return code;
+    }
+
+    let expansion = params + 2 * locals + 1;
+    let mut new_addr = Vec::with_capacity(code.len() + 1);
+    let mut addr = 0;
+    for i in 0..=code.len() {
+        new_addr.push(addr);
+        addr += if tails.contains(&i) {expansion} else {1};
+    }
+
+    let mut out = Vec::with_capacity(addr);
+    for (i, mut op) in code.into_iter().enumerate() {
+        if tails.contains(&i) {
+            // The last argument is on top
+            out.extend((0..params).rev().map(Op::Store));
+            for slot in params..params + locals {
+                out.push(Op::Push(Value::Nil));
+                out.push(Op::Store(slot));
+            }
+            out.push(Op::Jump(start as isize - out.len() as isize));
+            continue;
+        }
+        if let Some(a) = op.jump_offset() && let Some(target) = new_addr.get(i.wrapping_add_signed(a)) {
+            op.set_jump_offset(*target as isize - new_addr[i] as isize);
+        }
+        out.push(op);
+    }
+    out
 }
 
 fn format_calls(calls: &[(String, usize)]) -> String {
@@ -143,18 +595,23 @@ impl Display for Op {
             Load(a) => write!(f, "load {}", a),
             Store(a) => write!(f, "store {}", a),
             Get(s) => write!(f, "get \"{}\"", s),
-            Set(s) => write!(f, "get \"{}\"", s),
+            Set(s) => write!(f, "set \"{}\"", s),
             Push(v) => write!(f, "push {}", v),
+            Const(i) => write!(f, "const {}", i),
             Jump(a) => write!(f, "jump {}", a),
             JumpUnless(a) => write!(f, "jump_unless {}", a),
             JumpIf(a) => write!(f, "jump_if {}", a),
             Label(s) => write!(f, "{}:", s),
             Call(s, p) => write!(f, "call \"{}\" {}", s, p),
+            CallValue(s, p) => write!(f, "call_value \"{}\" {}", s, p),
+            Spawn(s, p) => write!(f, "spawn \"{}\" {}", s, p),
             // StartPara(n, m) => write!(f, "start_para {} {}", n, m),
             CallParallel(calls) => write!(f, "call_parallel{}", format_calls(calls)),
             CallRace(calls) => write!(f, "call_race{}", format_calls(calls)),
             Return => write!(f, "return"),
             Yield => write!(f, "yield"),
+            Statement => write!(f, "statement"),
+            Line(l) => write!(f, "line {}", l),
             Pop => write!(f, "pop"),
             Dup => write!(f, "dup"),
             Add => write!(f, "add"),
@@ -165,6 +622,13 @@ impl Display for Op {
             Exp => write!(f, "exp"),
             Neg => write!(f, "neg"),
             Abs => write!(f, "abs"),
+            Round => write!(f, "round"),
+            Index => write!(f, "index"),
+            Select => write!(f, "select"),
+            Format(n) => write!(f, "format {}", n),
+            Len => write!(f, "len"),
+            Halt => write!(f, "halt"),
+            NormalizeAngle(r) => write!(f, "normalize_angle {}", r),
             And => write!(f, "and"),
             Or => write!(f, "or"),
             Not => write!(f, "not"),
@@ -175,6 +639,18 @@ impl Display for Op {
             Le => write!(f, "le"),
             Gt => write!(f, "gt"),
             Ge => write!(f, "ge"),
+            JumpUnlessEq(a) => write!(f, "jump_unless_eq {}", a),
+            JumpUnlessNe(a) => write!(f, "jump_unless_ne {}", a),
+            JumpUnlessLt(a) => write!(f, "jump_unless_lt {}", a),
+            JumpUnlessLe(a) => write!(f, "jump_unless_le {}", a),
+            JumpUnlessGt(a) => write!(f, "jump_unless_gt {}", a),
+            JumpUnlessGe(a) => write!(f, "jump_unless_ge {}", a),
+            MakeList(n) => write!(f, "make_list {}", n),
+            Sort => write!(f, "sort"),
+            Now => write!(f, "now"),
+            InitCounter(a) => write!(f, "init_counter {}", a),
+            DecCounterJumpNonZero(a, j) => write!(f, "dec_counter_jump_nonzero {} {}", a, j),
+            Checkpoint(a) => write!(f, "checkpoint {}", a),
         }
     }
 }
@@ -183,26 +659,23 @@ fn shell_split(input: &str) -> Vec<&str> {
     let mut parts = Vec::new();
     let mut start = 0;
     let mut quoted = false;
-    let mut last_space = false;
     
     for (i, g) in input.grapheme_indices(true) {
-        if last_space {
-            last_space = false;
-            start += 1;
-            continue;
-        }
         if g == "\"" {
             quoted = !quoted;
             continue;
         }
         if g == " " && !quoted {
-            last_space = true;
-            parts.push(&input[start..i]);
+            if start < i {
+                parts.push(&input[start..i]);
+            }
             start = i+1;
         }
     }
 
-    parts.push(&input[start..]);
+    if start < input.len() {
+        parts.push(&input[start..]);
+    }
 
     parts
 }
@@ -244,20 +717,6 @@ macro_rules! expect_len {
 }
 
 
-macro_rules! parse_string {
-    ($value:expr, $op:expr) => {
-        {
-            if !$value.starts_with("\"") {
-                Err(Error::IRParse{line: 0, msg: format!("'{}' must take a string", $op)})
-            } else if $value.len() < 2 || !$value.ends_with("\"") {
-                Err(Error::IRParse{line: 0, msg: format!("Unterminated string in '{}'", $op)})
-            } else {
-                Ok($value[1..$value.len()-1].to_string())
-            }
-        }
-    }
-}
-
 impl std::str::FromStr for Op {
     type Err = Error;
     fn from_str(value: &str) -> Result<Op, Error> {
@@ -281,6 +740,35 @@ impl std::str::FromStr for Op {
             "get" => Ok(Op::Get(parse_string!(expect_len!(parts, 1, "get"), "get")?)),
             "set" => Ok(Op::Set(parse_string!(expect_len!(parts, 1, "set"), "set")?)),
             "push" => expect_len!(parts, 1, "push").parse().map(|v| Op::Push(v)),
+            "const" => expect_len!(parts, 1, "const").parse().map(Op::Const).map_err(|_| Error::IRParse {
+                line: 0,
+                msg: format!("Invalid constant index: '{}'", parts[1]),
+            }),
+            "line" => expect_len!(parts, 1, "line").parse().map(Op::Line).map_err(|_| Error::IRParse {
+                line: 0,
+                msg: format!("Invalid line number: '{}'", parts[1]),
+            }),
+            "make_list" => expect_len!(parts, 1, "make_list").parse().map(Op::MakeList).map_err(|_| Error::IRParse {
+                line: 0,
+                msg: format!("Invalid element count: '{}'", parts[1]),
+            }),
+            "sort" => Ok(Op::Sort),
+            "now" => Ok(Op::Now),
+            "init_counter" => expect_len!(parts, 1, "init_counter").parse().map(Op::InitCounter).map_err(|_| Error::IRParse {
+                line: 0,
+                msg: format!("Invalid stack address: '{}'", parts[1]),
+            }),
+            "dec_counter_jump_nonzero" => {
+                let offset = expect_len!(parts, 2, "dec_counter_jump_nonzero").parse().map_err(|_| Error::IRParse {
+                    line: 0,
+                    msg: format!("Invalid jump offset: '{}'", parts[2]),
+                })?;
+                let slot = parts[1].parse().map_err(|_| Error::IRParse {
+                    line: 0,
+                    msg: format!("Invalid stack address: '{}'", parts[1]),
+                })?;
+                Ok(Op::DecCounterJumpNonZero(slot, offset))
+            }
             "jump" => expect_len!(parts, 1, "jump").parse().map(|a| Op::Jump(a)).map_err(|_| Error::IRParse {
                 line: 0,
                 msg: format!("Invalid stack address: '{}'", parts[1]),
@@ -293,18 +781,41 @@ impl std::str::FromStr for Op {
                 line: 0,
                 msg: format!("Invalid stack address: '{}'", parts[1]),
             }),
-            "call" => {
-                let arity = expect_len!(parts, 2, "call").parse().map_err(|_| Error::IRParse {
+            "checkpoint" => expect_len!(parts, 1, "checkpoint").parse().map(Op::Checkpoint).map_err(|_| Error::IRParse {
+                line: 0,
+                msg: format!("Invalid jump offset: '{}'", parts[1]),
+            }),
+            cmp @ ("jump_unless_eq" | "jump_unless_ne" | "jump_unless_lt" | "jump_unless_le" | "jump_unless_gt" | "jump_unless_ge") => {
+                let a = expect_len!(parts, 1, cmp).parse().map_err(|_| Error::IRParse {
+                    line: 0,
+                    msg: format!("Invalid stack address: '{}'", parts[1]),
+                })?;
+                Ok(match cmp {
+                    "jump_unless_eq" => Op::JumpUnlessEq(a),
+                    "jump_unless_ne" => Op::JumpUnlessNe(a),
+                    "jump_unless_lt" => Op::JumpUnlessLt(a),
+                    "jump_unless_le" => Op::JumpUnlessLe(a),
+                    "jump_unless_gt" => Op::JumpUnlessGt(a),
+                    _ => Op::JumpUnlessGe(a),
+                })
+            }
+            call @ ("call" | "call_value" | "spawn") => {
+                let arity = expect_len!(parts, 2, call).parse().map_err(|_| Error::IRParse {
                     line: 0,
                     msg: "Calls require the arity as a second argument".into(),
                 })?;
-                let name = parse_string!(parts[1], "call")?;
-                Ok(Op::Call(name, arity))
+                let name = parse_string!(parts[1], call)?;
+                Ok(match call {
+                    "call" => Op::Call(name, arity),
+                    "call_value" => Op::CallValue(name, arity),
+                    _ => Op::Spawn(name, arity),
+                })
             }
             "call_parallel" => Ok(Op::CallParallel(parse_parallel_args(&parts[1..])?)),
             "call_race" => Ok(Op::CallRace(parse_parallel_args(&parts[1..])?)),
             "return" => Ok(Op::Return),
             "yield" => Ok(Op::Yield),
+            "statement" => Ok(Op::Statement),
             "pop" => Ok(Op::Pop),
             "dup" => Ok(Op::Dup),
             "add" => Ok(Op::Add),
@@ -315,6 +826,13 @@ impl std::str::FromStr for Op {
             "exp" => Ok(Op::Exp),
             "neg" => Ok(Op::Neg),
             "abs" => Ok(Op::Abs),
+            "round" => Ok(Op::Round),
+            "index" => Ok(Op::Index),
+            "len" => Ok(Op::Len),
+            "select" => Ok(Op::Select),
+            "halt" => Ok(Op::Halt),
+            "normalize_angle" => Ok(Op::NormalizeAngle(expect_len!(parts, 1, "normalize_angle").parse()?)),
+            "format" => Ok(Op::Format(expect_len!(parts, 1, "format").parse()?)),
             "and" => Ok(Op::And),
             "or" => Ok(Op::Or),
             "not" => Ok(Op::Not),
@@ -339,15 +857,31 @@ impl std::str::FromStr for Op {
     }
 }
 
+/// A single call in progress. The interpreter evaluates the call's arguments and hands them, in
+/// order, to `CallableGenerator::generate`, which makes a fresh `Callable` for each call, so a
+/// callable holds onto whatever arguments it needs itself. `call` is then polled until it returns
+/// true.
 pub trait Callable: Send + Sync {
     fn call(&mut self) -> Result<bool, Error>;
     fn terminate(&mut self) -> Result<(), Error> {Ok(())}
+    /// The value the call produced, read once `call` returns true. Only used when the signature
+    /// says the callable returns something.
+    fn result(&mut self) -> Value {Value::Nil}
     // fn arity(&self) -> usize;
 }
 
+#[allow(unused_variables)]
 pub trait CallableGenerator: Send + Sync {
     fn generate(&mut self, args: Vec<Value>) -> Result<Box<dyn Callable>, Error>;
-    fn check_syntax(&self, args: Vec<Arg>) -> Result<(), Error>;
+    /// Custom validation, run after the declarative `signature` (if any) has been checked.
+    fn check_syntax(&self, args: Vec<Arg>) -> Result<(), Error> {Ok(())}
+    /// The arguments this callable expects. The compiler validates calls against this before
+    /// `check_syntax` is called, so simple callables don't need to implement `check_syntax` at all.
+    /// The interpreter checks the values of each call against it too, before calling `generate`.
+    fn signature(&self) -> Option<Signature> {None}
+    /// Roughly how long a call takes, for `Program::estimated_cost`. `args` holds the value of each
+    /// argument, if it's known ahead of time.
+    fn estimated_duration(&self, args: &[Option<Value>]) -> Option<Duration> {None}
 }
 
 #[allow(unused_variables)]
@@ -355,6 +889,45 @@ pub trait Prop: Send + Sync {
     fn get(&self) -> Result<Value, Error>;
     fn set(&mut self, val: Value) -> Result<(), Error> {Ok(())}
     fn settable(&self) -> Result<bool, Error> {Ok(false)}
+    /// Whether reading the property has no side effects, and reading it twice in a row gives the
+    /// same value. A pure property is read at most once per statement, however often the statement
+    /// uses it.
+    fn pure(&self) -> bool {false}
+    /// The unit the property's value is in, if it's a number with one. The compiler converts
+    /// values in other units of the same kind when they're assigned to it or combined with it.
+    fn unit(&self) -> Option<Unit> {None}
+}
+
+/// A settable property backed by a pair of closures, for when implementing `Prop` would be
+/// overkill.
+pub struct ComputedProp {
+    get: Mutex<Box<dyn FnMut() -> Value + Send>>,
+    set: Mutex<Box<dyn FnMut(Value) + Send>>,
+}
+
+impl ComputedProp {
+    pub fn new<G, S>(get: G, set: S) -> ComputedProp
+    where
+        G: FnMut() -> Value + Send + 'static,
+        S: FnMut(Value) + Send + 'static,
+    {
+        ComputedProp {get: Mutex::new(Box::new(get)), set: Mutex::new(Box::new(set))}
+    }
+}
+
+impl Prop for ComputedProp {
+    fn get(&self) -> Result<Value, Error> {
+        let mut get = self.get.lock().map_err(|_| Error::ThreadingError)?;
+        Ok(get())
+    }
+
+    fn set(&mut self, val: Value) -> Result<(), Error> {
+        let set = self.set.get_mut().map_err(|_| Error::ThreadingError)?;
+        set(val);
+        Ok(())
+    }
+
+    fn settable(&self) -> Result<bool, Error> {Ok(true)}
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -390,12 +963,179 @@ impl Display for Arg {
     }
 }
 
+/// A single parameter in a callable's `Signature`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Param {
+    Number,
+    String,
+    Any,
+    Word(String),
+    Optional(Box<Param>),
+    /// Any number of arguments that fit the parameter, including none. The callable gets however
+    /// many values were passed, so `generate` can tell how many it got from its `args`.
+    Variadic(Box<Param>),
+    /// A number in `unit`. Arguments in other units of the same kind are converted.
+    Quantity(Unit),
+}
+
+impl Param {
+    // Values that aren't known at compile time can only be checked once they're known, so
+    // they're accepted here.
+    fn accepts(&self, arg: &Arg, value: Option<&Value>) -> bool {
+        match (self, arg) {
+            (Param::Word(w), Arg::Word(a)) => w == a,
+            (Param::Number | Param::Quantity(_), Arg::Value) => value.map(|v| v.is_num()).unwrap_or(true),
+            (Param::String, Arg::Value) => value.map(|v| v.is_str()).unwrap_or(true),
+            (Param::Any, Arg::Value) => true,
+            (Param::Optional(p) | Param::Variadic(p), _) => p.accepts(arg, value),
+            _ => false,
+        }
+    }
+
+    fn unit(&self) -> Option<Unit> {
+        match self {
+            Param::Quantity(unit) => Some(*unit),
+            Param::Optional(p) | Param::Variadic(p) => p.unit(),
+            _ => None,
+        }
+    }
+
+    fn takes_value(&self) -> bool {
+        match self {
+            Param::Word(_) => false,
+            Param::Optional(p) | Param::Variadic(p) => p.takes_value(),
+            _ => true,
+        }
+    }
+}
+
+impl Display for Param {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Param::Number => write!(f, "<number>"),
+            Param::String => write!(f, "<string>"),
+            Param::Any => write!(f, "<value>"),
+            Param::Word(w) => write!(f, "{}", w),
+            Param::Optional(p) => write!(f, "[{}]", p),
+            Param::Variadic(p) => write!(f, "{}...", p),
+            Param::Quantity(unit) => write!(f, "<{}>", unit),
+        }
+    }
+}
+
+/// A declarative description of the arguments a callable accepts. Optional parameters are
+/// skipped if the argument in their position doesn't fit them.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Signature {
+    pub params: Vec<Param>,
+    /// Whether the callable leaves a value behind, which lets its result be assigned to a
+    /// variable, as in `$d = measure;`.
+    pub returns: bool,
+}
+
+impl Signature {
+    pub fn new(params: Vec<Param>) -> Signature {
+        Signature {params, returns: false}
+    }
+
+    /// A callable that takes any number of arguments that fit `param`, like a `log` command.
+    pub fn variadic(param: Param) -> Signature {
+        Signature::new(vec![Param::Variadic(Box::new(param))])
+    }
+
+    pub fn returning(mut self) -> Signature {
+        self.returns = true;
+        self
+    }
+
+    /// `values` holds the value of each argument, if it's known at compile time.
+    pub fn check(&self, name: &str, args: &[Arg], values: &[Option<Value>]) -> Result<(), Error> {
+        self.bind(name, args, values).map(|_| ())
+    }
+
+    // Like `check`, but also returns the parameter each argument was matched to
+    fn bind(&self, name: &str, args: &[Arg], values: &[Option<Value>]) -> Result<Vec<&Param>, Error> {
+        let mut bound = Vec::new();
+        for param in self.params.iter() {
+            let i = bound.len();
+            let arg = args.get(i);
+            let value = values.get(i).and_then(|v| v.as_ref());
+            match (param, arg) {
+                (Param::Variadic(_), _) => {
+                    while let Some(arg) = args.get(bound.len()) && param.accepts(arg, values.get(bound.len()).and_then(|v| v.as_ref())) {
+                        bound.push(param);
+                    }
+                }
+                (Param::Optional(_), Some(arg)) if !param.accepts(arg, value) => continue,
+                (Param::Optional(_), None) => continue,
+                (_, None) => {
+                    return Err(Error::Call(format!("Call to '{}' is missing an argument. Expected '{} {}'",
+                                                    name, name, self)));
+                }
+                (_, Some(arg)) if !param.accepts(arg, value) => {
+                    return Err(Error::Call(format!("Argument at position {} of '{}' should be {}", i, name, param)));
+                }
+                _ => bound.push(param),
+            }
+        }
+        if bound.len() < args.len() {
+            return Err(Error::Call(format!("Call to '{}' has too many arguments. Expected '{} {}'",
+                                            name, name, self)));
+        }
+        Ok(bound)
+    }
+
+    /// Checks the values a call receives at runtime, which catches what `check` can't know ahead
+    /// of time, like the type of a property. Words are resolved by the compiler and never make it
+    /// to runtime, so only the parameters that take values are checked.
+    pub fn check_values(&self, name: &str, values: &[Value]) -> Result<(), Error> {
+        let mut values = values.iter().enumerate().peekable();
+        for param in self.params.iter().filter(|p| p.takes_value()) {
+            match (param, values.peek().copied()) {
+                (Param::Variadic(_), _) => {
+                    while values.next_if(|(_, value)| param.accepts(&Arg::Value, Some(value))).is_some() {}
+                }
+                (Param::Optional(_), Some((_, value))) if !param.accepts(&Arg::Value, Some(value)) => continue,
+                (Param::Optional(_), None) => continue,
+                (_, None) => {
+                    return Err(Error::Call(format!("Call to '{}' is missing an argument. Expected '{} {}'",
+                                                    name, name, self)));
+                }
+                (_, Some((i, value))) if !param.accepts(&Arg::Value, Some(value)) => {
+                    return Err(Error::Call(format!("Value {} passed to '{}' should be {}, but was {}",
+                                                    i, name, param, value.type_name())));
+                }
+                _ => {values.next();}
+            }
+        }
+        if values.next().is_some() {
+            return Err(Error::Call(format!("Call to '{}' has too many arguments. Expected '{} {}'",
+                                            name, name, self)));
+        }
+        Ok(())
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.params.iter().join(" "))
+    }
+}
 
 #[derive(Clone)]
 struct GroupData {
     name: String,
     // address: isize,
     params: Vec<Arg>,
+    inout: Vec<bool>, // for each value parameter
+}
+
+impl GroupData {
+    // Groups with inout parameters leave all of their parameters on the stack for the caller to
+    // clean up, so that it can write the inout ones back to its own variables.
+    fn has_inout(&self) -> bool {
+        self.inout.contains(&true)
+    }
 }
 
 #[derive(Clone)]
@@ -431,28 +1171,308 @@ impl CallableGenerator for CompiledGroup {
     }
 }
 
+/// The names of the variables in each slot of a frame, indexed by slot, for debuggers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugSymbols {
+    pub main: Vec<String>,
+    pub groups: HashMap<String, Vec<String>>,
+}
+
+// Slot names in slot order
+fn slot_names(scope: &HashMap<String, usize>) -> Vec<String> {
+    let mut names = vec![String::new(); scope.len()];
+    for (name, slot) in scope.iter() {
+        names[*slot] = name.clone();
+    }
+    names
+}
+
+/// What a group looks like from the outside, for generating documentation and help text.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GroupInfo {
+    pub params: Vec<Arg>,
+    /// From the group's `///` doc comments or `doc` attribute, with one line per comment.
+    pub description: Option<String>,
+}
+
 pub struct Program {
     pub code: Vec<Op>,
+    pub constants: Vec<Value>,
+    pub timeouts: HashMap<String, Duration>, // group name -> how long it may run before it's abandoned
+    pub symbols: DebugSymbols,
+    pub group_info: HashMap<String, GroupInfo>, // only for programs compiled from source
+    // Group name -> address of its label, so interpreters don't each have to find them. `None`
+    // for programs that weren't compiled, which are scanned instead. Set this back to `None` after
+    // changing `code` by hand.
+    pub groups: Option<HashMap<String, usize>>,
     pub callables: HashMap<String, Box<dyn CallableGenerator>>,
     pub props: HashMap<String, Box<dyn Prop>>,
+    // Address -> the source line the code from there on came from, in address order. Only for
+    // programs compiled from source. See `Interpreter::source_line`.
+    pub lines: Vec<(usize, usize)>,
 }
 
 impl Program {
+    /// Constants are exported ahead of the code as `.const` lines, in pool order.
     pub fn export(&self) -> String {
-        self.code.iter().join("\n")
+        self.constants.iter().map(|c| format!(".const {}", c))
+            .chain(self.code.iter().map(|op| op.to_string()))
+            .join("\n")
     }
-}
 
+    /// Checks that the program only uses operations in the `allowed` categories, returning
+    /// `Error::ForbiddenOperation` for the first one that isn't. Meant for hosts that run untrusted
+    /// programs.
+    pub fn check_ops(&self, allowed: &HashSet<OpCategory>) -> Result<(), Error> {
+        check_ops(&self.code, allowed)
+    }
 
-pub struct Compiler {
-    groups: HashMap<String, CompiledGroup>,
-    instructions: Vec<Op>,
+    /// Every number and string literal the program uses, in the order they appear in the code.
+    /// Literals used more than once are listed each time.
+    pub fn literals(&self) -> Vec<&Value> {
+        self.code.iter().filter_map(|op| match op {
+            Op::Push(v) => Some(v),
+            Op::Const(i) => self.constants.get(*i),
+            _ => None,
+        }).filter(|v| matches!(v, Value::Number(_) | Value::String(_))).collect()
+    }
+
+    /// The names of the program's groups, in the order they're laid out. Groups the compiler
+    /// generates for itself, whose names start with `#`, aren't included.
+    pub fn group_names(&self) -> Vec<&str> {
+        self.code.iter().filter_map(|op| match op {
+            Op::Label(name) if !name.starts_with('#') => Some(name.as_str()),
+            _ => None,
+        }).collect()
+    }
+
+    /// Runs the group `name` on its own with `args`, as `Interpreter::call_group` would, for
+    /// testing one command at a time. `props` and `callables` stand in for the program's own, so
+    /// they can be mocks, but like any run, they have to cover everything the program uses. Returns
+    /// once the group finishes.
+    pub fn test_group(&self, name: &str, args: Vec<Value>, props: HashMap<String, Box<dyn Prop>>,
+                      callables: HashMap<String, Box<dyn CallableGenerator>>) -> Result<(), Error> {
+        let program = Program {
+            code: self.code.clone(),
+            constants: self.constants.clone(),
+            timeouts: self.timeouts.clone(),
+            symbols: self.symbols.clone(),
+            group_info: self.group_info.clone(),
+            groups: self.groups.clone(),
+            callables,
+            props,
+            lines: self.lines.clone(),
+        };
+        let mut interpreter = Interpreter::from_program(program);
+        interpreter.call_group(name, args)?;
+        interpreter.interpret()
+    }
+
+    /// Combines separately compiled programs into one. Every group can be called from anywhere in
+    /// the result, and the top-level code of each program runs in the order they're given, so a
+    /// top-level `return` in one ends the programs after it too. Group names have to be unique
+    /// across all the programs.
+    pub fn link(programs: Vec<Program>) -> Result<Program, Vec<Error>> {
+        let mut errors = Vec::new();
+        let mut labels = HashSet::new();
+        let mut groups = Vec::new();
+        let mut main = Vec::new();
+        // Where each program's lines ended up, relative to the start of `groups` and `main`
+        let mut group_lines = Vec::new();
+        let mut main_lines = Vec::new();
+        let mut locals = 0;
+        let mut linked = Program {
+            code: Vec::new(),
+            constants: Vec::new(),
+            timeouts: HashMap::new(),
+            symbols: DebugSymbols::default(),
+            group_info: HashMap::new(),
+            groups: None,
+            callables: HashMap::new(),
+            props: HashMap::new(),
+            lines: Vec::new(),
+        };
+
+        for (i, program) in programs.into_iter().enumerate() {
+            let mut code = program.code;
+            // Every program starts by jumping over its groups to the top-level code
+            let start = match code.first() {
+                Some(Op::Jump(a)) if *a >= 1 && *a as usize <= code.len() => *a as usize,
+                _ => {
+                    errors.push(Error::Link(i, "code doesn't start with a jump past its groups".into()));
+                    continue;
+                }
+            };
+            let constants = linked.constants.len();
+            let relocate = |mut op: Op| {
+                if let Op::Const(c) = &mut op {
+                    *c += constants;
+                }
+                op
+            };
+
+            let top_level: Vec<Op> = code.drain(start..).map(relocate).collect();
+            let mut chunks: Vec<(usize, Vec<Op>)> = Vec::new(); // address in `code`, ops
+            for (addr, op) in (1..).zip(code.drain(1..)) {
+                match (&op, chunks.last_mut()) {
+                    (Op::Label(_), _) => chunks.push((addr, vec![op])),
+                    (_, Some((_, chunk))) => chunk.push(relocate(op)),
+                    (_, None) => {
+                        errors.push(Error::Link(i, "group code doesn't start with a label".into()));
+                        break;
+                    }
+                }
+            }
+            let mut moved = Vec::new(); // address in `code`, length, address in `groups`
+            for (addr, chunk) in chunks {
+                let Op::Label(name) = &chunk[0] else {unreachable!()};
+                if labels.contains(name) {
+                    // Hidden groups are generated wrappers, so ones with the same name are identical
+                    if !name.starts_with('#') {
+                        errors.push(Error::DuplicateGroup(name.clone()));
+                    }
+                    continue;
+                }
+                labels.insert(name.clone());
+                moved.push((addr, chunk.len(), groups.len()));
+                groups.extend(chunk);
+            }
+            for &(addr, line) in &program.lines {
+                if addr >= start {
+                    main_lines.push((main.len() + addr - start, line));
+                } else if let Some((from, _, to)) = moved.iter().find(|(from, len, _)| (*from..from + len).contains(&addr)) {
+                    group_lines.push((to + addr - from, line));
+                }
+            }
+
+            // The top-level variables of earlier programs are still on the stack, so this program's
+            // go after them.
+            let mut used = 0;
+            for mut op in top_level {
+                if let Op::Load(a) | Op::Store(a) | Op::InitCounter(a) | Op::DecCounterJumpNonZero(a, _) = &mut op {
+                    used = used.max(*a + 1);
+                    *a += locals;
+                }
+                main.push(op);
+            }
+            // Programs loaded from bytecode don't have names for their variables
+            linked.symbols.main.resize(locals, "#".into());
+            linked.symbols.main.extend(program.symbols.main);
+            linked.symbols.groups.extend(program.symbols.groups);
+            linked.group_info.extend(program.group_info);
+            locals += used;
+
+            linked.constants.extend(program.constants);
+            linked.timeouts.extend(program.timeouts);
+            for (name, callable) in program.callables {
+                linked.callables.entry(name).or_insert(callable);
+            }
+            for (name, prop) in program.props {
+                linked.props.entry(name).or_insert(prop);
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        // A program that calls another's group needed a stand-in callable to compile
+        linked.callables.retain(|name, _| !labels.contains(name));
+
+        linked.lines = group_lines.into_iter().map(|(addr, line)| (addr + 1, line))
+            .chain(main_lines.into_iter().map(|(addr, line)| (addr + 1 + groups.len(), line)))
+            .collect();
+        linked.code.push(Op::Jump(groups.len() as isize + 1));
+        linked.code.extend(groups);
+        linked.code.extend(main);
+        Ok(linked)
+    }
+}
+
+// Finds the address of each group's label, for programs that don't have `Program::groups`.
+pub(crate) fn scan_groups(code: &[Op]) -> HashMap<String, usize> {
+    let mut groups = HashMap::new();
+    for (i, op) in code.iter().enumerate() {
+        if let Op::Label(name) = op {
+            groups.insert(name.clone(), i);
+        }
+    }
+    groups
+}
+
+pub(crate) fn check_ops(code: &[Op], allowed: &HashSet<OpCategory>) -> Result<(), Error> {
+    let groups: HashSet<&str> = code.iter().filter_map(|op| match op {
+        Op::Label(name) => Some(name.as_str()),
+        _ => None,
+    }).collect();
+    for (ip, op) in code.iter().enumerate() {
+        let category = match op {
+            Op::Call(name, _) if groups.contains(name.as_str()) => OpCategory::ControlFlow,
+            op => op.category(),
+        };
+        if !allowed.contains(&category) {
+            return Err(Error::ForbiddenOperation(ip, category));
+        }
+    }
+    Ok(())
+}
+
+// `#[timeout(...)]` takes a number followed by a unit, like `5s` or `250 ms`.
+fn parse_timeout(args: &[Token]) -> Result<Duration, String> {
+    let usage = || "Expect a duration like '5s' for 'timeout'".to_string();
+    let [number, unit] = args else {return Err(usage())};
+    let Some(LexLiteral::Number(n)) = number.literal else {return Err(usage())};
+    let secs = match unit.lexeme {
+        "ms" => n / 1000.0,
+        "s" => n,
+        "min" => n * 60.0,
+        "h" => n * 3600.0,
+        _ => return Err(format!("Unknown duration unit '{}'. Expect 'ms', 's', 'min', or 'h'", unit.lexeme)),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|_| "Timeout must be a positive duration".to_string())
+}
+
+// The destination of a jump that was emitted before its destination was known. These are emitted as
+// `Op::Jump(0)`, since a jump of 0 doesn't make any sense otherwise, and backpatched once the
+// construct they belong to is laid out.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum JumpTarget {
+    LoopExit(usize), // depth of the loop in the loop stack
+    LoopContinue(usize), // where the loop goes on to its next iteration from
+    GroupEnd,
+}
+
+pub struct Compiler {
+    groups: HashMap<String, CompiledGroup>,
+    instructions: Vec<Op>,
+    constants: Vec<Value>,
+    interned: HashMap<String, usize>, // string literal -> index in `constants`
+    timeouts: HashMap<String, Duration>,
+    symbols: DebugSymbols,
+    group_info: HashMap<String, GroupInfo>,
     callables: HashMap<String, Box<dyn CallableGenerator>>,
     properties: HashMap<String, Box<dyn Prop>>,
     allowed_props: HashSet<String>,
+    cached_props: HashMap<String, usize>, // pure property -> slot it's cached in for this statement
 
     variables: Vec<HashMap<String, usize>>,
+    variable_units: Vec<HashMap<String, Unit>>, // the units of variables first assigned a number with one
+    loops: Vec<Option<String>>, // labels of the enclosing loops, innermost last
+    in_group: bool,
+    implicit_main: bool,
+    merge_groups: bool,
+    tail_calls: bool,
+    branchless_selects: bool,
+    statement_markers: bool,
+    flags: HashSet<String>, // picks the branches of `#if` blocks
+    merged_groups: HashMap<String, String>, // group that was merged away -> the one it's now part of
+    angle_range: AngleRange,
+    number_format: NumberFormat,
+    line_starts: Option<Vec<usize>>, // byte offset of each line of the source, if it's known
+    lines: Vec<(usize, usize)>, // from the last compile, for `Program::lines`
+    line: usize, // of the statement being compiled, or 0 if the source isn't known
+    operators: HashMap<String, String>, // custom operator -> the callable it calls
+    pending_jumps: Vec<JumpTarget>,
     errors: Vec<Error>,
+    warnings: Vec<Warning>,
     in_progress: AtomicBool,
 }
 
@@ -464,47 +1484,106 @@ impl<'a> Compiler {
         Compiler {
             groups: HashMap::new(),
             instructions: Vec::new(),
+            constants: Vec::new(),
+            interned: HashMap::new(),
+            timeouts: HashMap::new(),
+            symbols: DebugSymbols::default(),
+            group_info: HashMap::new(),
             callables: HashMap::new(),
             properties: HashMap::new(),
             allowed_props: HashSet::new(),
+            cached_props: HashMap::new(),
             variables: vec![HashMap::new()],
+            variable_units: vec![HashMap::new()],
+            loops: Vec::new(),
+            in_group: false,
+            implicit_main: false,
+            merge_groups: false,
+            tail_calls: false,
+            branchless_selects: false,
+            statement_markers: false,
+            flags: HashSet::new(),
+            merged_groups: HashMap::new(),
+            angle_range: AngleRange::default(),
+            number_format: NumberFormat::default(),
+            line_starts: None,
+            lines: Vec::new(),
+            line: 0,
+            operators: HashMap::new(),
+            pending_jumps: Vec::new(),
             errors: Vec::new(),
+            warnings: Vec::new(),
             in_progress: AtomicBool::new(false),
         }
     }
 
     pub fn package_program(&mut self, code: Vec<Op>) -> Program {
+        self.interned.clear();
         Program {
+            groups: Some(scan_groups(&code)),
             code,
+            constants: std::mem::take(&mut self.constants),
+            timeouts: std::mem::take(&mut self.timeouts),
+            symbols: std::mem::take(&mut self.symbols),
+            group_info: std::mem::take(&mut self.group_info),
             callables: std::mem::take(&mut self.callables),
             props: std::mem::take(&mut self.properties),
+            lines: std::mem::take(&mut self.lines),
         }
     }
 
     pub fn compile_nonconsuming(&mut self, ast: Vec<Stmt<'a>>) -> Result<Vec<Op>, Vec<Error>> {
-        self.in_progress.store(true, Ordering::Release);
-
+        // A `use` covers the whole program, wherever it is
         let (uses, program): (Vec<Stmt<'a>>, _) = ast.into_iter().partition(|stmt| {
             if let Stmt::Use(_) = stmt {true} else {false}
         });
+        self.compile_stream(uses.into_iter().chain(program))
+    }
 
-        for us in uses {
-            us.accept_mut(self);
-        }
-
-        let program = self.isolate(move |this| {
-            for stmt in program.iter() {
-                stmt.accept_mut(this);
+    /// Like `compile_nonconsuming`, but compiles each statement as soon as it's produced and drops
+    /// it, for scripts too big to comfortably hold as a whole AST. Since nothing's known about the
+    /// statements still to come, a `use` only covers the statements after it.
+    pub fn compile_stream(&mut self, statements: impl IntoIterator<Item = Stmt<'a>>) -> Result<Vec<Op>, Vec<Error>> {
+        self.in_progress.store(true, Ordering::Release);
+        self.warnings.clear();
+
+        let mut statements = statements.into_iter();
+        let since = self.pending_jumps.len();
+        self.in_group = self.implicit_main;
+        let mut program = self.isolate(move |this| {
+            for stmt in statements.by_ref() {
+                this.statement(&stmt);
             }
         });
+        self.in_group = false;
+        let locals = self.variables[0].len();
+        let program = if self.implicit_main {
+            let epilogue = program.len();
+            self.backpatch(&mut program, since, |target| match target {
+                JumpTarget::GroupEnd => Some(epilogue),
+                _ => None,
+            });
+            self.implicit_main_group(locals, program)
+        } else {
+            self.symbols.main = slot_names(&self.variables[0]);
+            let program = self.reserve_locals(locals, program);
+            self.check_uninitialized(&program, 0);
+            program
+        };
 
+        if self.merge_groups {
+            self.merge_identical_groups();
+        }
         let group_code = self.isolate(|this| {
             for (_name, group) in this.groups.iter_mut() {
                 this.instructions.extend(group.code.drain(..));
             }
         });
         
-        self.instructions.push(Op::Jump(group_code.len() as isize + 1));
+        // An empty source compiles to an empty program, rather than a jump over nothing
+        if !group_code.is_empty() || !program.is_empty() {
+            self.instructions.push(Op::Jump(group_code.len() as isize + 1));
+        }
         self.instructions.extend(group_code);
         self.instructions.extend(program);
         
@@ -514,90 +1593,990 @@ impl<'a> Compiler {
 
 
         
+        if !self.merged_groups.is_empty() {
+            let merged = &self.merged_groups;
+            let redirect = |name: &mut String| if let Some(kept) = merged.get(name) {
+                *name = kept.clone();
+            };
+            for op in self.instructions.iter_mut() {
+                match op {
+                    Op::Call(name, _) | Op::CallValue(name, _) | Op::Spawn(name, _) => redirect(name),
+                    Op::CallParallel(calls) | Op::CallRace(calls) => calls.iter_mut().for_each(|(name, _)| redirect(name)),
+                    _ => {}
+                }
+            }
+        }
+
+        let res = if self.errors.is_empty() {
+            let (code, lines) = strip_line_markers(fuse_compare_jumps(std::mem::take(&mut self.instructions)));
+            self.lines = lines;
+            Ok(code)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        };
+
+        self.in_progress.store(false, Ordering::Release);
+        res
+    }
+
+    // Drops the code of each group whose code is identical to a group that sorts before it, and
+    // everything else that refers to it by name. Groups compiled by earlier calls have already been
+    // emitted, so they aren't candidates.
+    fn merge_identical_groups(&mut self) {
+        let names = self.groups.iter()
+            .filter(|(name, group)| !group.code.is_empty() && !name.starts_with("__") && !name.starts_with('#'))
+            .map(|(name, _)| name.clone())
+            .sorted();
+        let mut kept: Vec<String> = Vec::new();
+        for name in names {
+            // Skipping the label, each group's code is only its body. Where it is in the source
+            // doesn't matter.
+            let body = |name: &String| self.groups[name].code[1..].iter().filter(|op| !matches!(op, Op::Line(_)));
+            let timeout = self.timeouts.get(&name);
+            let same = kept.iter()
+                .find(|k| body(k).eq(body(&name)) && self.timeouts.get(*k) == timeout)
+                .cloned();
+            let Some(same) = same else {
+                kept.push(name);
+                continue;
+            };
+            self.groups.get_mut(&name).unwrap().code.clear();
+            self.timeouts.remove(&name);
+            self.symbols.groups.remove(&name);
+            self.group_info.remove(&name);
+            self.merged_groups.insert(name, same);
+        }
+    }
+
+    // Moves the top-level code into the `__main` group, leaving just a call to it.
+    fn implicit_main_group(&mut self, locals: usize, program: Vec<Op>) -> Vec<Op> {
+        let name = "__main".to_string();
+        let mut code = vec![Op::Label(name.clone())];
+        code.extend(self.reserve_locals(locals, program));
+        self.check_uninitialized(&code, 0);
+        code.extend(std::iter::repeat_n(Op::Pop, locals));
+        code.push(Op::Return);
+
+        self.symbols.groups.insert(name.clone(), slot_names(&self.variables[0]));
+        let data = GroupData {name: name.clone(), params: Vec::new(), inout: Vec::new()};
+        self.groups.insert(name.clone(), CompiledGroup {data, code});
+        vec![Op::Call(name, 0)]
+    }
+
+    pub fn compile(mut self, ast: Vec<Stmt<'a>>) -> Result<Program, Vec<Error>> {
+        // No need to worry about updating `in_progress`, since this completely consumes the
+        // compiler
+        let code = self.compile_nonconsuming(ast)?;
+        let res = self.package_program(code);
+
+        Ok(res)
+    }
+
+    /// Warnings from the most recent compilation, whether or not it succeeded.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Compiles a lone expression, leaving its value on top of the stack. Since there's nowhere to
+    /// put a `use` statement, every registered property can be referenced.
+    pub fn compile_expression(&mut self, expr: &Expr<'a>) -> Result<Vec<Op>, Vec<Error>> {
+        self.in_progress.store(true, Ordering::Release);
+
+        let all_props = self.properties.keys().cloned().collect();
+        let allowed_props = std::mem::replace(&mut self.allowed_props, all_props);
+        expr.accept_mut(self);
+        self.allowed_props = allowed_props;
+
         let res = if self.errors.is_empty() {
             Ok(std::mem::take(&mut self.instructions))
         } else {
+            self.instructions.clear();
             Err(std::mem::take(&mut self.errors))
         };
 
-        self.in_progress.store(false, Ordering::Release);
-        res
+        self.in_progress.store(false, Ordering::Release);
+        res
+    }
+
+    // Takes back the externals handed over by `package_program`.
+    pub(crate) fn unpackage_program(&mut self, program: Program) {
+        for (i, constant) in program.constants.iter().enumerate() {
+            if let Value::String(s) = constant {
+                self.interned.insert(s.clone(), i);
+            }
+        }
+        self.constants = program.constants;
+        self.timeouts = program.timeouts;
+        self.symbols = program.symbols;
+        self.group_info = program.group_info;
+        self.callables = program.callables;
+        self.properties = program.props;
+    }
+
+    pub fn register_callable(&mut self, name: &str, callable: Box<dyn CallableGenerator>) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        if is_keyword(name) {
+            return Err(Error::ReservedName(name.into()));
+        }
+        if self.callables.contains_key(name) {
+            return Err(Error::DuplicateCallable(name.into()));
+        }
+        self.callables.insert(name.to_string(), callable);
+        Ok(())
+    }
+
+    /// Sets the range `normalize_angle` wraps angles into. Defaults to `[-180, 180)` degrees.
+    pub fn set_angle_range(&mut self, range: AngleRange) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        self.angle_range = range;
+        Ok(())
+    }
+
+    /// Gives the compiler the source the AST was parsed from, so compiled programs can say which
+    /// line each op came from, with `Program::lines`.
+    pub fn set_source(&mut self, source: &str) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        let starts = std::iter::once(0).chain(source.match_indices('\n').map(|(i, _)| i + 1));
+        self.line_starts = Some(starts.collect());
+        Ok(())
+    }
+
+    /// Sets how `format` writes numbers when it isn't given a format. Defaults to plain decimal.
+    pub fn set_number_format(&mut self, format: NumberFormat) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        self.number_format = format;
+        Ok(())
+    }
+
+    /// Makes the custom operator `name` call `callable` with its two operands, so `$a name $b`
+    /// compiles like `$x = callable $a $b;`. The parser has to be told about the operator too, with
+    /// `Parser::register_operator`. The callable has to return a value.
+    pub fn register_operator(&mut self, name: &str, callable: &str) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        self.operators.insert(name.to_string(), callable.to_string());
+        Ok(())
+    }
+
+    /// Compiles the top-level statements into a group named `__main`, which the program calls to
+    /// start. That makes the main flow callable by name, like with `Interpreter::call_group`.
+    /// Off by default.
+    pub fn set_implicit_main(&mut self, enabled: bool) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        self.implicit_main = enabled;
+        Ok(())
+    }
+
+    /// Collapses groups that compile to exactly the same code, which is common in generated
+    /// scripts, into whichever of them has the name that sorts first, and points every call at
+    /// it. Parameter names don't matter, but groups with different timeouts are kept apart. Merged
+    /// groups no longer exist under their own name, so they can't be started with
+    /// `Interpreter::call_group`, and group hooks see the name of the group they were merged into.
+    /// Special groups like `__end` are never merged. Off by default.
+    pub fn set_merge_identical_groups(&mut self, enabled: bool) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        self.merge_groups = enabled;
+        Ok(())
+    }
+
+    /// Makes a group that calls itself as the last thing it does jump back to its start instead,
+    /// so deep recursion like that doesn't build up frames. The group hook only sees the outermost
+    /// call start and finish. Groups with a timeout or inout parameters are left alone, since the
+    /// inner calls would behave differently. Off by default.
+    pub fn set_tail_calls(&mut self, enabled: bool) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        self.tail_calls = enabled;
+        Ok(())
+    }
+
+    /// Compiles an `if` whose branches each just assign a value to the same variable, like
+    /// `if $fast { $speed = 10; } else { $speed = 2; }`, into a `Select` op instead of jumps, so
+    /// that it takes the same time either way. Both values are worked out before picking one, so
+    /// this only happens when that can't fail or have side effects, e.g. for literals, variables and
+    /// pure properties. Other `if` statements compile as usual. Off by default.
+    pub fn set_branchless_selects(&mut self, enabled: bool) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        self.branchless_selects = enabled;
+        Ok(())
+    }
+
+    /// Puts a `Statement` op at the start of every statement, which is what
+    /// `Interpreter::set_yield_per_statement` stops at. Otherwise they do nothing, but they get in
+    /// the way of tail calls. Off by default.
+    pub fn set_statement_markers(&mut self, enabled: bool) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        self.statement_markers = enabled;
+        Ok(())
+    }
+
+    /// Defines a flag for `#if` blocks, so that `#if name { ... }` compiles its body rather than
+    /// its `#else` branch. Flags that aren't defined count as false.
+    pub fn define_flag(&mut self, name: &str) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        self.flags.insert(name.to_string());
+        Ok(())
+    }
+
+    pub fn register_property(&mut self, name: &str, prop: Box<dyn Prop>) -> Result<(), Error> {
+        if self.in_progress.load(Ordering::Acquire) {
+            return Err(Error::CompilerActive);
+        }
+        if is_keyword(name) {
+            return Err(Error::ReservedName(name.into()));
+        }
+        if self.properties.contains_key(name) {
+            return Err(Error::DuplicateProperty(name.into()));
+        }
+        self.properties.insert(name.to_string(), prop);
+        Ok(())
+    }
+
+    fn declare_var(&mut self, name: &'a str) -> usize {
+        let scope = unsafe {self.variables.last_mut().unwrap_unchecked()};
+        if scope.contains_key(name) {
+            return scope[name];
+        }
+        let idx = scope.len();
+        scope.insert(name.to_string(), idx);
+        idx
+    }
+
+    fn statement(&mut self, stmt: &Stmt<'a>) {
+        // Declarations don't do anything when they're reached, and `#if` blocks aren't there at all
+        let declaration = matches!(stmt, Stmt::Use(_) | Stmt::Group(_) | Stmt::Conditional(_));
+        let outer = self.line;
+        if let Some(line_starts) = &self.line_starts && let Some(start) = stmt.start() {
+            self.line = line_starts.partition_point(|s| *s <= start);
+            if !declaration {
+                self.instructions.push(Op::Line(self.line));
+            }
+        }
+        if self.statement_markers && !declaration {
+            self.instructions.push(Op::Statement);
+        }
+        stmt.accept_mut(self);
+        self.line = outer;
+    }
+
+    fn begin_scope(&mut self) {
+        self.variables.push(HashMap::new());
+        self.variable_units.push(HashMap::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.variables.pop();
+        self.variable_units.pop();
+    }
+
+    fn unit_of(&self, expr: &Expr<'a>) -> Option<Unit> {
+        let variables = unsafe {self.variable_units.last().unwrap_unchecked()};
+        expr.accept(&UnitOf {properties: &self.properties, variables})
+    }
+
+    // The unit a value in `unit` has to be converted to before it's assigned to `name`. A variable
+    // takes on the unit of the first value assigned to it.
+    fn target_unit(&mut self, name: &Token<'a>, unit: Option<Unit>) -> Option<Unit> {
+        let Some(LexLiteral::Ident(name)) = name.literal else {
+            return None;
+        };
+        if let Some(prop) = self.properties.get(name) {
+            return prop.unit();
+        }
+        let declared = unsafe {self.variables.last().unwrap_unchecked()}.contains_key(name);
+        let units = unsafe {self.variable_units.last_mut().unwrap_unchecked()};
+        match (units.get(name), unit) {
+            (Some(unit), _) => Some(*unit),
+            (None, Some(unit)) if !declared => {
+                units.insert(name.to_string(), unit);
+                Some(unit)
+            }
+            _ => None,
+        }
+    }
+
+    // Converts the number on top of the stack between units, if both are known
+    fn convert_unit(&mut self, from: Option<Unit>, to: Option<Unit>) {
+        let (Some(from), Some(to)) = (from, to) else {
+            return;
+        };
+        if from == to {
+            return;
+        }
+        match from.factor_to(to) {
+            Some(factor) => {
+                self.instructions.push(Op::Push(Value::Number(factor)));
+                self.instructions.push(Op::Mul);
+            }
+            None => self.errors.push(Error::Compile{line: self.line, msg: format!("Can't convert {} to {}", from, to)}),
+        }
+    }
+
+    // Reports arithmetic that doesn't make sense for the units involved, and returns whether the
+    // right operand needs converting to the left's unit. Durations have the strictest rules, since
+    // there's nothing a duration times a duration could mean here. Dividing one by another gives
+    // a plain ratio, and scaling one by a number gives another duration.
+    fn check_units(&mut self, op: TokenType, left: Option<Unit>, right: Option<Unit>) -> bool {
+        let is_time = |unit: Option<Unit>| unit.is_some_and(|u| u.dimension() == Dimension::Time);
+        let same = left.zip(right).is_some_and(|(l, r)| l.dimension() == r.dimension());
+        let msg = match (op, left, right) {
+            (TokenType::Star, Some(l), Some(r)) if is_time(left) || is_time(right) => format!("Can't multiply {} by {}", l, r),
+            (TokenType::Slash, None, Some(r)) if is_time(right) => format!("Can't divide a number by {}", r),
+            (TokenType::Slash, Some(l), Some(r)) if !same && (is_time(left) || is_time(right)) => format!("Can't divide {} by {}", l, r),
+            (TokenType::Caret, _, _) if is_time(left) || is_time(right) => "Durations can't be raised to powers or used as them".into(),
+            (TokenType::Star | TokenType::Slash | TokenType::Caret, _, _) => return same && op == TokenType::Slash,
+            (_, Some(l), Some(r)) if !same => format!("Can't combine {} and {}", l, r),
+            _ => return same,
+        };
+        self.errors.push(Error::Compile{line: self.line, msg});
+        false
+    }
+
+    fn compile_in_unit(&mut self, expr: &Expr<'a>, unit: Option<Unit>) {
+        let from = self.unit_of(expr);
+        expr.accept_mut(self);
+        self.convert_unit(from, unit);
+    }
+
+    // Custom operators call their callable with both operands as values
+    fn custom_operator(&mut self, expr: &Binary<'a>) {
+        let op = expr.op.lexeme;
+        let Some(name) = self.operators.get(op).cloned() else {
+            self.errors.push(Error::Compile{line: self.line, msg: format!("'{}' isn't a registered operator", op)});
+            return;
+        };
+        if let Err(e) = self.call_for_value(&name, &[&expr.left, &expr.right]) {
+            self.errors.push(e);
+        }
+    }
+
+    // Calls a callable for its result, leaving it on the stack. Every argument is a value.
+    fn call_for_value(&mut self, name: &str, args: &[&Expr<'a>]) -> Result<(), Error> {
+        let Some(callable) = self.callables.get(name) else {
+            return Err(Error::UnknownCallable(name.to_string()));
+        };
+        let Some(signature) = callable.signature().filter(|s| s.returns) else {
+            return Err(Error::Compile{line: self.line, msg: format!("'{}' doesn't return a value, so it can't be used in an expression", name)});
+        };
+        let kinds = vec![Arg::Value; args.len()];
+        let values: Vec<_> = args.iter().map(|arg| arg.accept(&ConstantFolder)).collect();
+        let units: Vec<_> = signature.bind(name, &kinds, &values)?.iter().map(|p| p.unit()).collect();
+        for (i, arg) in args.iter().enumerate() {
+            self.compile_in_unit(arg, units.get(i).copied().flatten());
+        }
+        self.instructions.push(Op::CallValue(name.to_string(), args.len()));
+        Ok(())
+    }
+
+    // The unit each value argument of a call has to be in, going by the callable's signature
+    fn arg_units(&self, name: &str, args: &[AstArg<'a>]) -> Vec<Option<Unit>> {
+        let signature = match self.callables.get(name) {
+            Some(callable) if !self.groups.contains_key(name) => callable.signature(),
+            _ => None,
+        };
+        let Some(signature) = signature else {
+            return Vec::new();
+        };
+        let kinds: Vec<_> = args.iter().map(|a| match a {
+            AstArg::Word(w) => Arg::Word(w.lexeme.into()),
+            AstArg::Value(_) => Arg::Value,
+        }).collect();
+        let values: Vec<_> = args.iter().map(|a| match a {
+            AstArg::Value(expr) => expr.accept(&ConstantFolder),
+            AstArg::Word(_) => None,
+        }).collect();
+        let Ok(params) = signature.bind(name, &kinds, &values) else {
+            return Vec::new();
+        };
+        args.iter().zip(params)
+            .filter(|(arg, _)| matches!(arg, AstArg::Value(_)))
+            .map(|(_, param)| param.unit())
+            .collect()
+    }
+
+    fn get_var(&mut self, name: &'a str) -> Result<usize, Error> {
+        let scope = unsafe {self.variables.last_mut().unwrap_unchecked()};
+        scope.get(name).map(|i| *i).ok_or(Error::Compile{line: self.line, msg: format!("Variable '{}' used before it was declared", name)})
+    }
+
+    // FIXME if useful, change output to Result<_> Halting parsing may be useful?
+    fn isolate<F: FnMut(&mut Self)>(&mut self, mut f: F) -> Vec<Op> {
+        let current_program = std::mem::take(&mut self.instructions);
+        f(self);
+        let sub_program = std::mem::replace(&mut self.instructions, current_program);
+        sub_program
+    }
+
+    // Finds the callable an exec statement refers to. Callables can be registered with multi-word
+    // names (e.g. "go left"), so the longest registered name made up of the call's name and its
+    // leading word arguments is used. Returns the resolved name and how many arguments it consumed.
+    fn resolve_callable(&self, name: &str, args: &[AstArg<'a>]) -> Result<(String, usize), Error> {
+        let words = args.iter().map_while(|a| match a {
+            AstArg::Word(w) => Some(w.lexeme),
+            AstArg::Value(_) => None,
+        });
+        let mut candidates = Vec::new();
+        if self.groups.contains_key(name) || self.callables.contains_key(name) {
+            candidates.push((name.to_string(), 0));
+        }
+        let mut full_name = name.to_string();
+        for (i, word) in words.enumerate() {
+            full_name = format!("{} {}", full_name, word);
+            if self.callables.contains_key(&full_name) {
+                candidates.push((full_name.clone(), i + 1));
+            }
+        }
+
+        let Some(longest) = candidates.pop() else {
+            return Err(Error::UnknownCallable(name.into()));
+        };
+        // A shorter name that would also accept the call means the call could mean either. A
+        // callable without a signature accepts anything, so it doesn't count.
+        for (shorter, consumed) in candidates {
+            let checkable = self.groups.contains_key(&shorter) || self.callables[&shorter].signature().is_some();
+            if checkable && self.check_call(&shorter, &args[consumed..]).is_ok() {
+                return Err(Error::Call(format!("Call to '{}' is ambiguous between '{}' and '{}'",
+                                                longest.0, shorter, longest.0)));
+            }
+        }
+        Ok(longest)
+    }
+
+    fn check_call(&self, name: &str, args: &[AstArg<'a>]) -> Result<(), Error> {
+        let arg_kinds: Vec<_> = args.iter().map(|a| {
+            match a {
+                AstArg::Word(w) => Arg::Word(w.lexeme.into()),
+                AstArg::Value(_) => Arg::Value,
+            }
+        }).collect();
+
+        if let Some(group) = self.groups.get(name) {
+            return group.check_syntax(arg_kinds);
+        }
+
+        let callable = &self.callables[name];
+        if let Some(signature) = callable.signature() {
+            let values: Vec<_> = args.iter().map(|a| {
+                match a {
+                    AstArg::Value(expr) => expr.accept(&ConstantFolder),
+                    AstArg::Word(_) => None,
+                }
+            }).collect();
+            signature.check(name, &arg_kinds, &values)?;
+        }
+        callable.check_syntax(arg_kinds)
+    }
+
+    // Identical string literals all share one slot in the constants pool.
+    fn intern(&mut self, s: &str) -> usize {
+        if let Some(&i) = self.interned.get(s) {
+            return i;
+        }
+        let i = self.constants.len();
+        self.constants.push(Value::String(s.to_string()));
+        self.interned.insert(s.to_string(), i);
+        i
+    }
+
+    fn check_constant_condition(&mut self, condition: &Expr<'a>, invert: bool, is_loop: bool) {
+        let Some(value) = condition.accept(&ConstantFolder) else {
+            return;
+        };
+        let value = value.truthy() != invert;
+        let msg = match (is_loop, value) {
+            // Intentional infinite loops are common enough that they aren't worth a warning
+            (true, true) => return,
+            (true, false) => "Loop condition is always false, so the loop body is dead code",
+            (false, true) => "Condition is always true, so the branch is always taken",
+            (false, false) => "Condition is always false, so the branch is never taken",
+        };
+        self.warnings.push(Warning::ConstantCondition{line: self.line, value, msg: msg.into()});
+    }
+
+    // Warns about a group parameter that's easy to mix up with something of the same name outside
+    // the group. Properties take precedence when they're read, so the parameter isn't even visible.
+    fn check_shadowing(&mut self, param: &str) {
+        let property = self.allowed_props.contains(param);
+        if property || self.variables.iter().any(|scope| scope.contains_key(param)) {
+            self.warnings.push(Warning::ParameterShadowsOuter{line: self.line, name: param.into(), property});
+        }
+    }
+
+    // Warns about each variable in the current scope that `code` might read before assigning it.
+    fn check_uninitialized(&mut self, code: &[Op], params: usize) {
+        let names = slot_names(unsafe {self.variables.last().unwrap_unchecked()});
+        for (slot, addr) in possibly_uninitialized(code, params) {
+            // Hidden compiler temporaries are always set up before they're used
+            if let Some(name) = names.get(slot) && !name.starts_with('#') {
+                // Line markers haven't been stripped yet, so the read's line is the last one before it
+                let line = code[..addr].iter().rev().find_map(|op| if let Op::Line(l) = op {Some(*l)} else {None});
+                self.warnings.push(Warning::PossiblyUninitialized{line: line.unwrap_or(0), name: name.clone()});
+            }
+        }
+    }
+
+    // The variable and the two values of an `if` that can be compiled into a `Select`, as set out
+    // in `set_branchless_selects`.
+    fn select_candidate<'s>(&self, stmt: &'s If<'a>) -> Option<(&'s Token<'a>, &'s Expr<'a>, &'s Expr<'a>)> {
+        if !self.branchless_selects {
+            return None;
+        }
+        let ([Stmt::Var(then)], [Stmt::Var(els)]) = (&stmt.then_branch[..], &stmt.else_branch[..]) else {
+            return None;
+        };
+        let safe = SafeToEvaluate {properties: &self.properties};
+        let candidate = then.name.lexeme == els.name.lexeme && then.value.accept(&safe) && els.value.accept(&safe);
+        candidate.then_some((&then.name, &then.value, &els.value))
+    }
+
+    // Stores the value on top of the stack in the variable or property `name`.
+    fn assign(&mut self, name: &Token<'a>) {
+        let LexLiteral::Ident(name) = name.literal.unwrap() else {
+            self.errors.push(Error::Compile{line: self.line, msg: "Invalid variable name".into()});
+            return;
+        };
+
+        if let Some(prop) = self.properties.get(name) {
+            if !self.allowed_props.contains(name) {
+                self.errors.push(Error::UndeclaredProperty(name.into()));
+                return;
+            }
+            match prop.settable() {
+                Ok(false) => {
+                    self.errors.push(Error::Compile{line: self.line, msg: format!("External property '{}' not settable", name)});
+                    return;
+                }
+                Err(e) => {
+                    self.errors.push(e);
+                    return;
+                }
+                _ => {}
+            }
+            self.instructions.push(Op::Set(name.into()));
+        } else {
+            let idx = self.declare_var(name);
+            self.instructions.push(Op::Store(idx));
+        }
+    }
+
+    // The ops that clean up after a call to a group with inout parameters, writing each inout
+    // parameter back to the variable that was passed for it, and discarding the rest.
+    fn inout_writeback(&mut self, name: &str, args: &[AstArg<'a>]) -> Result<Vec<Op>, Error> {
+        let Some(group) = self.groups.get(name) else {
+            return Ok(Vec::new());
+        };
+        if !group.data.has_inout() {
+            return Ok(Vec::new());
+        }
+        let inout = group.data.inout.clone();
+
+        let values = args.iter().filter_map(|a| match a {
+            AstArg::Value(expr) => Some(expr),
+            AstArg::Word(_) => None,
+        });
+        let mut cleanup = Vec::new();
+        for (i, (expr, inout)) in values.zip(inout).enumerate() {
+            if !inout {
+                cleanup.push(Op::Pop);
+                continue;
+            }
+            let Expr::Variable(var) = &**expr else {
+                return Err(Error::Compile{line: self.line, msg: format!("Argument at position {} of '{}' is inout, so it must be a variable", i, name)});
+            };
+            let LexLiteral::Ident(var_name) = var.name.literal.unwrap() else {unreachable!()};
+            if self.properties.contains_key(var_name) {
+                return Err(Error::Compile{line: self.line, msg: format!("Property '{}' can't be passed as an inout argument", var_name)});
+            }
+            cleanup.push(Op::Store(self.get_var(var_name)?));
+        }
+        cleanup.reverse();
+        Ok(cleanup)
+    }
+
+    // Reads each pure property that's used more than once in `exprs` into a hidden local up front,
+    // and has the expressions load it from there. Must be followed by `end_prop_cache` once the
+    // expressions have been compiled.
+    fn cache_pure_props(&mut self, exprs: &[&Expr<'a>]) {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for name in exprs.iter().flat_map(|e| e.accept(&VariableReads)) {
+            *counts.entry(name).or_default() += 1;
+        }
+        for (name, count) in counts.into_iter().sorted() {
+            let pure = self.properties.get(name).map(|p| p.pure()).unwrap_or(false);
+            if count < 2 || !pure || !self.allowed_props.contains(name) {
+                continue;
+            }
+            // '#' can't appear in variable names, so this never clashes with a real variable
+            let scope = unsafe {self.variables.last_mut().unwrap_unchecked()};
+            let len = scope.len();
+            let idx = *scope.entry(format!("#{}", name)).or_insert(len);
+            self.instructions.push(Op::Get(name.to_string()));
+            self.instructions.push(Op::Store(idx));
+            self.cached_props.insert(name.to_string(), idx);
+        }
+    }
+
+    fn end_prop_cache(&mut self) {
+        self.cached_props.clear();
+    }
+
+    // Prepends a slot for each local variable to `code`. Otherwise, a variable assigned on a branch
+    // that's never taken wouldn't get a slot, and every variable after it would be off by one.
+    fn reserve_locals(&self, locals: usize, code: Vec<Op>) -> Vec<Op> {
+        let mut reserved = vec![Op::Push(Value::Nil); locals];
+        reserved.extend(code);
+        reserved
+    }
+
+    fn current_ip(&self) -> isize {
+        self.instructions.len() as isize
+    }
+
+    // The depth in `self.loops` of the loop a `break` or `continue` refers to
+    fn target_loop(&mut self, keyword: &Token<'a>, label: &Option<Token<'a>>) -> Option<usize> {
+        let depth = match label {
+            Some(label) => self.loops.iter().rposition(|l| l.as_deref() == Some(label.lexeme)),
+            None => self.loops.len().checked_sub(1),
+        };
+        if depth.is_none() {
+            let msg = match label {
+                Some(label) => format!("'{} {}' does not refer to an enclosing loop", keyword.lexeme, label.lexeme),
+                None => format!("'{}' is not allowed outside of loops", keyword.lexeme),
+            };
+            self.errors.push(Error::Compile{line: self.line, msg});
+        }
+        depth
+    }
+
+    fn placeholder_jump(&mut self, target: JumpTarget) {
+        self.pending_jumps.push(target);
+        self.instructions.push(Op::Jump(0));
+    }
+
+    // Patches every placeholder jump in `code` emitted since `since` that `resolve` knows the
+    // destination of. Placeholders are matched to their targets in the order they were emitted,
+    // which is the same order they're laid out in, since nested constructs are always appended whole.
+    fn backpatch<F: Fn(JumpTarget) -> Option<usize>>(&mut self, code: &mut [Op], since: usize, resolve: F) {
+        let mut targets = self.pending_jumps.drain(since..).collect::<Vec<_>>().into_iter();
+        let mut unresolved = Vec::new();
+        for (i, op) in code.iter_mut().enumerate() {
+            if let Op::Jump(0) = op {
+                let Some(target) = targets.next() else {break};
+                match resolve(target) {
+                    Some(dest) => *op = Op::Jump(dest as isize - i as isize),
+                    None => unresolved.push(target),
+                }
+            }
+        }
+        self.pending_jumps.extend(unresolved);
+    }
+}
+
+// Evaluates expressions whose value is known at compile time, following the same rules as the
+// interpreter. Anything involving a variable or property is unknown.
+struct ConstantFolder;
+
+// The unit an expression's value is in, where it's known. Mixing units that can't be converted
+// isn't reported here, but where the compiler would convert between them.
+struct UnitOf<'c> {
+    properties: &'c HashMap<String, Box<dyn Prop>>,
+    variables: &'c HashMap<String, Unit>,
+}
+
+impl<'a, 'c> ExprVisitor<'a, Option<Unit>> for UnitOf<'c> {
+    fn visit_literal_expr(&self, _expr: &Literal<'a>) -> Option<Unit> {
+        None
+    }
+
+    fn visit_variable_expr(&self, expr: &Variable<'a>) -> Option<Unit> {
+        let Some(LexLiteral::Ident(name)) = expr.name.literal else {
+            return None;
+        };
+        match self.properties.get(name) {
+            Some(prop) => prop.unit(),
+            None => self.variables.get(name).copied(),
+        }
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping<'a>) -> Option<Unit> {
+        expr.expression.accept(self)
+    }
+
+    fn visit_unary_expr(&self, expr: &Unary<'a>) -> Option<Unit> {
+        match expr.op.ty {
+            TokenType::Minus => expr.right.accept(self),
+            _ => None,
+        }
+    }
+
+    // Scaling by a plain number keeps the unit, but units of units, like meters per second,
+    // aren't tracked
+    fn visit_binary_expr(&self, expr: &Binary<'a>) -> Option<Unit> {
+        let (left, right) = (expr.left.accept(self), expr.right.accept(self));
+        match expr.op.ty {
+            TokenType::Plus | TokenType::Minus | TokenType::Percent => left.or(right),
+            TokenType::Star => left.xor(right),
+            TokenType::Slash if right.is_none() => left,
+            _ => None,
+        }
+    }
+
+    fn visit_logical_expr(&self, _expr: &Logical<'a>) -> Option<Unit> {
+        None
+    }
+
+    fn visit_call_expr(&self, _expr: &Call<'a>) -> Option<Unit> {
+        None
+    }
+
+    fn visit_list_expr(&self, _expr: &List<'a>) -> Option<Unit> {
+        None
+    }
+
+    fn visit_index_expr(&self, _expr: &Index<'a>) -> Option<Unit> {
+        None
+    }
+}
+
+// Whether an expression can be evaluated without failing or having side effects, even when its
+// result isn't going to be used.
+struct SafeToEvaluate<'c> {
+    properties: &'c HashMap<String, Box<dyn Prop>>,
+}
+
+impl<'a, 'c> ExprVisitor<'a, bool> for SafeToEvaluate<'c> {
+    fn visit_literal_expr(&self, _expr: &Literal<'a>) -> bool {
+        true
+    }
+
+    fn visit_variable_expr(&self, expr: &Variable<'a>) -> bool {
+        match expr.name.literal {
+            Some(LexLiteral::Ident(name)) => self.properties.get(name).is_none_or(|prop| prop.pure()),
+            _ => false,
+        }
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping<'a>) -> bool {
+        if expr.abs {
+            return ConstantFolder.visit_grouping_expr(expr).is_some();
+        }
+        expr.expression.accept(self)
+    }
+
+    // Anything that might do arithmetic on the wrong type only counts if it's constant
+    fn visit_unary_expr(&self, expr: &Unary<'a>) -> bool {
+        match expr.op.ty {
+            TokenType::Not => expr.right.accept(self),
+            _ => ConstantFolder.visit_unary_expr(expr).is_some(),
+        }
+    }
+
+    fn visit_binary_expr(&self, expr: &Binary<'a>) -> bool {
+        ConstantFolder.visit_binary_expr(expr).is_some()
+    }
+
+    // Short circuiting takes jumps
+    fn visit_logical_expr(&self, expr: &Logical<'a>) -> bool {
+        ConstantFolder.visit_logical_expr(expr).is_some()
+    }
+
+    fn visit_call_expr(&self, expr: &Call<'a>) -> bool {
+        ConstantFolder.visit_call_expr(expr).is_some()
+    }
+
+    fn visit_list_expr(&self, expr: &List<'a>) -> bool {
+        expr.elements.iter().all(|e| e.accept(self))
+    }
+
+    fn visit_index_expr(&self, expr: &Index<'a>) -> bool {
+        ConstantFolder.visit_index_expr(expr).is_some()
+    }
+}
+
+// Lists the name of every variable and property an expression reads, once per read.
+struct VariableReads;
+
+impl<'a> ExprVisitor<'a, Vec<&'a str>> for VariableReads {
+    fn visit_literal_expr(&self, _expr: &Literal<'a>) -> Vec<&'a str> {
+        Vec::new()
+    }
+
+    fn visit_variable_expr(&self, expr: &Variable<'a>) -> Vec<&'a str> {
+        match expr.name.literal {
+            Some(LexLiteral::Ident(name)) => vec![name],
+            _ => Vec::new(),
+        }
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping<'a>) -> Vec<&'a str> {
+        expr.expression.accept(self)
+    }
+
+    fn visit_unary_expr(&self, expr: &Unary<'a>) -> Vec<&'a str> {
+        expr.right.accept(self)
+    }
+
+    fn visit_binary_expr(&self, expr: &Binary<'a>) -> Vec<&'a str> {
+        let mut reads = expr.left.accept(self);
+        reads.extend(expr.right.accept(self));
+        reads
+    }
+
+    fn visit_logical_expr(&self, expr: &Logical<'a>) -> Vec<&'a str> {
+        let mut reads = expr.left.accept(self);
+        reads.extend(expr.right.accept(self));
+        reads
+    }
+
+    fn visit_call_expr(&self, expr: &Call<'a>) -> Vec<&'a str> {
+        expr.args.iter().flat_map(|arg| arg.accept(self)).collect()
     }
 
-    pub fn compile(mut self, ast: Vec<Stmt<'a>>) -> Result<Program, Vec<Error>> {
-        // No need to worry about updating `in_progress`, since this completely consumes the
-        // compiler
-        let code = self.compile_nonconsuming(ast)?;
-        let res = self.package_program(code);
+    fn visit_list_expr(&self, expr: &List<'a>) -> Vec<&'a str> {
+        expr.elements.iter().flat_map(|e| e.accept(self)).collect()
+    }
 
-        Ok(res)
+    fn visit_index_expr(&self, expr: &Index<'a>) -> Vec<&'a str> {
+        let mut reads = expr.target.accept(self);
+        reads.extend(expr.index.accept(self));
+        reads
     }
+}
 
-    pub fn register_callable(&mut self, name: &str, callable: Box<dyn CallableGenerator>) -> Result<(), Error> {
-        if self.in_progress.load(Ordering::Acquire) {
-            return Err(Error::CompilerActive);
-        }
-        if self.callables.contains_key(name) {
-            return Err(Error::DuplicateCallable(name.into()));
+impl<'a> ExprVisitor<'a, Option<Value>> for ConstantFolder {
+    fn visit_literal_expr(&self, expr: &Literal<'a>) -> Option<Value> {
+        match expr.value {
+            LexLiteral::String(_) | LexLiteral::EscapedString(_) => expr.value.string().map(|s| Value::String(s.into_owned())),
+            LexLiteral::Number(n) => Some(Value::Number(n)),
+            LexLiteral::Bool(b) => Some(Value::Bool(b)),
+            LexLiteral::Nil => Some(Value::Nil),
+            LexLiteral::Ident(_) => None,
         }
-        self.callables.insert(name.to_string(), callable);
-        Ok(())
     }
 
-    pub fn register_property(&mut self, name: &str, prop: Box<dyn Prop>) -> Result<(), Error> {
-        if self.in_progress.load(Ordering::Acquire) {
-            return Err(Error::CompilerActive);
-        }
-        if self.properties.contains_key(name) {
-            return Err(Error::DuplicateProperty(name.into()));
+    fn visit_variable_expr(&self, _expr: &Variable<'a>) -> Option<Value> {
+        None
+    }
+
+    fn visit_grouping_expr(&self, expr: &Grouping<'a>) -> Option<Value> {
+        let value = expr.expression.accept(self)?;
+        match value {
+            Value::Number(n) if expr.abs => Some(Value::Number(n.abs())),
+            _ if expr.abs => None,
+            value => Some(value),
         }
-        self.properties.insert(name.to_string(), prop);
-        Ok(())
     }
 
-    fn declare_var(&mut self, name: &'a str) -> usize {
-        let scope = unsafe {self.variables.last_mut().unwrap_unchecked()};
-        if scope.contains_key(name) {
-            return scope[name];
+    fn visit_unary_expr(&self, expr: &Unary<'a>) -> Option<Value> {
+        let value = expr.right.accept(self)?;
+        match (expr.op.ty, value) {
+            (TokenType::Not, value) => Some(Value::Bool(!value.truthy())),
+            (TokenType::Minus, Value::Number(n)) => Some(Value::Number(-n)),
+            _ => None,
         }
-        let idx = scope.len();
-        scope.insert(name.to_string(), idx);
-        idx
     }
 
-    fn begin_scope(&mut self) {
-        self.variables.push(HashMap::new());
+    fn visit_binary_expr(&self, expr: &Binary<'a>) -> Option<Value> {
+        let left = expr.left.accept(self)?;
+        let right = expr.right.accept(self)?;
+        use TokenType::*;
+        match (expr.op.ty, left, right) {
+            (EqualEqual, l, r) => Some(Value::Bool(l == r)),
+            (BangEqual, l, r) => Some(Value::Bool(l != r)),
+            (Plus, Value::String(l), Value::String(r)) => Some(Value::String(l + &r)),
+            (op, Value::String(l), Value::String(r)) => Some(Value::Bool(match op {
+                Greater => l > r,
+                GreaterEqual => l >= r,
+                Less => l < r,
+                LessEqual => l <= r,
+                _ => return None,
+            })),
+            (op, Value::Bool(l), Value::Bool(r)) => Some(Value::Bool(match op {
+                Greater => l & !r,
+                GreaterEqual => l >= r,
+                Less => !l & r,
+                LessEqual => l <= r,
+                _ => return None,
+            })),
+            (op, Value::Number(l), Value::Number(r)) => Some(match op {
+                Plus => Value::Number(l + r),
+                Minus => Value::Number(l - r),
+                Star => Value::Number(l * r),
+                Slash => Value::Number(l / r),
+                Percent => Value::Number(l % r),
+                Caret => Value::Number(l.powf(r)),
+                Greater => Value::Bool(l > r),
+                GreaterEqual => Value::Bool(l >= r),
+                Less => Value::Bool(l < r),
+                LessEqual => Value::Bool(l <= r),
+                _ => return None,
+            }),
+            _ => None,
+        }
     }
 
-    fn end_scope(&mut self) {
-        self.variables.pop();
+    fn visit_call_expr(&self, _expr: &Call<'a>) -> Option<Value> {
+        None
     }
 
-    fn get_var(&mut self, name: &'a str) -> Result<usize, Error> {
-        let scope = unsafe {self.variables.last_mut().unwrap_unchecked()};
-        scope.get(name).map(|i| *i).ok_or(Error::Compile{line: 0, msg: format!("Variable '{}' used before it was declared", name)})
+    fn visit_list_expr(&self, expr: &List<'a>) -> Option<Value> {
+        expr.elements.iter().map(|e| e.accept(self)).collect::<Option<_>>().map(Value::List)
     }
 
-    // FIXME if useful, change output to Result<_> Halting parsing may be useful?
-    fn isolate<F: FnMut(&mut Self)>(&mut self, mut f: F) -> Vec<Op> {
-        let current_program = std::mem::take(&mut self.instructions);
-        f(self);
-        let sub_program = std::mem::replace(&mut self.instructions, current_program);
-        sub_program
+    // Bad indices are left for the interpreter to report
+    fn visit_index_expr(&self, expr: &Index<'a>) -> Option<Value> {
+        let target = expr.target.accept(self)?;
+        match expr.index.accept(self)? {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => target.element(n as usize),
+            _ => None,
+        }
     }
 
-    fn current_ip(&self) -> isize {
-        self.instructions.len() as isize
+    fn visit_logical_expr(&self, expr: &Logical<'a>) -> Option<Value> {
+        let left = expr.left.accept(self)?.truthy();
+        // Short circuiting means the right side doesn't matter in some cases
+        let right = || expr.right.accept(self).map(|v| v.truthy());
+        Some(Value::Bool(match expr.op.ty {
+            TokenType::And => left && right()?,
+            TokenType::Or => left || right()?,
+            TokenType::Xor => left != right()?,
+            _ => return None,
+        }))
     }
 }
 
 impl<'a> ExprVisitorMut<'a, ()> for Compiler {
     fn visit_variable_expr(&mut self, expr: &Variable<'a>) {
         let LexLiteral::Ident(name) = expr.name.literal.unwrap() else {
-            self.errors.push(Error::Compile{line: 0, msg: "Variable was somehow called without an associated identifier".into()});
+            self.errors.push(Error::Compile{line: self.line, msg: "Variable was somehow called without an associated identifier".into()});
             return;
         };
 
+        if let Some(idx) = self.cached_props.get(name) {
+            self.instructions.push(Op::Load(*idx));
+            return;
+        }
+
         if self.properties.contains_key(name) {
             if !self.allowed_props.contains(name) {
                 self.errors.push(Error::UndeclaredProperty(name.into()));
@@ -619,7 +2598,11 @@ impl<'a> ExprVisitorMut<'a, ()> for Compiler {
     
     fn visit_literal_expr(&mut self, expr: &Literal<'a>) {
         let value = match expr.value {
-            LexLiteral::String(s) => Value::String(s.into()),
+            LexLiteral::String(_) | LexLiteral::EscapedString(_) => {
+                let i = self.intern(&expr.value.string().unwrap_or_default());
+                self.instructions.push(Op::Const(i));
+                return;
+            }
             LexLiteral::Number(n) => Value::Number(n),
             LexLiteral::Bool(b) => Value::Bool(b),
             LexLiteral::Nil => Value::Nil,
@@ -641,13 +2624,29 @@ impl<'a> ExprVisitorMut<'a, ()> for Compiler {
         match expr.op.ty {
             TokenType::Not => self.instructions.push(Op::Not),
             TokenType::Minus => self.instructions.push(Op::Neg),
-            _ => self.errors.push(Error::Compile{line: 0, msg: "Invalid unary operator".into()}),
+            _ => self.errors.push(Error::Compile{line: self.line, msg: "Invalid unary operator".into()}),
         }
     }
 
     fn visit_binary_expr(&mut self, expr: &Binary<'a>) {
+        // Joined up here, so that `'a' 'b'` is a single constant
+        if expr.op.ty == TokenType::Plus && let Some(Value::String(s)) = ConstantFolder.visit_binary_expr(expr) {
+            let i = self.intern(&s);
+            self.instructions.push(Op::Const(i));
+            return;
+        }
+        if expr.op.ty == TokenType::Word {
+            self.custom_operator(expr);
+            return;
+        }
+        let (left_unit, right_unit) = (self.unit_of(&expr.left), self.unit_of(&expr.right));
+        let convert = self.check_units(expr.op.ty, left_unit, right_unit);
         expr.left.accept_mut(self);
         expr.right.accept_mut(self);
+        // The right operand is converted to the left's unit
+        if convert {
+            self.convert_unit(right_unit, left_unit);
+        }
 
         match expr.op.ty {
             TokenType::BangEqual => self.instructions.push(Op::Ne),
@@ -662,8 +2661,77 @@ impl<'a> ExprVisitorMut<'a, ()> for Compiler {
             TokenType::Star => self.instructions.push(Op::Mul),
             TokenType::Percent => self.instructions.push(Op::Mod),
             TokenType::Caret => self.instructions.push(Op::Exp),
-            _ => self.errors.push(Error::Compile{line: 0, msg: "Invalid binary operator".into()}),
+            _ => self.errors.push(Error::Compile{line: self.line, msg: "Invalid binary operator".into()}),
+        }
+    }
+
+    fn visit_call_expr(&mut self, expr: &Call<'a>) {
+        let name = expr.callee.lexeme;
+        // Methods are called on their first argument, so that's counted in their arity too
+        let (op, arity) = match (name, expr.method) {
+            ("normalize_angle", _) => (Op::NormalizeAngle(self.angle_range), 1),
+            ("sort", _) => (Op::Sort, 1),
+            ("format", false) if expr.args.len() == 2 => {
+                // The format has to be known at compile time, since it's part of the op
+                let format = match &expr.args[1] {
+                    Expr::Literal(Literal{value}) => value.string().and_then(|s| s.parse().ok()),
+                    _ => None,
+                };
+                let Some(format) = format else {
+                    self.errors.push(Error::Compile{line: self.line, msg: "The format must be one of 'auto', 'fixed', or 'scientific'".into()});
+                    return;
+                };
+                expr.args[0].accept_mut(self);
+                self.instructions.push(Op::Format(format));
+                return;
+            }
+            ("format", false) => (Op::Format(self.number_format), 1),
+            ("abs", true) => (Op::Abs, 1),
+            ("round", true) => (Op::Round, 1),
+            ("len", true) => (Op::Len, 1),
+            (_, true) => {
+                self.errors.push(Error::Compile{line: self.line, msg: format!("Unknown method '{}'", name)});
+                return;
+            }
+            // Callables that return something can be called like functions
+            (_, false) if self.callables.contains_key(name) && !self.groups.contains_key(name) => {
+                let args: Vec<_> = expr.args.iter().collect();
+                if let Err(e) = self.call_for_value(name, &args) {
+                    self.errors.push(e);
+                }
+                return;
+            }
+            (_, false) => {
+                self.errors.push(Error::Compile{line: self.line, msg: format!("Unknown function '{}'", name)});
+                return;
+            }
+        };
+        if expr.args.len() != arity {
+            let msg = if expr.method {
+                format!("Method '{}' expected {} arguments but got {}", name, arity - 1, expr.args.len() - 1)
+            } else {
+                format!("Call to '{}' expected {} arguments but got {}", name, arity, expr.args.len())
+            };
+            self.errors.push(Error::Call(msg));
+            return;
+        }
+        for arg in expr.args.iter() {
+            arg.accept_mut(self);
         }
+        self.instructions.push(op);
+    }
+
+    fn visit_list_expr(&mut self, expr: &List<'a>) {
+        for element in expr.elements.iter() {
+            element.accept_mut(self);
+        }
+        self.instructions.push(Op::MakeList(expr.elements.len()));
+    }
+
+    fn visit_index_expr(&mut self, expr: &Index<'a>) {
+        expr.target.accept_mut(self);
+        expr.index.accept_mut(self);
+        self.instructions.push(Op::Index);
     }
 
     // binary_expr!('a, or, xor, logical, [Or]);
@@ -677,15 +2745,16 @@ impl<'a> ExprVisitorMut<'a, ()> for Compiler {
                 let sub_program = self.isolate(|this| {
                     expr.right.accept_mut(this);
                 });
-                self.instructions.push(Op::JumpUnless(sub_program.len() as isize + 1));
+                // Short-circuiting leaves the duplicated left operand as the result
+                self.instructions.push(Op::JumpUnless(sub_program.len() as isize + 2));
                 self.instructions.extend(sub_program);
                 self.instructions.push(Op::And);
             }
             TokenType::Or => {
                 let sub_program = self.isolate(|this| {
-                    expr.left.accept_mut(this);
+                    expr.right.accept_mut(this);
                 });
-                self.instructions.push(Op::JumpIf(sub_program.len() as isize + 1));
+                self.instructions.push(Op::JumpIf(sub_program.len() as isize + 2));
                 self.instructions.extend(sub_program);
                 self.instructions.push(Op::Or);
             }
@@ -694,7 +2763,7 @@ impl<'a> ExprVisitorMut<'a, ()> for Compiler {
                 self.instructions.push(Op::Xor);
             }
             _ => {
-                self.errors.push(Error::Compile{line: 0, msg: "Invalid logical operator".into()})
+                self.errors.push(Error::Compile{line: self.line, msg: "Invalid logical operator".into()})
             }
         }
     }
@@ -702,34 +2771,54 @@ impl<'a> ExprVisitorMut<'a, ()> for Compiler {
 
 impl<'a> StmtVisitorMut<'a, ()> for Compiler {
     fn visit_var_stmt(&mut self, stmt: &Var<'a>) {
-        stmt.value.accept_mut(self);
-        let LexLiteral::Ident(name) = stmt.name.literal.unwrap() else {
-            self.errors.push(Error::Compile{line: 0, msg: "Invalid variable name".into()});
-            return;
-        };
+        let unit = self.unit_of(&stmt.value);
+        let target = self.target_unit(&stmt.name, unit);
+        self.cache_pure_props(&[&stmt.value]);
+        self.compile_in_unit(&stmt.value, target);
+        self.end_prop_cache();
+        self.assign(&stmt.name);
+    }
 
-        if let Some(prop) = self.properties.get(name) {
-            match prop.settable() {
-                Ok(false) => {
-                    self.errors.push(Error::Compile{line: 0, msg: format!("External property '{}' not settable", name)});
-                    return;
-                }
-                Err(e) => {
-                    self.errors.push(e);
-                    return;
-                }
-                _ => {}
+    fn visit_capture_stmt(&mut self, stmt: &Capture<'a>) {
+        let call = &stmt.call;
+        let (name, consumed) = match self.resolve_callable(call.name.lexeme, &call.args) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                self.errors.push(e);
+                return;
             }
-            self.instructions.push(Op::Set(name.into()));
-        } else {
-            let idx = self.declare_var(name);
-            self.instructions.push(Op::Store(idx));
+        };
+        let args = &call.args[consumed..];
+
+        if let Err(e) = self.check_call(&name, args) {
+            self.errors.push(e);
+            return;
+        }
+        let returns = self.callables.get(&name)
+            .and_then(|c| c.signature())
+            .is_some_and(|s| s.returns);
+        if !returns {
+            self.errors.push(Error::Compile{line: self.line, msg: format!("'{}' doesn't return a value, so its result can't be assigned", name)});
+            return;
         }
+
+        let values: Vec<_> = args.iter().filter_map(|arg| match arg {
+            AstArg::Value(v) => Some(v.as_ref()),
+            AstArg::Word(_) => None,
+        }).collect();
+        let units = self.arg_units(&name, args);
+        self.cache_pure_props(&values);
+        for (i, v) in values.iter().enumerate() {
+            self.compile_in_unit(v, units.get(i).copied().flatten());
+        }
+        self.end_prop_cache();
+        self.instructions.push(Op::CallValue(name, values.len()));
+        self.assign(&stmt.name);
     }
 
     fn visit_use_stmt(&mut self, stmt: &Use<'a>) {
         let LexLiteral::Ident(name) = stmt.name.literal.unwrap() else {
-            self.errors.push(Error::Compile{line: 0, msg: "'use' statements must only use identifiers".into()});
+            self.errors.push(Error::Compile{line: self.line, msg: "'use' statements must only use identifiers".into()});
             return;
         };
 
@@ -741,72 +2830,113 @@ impl<'a> StmtVisitorMut<'a, ()> for Compiler {
         self.allowed_props.insert(name.into());
     }
     
-    fn visit_exec_stmt(&mut self, stmt: &Exec<'a>) {
-        let name = stmt.name.lexeme;
-        if !self.groups.contains_key(name) && !self.callables.contains_key(name) {
-            self.errors.push(Error::UnknownCallable(name.into()));
+    fn visit_spawn_stmt(&mut self, stmt: &Spawn<'a>) {
+        let call = &stmt.call;
+        let (name, consumed) = match self.resolve_callable(call.name.lexeme, &call.args) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                self.errors.push(e);
+                return;
+            }
+        };
+        let args = &call.args[consumed..];
+        let Some(group) = self.groups.get(&name) else {
+            self.errors.push(Error::Compile{line: self.line, msg: format!("Only groups can be spawned, and '{}' isn't one", name)});
             return;
-        } 
+        };
+        if group.data.has_inout() {
+            self.errors.push(Error::Compile{line: self.line, msg: format!("'{}' has inout parameters, so it can't be spawned", name)});
+            return;
+        }
+        if let Err(e) = self.check_call(&name, args) {
+            self.errors.push(e);
+            return;
+        }
 
-        let arg_kinds = stmt.args.iter().map(|a| {
-            match a {
-                AstArg::Word(w) => Arg::Word(w.lexeme.into()),
-                AstArg::Value(_) => Arg::Value,
-            }
+        // Pushed in reverse, like parallel calls, since the new context pops them off in order
+        let values: Vec<_> = args.iter().filter_map(|arg| match arg {
+            AstArg::Value(v) => Some(v.as_ref()),
+            AstArg::Word(_) => None,
         }).collect();
+        for v in values.iter().rev() {
+            v.accept_mut(self);
+        }
+        self.instructions.push(Op::Spawn(name, values.len()));
+    }
 
-        if let Some(callable) = self.groups.get(name) {
-            if let Err(e) = callable.check_syntax(arg_kinds) {
+    fn visit_exec_stmt(&mut self, stmt: &Exec<'a>) {
+        let (name, consumed) = match self.resolve_callable(stmt.name.lexeme, &stmt.args) {
+            Ok(resolved) => resolved,
+            Err(e) => {
                 self.errors.push(e);
                 return;
             }
-        } else {
-            if let Err(e) = self.callables[name].check_syntax(arg_kinds) {
+        };
+        let args = &stmt.args[consumed..];
+
+        if let Err(e) = self.check_call(&name, args) {
+            self.errors.push(e);
+            return;
+        }
+
+        let cleanup = match self.inout_writeback(&name, args) {
+            Ok(cleanup) => cleanup,
+            Err(e) => {
                 self.errors.push(e);
                 return;
             }
-        }
+        };
 
-        let mut arity = 0;
-        for arg in stmt.args.iter() {
-            if let AstArg::Value(v) = arg {
-                arity += 1;
-                v.accept_mut(self);
-            }
+        let values: Vec<_> = args.iter().filter_map(|arg| match arg {
+            AstArg::Value(v) => Some(v.as_ref()),
+            AstArg::Word(_) => None,
+        }).collect();
+        let units = self.arg_units(&name, args);
+        self.cache_pure_props(&values);
+        for (i, v) in values.iter().enumerate() {
+            self.compile_in_unit(v, units.get(i).copied().flatten());
         }
+        self.end_prop_cache();
+        let arity = values.len();
 
-        self.instructions.push(Op::Call(name.into(), arity));
+        self.instructions.push(Op::Call(name, arity));
+        self.instructions.extend(cleanup);
     }
 
 
     fn visit_parallel_stmt(&mut self, stmt: &Parallel<'a>) {
         let mut call_args = Vec::new();
         for call in stmt.calls.iter() {
-            let name = call.name.lexeme;
-            if !self.groups.contains_key(name) && !self.callables.contains_key(name) {
-                self.errors.push(Error::UnknownCallable(name.into()));
-                return;
-            } 
+            let (name, consumed) = match self.resolve_callable(call.name.lexeme, &call.args) {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    self.errors.push(e);
+                    return;
+                }
+            };
+            let name = name.as_str();
+            let args = &call.args[consumed..];
         
-            let arg_kinds = call.args.iter().map(|a| {
+            let arg_kinds = args.iter().map(|a| {
                 match a {
                     AstArg::Word(w) => Arg::Word(w.lexeme.into()),
                     AstArg::Value(_) => Arg::Value,
                 }
             }).collect();
 
-            let arity = call.args.iter().filter(|a| if let AstArg::Value(_) = a {true} else {false}).count();
-            let name = if let Some(callable) = self.groups.get(name) {
-                if let Err(e) = callable.check_syntax(arg_kinds) {
-                    self.errors.push(e);
+            if let Err(e) = self.check_call(name, args) {
+                self.errors.push(e);
+                return;
+            }
+
+            let arity = args.iter().filter(|a| if let AstArg::Value(_) = a {true} else {false}).count();
+            let name = if let Some(group) = self.groups.get(name) {
+                if group.data.has_inout() {
+                    self.errors.push(Error::Compile{line: self.line, msg: format!("'{}' has inout parameters, so it can't be called in parallel", name)});
                     return;
                 }
                 name.to_string()
             } else {
-                if let Err(e) = self.callables[name].check_syntax(arg_kinds.clone()) {
-                    self.errors.push(e);
-                    return;
-                }
 
                 let anonymous_name = format!("#{}", name);
                 
@@ -818,6 +2948,7 @@ impl<'a> StmtVisitorMut<'a, ()> for Compiler {
                     data: GroupData {
                         name: anonymous_name.clone(),
                         params: arg_kinds,
+                        inout: Vec::new(),
                     },
                     code: vec![
                         // There's no need to push call arguments on the stack since the groups args 
@@ -839,11 +2970,16 @@ impl<'a> StmtVisitorMut<'a, ()> for Compiler {
         
         let prep = self.isolate(|this| {
             for call in stmt.calls.iter().rev() {
-                for arg in call.args.iter().rev() {
-                    match arg {
-                        AstArg::Value(expr) => expr.accept_mut(this),
-                        AstArg::Word(_) => continue,
-                    }
+                let units = match this.resolve_callable(call.name.lexeme, &call.args) {
+                    Ok((name, consumed)) => this.arg_units(&name, &call.args[consumed..]),
+                    Err(_) => Vec::new(),
+                };
+                let values = call.args.iter().filter_map(|arg| match arg {
+                    AstArg::Value(expr) => Some(expr),
+                    AstArg::Word(_) => None,
+                });
+                for (i, expr) in values.enumerate().collect::<Vec<_>>().into_iter().rev() {
+                    this.compile_in_unit(expr, units.get(i).copied().flatten());
                 }
             }
         });
@@ -861,119 +2997,448 @@ impl<'a> StmtVisitorMut<'a, ()> for Compiler {
     }
 
     fn visit_group_stmt(&mut self, stmt: &Group<'a>) {
-        self.begin_scope();
-        
         let name = stmt.name.lexeme.to_string();
     
         if self.callables.contains_key(&name) {
             self.errors.push(Error::DuplicateCallable(name));
             return;
         }
+        if self.implicit_main && name == "__main" {
+            self.errors.push(Error::Compile{line: self.line, msg: "'__main' is reserved for the implicit main group".into()});
+            return;
+        }
+
+        let mut description = Vec::new();
+        let mut best_effort = false;
+        for attribute in stmt.attributes.iter() {
+            match attribute.name.lexeme {
+                "timeout" => match parse_timeout(&attribute.args) {
+                    Ok(timeout) => {self.timeouts.insert(name.clone(), timeout);}
+                    Err(msg) => self.errors.push(Error::Compile{line: self.line, msg}),
+                }
+                "best_effort" if attribute.args.is_empty() => best_effort = true,
+                "best_effort" => self.errors.push(Error::Compile{line: self.line, msg: "'best_effort' doesn't take arguments".into()}),
+                "doc" => for arg in attribute.args.iter() {
+                    match arg.literal.and_then(|lit| lit.string()) {
+                        Some(line) => description.push(line),
+                        None => self.errors.push(Error::Compile{line: self.line, msg: "Expect strings in 'doc' attribute".into()}),
+                    }
+                }
+                other => self.errors.push(Error::Compile{line: self.line, msg: format!("Unknown attribute '{}'", other)}),
+            }
+        }
+        // There'd be nothing to write back if the group were abandoned partway through
+        if self.timeouts.contains_key(&name) && stmt.params.iter().any(|p| p.inout) {
+            self.errors.push(Error::Compile{line: self.line, msg: format!("Group '{}' has inout parameters, so it can't have a timeout", name)});
+        }
+
+        for p in stmt.params.iter() {
+            if let Some(LexLiteral::Ident(param)) = p.name.literal {
+                self.check_shadowing(param);
+            }
+        }
+
+        self.begin_scope();
 
-        let params: Vec<_> = stmt.params.iter().map(|t| {
-            match t.ty {
-                TokenType::Word => Arg::Word(t.lexeme.to_string()),
+        let mut inout = Vec::new();
+        let params: Vec<_> = stmt.params.iter().map(|p| {
+            match p.name.ty {
+                TokenType::Word => Arg::Word(p.name.lexeme.to_string()),
                 TokenType::Ident => {
-                    let LexLiteral::Ident(name) = t.literal.unwrap() else {unreachable!()};
+                    let LexLiteral::Ident(name) = p.name.literal.unwrap() else {unreachable!()};
                     self.declare_var(name);
+                    inout.push(p.inout);
                     Arg::Value
                 }
                 _ => unreachable!(),
             }
         }).collect();
         
+        // Declared before its body is compiled, so that it can call itself
+        let data = GroupData {name: name.clone(), params: params.clone(), inout: inout.clone()};
+        self.groups.insert(name.clone(), CompiledGroup {data, code: Vec::new()});
 
-        let body = self.isolate(|this| {
+        let since = self.pending_jumps.len();
+        let was_in_group = std::mem::replace(&mut self.in_group, true);
+        let mut body = self.isolate(|this| {
             this.instructions.push(Op::Label(name.clone()));
             for stmt in stmt.statements.iter() {
-                stmt.accept_mut(this);
-            }
-            for _ in 0..params.len() {
-                this.instructions.push(Op::Pop);
+                if !best_effort {
+                    this.statement(stmt);
+                    continue;
+                }
+                // If the statement fails, the group skips to the next one
+                let checkpoint = this.instructions.len();
+                this.instructions.push(Op::Checkpoint(0));
+                this.statement(stmt);
+                this.instructions[checkpoint] = Op::Checkpoint((this.instructions.len() - checkpoint) as isize);
             }
-            this.instructions.push(Op::Return);
         });
+        self.in_group = was_in_group;
+
+        let epilogue = body.len();
+        self.backpatch(&mut body, since, |target| match target {
+            JumpTarget::GroupEnd => Some(epilogue),
+            _ => None,
+        });
+
+        self.group_info.insert(name.clone(), GroupInfo {
+            params: params.clone(),
+            description: (!description.is_empty()).then(|| description.join("\n")),
+        });
+        let data = GroupData {
+            name: name.clone(),
+            params,
+            inout,
+            // address: self.current_ip(),
+        };
+        let variables = unsafe {self.variables.last().unwrap_unchecked()}.len();
+        let locals = variables - data.inout.len();
+        // After the label, so the group's address doesn't change
+        let label = body.remove(0);
+        let mut body = self.reserve_locals(locals, body);
+        body.insert(0, label);
+        self.check_uninitialized(&body, data.inout.len());
+
+        let pops = if data.has_inout() {
+            locals
+        } else {
+            variables
+        };
+        for _ in 0..pops {
+            body.push(Op::Pop);
+        }
+        body.push(Op::Return);
+        if self.tail_calls && !data.has_inout() && !self.timeouts.contains_key(&name) {
+            body = eliminate_tail_calls(body, &name, data.inout.len(), locals);
+        }
     
         let group = CompiledGroup {
-            data: GroupData {
-                name: name.clone(),
-                params,
-                // address: self.current_ip(),
-            },
+            data,
             code: body,
         };
         
         // self.instructions.extend(body);
         self.groups.insert(name.clone(), group);
 
+        let scope = unsafe {self.variables.last().unwrap_unchecked()};
+        self.symbols.groups.insert(name, slot_names(scope));
         self.end_scope();
     }
 
 
+    // The branch that isn't picked doesn't exist as far as the program's concerned
+    fn visit_conditional_stmt(&mut self, stmt: &Conditional<'a>) {
+        let branch = if self.flags.contains(stmt.flag.lexeme) {&stmt.then_branch} else {&stmt.else_branch};
+        for line in branch.iter() {
+            self.statement(line);
+        }
+    }
+
     fn visit_if_stmt(&mut self, stmt: &If<'a>) {
+        self.check_constant_condition(&stmt.condition, stmt.invert, false);
+        self.cache_pure_props(&[&stmt.condition]);
         stmt.condition.accept_mut(self);
+        self.end_prop_cache();
         if stmt.invert {
             self.instructions.push(Op::Not);
         }
+        if let Some((name, then_value, else_value)) = self.select_candidate(stmt) {
+            let unit = self.unit_of(then_value).or(self.unit_of(else_value));
+            let target = self.target_unit(name, unit);
+            self.compile_in_unit(then_value, target);
+            self.compile_in_unit(else_value, target);
+            self.instructions.push(Op::Select);
+            self.assign(name);
+            return;
+        }
         let then_branch = self.isolate(|this| {
             for then in stmt.then_branch.iter() {
-                then.accept_mut(this);
+                this.statement(then);
             }
         });
 
         let else_branch = self.isolate(|this| {
             for els in stmt.else_branch.iter() {
-                els.accept_mut(this);
+                this.statement(els);
             }
         });
         let then_len = then_branch.len() as isize;
         let else_len = else_branch.len() as isize;
 
-        self.instructions.push(Op::JumpUnless(then_len + 1));
-        self.instructions.extend(then_branch);
-        self.instructions.push(Op::Jump(else_len + 1));
+        if else_branch.is_empty() {
+            self.instructions.push(Op::JumpUnless(then_len + 1));
+            self.instructions.extend(then_branch);
+        } else {
+            self.instructions.push(Op::JumpUnless(then_len + 2));
+            self.instructions.extend(then_branch);
+            self.instructions.push(Op::Jump(else_len + 1));
+            self.instructions.extend(else_branch);
+        }
     }
 
     fn visit_while_stmt(&mut self, stmt: &While<'a>) {
+        let depth = self.loops.len();
+        self.loops.push(stmt.label.as_ref().map(|l| l.lexeme.to_string()));
+        let since = self.pending_jumps.len();
+
+        self.check_constant_condition(&stmt.condition, stmt.invert, true);
         let condition = self.isolate(|this| {
+            this.cache_pure_props(&[&stmt.condition]);
             stmt.condition.accept_mut(this);
+            this.end_prop_cache();
             if stmt.invert {
                 this.instructions.push(Op::Not);
             }
         });
         let body = self.isolate(|this| {
             for line in stmt.body.iter() {
-                line.accept_mut(this)
+                this.statement(line)
             }
         });
+        self.loops.pop();
+
+        let mut code = Vec::new();
+        let mut check_deadline = Vec::new();
+        let mut on_timeout = Vec::new();
+        if let Some(timeout) = &stmt.timeout {
+            let secs = match parse_timeout(&timeout.duration) {
+                Ok(d) => d.as_secs_f64(),
+                Err(msg) => {
+                    self.errors.push(Error::Compile{line: self.line, msg});
+                    0.0
+                }
+            };
+            // Nested loops each need their own deadline, but consecutive ones can share
+            let scope = unsafe {self.variables.last_mut().unwrap_unchecked()};
+            let len = scope.len();
+            let deadline = *scope.entry(format!("#deadline{}", depth)).or_insert(len);
+            code.extend([Op::Now, Op::Push(Value::Number(secs)), Op::Add, Op::Store(deadline)]);
+
+            on_timeout = self.isolate(|this| {
+                for line in timeout.else_body.iter() {
+                    this.statement(line)
+                }
+            });
+            let skip_else = if on_timeout.is_empty() {0} else {1};
+            check_deadline.extend([Op::Now, Op::Load(deadline), Op::Lt]);
+            check_deadline.push(Op::JumpUnless(body.len() as isize + 2 + skip_else));
+        }
+
+        if !on_timeout.is_empty() {
+            // Skips the timeout's else body when the condition ends the loop instead
+            on_timeout.insert(0, Op::Jump(on_timeout.len() as isize + 1));
+        }
+        let len = (condition.len() + check_deadline.len() + body.len()) as isize;
+        let next = code.len();
+        code.extend(condition);
+        code.push(Op::JumpUnless((check_deadline.len() + body.len() + on_timeout.len()) as isize + 2));
+        code.extend(check_deadline);
+        code.extend(body);
+        code.push(Op::Jump(-(len + 1)));
+        code.extend(on_timeout);
+
+        let exit = code.len();
+        self.backpatch(&mut code, since, |target| match target {
+            JumpTarget::LoopExit(d) if d == depth => Some(exit),
+            JumpTarget::LoopContinue(d) if d == depth => Some(next),
+            _ => None,
+        });
+        self.instructions.extend(code);
+    }
 
-        let len = (condition.len() + body.len()) as isize;
-        self.instructions.extend(condition);
-        self.instructions.push(Op::JumpUnless(body.len() as isize + 2));
-        self.instructions.extend(body);
-        self.instructions.push(Op::Jump(-(len + 1)));
+    fn visit_repeat_stmt(&mut self, stmt: &Repeat<'a>) {
+        let depth = self.loops.len();
+        self.loops.push(stmt.label.as_ref().map(|l| l.lexeme.to_string()));
+        let since = self.pending_jumps.len();
 
-        let sofar = self.instructions.len();
-        // backpatch any breaks
-        for (i, inst) in self.instructions.iter_mut().enumerate() {
-            if let Op::Jump(a) = inst {
-                if *a == 0 {
-                    *a = (sofar - i) as isize;
-                }
+        let mut code = self.isolate(|this| {
+            this.cache_pure_props(&[&stmt.count]);
+            stmt.count.accept_mut(this);
+            this.end_prop_cache();
+        });
+        let body = self.isolate(|this| {
+            for line in stmt.body.iter() {
+                this.statement(line)
             }
-        }
+        });
+        self.loops.pop();
+
+        let scope = unsafe {self.variables.last_mut().unwrap_unchecked()};
+        let len = scope.len();
+        let counter = *scope.entry(format!("#counter{}", depth)).or_insert(len);
+
+        // The count is checked at the bottom, so a count of 0 skips the body entirely
+        code.push(Op::InitCounter(counter));
+        code.push(Op::Jump(body.len() as isize + 1));
+        let len = body.len() as isize;
+        code.extend(body);
+        let next = code.len();
+        code.push(Op::DecCounterJumpNonZero(counter, -len));
+
+        let exit = code.len();
+        self.backpatch(&mut code, since, |target| match target {
+            JumpTarget::LoopExit(d) if d == depth => Some(exit),
+            JumpTarget::LoopContinue(d) if d == depth => Some(next),
+            _ => None,
+        });
+        self.instructions.extend(code);
+    }
+
+    fn visit_foreach_stmt(&mut self, stmt: &ForEach<'a>) {
+        let depth = self.loops.len();
+        self.loops.push(stmt.label.as_ref().map(|l| l.lexeme.to_string()));
+        let since = self.pending_jumps.len();
+
+        let mut code = self.isolate(|this| stmt.iterable.accept_mut(this));
+        // Loading the element is compiled with the body, so the loop variable is declared in order
+        let body = self.isolate(|this| {
+            let scope = unsafe {this.variables.last_mut().unwrap_unchecked()};
+            let len = scope.len();
+            let iter = *scope.entry(format!("#iter{}", depth)).or_insert(len);
+            let len = scope.len();
+            let index = *scope.entry(format!("#index{}", depth)).or_insert(len);
+            this.instructions.extend([Op::Load(iter), Op::Load(index), Op::Index]);
+            this.assign(&stmt.var);
+            for line in stmt.body.iter() {
+                this.statement(line)
+            }
+            this.instructions.extend([Op::Load(index), Op::Push(Value::Number(1.0)), Op::Add, Op::Store(index)]);
+        });
+        self.loops.pop();
+
+        let scope = unsafe {self.variables.last().unwrap_unchecked()};
+        let iter = scope[&format!("#iter{}", depth)];
+        let index = scope[&format!("#index{}", depth)];
+
+        // The list is evaluated once, so changing it inside the loop doesn't change what's looped over
+        code.extend([Op::Store(iter), Op::Push(Value::Number(0.0)), Op::Store(index)]);
+        let check = [Op::Load(index), Op::Load(iter), Op::Len, Op::Lt];
+        let len = (check.len() + body.len()) as isize;
+        code.extend(check);
+        code.push(Op::JumpUnless(body.len() as isize + 2));
+        // `continue` still moves on to the next element
+        let next = code.len() + body.len() - 4;
+        code.extend(body);
+        code.push(Op::Jump(-(len + 1)));
+
+        let exit = code.len();
+        self.backpatch(&mut code, since, |target| match target {
+            JumpTarget::LoopExit(d) if d == depth => Some(exit),
+            JumpTarget::LoopContinue(d) if d == depth => Some(next),
+            _ => None,
+        });
+        self.instructions.extend(code);
+    }
+
+    fn visit_forrange_stmt(&mut self, stmt: &ForRange<'a>) {
+        let depth = self.loops.len();
+        self.loops.push(stmt.label.as_ref().map(|l| l.lexeme.to_string()));
+        let since = self.pending_jumps.len();
+
+        let mut code = self.isolate(|this| {
+            this.cache_pure_props(&[&stmt.start, &stmt.end]);
+            stmt.start.accept_mut(this);
+            stmt.end.accept_mut(this);
+            this.end_prop_cache();
+        });
+        // The loop variable is set from a hidden counter, so assigning to it in the body doesn't
+        // change how many times the loop runs
+        let body = self.isolate(|this| {
+            let scope = unsafe {this.variables.last_mut().unwrap_unchecked()};
+            let len = scope.len();
+            let index = *scope.entry(format!("#index{}", depth)).or_insert(len);
+            this.instructions.push(Op::Load(index));
+            this.assign(&stmt.var);
+            for line in stmt.body.iter() {
+                this.statement(line)
+            }
+            this.instructions.extend([Op::Load(index), Op::Push(Value::Number(1.0)), Op::Add, Op::Store(index)]);
+        });
+        self.loops.pop();
+
+        let scope = unsafe {self.variables.last_mut().unwrap_unchecked()};
+        let index = scope[&format!("#index{}", depth)];
+        let len = scope.len();
+        let end = *scope.entry(format!("#end{}", depth)).or_insert(len);
+
+        code.extend([Op::Store(end), Op::Store(index)]);
+        let check = [Op::Load(index), Op::Load(end), Op::Lt];
+        let len = (check.len() + body.len()) as isize;
+        code.extend(check);
+        code.push(Op::JumpUnless(body.len() as isize + 2));
+        // `continue` still moves on to the next element
+        let next = code.len() + body.len() - 4;
+        code.extend(body);
+        code.push(Op::Jump(-(len + 1)));
+
+        let exit = code.len();
+        self.backpatch(&mut code, since, |target| match target {
+            JumpTarget::LoopExit(d) if d == depth => Some(exit),
+            JumpTarget::LoopContinue(d) if d == depth => Some(next),
+            _ => None,
+        });
+        self.instructions.extend(code);
+    }
+
+    fn visit_dowhile_stmt(&mut self, stmt: &DoWhile<'a>) {
+        let depth = self.loops.len();
+        self.loops.push(stmt.label.as_ref().map(|l| l.lexeme.to_string()));
+        let since = self.pending_jumps.len();
+
+        let mut code = self.isolate(|this| {
+            for line in stmt.body.iter() {
+                this.statement(line)
+            }
+        });
+        self.loops.pop();
+        // `continue` checks the condition, like reaching the end of the body does
+        let next = code.len();
+        // No constant condition warning, since `do { ... } while false;` is a reasonable way to
+        // get a block that can be broken out of
+        code.extend(self.isolate(|this| {
+            this.cache_pure_props(&[&stmt.condition]);
+            stmt.condition.accept_mut(this);
+            this.end_prop_cache();
+        }));
+        let back = -(code.len() as isize);
+        code.push(if stmt.invert {Op::JumpUnless(back)} else {Op::JumpIf(back)});
+
+        let exit = code.len();
+        self.backpatch(&mut code, since, |target| match target {
+            JumpTarget::LoopExit(d) if d == depth => Some(exit),
+            JumpTarget::LoopContinue(d) if d == depth => Some(next),
+            _ => None,
+        });
+        self.instructions.extend(code);
     }
 
     fn visit_return_stmt(&mut self, _stmt: &Return<'a>) {
-        self.instructions.push(Op::Return);
+        if self.in_group {
+            // The group's epilogue still needs to clean up the stack
+            self.placeholder_jump(JumpTarget::GroupEnd);
+        } else {
+            self.instructions.push(Op::Return);
+        }
     }
     
+    fn visit_halt_stmt(&mut self, _stmt: &Halt<'a>) {
+        self.instructions.push(Op::Halt);
+    }
+
     fn visit_yield_stmt(&mut self, _stmt: &Yield<'a>) {
         self.instructions.push(Op::Yield);
     }
 
-    fn visit_break_stmt(&mut self, _stmt: &Break<'a>) {
-        self.instructions.push(Op::Jump(0)); // We'll use 0 to indicate that backpatching is
-                                             // necessary, since jump 0 doesn't make any sense
+    fn visit_break_stmt(&mut self, stmt: &Break<'a>) {
+        if let Some(depth) = self.target_loop(&stmt.tok, &stmt.label) {
+            self.placeholder_jump(JumpTarget::LoopExit(depth));
+        }
+    }
+
+    fn visit_continue_stmt(&mut self, stmt: &Continue<'a>) {
+        if let Some(depth) = self.target_loop(&stmt.tok, &stmt.label) {
+            self.placeholder_jump(JumpTarget::LoopContinue(depth));
+        }
     }
 }
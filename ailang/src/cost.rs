@@ -0,0 +1,199 @@
+use crate::compiler::{Op, Value, Program, scan_groups};
+
+use std::collections::HashMap;
+use std::ops::AddAssign;
+use std::time::Duration;
+
+/// A rough estimate of how long a program runs, worked out without running it. See
+/// `Program::estimated_cost`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CostEstimate {
+    /// The time spent in calls whose durations are known ahead of time. Only the longer side of an
+    /// `if`/`else` is counted, but an `if` without an `else` is counted as if it's always taken.
+    pub known: Duration,
+    /// How many calls were left out because their duration isn't known.
+    pub unknown_calls: usize,
+    /// Whether the program has a loop that runs an unknown number of times, in which case `known`
+    /// only covers a single pass through it.
+    pub unbounded: bool,
+}
+
+impl AddAssign for CostEstimate {
+    fn add_assign(&mut self, other: CostEstimate) {
+        self.known += other.known;
+        self.unknown_calls += other.unknown_calls;
+        self.unbounded |= other.unbounded;
+    }
+}
+
+impl CostEstimate {
+    fn times(self, n: u32) -> CostEstimate {
+        CostEstimate {
+            known: self.known * n,
+            unknown_calls: self.unknown_calls * n as usize,
+            unbounded: self.unbounded,
+        }
+    }
+
+    // For branches, where only one of them runs
+    fn max(self, other: CostEstimate) -> CostEstimate {
+        CostEstimate {
+            known: self.known.max(other.known),
+            unknown_calls: self.unknown_calls.max(other.unknown_calls),
+            unbounded: self.unbounded || other.unbounded,
+        }
+    }
+}
+
+struct Estimator<'a> {
+    program: &'a Program,
+    groups: HashMap<String, usize>,
+    main_start: usize,
+    estimated: HashMap<usize, CostEstimate>, // group address -> its cost
+    visiting: Vec<usize>, // groups being estimated, to catch recursion
+}
+
+impl<'a> Estimator<'a> {
+    // The value an op pushes, if it's a constant
+    fn constant(&self, op: &Op) -> Option<Value> {
+        match op {
+            Op::Push(v) => Some(v.clone()),
+            Op::Const(i) => self.program.constants.get(*i).cloned(),
+            _ => None,
+        }
+    }
+
+    fn group(&mut self, name: &str) -> CostEstimate {
+        let Some(&addr) = self.groups.get(name) else {
+            return CostEstimate {unknown_calls: 1, ..Default::default()};
+        };
+        if let Some(cost) = self.estimated.get(&addr) {
+            return *cost;
+        }
+        if self.visiting.contains(&addr) {
+            return CostEstimate {unbounded: true, ..Default::default()};
+        }
+        self.visiting.push(addr);
+        let end = self.program.code[addr+1..self.main_start].iter()
+            .position(|op| matches!(op, Op::Label(_)))
+            .map(|i| addr + 1 + i)
+            .unwrap_or(self.main_start);
+        let mut cost = self.region(addr + 1, end);
+        self.visiting.pop();
+
+        // A group that's cut off by its timeout can't take any longer than that
+        if let Some(timeout) = self.program.timeouts.get(name) && (cost.unbounded || cost.known > *timeout) {
+            cost = CostEstimate {known: *timeout, unknown_calls: cost.unknown_calls, unbounded: false};
+        }
+        self.estimated.insert(addr, cost);
+        cost
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Option<Value>>) -> CostEstimate {
+        if self.groups.contains_key(name) {
+            return self.group(name);
+        }
+        match self.program.callables.get(name).and_then(|c| c.estimated_duration(&args)) {
+            Some(known) => CostEstimate {known, ..Default::default()},
+            None => CostEstimate {unknown_calls: 1, ..Default::default()},
+        }
+    }
+
+    // The cost of running the code from `start` up to `end` once
+    fn region(&mut self, start: usize, end: usize) -> CostEstimate {
+        let program = self.program;
+        let code = &program.code[..];
+        let mut cost = CostEstimate::default();
+        let mut i = start;
+        while i < end {
+            let op = &code[i];
+            match op {
+                Op::Call(name, arity) | Op::CallValue(name, arity) => {
+                    let args = code[i.saturating_sub(*arity)..i].iter().map(|op| self.constant(op)).collect();
+                    cost += self.call(name, args);
+                }
+                Op::CallParallel(calls) => {
+                    let mut slowest = CostEstimate::default();
+                    for (name, _) in calls {
+                        slowest = slowest.max(self.group(name));
+                    }
+                    cost += slowest;
+                }
+                Op::CallRace(calls) => {
+                    let mut costs = calls.iter().map(|(name, _)| self.group(name)).collect::<Vec<_>>();
+                    // The race is over as soon as its quickest bounded member finishes
+                    costs.sort_by_key(|c| (c.unbounded, c.known));
+                    cost += costs.first().copied().unwrap_or_default();
+                }
+                // `repeat n { ... }`, laid out as `InitCounter`, a jump to the bottom, the body, and
+                // `DecCounterJumpNonZero`
+                Op::InitCounter(_) if let Some(Op::Jump(skip)) = code.get(i + 1) => {
+                    let check = (i + 1).wrapping_add_signed(*skip);
+                    let body = self.region(i + 2, check.min(end));
+                    let count = i.checked_sub(1).and_then(|c| self.constant(&code[c]));
+                    cost += match count {
+                        Some(Value::Number(n)) if n.is_finite() => body.times(n.clamp(0.0, u32::MAX as f64) as u32),
+                        _ => CostEstimate {unbounded: true, ..body},
+                    };
+                    i = check + 1;
+                    continue;
+                }
+                // Only taken when something goes wrong
+                Op::Checkpoint(_) => {}
+                op if let Some(offset) = op.jump_offset() => {
+                    let target = i.wrapping_add_signed(offset);
+                    if offset <= 0 {
+                        // Any other jump backwards is a loop that runs until some condition is met
+                        cost.unbounded = true;
+                    } else if matches!(op, Op::Jump(_)) {
+                        // Leaving the region, like `break` does, ends this path through it
+                        if target > end {
+                            return cost;
+                        }
+                        i = target;
+                        continue;
+                    } else if target <= end {
+                        // An `else` branch starts where the `then` branch jumps over it
+                        let then_end = target - 1;
+                        if let Some(Op::Jump(skip)) = code.get(then_end) && *skip > 0 && then_end > i {
+                            let else_end = then_end.wrapping_add_signed(*skip).min(end);
+                            let then_branch = self.region(i + 1, then_end);
+                            let else_branch = self.region(target, else_end);
+                            cost += then_branch.max(else_branch);
+                            i = else_end;
+                            continue;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        cost
+    }
+}
+
+impl Program {
+    /// Estimates how long the program takes to run from the durations its callables report with
+    /// `CallableGenerator::estimated_duration`, and the number of times each loop runs, where those
+    /// are known ahead of time. It's only a rough guide for planning. Programs that weren't compiled
+    /// don't have their callables, so none of their calls can be estimated.
+    pub fn estimated_cost(&self) -> CostEstimate {
+        let main_start = match self.code.first() {
+            Some(Op::Jump(a)) if *a > 0 => *a as usize,
+            _ => 0,
+        };
+        let mut estimator = Estimator {
+            program: self,
+            groups: self.groups.clone().unwrap_or_else(|| scan_groups(&self.code)),
+            main_start,
+            estimated: HashMap::new(),
+            visiting: Vec::new(),
+        };
+        let mut cost = estimator.region(main_start, self.code.len());
+        if estimator.groups.contains_key("__end") {
+            cost += estimator.group("__end");
+        }
+        cost
+    }
+}
@@ -0,0 +1,96 @@
+use crate::compiler::{Op, Program, scan_groups};
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use itertools::Itertools;
+
+// For text inside a double-quoted DOT string
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl Program {
+    /// Draws the program's control flow as a Graphviz graph, for documentation and review. Each
+    /// node is a basic block, i.e. a run of ops that always run one after another, headed with the
+    /// group it starts, if any. Solid edges are jumps and falling through to the next block, with
+    /// conditional jumps labelled "taken", and dashed edges are calls to groups.
+    pub fn to_dot(&self) -> String {
+        let code = &self.code[..];
+        let groups = self.groups.clone().unwrap_or_else(|| scan_groups(code));
+        let main_start = match code.first() {
+            Some(Op::Jump(a)) if *a > 0 => (*a as usize).min(code.len()),
+            _ => 0,
+        };
+
+        // Blocks start at labels and jump targets, and straight after anything that can jump
+        let mut starts = BTreeSet::from([0, main_start]);
+        for (i, op) in code.iter().enumerate() {
+            if let Op::Label(_) = op {
+                starts.insert(i);
+            }
+            if let Some(a) = op.jump_offset() {
+                starts.insert(i.wrapping_add_signed(a));
+                starts.insert(i + 1);
+            }
+            if matches!(op, Op::Return | Op::Halt) {
+                starts.insert(i + 1);
+            }
+        }
+        let starts: Vec<usize> = starts.into_iter().filter(|s| *s < code.len()).collect();
+        // The block an address is in, or the end of the program
+        let node = |addr: usize| if addr < code.len() {
+            format!("b{}", starts[starts.partition_point(|s| *s <= addr) - 1])
+        } else {
+            "end".to_string()
+        };
+
+        let mut dot = String::from("digraph program {\n    node [shape=box, fontname=monospace];\n    end [shape=oval];\n");
+        for (n, &start) in starts.iter().enumerate() {
+            let end = starts.get(n + 1).copied().unwrap_or(code.len());
+            let block = &code[start..end];
+            let heading = match block.first() {
+                Some(Op::Label(name)) => format!("group {}\\n", escape(name)),
+                _ if start == main_start => "main\\n".to_string(),
+                _ => String::new(),
+            };
+            let ops: String = block.iter().enumerate()
+                .filter(|(_, op)| !matches!(op, Op::Label(_)))
+                .map(|(i, op)| format!("{}: {}\\l", start + i, escape(&op.to_string())))
+                .collect();
+            let _ = writeln!(dot, "    b{} [label=\"{}{}\"];", start, heading, ops);
+
+            let mut edges = Vec::new();
+            for op in block.iter() {
+                let (called, kind): (Vec<&str>, _) = match op {
+                    Op::Call(name, _) | Op::CallValue(name, _) => (vec![name], "call"),
+                    Op::Spawn(name, _) => (vec![name], "spawn"),
+                    Op::CallParallel(calls) => (calls.iter().map(|(name, _)| name.as_str()).collect(), "parallel"),
+                    Op::CallRace(calls) => (calls.iter().map(|(name, _)| name.as_str()).collect(), "race"),
+                    _ => continue,
+                };
+                // Calls to callables don't go anywhere in the program
+                for addr in called.into_iter().filter_map(|name| groups.get(name)) {
+                    edges.push(format!("{} [style=dashed, label=\"{}\"]", node(*addr), kind));
+                }
+            }
+            let last = end - 1;
+            match &code[last] {
+                Op::Return => {}
+                Op::Halt => edges.push("end [label=\"halt\"]".to_string()),
+                Op::Jump(a) => edges.push(node(last.wrapping_add_signed(*a))),
+                op => {
+                    if let Some(a) = op.jump_offset() {
+                        let label = if let Op::Checkpoint(_) = op {"on error"} else {"taken"};
+                        edges.push(format!("{} [label=\"{}\"]", node(last.wrapping_add_signed(a)), label));
+                    }
+                    edges.push(node(end));
+                }
+            }
+            for edge in edges.into_iter().unique() {
+                let _ = writeln!(dot, "    b{} -> {};", start, edge);
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
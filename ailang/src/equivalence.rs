@@ -0,0 +1,143 @@
+use crate::compiler::{Op, Program, scan_groups};
+
+use std::collections::{HashMap, VecDeque};
+
+// A program split into the bodies of its groups and its top-level code
+struct Layout<'a> {
+    program: &'a Program,
+    groups: HashMap<&'a str, &'a [Op]>,
+    main: &'a [Op],
+}
+
+impl<'a> Layout<'a> {
+    fn new(program: &'a Program) -> Layout<'a> {
+        let code = &program.code[..];
+        let main_start = match code.first() {
+            Some(Op::Jump(a)) if *a > 0 => (*a as usize).min(code.len()),
+            _ => 0,
+        };
+        let mut starts: Vec<_> = scan_groups(&code[..main_start]).into_values().collect();
+        starts.sort();
+        let ends = starts.iter().skip(1).copied().chain([main_start]);
+        let groups = starts.iter().zip(ends).map(|(&start, end)| {
+            let Op::Label(name) = &code[start] else {unreachable!()};
+            (name.as_str(), &code[start + 1..end])
+        }).collect();
+        Layout {program, groups, main: &code[main_start..]}
+    }
+}
+
+// Matches up the groups of two programs as it compares their code
+#[derive(Clone)]
+struct Renaming<'a> {
+    renamed: HashMap<&'a str, &'a str>,
+    renamed_from: HashMap<&'a str, &'a str>,
+    unchecked: VecDeque<(&'a str, &'a str)>, // matched groups whose bodies haven't been compared
+}
+
+struct Matcher<'a> {
+    ours: Layout<'a>,
+    theirs: Layout<'a>,
+    renaming: Renaming<'a>,
+}
+
+impl<'a> Matcher<'a> {
+    fn same_name(&mut self, a: &'a str, b: &'a str) -> bool {
+        let renaming = &mut self.renaming;
+        match (self.ours.groups.contains_key(a), self.theirs.groups.contains_key(b)) {
+            (true, true) => match (renaming.renamed.get(a), renaming.renamed_from.get(b)) {
+                (None, None) => {
+                    renaming.renamed.insert(a, b);
+                    renaming.renamed_from.insert(b, a);
+                    renaming.unchecked.push_back((a, b));
+                    true
+                }
+                (Some(x), Some(y)) => *x == b && *y == a,
+                _ => false,
+            },
+            // Callables aren't renamed
+            (false, false) => a == b,
+            _ => false,
+        }
+    }
+
+    fn same_op(&mut self, ours: &'a Op, theirs: &'a Op) -> bool {
+        match (ours, theirs) {
+            (Op::Call(a, n), Op::Call(b, m)) | (Op::CallValue(a, n), Op::CallValue(b, m))
+                    | (Op::Spawn(a, n), Op::Spawn(b, m)) => n == m && self.same_name(a, b),
+            (Op::CallParallel(ours), Op::CallParallel(theirs)) | (Op::CallRace(ours), Op::CallRace(theirs)) => {
+                ours.len() == theirs.len()
+                    && ours.iter().zip(theirs.iter()).all(|((a, n), (b, m))| n == m && self.same_name(a, b))
+            }
+            (Op::Const(i), Op::Const(j)) => self.ours.program.constants.get(*i) == self.theirs.program.constants.get(*j),
+            (a, b) => a == b,
+        }
+    }
+
+    fn same_code(&mut self, ours: &'a [Op], theirs: &'a [Op]) -> bool {
+        ours.len() == theirs.len() && ours.iter().zip(theirs.iter()).all(|(a, b)| self.same_op(a, b))
+    }
+
+    // Comparing groups can match up the groups they call, so this keeps going until there are none
+    // left to compare
+    fn same_unchecked(&mut self) -> bool {
+        while let Some((a, b)) = self.renaming.unchecked.pop_front() {
+            let (ours, theirs) = (self.ours.groups[a], self.theirs.groups[b]);
+            if !self.same_code(ours, theirs) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Groups that nothing calls can only be matched up by trying each pairing
+    fn same_uncalled(&mut self) -> bool {
+        let mut ours: Vec<_> = self.ours.groups.keys().copied()
+            .filter(|name| !self.renaming.renamed.contains_key(name))
+            .collect();
+        ours.sort();
+        for a in ours {
+            let mut theirs: Vec<_> = self.theirs.groups.keys().copied()
+                .filter(|name| !self.renaming.renamed_from.contains_key(name))
+                .collect();
+            theirs.sort();
+            let matched = theirs.into_iter().any(|b| {
+                let before = self.renaming.clone();
+                if self.same_name(a, b) && self.same_unchecked() {
+                    return true;
+                }
+                self.renaming = before;
+                false
+            });
+            if !matched {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Program {
+    /// Whether two programs do the same thing, even if their groups are named differently, for
+    /// checking a compiler's output without depending on the names it picks. Groups have to be
+    /// renamed consistently, and can be laid out in any order, but calls to callables have to match
+    /// exactly. Constants are compared by value rather than by their place in the pool. Debug
+    /// symbols and group info are ignored.
+    pub fn structurally_eq(&self, other: &Program) -> bool {
+        let (ours, theirs) = (Layout::new(self), Layout::new(other));
+        if self.code.len() != other.code.len() || ours.groups.len() != theirs.groups.len() {
+            return false;
+        }
+        let (main, their_main) = (ours.main, theirs.main);
+        let mut matcher = Matcher {
+            ours,
+            theirs,
+            renaming: Renaming {renamed: HashMap::new(), renamed_from: HashMap::new(), unchecked: VecDeque::new()},
+        };
+        let same_code = matcher.same_code(main, their_main) && matcher.same_unchecked() && matcher.same_uncalled();
+        let renamed = &matcher.renaming.renamed;
+        same_code && self.timeouts.len() == other.timeouts.len() && self.timeouts.iter().all(|(name, timeout)| {
+            renamed.get(name.as_str()).and_then(|name| other.timeouts.get(*name)) == Some(timeout)
+        })
+    }
+}
@@ -1,21 +1,28 @@
 use crate::token::OwnedToken;
+use crate::compiler::OpCategory;
+
+use std::collections::HashMap;
 
 #[derive(Debug, thiserror::Error, Clone)]
 #[allow(dead_code)]
 pub enum Error {
-    #[error("[line {line}] {msg}")]
-    Lex{line: usize, msg: String},
+    #[error("[line {line}, column {column}] {msg}")]
+    Lex{line: usize, column: usize, msg: String},
     #[error("[end] {msg}")]
     EndOfStream{msg: String},
-    #[error("[line {line}] {msg}", line = tok.line)]
+    #[error("[line {line}, column {column}] {msg}", line = tok.line, column = tok.column)]
     Parse{tok: OwnedToken, msg: String},
     #[error("[line {line}] {msg}")]
     Compile{line: usize, msg: String},
+    #[error("[line {line}, column {column}] Expression is nested more than {limit} levels deep", line = tok.line, column = tok.column)]
+    ExpressionTooDeep{tok: OwnedToken, limit: usize},
 
     #[error("A callable named '{0}' is already registered")]
     DuplicateCallable(String),
     #[error("A property named '{0}' is already registered")]
     DuplicateProperty(String),
+    #[error("'{0}' is a keyword, so it can't be registered as a name")]
+    ReservedName(String),
 
     #[error("No callable named '{0}' was registered")]
     UnknownCallable(String),
@@ -26,10 +33,11 @@ pub enum Error {
 
     #[error("[address {0}] '{1}' is not a settable property")]
     UnsettableProperty(usize, String),
-    #[error("[address {0}] '{1}' is not a registered property")]
-    UnregisteredProperty(usize, String),
-    #[error("[address {0}] '{1}' is not a registered callable")]
-    UnregisteredCallable(usize, String),
+    /// The last field is the closest registered name, if one is close enough to be a typo.
+    #[error("[address {0}] '{1}' is not a registered property{hint}", hint = did_you_mean(.2))]
+    UnregisteredProperty(usize, String, Option<String>),
+    #[error("[address {0}] '{1}' is not a registered callable{hint}", hint = did_you_mean(.2))]
+    UnregisteredCallable(usize, String, Option<String>),
     #[error("[address {0}] Only built-in callables can be called in parallel")]
     InvalidCall(usize),
 
@@ -40,16 +48,39 @@ pub enum Error {
     StackUnderflow(usize),
     // #[error("[address {0}] Stack overflow")]
     // StackOverflow(usize),
-    #[error("[address {0}] Attempt to index outside of the stack")]
+    /// Raised for indices past the end of a list or string, as well as bad stack slots.
+    #[error("[address {0}] Index is out of bounds")]
     IndexOutOfBounds(usize),
     #[error("{0}")]
     Type(String),
+    #[error("[address {0}] Execution left a group without returning from it")]
+    UnbalancedCall(usize),
+    #[error("[address {0}] Arithmetic produced a result that isn't a finite number")]
+    #[allow(clippy::enum_variant_names)]
+    NumericError(usize),
+    #[error("[address {0}] Execution went differently than the trace being replayed: {1}")]
+    TraceMismatch(usize, String),
+    #[error("[address {0}] Operations of kind '{1}' are not allowed")]
+    ForbiddenOperation(usize, OpCategory),
+    #[error("[address {0}] '{1}' kept yielding without finishing")]
+    CallableStalled(usize, String),
+    #[error("[address {0}] This call would take the program past its limit on time spent waiting")]
+    WaitBudgetExceeded(usize),
+    #[error("[address {0}] The call to '{1}' was refused")]
+    CallRefused(usize, String),
+
 
+    #[error("'{0}' can't be used to start variable names")]
+    InvalidSigil(char),
+    #[error("'{0}' can't be used as an operator, since it isn't a plain word")]
+    InvalidOperator(String),
 
     #[error("Cannot modify compiler state while it's running")]
     CompilerActive,
     #[error("Cannot modify interpreter state while it's running")]
     InterpreterActive,
+    #[error("'{0}' cannot be replaced while a call to it is in progress")]
+    CallableInUse(String),
 
     #[error("Something went wrong while accessing from multiple threads. Consider not doing that.")]
     ThreadingError,
@@ -61,6 +92,14 @@ pub enum Error {
 
     #[error("[line {line}] {msg}")]
     IRParse{line: usize, msg: String},
+    #[error("Bytecode version {0} is not supported")]
+    UnsupportedBytecodeVersion(u16),
+    #[error("Invalid bytecode: {0}")]
+    InvalidBytecode(String),
+    #[error("A group named '{0}' is defined in more than one program")]
+    DuplicateGroup(String),
+    #[error("Cannot link program {0}: {1}")]
+    Link(usize, String),
 
     #[error("{0}")]
     #[allow(dead_code)]
@@ -68,3 +107,202 @@ pub enum Error {
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+fn did_you_mean(suggestion: &Option<String>) -> String {
+    match suggestion {
+        Some(name) => format!(". Did you mean '{}'?", name),
+        None => String::new(),
+    }
+}
+
+// The candidate closest to `name`, if it's within a few edits, i.e. what `name` is likely a typo of.
+pub(crate) fn closest_name<'n>(name: &str, candidates: impl IntoIterator<Item = &'n str>) -> Option<String> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates.into_iter()
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(d, _)| *d <= limit)
+        .min()
+        .map(|(_, c)| c.to_string())
+}
+
+// Levenshtein distance, in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// How many errors to report when one mistake leads to several, e.g. when the parser recovers
+/// partway through a malformed statement and trips over the rest of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorDetail {
+    /// Every error, however many come from the same mistake.
+    #[default]
+    Full,
+    /// Only the first error on each line, or the first the lexer found there if there are any,
+    /// since parse errors on a line the lexer couldn't make sense of are usually knock-on effects.
+    /// Errors that aren't tied to a line are all kept.
+    Summarized,
+}
+
+impl ErrorDetail {
+    pub(crate) fn apply(self, errors: Vec<Error>) -> Vec<Error> {
+        if self == ErrorDetail::Full {
+            return errors;
+        }
+        let mut first: HashMap<usize, (usize, bool)> = HashMap::new();
+        for (i, e) in errors.iter().enumerate() {
+            if let Some(line) = e.line() {
+                let lex = matches!(e, Error::Lex{..});
+                first.entry(line)
+                    .and_modify(|(kept, kept_lex)| if lex && !*kept_lex {*kept = i; *kept_lex = true})
+                    .or_insert((i, lex));
+            }
+        }
+        errors.into_iter().enumerate()
+            .filter(|(i, e)| e.line().is_none_or(|line| first[&line].0 == *i))
+            .map(|(_, e)| e)
+            .collect()
+    }
+}
+
+/// Problems the compiler noticed that don't stop a program from compiling, but are likely mistakes.
+#[derive(Debug, thiserror::Error, Clone, PartialEq)]
+pub enum Warning {
+    #[error("[line {line}] {msg}")]
+    ConstantCondition{line: usize, value: bool, msg: String},
+    #[error("[line {line}] Variable '{name}' might be read before it's assigned")]
+    PossiblyUninitialized{line: usize, name: String},
+    /// `property` is whether it's a property that's shadowed, rather than a variable outside the group.
+    #[error("[line {line}] Parameter '{name}' has the same name as {outer}", outer = if *property {"a property, which is what it refers to"} else {"a variable outside its group"})]
+    ParameterShadowsOuter{line: usize, name: String, property: bool},
+}
+
+impl Error {
+    /// The line the error was found on, for errors that come from source code. Code compiled
+    /// without its source gives line 0, which doesn't count as a line.
+    pub fn line(&self) -> Option<usize> {
+        let line = match self {
+            Error::Lex{line, ..} | Error::Compile{line, ..} | Error::IRParse{line, ..} => *line,
+            Error::Parse{tok, ..} | Error::ExpressionTooDeep{tok, ..} => tok.line,
+            _ => return None,
+        };
+        (line > 0).then_some(line)
+    }
+
+    /// The column the error was found at, for errors that can point to one.
+    pub fn column(&self) -> Option<usize> {
+        match self {
+            Error::Lex{column, ..} => Some(*column),
+            Error::Parse{tok, ..} | Error::ExpressionTooDeep{tok, ..} => Some(tok.column),
+            _ => None,
+        }
+    }
+}
+
+impl Warning {
+    pub fn line(&self) -> usize {
+        match self {
+            Warning::ConstantCondition{line, ..} | Warning::PossiblyUninitialized{line, ..} |
+            Warning::ParameterShadowsOuter{line, ..} => *line,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub enum Diagnostic {
+    Error(Error),
+    Warning(Warning),
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Diagnostic::Error(_) => Severity::Error,
+            Diagnostic::Warning(_) => Severity::Warning,
+        }
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Diagnostic::Error(e) => e.line(),
+            Diagnostic::Warning(w) => Some(w.line()).filter(|line| *line > 0),
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Diagnostic::Error(e) => write!(f, "error: {}", e),
+            Diagnostic::Warning(w) => write!(f, "warning: {}", w),
+        }
+    }
+}
+
+/// Everything a compilation found wrong with a program, in the order it appears in the source.
+/// Problems that aren't tied to a line come last.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    entries: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new(errors: Vec<Error>, warnings: Vec<Warning>) -> Diagnostics {
+        let mut entries: Vec<_> = errors.into_iter().map(Diagnostic::Error)
+            .chain(warnings.into_iter().map(Diagnostic::Warning))
+            .collect();
+        // stable, so problems on the same line keep the order they were found in
+        entries.sort_by_key(|d| d.line().unwrap_or(usize::MAX));
+        Diagnostics {entries}
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.entries.iter().filter(|d| d.severity() == severity).count()
+    }
+
+    /// Whether anything was found at `severity` or worse, e.g. to fail a build on warnings.
+    pub fn any_at_least(&self, severity: Severity) -> bool {
+        self.entries.iter().any(|d| d.severity() >= severity)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.entries.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Diagnostics {
+    type Item = &'a Diagnostic;
+    type IntoIter = std::slice::Iter<'a, Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
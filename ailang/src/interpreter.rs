@@ -3,16 +3,24 @@ use std::collections::{HashMap, HashSet, VecDeque};
 
 // use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Mutex};
+use std::time::{Duration, Instant};
 
-use crate::compiler::{Program, Op, Value, Callable, CallableGenerator, Prop};
+use crate::compiler::{Program, Op, Value, Callable, CallableGenerator, Prop, ComputedProp, DebugSymbols, GroupInfo, scan_groups};
 use crate::ast::{GroupKind};
-use crate::error::{Error};
+use crate::error::{Error, closest_name};
+use crate::lexer::is_keyword;
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct StackFrame {
     return_addr: usize,
+    // Where the group's parameters start. They're pushed by the caller, so a group's parameters
+    // are the first of its slots, followed by its locals.
     stack_offset: usize,
+    group: usize, // address of the group's label
+    region: Option<(usize, usize)>, // only tracked when checking calls
+    deadline: Option<Duration>, // clock time the group is abandoned at, if it has a timeout
+    checkpoint: Option<(usize, usize)>, // where a best-effort group carries on after an error, and its stack height there
 }
 
 #[derive(Debug)]
@@ -26,6 +34,15 @@ struct ExecutionContext {
     parent: Option<*mut ExecutionContext>,
     native_proxy: bool,
     current_callable: Option<u32>,
+    called: String, // the name the current callable is registered as, once middleware's had its say
+    polls: u32, // how many times the current callable has yielded
+    // The group a parallel sub-context was started in, since it has no frame for it. Only tracked
+    // when checking calls.
+    base_region: Option<(usize, usize)>,
+    // Likewise, the deadline of a timed group started in parallel
+    deadline: Option<Duration>,
+    group: Option<usize>, // the group a parallel sub-context runs, if any
+    base_checkpoint: Option<(usize, usize)>, // likewise, for a best-effort group started in parallel
 }
 
 impl ExecutionContext {
@@ -40,6 +57,12 @@ impl ExecutionContext {
             parent: None,
             native_proxy: false,
             current_callable: None,
+            called: String::new(),
+            polls: 0,
+            base_region: None,
+            deadline: None,
+            group: None,
+            base_checkpoint: None,
         }
     }
 
@@ -71,6 +94,40 @@ impl ExecutionContext {
     fn stack_offset(&self) -> usize {
         self.call_stack.last().map(|frame| frame.stack_offset).unwrap_or(0)
     }
+
+    // A copy to go back to later, as long as nothing's running under it
+    fn snapshot(&self) -> Option<ExecutionContext> {
+        if !self.dependencies.is_empty() || self.current_callable.is_some() {
+            return None;
+        }
+        Some(ExecutionContext {
+            call_stack: self.call_stack.clone(),
+            stack: self.stack.clone(),
+            ip: self.ip,
+            dependencies: Vec::new(),
+            active: self.active,
+            dependency_type: self.dependency_type,
+            parent: None,
+            native_proxy: self.native_proxy,
+            current_callable: None,
+            called: String::new(),
+            polls: self.polls,
+            base_region: self.base_region,
+            deadline: self.deadline,
+            group: self.group,
+            base_checkpoint: self.base_checkpoint,
+        })
+    }
+}
+
+// The state before a step, for `Interpreter::step_back`
+struct Snapshot {
+    root: ExecutionContext,
+    state: InternalState,
+    callable_index: u32,
+    total_wait: Duration,
+    globals: Option<VecDeque<Value>>,
+    writes: Vec<(String, Option<Value>)>, // properties set since, with the values they had before if they could be read
 }
 
 
@@ -81,6 +138,7 @@ enum ExecutionState {
     ThreadsAdded,
     CallEnd,
     Stop,
+    Halt,
 }
 
 #[derive(PartialEq, Debug)]
@@ -89,7 +147,7 @@ pub enum InterpreterState {
     Stop,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 enum InternalState {
     Unstarted,
     Suspended,
@@ -98,17 +156,205 @@ enum InternalState {
     Finished,
 }
 
+/// A source of the current time, used to enforce group timeouts. Only differences between times
+/// matter, so the epoch is up to the implementation.
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+/// The default `Clock`, which measures time since its creation.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {start: Instant::now()}
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+type GroupHook = Box<dyn FnMut(GroupEvent)>;
+// Given a stalled callable's name and how many times it's yielded, returns whether to keep waiting
+type StallHook = Box<dyn FnMut(&str, u32) -> bool>;
+// Given the name of a callable about to be started and its arguments, returns the name of the
+// callable to start instead, or `None` to refuse the call
+type CallMiddleware = Box<dyn FnMut(&str, &[Value]) -> Option<String>>;
+type RecoveryHook = Box<dyn FnMut(&Error)>;
+
+/// Passed to the hook set with `Interpreter::set_group_hook` as groups start and finish.
+#[derive(Debug, PartialEq)]
+pub enum GroupEvent<'a> {
+    Enter {name: &'a str},
+    Exit {name: &'a str},
+}
+
+/// An interaction with the outside world, as recorded by `Interpreter::record`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    /// A property was read.
+    Get(String),
+    /// A property was set.
+    Set(String),
+    /// A callable was polled, but hadn't finished yet.
+    Wait(String),
+    /// A callable finished. Its entry holds its result, or nil if it doesn't return one.
+    Call(String),
+}
+
+impl std::fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceEvent::Get(name) => write!(f, "a read of '{}'", name),
+            TraceEvent::Set(name) => write!(f, "a write to '{}'", name),
+            TraceEvent::Wait(name) | TraceEvent::Call(name) => write!(f, "a call to '{}'", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    /// The address of the op that caused the event.
+    pub ip: usize,
+    pub event: TraceEvent,
+    pub value: Value,
+}
+
+/// Something a dry run skipped, as returned by `Interpreter::run_dry`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DryRunAction {
+    /// A property would have been set to the value.
+    Set(String, Value),
+    /// A callable would have been called with the arguments.
+    Call(String, Vec<Value>),
+}
+
+/// Which ops have run at least once, as collected after `Interpreter::set_coverage`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Coverage {
+    /// Whether the op at each address has run.
+    pub executed: Vec<bool>,
+}
+
+impl Coverage {
+    pub fn covered(&self, addr: usize) -> bool {
+        self.executed.get(addr).copied().unwrap_or(false)
+    }
+
+    /// The addresses of the ops that never ran, in order.
+    pub fn uncovered(&self) -> Vec<usize> {
+        self.executed.iter().enumerate().filter(|(_, ran)| !**ran).map(|(addr, _)| addr).collect()
+    }
+
+    /// The fraction of ops that ran, from 0 to 1. An empty program counts as fully covered.
+    pub fn ratio(&self) -> f64 {
+        if self.executed.is_empty() {
+            return 1.0;
+        }
+        self.executed.iter().filter(|ran| **ran).count() as f64 / self.executed.len() as f64
+    }
+
+    /// Adds the ops that ran in `other`, e.g. from another run of the same program.
+    pub fn merge(&mut self, other: &Coverage) {
+        if self.executed.len() < other.executed.len() {
+            self.executed.resize(other.executed.len(), false);
+        }
+        for (ran, other) in self.executed.iter_mut().zip(other.executed.iter()) {
+            *ran |= *other;
+        }
+    }
+}
+
+enum Trace {
+    Off,
+    Recording(Vec<TraceEntry>),
+    Replaying(VecDeque<TraceEntry>),
+}
+
+impl Trace {
+    fn replaying(&self) -> bool {
+        matches!(self, Trace::Replaying(_))
+    }
+
+    fn record(&mut self, ip: usize, event: TraceEvent, value: &Value) {
+        if let Trace::Recording(trace) = self {
+            trace.push(TraceEntry {ip, event, value: value.clone()});
+        }
+    }
+
+    // Takes the next entry being replayed, which has to be one of `events` at `ip`.
+    fn next(&mut self, ip: usize, events: &[TraceEvent]) -> Result<TraceEntry, Error> {
+        let Trace::Replaying(trace) = self else {unreachable!()};
+        match trace.front() {
+            Some(entry) if entry.ip == ip && events.contains(&entry.event) => Ok(trace.pop_front().unwrap()),
+            Some(entry) => Err(Error::TraceMismatch(ip, format!("expected {} at address {}", entry.event, entry.ip))),
+            None => Err(Error::TraceMismatch(ip, "the trace has already ended".into())),
+        }
+    }
+
+    // Like `next`, for the call at `ip`. Middleware may have sent it to a different callable while
+    // it was recorded, so an entry for any callable is accepted.
+    fn next_call(&mut self, ip: usize) -> Result<TraceEntry, Error> {
+        let Trace::Replaying(trace) = self else {unreachable!()};
+        let called = match trace.front() {
+            Some(TraceEntry {event: TraceEvent::Wait(name) | TraceEvent::Call(name), ..}) => name.clone(),
+            _ => String::new(),
+        };
+        self.next(ip, &[TraceEvent::Wait(called.clone()), TraceEvent::Call(called)])
+    }
+}
+
 pub struct Interpreter {
     program: Vec<Op>,
+    constants: Vec<Value>,
+    timeouts: HashMap<String, Duration>,
+    symbols: DebugSymbols,
+    group_info: HashMap<String, GroupInfo>,
+    clock: Box<dyn Clock>,
     root_context: ExecutionContext,
+    // Groups started with `spawn`. Boxed so that they stay put while the list grows.
+    #[allow(clippy::vec_box)]
+    background: Vec<Box<ExecutionContext>>,
     // ip: usize,
     // stack: Vec<Value>,
     // call_stack: Vec<StackFrame>,
     props: HashMap<String, Box<dyn Prop>>,
+    prop_defaults: HashMap<String, Value>, // read in place of properties that aren't registered
     callables: HashMap<String, Box<dyn CallableGenerator>>,
     active_callables: HashMap<u32, Box<dyn Callable>>,
     callable_index: u32,
     groups: HashMap<String, usize>,
+    check_calls: bool,
+    yield_per_statement: bool,
+    checked_arithmetic: bool,
+    group_hook: Option<GroupHook>,
+    stall_limit: Option<u32>,
+    stall_hook: Option<StallHook>,
+    call_middleware: Vec<CallMiddleware>,
+    recovery_hook: Option<RecoveryHook>,
+    trace: Trace,
+    step_over_target: Option<usize>, // the call depth `step_over_group` is running back to
+    dry_run: Option<Vec<DryRunAction>>, // what's been skipped so far, during `run_dry`
+    globals: Option<VecDeque<Value>>, // what the main flow left in its variables, once it's finished
+    coverage: Option<Vec<bool>>, // whether each op has run, once enabled with `set_coverage`
+    max_total_wait: Option<Duration>,
+    total_wait: Duration, // how long the calls made so far said they'd take
+    history: VecDeque<Snapshot>,
+    history_limit: usize,
+    time_steps: bool,
+    last_step_duration: Duration,
+    lines: Vec<(usize, usize)>, // see `Program::lines`
     state: Mutex<InternalState>,
 }
 
@@ -132,6 +378,37 @@ macro_rules! binop {
         }
     }
 }
+// Booleans are ordered too, with false before true, and strings by their characters' code points,
+// but values of different types can't be compared
+macro_rules! compare {
+    ($self:expr, $op:tt) => {{
+        let a = pop!($self)?;
+        let b = pop!($self)?;
+
+        match (a, b) {
+            (Value::Number(n), Value::Number(m)) => m $op n,
+            (Value::Bool(n), Value::Bool(m)) => m $op n,
+            (Value::String(n), Value::String(m)) => m $op n,
+            (right, left) => {return Err(Error::Type(format!("Can't order {} and {}; both operands must be numbers, booleans, or strings", left.type_name(), right.type_name())));},
+        }
+    }}
+}
+
+macro_rules! cmpop {
+    ($self:expr, $op:tt) => {
+        let cond = compare!($self, $op);
+        $self.stack.push_back(Value::Bool(cond));
+    }
+}
+
+// A comparison fused with a `JumpUnless`
+macro_rules! cmpjump {
+    ($self:expr, $offset:expr, $op:tt) => {
+        let cond = compare!($self, $op);
+        if !cond {$self.ip = $self.ip.wrapping_add_signed($offset - 1);}
+    }
+}
+
 macro_rules! logicop {
     ($self:expr, $op:tt) => {
         let a = pop!($self)?;
@@ -141,6 +418,17 @@ macro_rules! logicop {
     }
 }
 
+// Takes the hook and program separately so that it can be called while an op is borrowed
+fn fire_group_event(hook: &mut Option<GroupHook>, program: &[Op], addr: usize, enter: bool) {
+    let Some(hook) = hook.as_mut() else {
+        return;
+    };
+    // Groups starting with '#' are generated to run callables in parallel
+    if let Some(Op::Label(name)) = program.get(addr) && !name.starts_with('#') {
+        hook(if enter {GroupEvent::Enter {name}} else {GroupEvent::Exit {name}});
+    }
+}
+
 // These are safe because all modifying access to non-internal non-Send/Sync resources is strictly
 // governed by the `state` variable, which *is* thread-safe.
 
@@ -150,61 +438,90 @@ unsafe impl Sync for Interpreter {}
 impl Interpreter {
     #[allow(dead_code)]
     pub fn new(program: Vec<Op>) -> Interpreter {
+        let groups = scan_groups(&program);
+        Interpreter::with_groups(program, groups)
+    }
+
+    // An interpreter for `program` with nothing registered and every option at its default
+    fn with_groups(program: Vec<Op>, groups: HashMap<String, usize>) -> Interpreter {
         Interpreter {
-            groups: Self::scan_groups(&program),
+            groups,
             program,
+            constants: Vec::new(),
+            timeouts: HashMap::new(),
+            symbols: DebugSymbols::default(),
+            group_info: HashMap::new(),
+            clock: Box::new(SystemClock::new()),
             root_context: ExecutionContext::new(0),
+            background: Vec::new(),
             props: HashMap::new(),
+            prop_defaults: HashMap::new(),
             callables: HashMap::new(),
             active_callables: HashMap::new(),
             callable_index: 0,
+            check_calls: false,
+            yield_per_statement: false,
+            checked_arithmetic: false,
+            group_hook: None,
+            stall_limit: None,
+            stall_hook: None,
+            call_middleware: Vec::new(),
+            recovery_hook: None,
+            trace: Trace::Off,
+            step_over_target: None,
+            dry_run: None,
+            globals: None,
+            history: VecDeque::new(),
+            history_limit: 0,
+            time_steps: false,
+            lines: Vec::new(),
+            last_step_duration: Duration::ZERO,
+            coverage: None,
+            max_total_wait: None,
+            total_wait: Duration::ZERO,
             state: Mutex::new(InternalState::Unstarted),
         }
     }
 
     pub fn from_ir(program: &str) -> Result<Interpreter, Error> {
         let mut prog: Vec<Op> = Vec::new();
+        let mut constants = Vec::new();
         for (i, line) in program.lines().enumerate() {
-            if line.len() > 0 {
-                let op = line.parse().map_err(|e| {
-                    match e {
-                        Error::IRParse{msg, ..} => Error::IRParse{line: i+1, msg},
-                        err => err,
-                    }
-                })?;
+            let backpatch_line = |e| {
+                match e {
+                    Error::IRParse{msg, ..} => Error::IRParse{line: i+1, msg},
+                    err => err,
+                }
+            };
+            if let Some(constant) = line.strip_prefix(".const ") {
+                constants.push(constant.parse().map_err(backpatch_line)?);
+            } else if !line.is_empty() {
+                let op = line.parse().map_err(backpatch_line)?;
                 prog.push(op);
             }
         }
-        Ok(Interpreter::new(prog))
+        let mut interpreter = Interpreter::new(prog);
+        interpreter.constants = constants;
+        Ok(interpreter)
     }
 
     pub fn from_program(program: Program) -> Interpreter {
+        let groups = program.groups.unwrap_or_else(|| scan_groups(&program.code));
         Interpreter {
-            groups: Self::scan_groups(&program.code),
-            program: program.code,
-            root_context: ExecutionContext::new(0),
+            constants: program.constants,
+            timeouts: program.timeouts,
+            symbols: program.symbols,
+            group_info: program.group_info,
             props: program.props,
             callables: program.callables,
-            active_callables: HashMap::new(),
-            callable_index: 0,
-            state: Mutex::new(InternalState::Unstarted),
+            lines: program.lines,
+            ..Interpreter::with_groups(program.code, groups)
         }
     }
 
     #[allow(dead_code)]
     pub fn run(program: Program) -> Result<(), Error> {
-        let mut interpreter = Interpreter {
-            groups: Self::scan_groups(&program.code),
-            program: program.code,
-            root_context: ExecutionContext::new(0),
-            props: program.props,
-            callables: program.callables,
-            active_callables: HashMap::new(),
-            callable_index: 0,
-            state: Mutex::new(InternalState::Unstarted),
-        };
-
-        interpreter.interpret()
+        Interpreter::from_program(program).interpret()
     }
 
     #[allow(dead_code)]
@@ -212,6 +529,9 @@ impl Interpreter {
         if *self.state.lock().map_err(|_| Error::ThreadingError)? != InternalState::Unstarted {
             return Err(Error::InterpreterActive);
         }
+        if is_keyword(name) {
+            return Err(Error::ReservedName(name.into()));
+        }
         if self.callables.contains_key(name) || self.groups.contains_key(name) {
             return Err(Error::DuplicateCallable(name.into()));
         }
@@ -219,11 +539,43 @@ impl Interpreter {
         Ok(())
     }
     
+    /// Replaces the implementation of an already registered callable. Unlike `register_callable`,
+    /// this works while the program is running, so long as no call to `name` is in progress (i.e.
+    /// no call has yielded and is waiting to be polled again). Calls made after this returns use
+    /// the new implementation. Since the compiler checked calls against the old implementation's
+    /// syntax, the replacement must accept the same arguments.
+    pub fn replace_callable(&mut self, name: &str, callable: Box<dyn CallableGenerator>) -> Result<(), Error> {
+        if !self.callables.contains_key(name) {
+            return Err(Error::UnknownCallable(name.into()));
+        }
+        if self.callable_in_progress(name) {
+            return Err(Error::CallableInUse(name.into()));
+        }
+        self.callables.insert(name.to_string(), callable);
+        Ok(())
+    }
+
+    fn callable_in_progress(&self, name: &str) -> bool {
+        let mut stack = vec![&self.root_context];
+        stack.extend(self.background.iter().map(|ctx| &**ctx));
+        while let Some(ctx) = stack.pop() {
+            // A pending call always rewinds to its own `Call` op
+            if ctx.current_callable.is_some() && ctx.called == name {
+                return true;
+            }
+            stack.extend(ctx.dependencies.iter());
+        }
+        false
+    }
+
     #[allow(dead_code)]
     pub fn register_property(&mut self, name: &str, prop: Box<dyn Prop>) -> Result<(), Error> {
         if *self.state.lock().map_err(|_| Error::ThreadingError)? != InternalState::Unstarted {
             return Err(Error::InterpreterActive);
         }
+        if is_keyword(name) {
+            return Err(Error::ReservedName(name.into()));
+        }
         if self.props.contains_key(name) {
             return Err(Error::DuplicateProperty(name.into()));
         }
@@ -231,11 +583,87 @@ impl Interpreter {
         Ok(())
     }
 
+    /// Registers a settable property that reads its value from `get` and writes it with `set`. See
+    /// `ComputedProp`.
+    pub fn register_computed_property<G, S>(&mut self, name: &str, get: G, set: S) -> Result<(), Error>
+    where
+        G: FnMut() -> Value + Send + 'static,
+        S: FnMut(Value) + Send + 'static,
+    {
+        self.register_property(name, Box::new(ComputedProp::new(get, set)))
+    }
+
+    /// Registers every callable in `callables`, as `register_callable` would. A callable that
+    /// can't be registered doesn't stop the rest, and all of their errors are returned together.
+    pub fn register_callables(&mut self, callables: HashMap<String, Box<dyn CallableGenerator>>) -> Result<(), Vec<Error>> {
+        let errors: Vec<_> = callables.into_iter()
+            .filter_map(|(name, callable)| self.register_callable(&name, callable).err())
+            .collect();
+        if errors.is_empty() {Ok(())} else {Err(errors)}
+    }
+
+    /// Registers every property in `props`, like `register_callables`.
+    pub fn register_properties(&mut self, props: HashMap<String, Box<dyn Prop>>) -> Result<(), Vec<Error>> {
+        let errors: Vec<_> = props.into_iter()
+            .filter_map(|(name, prop)| self.register_property(&name, prop).err())
+            .collect();
+        if errors.is_empty() {Ok(())} else {Err(errors)}
+    }
+
+    /// Sets the value read from the property `name` when no property by that name is registered,
+    /// e.g. to run a program in simulation without all of its hardware. Registered properties
+    /// always take precedence. Properties that are only defaulted can't be set.
+    pub fn set_property_default(&mut self, name: &str, value: Value) {
+        self.prop_defaults.insert(name.to_string(), value);
+    }
+
     pub fn interpret(&mut self) -> Result<(), Error> {
         while self.step()? != InterpreterState::Stop {}
         Ok(())
     }
 
+    /// Runs the program like `interpret`, but without setting any properties or calling any
+    /// callables, to preview what it would do. Properties are still read, so conditions take the
+    /// branches they would for real. Skipped calls finish straight away, with a nil result.
+    /// Returns everything that was skipped, in order.
+    pub fn run_dry(&mut self) -> Result<Vec<DryRunAction>, Error> {
+        self.dry_run = Some(Vec::new());
+        let result = self.interpret();
+        let actions = self.dry_run.take().unwrap_or_default();
+        result.map(|_| actions)
+    }
+
+    // Runs the program to completion and returns whatever it left on top of the stack. Used for
+    // evaluating lone expressions, so there's no end group to run.
+    pub(crate) fn evaluate(&mut self) -> Result<Value, Error> {
+        if let Err(es) = self.verify_externals() {
+            return Err(es[0].clone());
+        }
+        let mut ctx = ExecutionContext::new(0);
+        loop {
+            match self.step_with(&mut ctx)? {
+                ExecutionState::Stop => break,
+                ExecutionState::ThreadsAdded => return Err(Error::InvalidCall(ctx.ip - 1)),
+                _ => {}
+            }
+        }
+        Ok(ctx.stack.pop_back().unwrap_or(Value::Nil))
+    }
+
+    pub(crate) fn into_program(self) -> Program {
+        Program {
+            code: self.program,
+            constants: self.constants,
+            timeouts: self.timeouts,
+            symbols: self.symbols,
+            group_info: self.group_info,
+            groups: Some(self.groups),
+            callables: self.callables,
+            props: self.props,
+            lines: self.lines,
+        }
+    }
+
     pub fn reset(&mut self) -> Result<(), Error> {
         match *self.state.lock().map_err(|_| Error::ThreadingError)? {
             InternalState::Active | InternalState::Ending => {
@@ -246,13 +674,39 @@ impl Interpreter {
         self.active_callables.clear();
         self.callable_index = 0;
         self.root_context = ExecutionContext::new(0);
+        self.background.clear();
+        self.step_over_target = None;
+        self.total_wait = Duration::ZERO;
+        self.globals = None;
+        self.history.clear();
+        self.last_step_duration = Duration::ZERO;
         *self.state.get_mut().map_err(|_| Error::ThreadingError)? = InternalState::Unstarted;
         Ok(())
     }
 
+    /// Resets the interpreter to run the group `name` with `args` in place of the program's main
+    /// flow. The group runs on the following calls to `step`, and the program stops once it
+    /// returns. Programs compiled with `Compiler::set_implicit_main` can rerun their main flow
+    /// this way, as `__main`.
+    pub fn call_group(&mut self, name: &str, args: Vec<Value>) -> Result<(), Error> {
+        let Some(&addr) = self.groups.get(name) else {
+            return Err(Error::UnknownCallable(name.into()));
+        };
+        self.reset()?;
+        let ctx = &mut self.root_context;
+        ctx.ip = addr;
+        ctx.stack.extend(args);
+        ctx.group = Some(addr);
+        ctx.deadline = self.timeouts.get(name).map(|t| self.clock.now() + *t);
+        self.group_event(addr, true);
+        Ok(())
+    }
+
     fn run_end(&mut self) -> Result<(), Error> {
         *self.state.get_mut().map_err(|_| Error::ThreadingError)? = InternalState::Ending;
-        self.root_context = ExecutionContext::new(0);
+        let finished = std::mem::replace(&mut self.root_context, ExecutionContext::new(0));
+        // A group started with `call_group` doesn't leave anything behind
+        self.globals = Some(if finished.group.is_none() {finished.stack} else {VecDeque::new()});
         if let Some(addr) = self.groups.get("__end") {
             self.root_context.ip = *addr;
             while let InterpreterState::Yield = self.step()? {}
@@ -263,30 +717,359 @@ impl Interpreter {
 
     pub fn end(&mut self) -> Result<(), Error> {
         unsafe {
-            let mut stack: VecDeque<_> = vec![&mut self.root_context as *mut ExecutionContext].into();
+            let root = &mut self.root_context as *mut ExecutionContext;
+            self.exit_groups(root);
+            self.cancel_all(root)?;
+            self.cancel_background()?;
+        }
+        self.run_end()?;
+
+        Ok(())
+    }
+
+    unsafe fn cancel_background(&mut self) -> Result<(), Error> {
+        let mut background = std::mem::take(&mut self.background);
+        for ctx in background.iter_mut() {
+            let ctx = &mut **ctx as *mut ExecutionContext;
+            unsafe {
+                self.exit_groups(ctx);
+                self.cancel_all(ctx)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// How many groups started with `spawn` are still running. The program doesn't finish until
+    /// they all have.
+    pub fn background_task_count(&self) -> usize {
+        self.background.iter().filter(|ctx| ctx.active).count()
+    }
+
+    // Stops every branch of a race that's still running, once one of them has finished. They're
+    // only marked finished rather than removed, since they may still be queued for this step.
+    unsafe fn stop_race(&mut self, race: *mut ExecutionContext) -> Result<(), Error> {
+        unsafe {
+            let mut stack: Vec<*mut ExecutionContext> = Vec::new();
+            for dep in (*race).dependencies.iter_mut() {
+                self.exit_groups(dep);
+                stack.push(dep);
+            }
+            while let Some(ctx) = stack.pop() {
+                if let Some(id) = (*ctx).current_callable.take() {
+                    if (*ctx).active {
+                        self.active_callables.get_mut(&id).unwrap().terminate()?;
+                    }
+                    self.active_callables.remove(&id);
+                }
+                (*ctx).finalize();
+                stack.extend((*ctx).dependencies.iter_mut().map(|dep| dep as *mut ExecutionContext));
+            }
+        }
+        Ok(())
+    }
+
+    // Terminates every callable in progress in `ctx` and the contexts it's waiting on, and drops
+    // those contexts.
+    unsafe fn cancel_all(&mut self, ctx: *mut ExecutionContext) -> Result<(), Error> {
+        unsafe {
+            let mut stack: VecDeque<_> = vec![ctx].into();
             while !stack.is_empty() {
                 let ctx = stack.pop_back().unwrap_unchecked();
 
-                if (*ctx).dependencies.is_empty() {
-                    if let Some(id) = (*ctx).current_callable {
-                        if (*ctx).active {
-                            self.active_callables.get_mut(&id).unwrap().terminate()?;
-                        }
-                        self.active_callables.remove(&id);
+                if let Some(id) = (*ctx).current_callable.take() {
+                    if (*ctx).active {
+                        self.active_callables.get_mut(&id).unwrap().terminate()?;
                     }
+                    self.active_callables.remove(&id);
                 }
 
                 for dep in (*ctx).dependencies.iter_mut() {
                     stack.push_back(dep as *mut ExecutionContext);
                 }
             }
+            (*ctx).dependencies.clear();
+            (*ctx).make_sequential();
         }
-        self.run_end()?;
-
         Ok(())
     }
 
+    /// The named variables of the frame the main thread of execution is currently in, with their
+    /// current values. Only available for programs compiled from source, since names aren't kept
+    /// in IR or bytecode.
+    pub fn locals(&self) -> Vec<(String, Value)> {
+        let ctx = &self.root_context;
+        // Groups started with `call_group` run without a frame
+        let group = ctx.call_stack.last().map(|frame| frame.group).or(ctx.group);
+        let names = match group {
+            Some(addr) => match self.program.get(addr) {
+                Some(Op::Label(group)) => self.symbols.groups.get(group),
+                _ => None,
+            },
+            None => Some(&self.symbols.main),
+        };
+        let offset = ctx.stack_offset();
+        names.into_iter().flatten().enumerate()
+            // Hidden compiler temporaries have names no variable can have
+            .filter(|(_, name)| !name.starts_with('#'))
+            .filter_map(|(slot, name)| Some((name.clone(), ctx.stack.get(offset + slot)?.clone())))
+            .collect()
+    }
+
+    /// The value of the top-level variable `name` (without its sigil), e.g. to read a result the
+    /// script worked out once `interpret` has finished. `None` if the program has no such variable,
+    /// or it wasn't compiled from source. With `Compiler::set_implicit_main`, top-level variables
+    /// belong to `__main` and are gone once it returns.
+    pub fn global(&self, name: &str) -> Option<Value> {
+        let slot = self.symbols.main.iter().position(|n| n == name)?;
+        let stack = match &self.globals {
+            Some(globals) => globals,
+            None if self.root_context.group.is_none() => &self.root_context.stack,
+            None => return None,
+        };
+        stack.get(slot).cloned()
+    }
+
+    /// Sets a hook that's called whenever a group starts or finishes, including when it's cut short
+    /// by a race, a timeout, or `end`.
+    pub fn set_group_hook(&mut self, hook: GroupHook) {
+        self.group_hook = Some(hook);
+    }
+
+    /// Treats a callable that yields more than `polls` times in a row as stuck, e.g. a hardware
+    /// routine that's never going to finish. Unless a hook set with `set_stall_hook` says to keep
+    /// waiting, the program stops with `Error::CallableStalled`.
+    pub fn set_stall_limit(&mut self, polls: u32) {
+        self.stall_limit = Some(polls);
+    }
+
+    /// Sets a hook that's called with a callable's name and how many times it's yielded so far, each
+    /// time it yields past the limit from `set_stall_limit`. It returns whether to keep waiting, e.g.
+    /// after logging a warning.
+    pub fn set_stall_hook(&mut self, hook: StallHook) {
+        self.stall_hook = Some(hook);
+    }
+
+    /// Adds middleware that sees every callable as it's about to start, given its name and
+    /// arguments. It returns the name of the callable to run instead, which can be the same one, or
+    /// `None` to refuse the call, which stops the program with `Error::CallRefused`. When there's
+    /// more than one, each sees the name the one before it returned. From then on, the stall hook,
+    /// errors and recorded traces all name the callable that actually ran. Calls to groups don't go
+    /// through middleware, and neither do dry runs or replays, which follow the trace instead.
+    pub fn add_call_middleware(&mut self, middleware: CallMiddleware) {
+        self.call_middleware.push(middleware);
+    }
+
+    /// Sets a hook that's given each error a `#[best_effort]` group recovers from, e.g. to log it.
+    pub fn set_recovery_hook(&mut self, hook: RecoveryHook) {
+        self.recovery_hook = Some(hook);
+    }
+
+    fn group_event(&mut self, addr: usize, enter: bool) {
+        fire_group_event(&mut self.group_hook, &self.program, addr, enter);
+    }
+
+    // Reports every group still running in `ctx` and its dependencies as finished, innermost first.
+    unsafe fn exit_groups(&mut self, ctx: *const ExecutionContext) {
+        if self.group_hook.is_none() {
+            return;
+        }
+        unsafe {
+            for dep in (*ctx).dependencies.iter() {
+                self.exit_groups(dep);
+            }
+            if !(*ctx).active {
+                return;
+            }
+            let frames: Vec<usize> = (*ctx).call_stack.iter().rev().map(|frame| frame.group).collect();
+            for group in frames.into_iter().chain((*ctx).group) {
+                self.group_event(group, false);
+            }
+        }
+    }
+
+    /// Starts recording every property read and write, and every poll of a callable, along with
+    /// the values involved. The trace can be taken with `take_trace` and fed to `replay` to run the
+    /// program again exactly the same way. Starting over discards anything recorded so far.
+    pub fn record(&mut self) {
+        self.trace = Trace::Recording(Vec::new());
+    }
+
+    /// Stops recording, and returns what was recorded.
+    pub fn take_trace(&mut self) -> Vec<TraceEntry> {
+        match std::mem::replace(&mut self.trace, Trace::Off) {
+            Trace::Recording(trace) => trace,
+            other => {
+                self.trace = other;
+                Vec::new()
+            }
+        }
+    }
+
+    /// Runs the program against a recorded trace instead of its properties and callables, which
+    /// don't need to be registered. Reads and call results come from the trace, writes are checked
+    /// against it, and calls take as many steps as they did when recorded. Anything that doesn't
+    /// happen in the same order as in the trace raises `Error::TraceMismatch`, as does finishing
+    /// before the trace does. The clock isn't recorded, so programs with timeouts need a
+    /// deterministic one from `set_clock` to replay reliably.
+    pub fn replay(&mut self, trace: Vec<TraceEntry>) {
+        self.trace = Trace::Replaying(trace.into());
+    }
+
+    /// Sets the clock used to enforce group timeouts. Defaults to a `SystemClock`.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Times each step with the interpreter's clock, for `last_step_duration`. Off by default,
+    /// since it reads the clock twice a step.
+    pub fn set_step_timing(&mut self, enabled: bool) {
+        self.time_steps = enabled;
+    }
+
+    /// How long the most recent step took by the interpreter's clock, e.g. for a scheduler to
+    /// notice overruns. Zero unless `set_step_timing` is on.
+    pub fn last_step_duration(&self) -> Duration {
+        self.last_step_duration
+    }
+
+    /// The source line the op at `addr` was compiled from, such as the address in a runtime error.
+    /// Only known for programs compiled from source.
+    pub fn source_line(&self, addr: usize) -> Option<usize> {
+        let i = self.lines.partition_point(|(start, _)| *start <= addr);
+        i.checked_sub(1).map(|i| self.lines[i].1)
+    }
+
+    // Abandons the outermost group in `ctx` that has run past its timeout, cancelling whatever it
+    // was waiting on. Returns whether the whole context was abandoned.
+    unsafe fn abandon_expired(&mut self, ctx: *mut ExecutionContext) -> Result<bool, Error> {
+        if self.timeouts.is_empty() {
+            return Ok(false);
+        }
+        let now = self.clock.now();
+        let expired = |deadline: Option<Duration>| deadline.is_some_and(|d| d <= now);
+        unsafe {
+            if expired((*ctx).deadline) {
+                self.exit_groups(ctx);
+                self.cancel_all(ctx)?;
+                (*ctx).finalize();
+                return Ok(true);
+            }
+            if let Some(i) = (*ctx).call_stack.iter().position(|frame| expired(frame.deadline)) {
+                for dep in (*ctx).dependencies.iter() {
+                    self.exit_groups(dep);
+                }
+                self.cancel_all(ctx)?;
+                let abandoned: Vec<usize> = (&(*ctx).call_stack)[i..].iter().rev().map(|frame| frame.group).collect();
+                for group in abandoned {
+                    self.group_event(group, false);
+                }
+                let ctx = &mut *ctx;
+                let frame = &ctx.call_stack[i];
+                ctx.stack.truncate(frame.stack_offset);
+                ctx.ip = frame.return_addr;
+                ctx.call_stack.truncate(i);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Runs until the program has to wait, then returns `InterpreterState::Yield`, or `Stop` once
+    /// it's finished. A callable whose `call` returns false is polled again on the next step, and
+    /// the program only moves past it once it returns true.
     pub fn step(&mut self) -> Result<InterpreterState, Error> {
+        self.step_until(None)
+    }
+
+    /// Keeps what's needed for `step_back` to undo each of the last `steps` steps. 0, the default,
+    /// keeps nothing. Undoing a step sets any properties it wrote back to what they were, as long as
+    /// they could be read before they were written, so this is meant for debugging against
+    /// simulated properties, since real hardware can't be put back the way it was.
+    pub fn set_step_history(&mut self, steps: usize) {
+        self.history_limit = steps;
+        while self.history.len() > steps {
+            self.history.pop_front();
+        }
+    }
+
+    /// Puts the program back the way it was before the last step, as kept by `set_step_history`,
+    /// and returns whether there was a step to undo. Steps taken while a callable or a parallel or
+    /// background group is running can't be undone one at a time, so undoing one goes back to
+    /// before the step that started it, stopping anything that's still running. Group hooks and
+    /// recorded traces don't see steps being undone.
+    pub fn step_back(&mut self) -> Result<bool, Error> {
+        let Some(snapshot) = self.history.pop_back() else {
+            return Ok(false);
+        };
+        for (name, value) in snapshot.writes.into_iter().rev() {
+            if let Some(value) = value && let Some(prop) = self.props.get_mut(&name) {
+                prop.set(value)?;
+            }
+        }
+        for (_, mut callable) in self.active_callables.drain() {
+            callable.terminate()?;
+        }
+        self.background.clear();
+        self.root_context = snapshot.root;
+        self.callable_index = snapshot.callable_index;
+        self.total_wait = snapshot.total_wait;
+        self.globals = snapshot.globals;
+        self.step_over_target = None;
+        *self.state.get_mut().map_err(|_| Error::ThreadingError)? = snapshot.state;
+        Ok(true)
+    }
+
+    // Keeps the state before a step for `step_back`, unless something's running that can't be
+    // copied
+    fn save_step(&mut self) -> Result<(), Error> {
+        if !self.background.is_empty() || !self.active_callables.is_empty() {
+            return Ok(());
+        }
+        let Some(root) = self.root_context.snapshot() else {
+            return Ok(());
+        };
+        let state = *self.state.get_mut().map_err(|_| Error::ThreadingError)?;
+        self.history.push_back(Snapshot {
+            root,
+            state,
+            callable_index: self.callable_index,
+            total_wait: self.total_wait,
+            globals: self.globals.clone(),
+            writes: Vec::new(),
+        });
+        if self.history.len() > self.history_limit {
+            self.history.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Runs until the main thread of execution finishes the op it's on, running any group that op
+    /// calls to completion, so a debugger can step over `g;` rather than into it. Like `step`, it
+    /// returns early when the program yields, and the next call carries on to the same place.
+    pub fn step_over_group(&mut self) -> Result<InterpreterState, Error> {
+        let target = *self.step_over_target.get_or_insert(self.root_context.call_stack.len());
+        let state = self.step_until(Some(target));
+        if !matches!(state, Ok(InterpreterState::Yield)) || self.root_context.call_stack.len() <= target {
+            self.step_over_target = None;
+        }
+        state
+    }
+
+    // Steps as usual, but with a `depth`, the main thread stops as soon as it's back to that many
+    // frames deep.
+    fn step_until(&mut self, depth: Option<usize>) -> Result<InterpreterState, Error> {
+        if !self.time_steps {
+            return self.run_step(depth);
+        }
+        let start = self.clock.now();
+        let state = self.run_step(depth);
+        self.last_step_duration = self.clock.now().saturating_sub(start);
+        state
+    }
+
+    fn run_step(&mut self, depth: Option<usize>) -> Result<InterpreterState, Error> {
+        if self.history_limit > 0 {
+            self.save_step()?;
+        }
         if *self.state.lock().map_err(|_| Error::ThreadingError)? == InternalState::Unstarted {
             // FIXME This only returns the first error, which isn't ideal.
             if let Err(es) = self.verify_externals() {
@@ -301,12 +1084,16 @@ impl Interpreter {
         }
 
         unsafe {
-            let mut queue: VecDeque<_> = vec![&mut self.root_context as *mut ExecutionContext].into();
+            let root = &mut self.root_context as *mut ExecutionContext;
+            let mut queue: VecDeque<_> = vec![root].into();
+            // Anything spawned during this step starts on the next one
+            self.background.retain(|ctx| ctx.active);
+            queue.extend(self.background.iter_mut().map(|ctx| &mut **ctx as *mut ExecutionContext));
             while !queue.is_empty() {
                 // println!("{:?}", queue);
                 let ctx = queue.pop_front().unwrap_unchecked();
 
-                if !(*ctx).active {
+                if !(*ctx).active || self.abandon_expired(ctx)? {
                     continue;
                 }
 
@@ -321,6 +1108,9 @@ impl Interpreter {
                             self.active_callables.remove(&id);
                         }
                     }
+                    for dep in (*ctx).dependencies.iter() {
+                        self.exit_groups(dep);
+                    }
                     (*ctx).dependencies.clear();
                 } else {
                     (*ctx).dependencies.retain(|c| c.active);
@@ -330,8 +1120,26 @@ impl Interpreter {
                 if (*ctx).dependencies.len() == 0 {
                     (*ctx).make_sequential();
                     loop {
-                        match self.step_with(ctx.as_mut().unwrap_unchecked())? {
-                            ExecutionState::Continue => {continue;}
+                        let state = match self.step_with(ctx.as_mut().unwrap_unchecked()) {
+                            Ok(state) => state,
+                            Err(e) => {
+                                // An error in one branch of a parallel group takes the rest of the group down with it
+                                if let Some(parent) = (*ctx).parent {
+                                    for dep in (*parent).dependencies.iter() {
+                                        self.exit_groups(dep);
+                                    }
+                                    self.cancel_all(parent)?;
+                                }
+                                return Err(e);
+                            }
+                        };
+                        match state {
+                            ExecutionState::Continue => {
+                                if let Some(depth) = depth && ctx == root && (*ctx).call_stack.len() <= depth {
+                                    break;
+                                }
+                                continue;
+                            }
                             ExecutionState::Yield => {break;}
                             ExecutionState::ThreadsAdded => { 
                                 for dep in (*ctx).dependencies.iter_mut() {
@@ -345,44 +1153,24 @@ impl Interpreter {
                                     // root will never be a native proxy
                                     let Some(parent) = (*ctx).parent else {unreachable!()};
                                     if (*parent).dependency_type == GroupKind::Race {
-                                        for dep in (*parent).dependencies.iter_mut() {
-                                            if dep.current_callable.is_some() {
-                                                let Some(id) = dep.current_callable.take() else {unreachable!()};
-                                                if dep.active {
-                                                    self.active_callables.get_mut(&id).unwrap().terminate()?;
-                                                }
-                                                self.active_callables.remove(&id);
-                                            }
-                                            // if dep.active && dep.native_proxy {
-                                            //     let Some(Op::Call(name, _)) = self.program.get(dep.ip) else {unreachable!()};
-                                            //     let Some(callable) = self.callables.get_mut(name) else {unreachable!()};
-                                            //     callable.terminate();
-                                            //     dep.finalize();
-                                            // }
-                                        }
+                                        self.stop_race(parent)?;
                                     }
                                 }
                                 break;
                             }
+                            ExecutionState::Halt => {
+                                self.exit_groups(root);
+                                self.cancel_all(root)?;
+                                self.cancel_background()?;
+                                (*root).finalize();
+                                queue.clear();
+                                break;
+                            }
                             ExecutionState::Stop => {
                                 (*ctx).finalize(); 
                                 if let Some(parent) = (*ctx).parent {
                                     if (*parent).dependency_type == GroupKind::Race {
-                                        for dep in (*parent).dependencies.iter_mut() {
-                                            if dep.current_callable.is_some() {
-                                                let Some(id) = dep.current_callable.take() else {unreachable!()};
-                                                if dep.active {
-                                                    self.active_callables.get_mut(&id).unwrap().terminate()?;
-                                                }
-                                                self.active_callables.remove(&id);
-                                            }
-                                            // if dep.active && dep.native_proxy {
-                                            //     let Some(Op::Call(name, _)) = self.program.get(dep.ip) else {unreachable!()};
-                                            //     let Some(callable) = self.callables.get_mut(name) else {unreachable!()};
-                                            //     callable.terminate();
-                                            //     dep.finalize();
-                                            // }
-                                        }
+                                        self.stop_race(parent)?;
                                         queue.push_back(parent);
                                     }
                                 }
@@ -398,10 +1186,14 @@ impl Interpreter {
             }
         }
 
-        if !self.root_context.active {
+        self.background.retain(|ctx| ctx.active);
+        if !self.root_context.active && self.background.is_empty() {
             if *self.state.lock().map_err(|_| Error::ThreadingError)? != InternalState::Ending {
                 self.run_end()?;
             }
+            if let Trace::Replaying(trace) = &self.trace && let Some(entry) = trace.front() {
+                return Err(Error::TraceMismatch(entry.ip, format!("the program finished before {}", entry.event)));
+            }
             Ok(InterpreterState::Stop)
         } else {
             {
@@ -414,16 +1206,128 @@ impl Interpreter {
         }
     }
 
+    /// Enables checking that execution never leaves a group's code without returning from it,
+    /// which raises `Error::UnbalancedCall` when it happens. This is a debugging aid for hand-built
+    /// or generated programs, and slows down calls.
+    pub fn set_call_checking(&mut self, enabled: bool) {
+        self.check_calls = enabled;
+    }
+
+    /// Makes each step end at the start of the next statement, so a debugger can go through a
+    /// program a statement at a time. This relies on the program being compiled with
+    /// `Compiler::set_statement_markers`, and does nothing otherwise. Steps still end wherever
+    /// they usually would too, like when a callable hasn't finished. In a `parallel` block, each
+    /// branch stops at its own statements.
+    pub fn set_yield_per_statement(&mut self, enabled: bool) {
+        self.yield_per_statement = enabled;
+    }
+
+    /// Makes arithmetic that produces infinity or NaN, like `0 / 0` or `10 ^ 1000`, raise
+    /// `Error::NumericError` instead of carrying on with the result. Off by default, which follows
+    /// the usual floating point rules.
+    pub fn set_checked_arithmetic(&mut self, enabled: bool) {
+        self.checked_arithmetic = enabled;
+    }
+
+    /// Limits how long a run can spend in calls, going by the durations their callables report with
+    /// `CallableGenerator::estimated_duration`, so a buggy script can't schedule hours of waiting.
+    /// A call that would take the total past `max` raises `Error::WaitBudgetExceeded` instead of
+    /// starting. Calls whose durations aren't known don't count. The total starts over on `reset`.
+    pub fn set_max_total_wait(&mut self, max: Duration) {
+        self.max_total_wait = Some(max);
+    }
+
+    /// Starts or stops keeping track of which ops run, for `coverage`. Off by default, since it
+    /// costs a little on every op. Turning it on again starts from nothing, but `reset` keeps what's
+    /// been collected, so several runs can be covered together.
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.coverage = enabled.then(|| vec![false; self.program.len()]);
+    }
+
+    /// The ops that have run since coverage was turned on with `set_coverage`. Nothing is covered
+    /// if it's off.
+    pub fn coverage(&self) -> Coverage {
+        Coverage {executed: self.coverage.clone().unwrap_or_else(|| vec![false; self.program.len()])}
+    }
+
+    // A group's code runs from its label up to the next label, or to the start of the main code.
+    fn group_region(&self, addr: usize) -> (usize, usize) {
+        let main_start = match self.program.first() {
+            Some(Op::Jump(a)) if *a >= 1 && *a as usize > addr => (*a as usize).min(self.program.len()),
+            _ => self.program.len(),
+        };
+        let end = self.program.get(addr+1..main_start).unwrap_or_default().iter()
+            .position(|op| matches!(op, Op::Label(_)))
+            .map(|i| addr + 1 + i)
+            .unwrap_or(main_start);
+        (addr, end)
+    }
+
     fn step_with(&mut self, ctx: &mut ExecutionContext) -> Result<ExecutionState, Error> {
+        let state = match self.execute_op(ctx) {
+            Err(e) if self.recover(ctx, &e) => ExecutionState::Continue,
+            res => res?,
+        };
+        if self.check_calls && state != ExecutionState::Stop {
+            let region = ctx.call_stack.last().and_then(|frame| frame.region).or(ctx.base_region);
+            if let Some((start, end)) = region && !(start..end).contains(&ctx.ip) {
+                return Err(Error::UnbalancedCall(ctx.ip));
+            }
+        }
+        Ok(state)
+    }
+
+    // Skips the rest of the statement that failed in the innermost `#[best_effort]` group that's
+    // running, abandoning anything it called that hasn't finished. Returns whether there was one.
+    fn recover(&mut self, ctx: &mut ExecutionContext, error: &Error) -> bool {
+        // Nothing can carry on sensibly from these
+        if matches!(error, Error::ThreadingError | Error::TraceMismatch(..)) {
+            return false;
+        }
+        let (depth, (resume, height)) = match ctx.call_stack.iter().rposition(|frame| frame.checkpoint.is_some()) {
+            Some(i) => (i + 1, ctx.call_stack[i].checkpoint.unwrap()),
+            None => match ctx.base_checkpoint {
+                Some(checkpoint) => (0, checkpoint),
+                None => return false,
+            },
+        };
+        while ctx.call_stack.len() > depth {
+            let frame = ctx.call_stack.pop().unwrap();
+            self.group_event(frame.group, false);
+        }
+        if let Some(id) = ctx.current_callable.take() && let Some(mut callable) = self.active_callables.remove(&id) {
+            let _ = callable.terminate();
+        }
+        ctx.stack.truncate(height);
+        ctx.ip = resume;
+        if let Some(hook) = self.recovery_hook.as_mut() {
+            hook(error);
+        }
+        true
+    }
+
+    fn execute_op(&mut self, ctx: &mut ExecutionContext) -> Result<ExecutionState, Error> {
         // println!("{:?}", ctx);
         // println!("{} {:?}", ctx.ip, ctx.stack);
+        // Labels only mark where groups start, so they're passed over rather than spending a step
+        // on them.
+        while let Some(Op::Label(_)) = self.program.get(ctx.ip) {
+            if let Some(coverage) = &mut self.coverage {
+                coverage[ctx.ip] = true;
+            }
+            ctx.ip += 1;
+        }
         let Some(op) = self.program.get(ctx.ip) else {
             // println!("IP out of bounds");
             return Ok(ExecutionState::Stop);
         };
+        if let Some(coverage) = &mut self.coverage {
+            coverage[ctx.ip] = true;
+        }
         ctx.ip += 1;
        
         use Op::*;
+        let check_result = self.checked_arithmetic && matches!(op, Add | Sub | Mul | Div | Mod | Exp | Neg);
         match op {
             Load(a) => {
                 let offset = ctx.stack_offset();
@@ -438,17 +1342,46 @@ impl Interpreter {
                     *slot = value;
                 }
             }
+            Get(name) if self.trace.replaying() => {
+                let entry = self.trace.next(ctx.ip - 1, &[TraceEvent::Get(name.clone())])?;
+                ctx.stack.push_back(entry.value);
+            }
             Get(name) => {
-                // we assume the property exists at this point
-                let value = self.props[name].get()?;
+                // we assume the property exists or is defaulted at this point
+                let value = match self.props.get(name) {
+                    Some(prop) => prop.get()?,
+                    None => self.prop_defaults[name].clone(),
+                };
+                self.trace.record(ctx.ip - 1, TraceEvent::Get(name.clone()), &value);
                 ctx.stack.push_back(value);
             }
+            Set(name) if let Some(actions) = self.dry_run.as_mut() => {
+                let value = pop!(ctx)?;
+                actions.push(DryRunAction::Set(name.clone(), value));
+            }
+            Set(name) if self.trace.replaying() => {
+                let value = pop!(ctx)?;
+                let entry = self.trace.next(ctx.ip - 1, &[TraceEvent::Set(name.clone())])?;
+                if entry.value != value {
+                    return Err(Error::TraceMismatch(ctx.ip - 1, format!("'{}' was set to {} instead of {}", name, value, entry.value)));
+                }
+            }
             Set(name) => {
                 // we assume the property exists and is settable at this point
                 let value = pop!(ctx)?;
-                self.props.get_mut(name).unwrap().set(value)?;
+                self.trace.record(ctx.ip - 1, TraceEvent::Set(name.clone()), &value);
+                let prop = self.props.get_mut(name).unwrap();
+                if let Some(snapshot) = self.history.back_mut() {
+                    // Write-only properties can't be put back, but that's no reason not to write them
+                    snapshot.writes.push((name.clone(), prop.get().ok()));
+                }
+                prop.set(value)?;
             }
             Push(v) => ctx.stack.push_back(v.clone()),
+            Const(i) => {
+                let value = self.constants.get(*i).ok_or(Error::IndexOutOfBounds(ctx.ip - 1))?;
+                ctx.stack.push_back(value.clone());
+            }
             Pop => {ctx.stack.pop_back();},
             Dup => ctx.stack.push_back(ctx.stack.back().ok_or(Error::StackUnderflow(ctx.ip - 1))?.clone()),
             Add => {
@@ -491,6 +1424,96 @@ impl Interpreter {
                     _ => {return Err(Error::Type("Absolute value only works with numbers".into()))}
                 }
             }
+            Round => {
+                match ctx.stack.back_mut() {
+                    Some(Value::Number(n)) => {*n = n.round();},
+                    None => {return Err(Error::StackUnderflow(ctx.ip - 1))}
+                    _ => {return Err(Error::Type("Only numbers can be rounded".into()))}
+                }
+            }
+            Index => {
+                let index = pop!(ctx)?;
+                let target = pop!(ctx)?;
+                let i = match index {
+                    Value::Number(n) if n.fract() == 0.0 => n,
+                    Value::Number(_) => return Err(Error::Type("Indices must be whole numbers".into())),
+                    other => return Err(Error::Type(format!("Can't index with {}", other.type_name()))),
+                };
+                if target.len().is_none() {
+                    return Err(Error::Type(format!("Can't index into {}", target.type_name())));
+                }
+                let element = if i < 0.0 {None} else {target.element(i as usize)};
+                ctx.stack.push_back(element.ok_or(Error::IndexOutOfBounds(ctx.ip - 1))?);
+            }
+            Len => {
+                let value = pop!(ctx)?;
+                let len = value.len().ok_or_else(|| Error::Type(format!("Can't take the length of {}", value.type_name())))?;
+                ctx.stack.push_back(Value::Number(len as f64));
+            }
+            Select => {
+                let if_false = pop!(ctx)?;
+                let if_true = pop!(ctx)?;
+                let value = if pop!(ctx)?.truthy() {if_true} else {if_false};
+                ctx.stack.push_back(value);
+            }
+            InitCounter(a) => {
+                let count = match pop!(ctx)? {
+                    Value::Number(n) if n.is_nan() => 0.0,
+                    Value::Number(n) => n.trunc().max(0.0),
+                    _ => return Err(Error::Type("Repeat count must be a number".into())),
+                };
+                let offset = ctx.stack_offset();
+                let slot = ctx.stack.get_mut(offset + a).ok_or(Error::IndexOutOfBounds(ctx.ip - 1))?;
+                *slot = Value::Number(count);
+            }
+            DecCounterJumpNonZero(a, j) => {
+                let offset = ctx.stack_offset();
+                let slot = ctx.stack.get_mut(offset + a).ok_or(Error::IndexOutOfBounds(ctx.ip - 1))?;
+                // Only `InitCounter` writes to the slot, so it's always a number
+                if let Value::Number(n) = slot && *n > 0.0 {
+                    *n -= 1.0;
+                    ctx.ip = ctx.ip.wrapping_add_signed(*j - 1);
+                }
+            }
+            Now => ctx.stack.push_back(Value::Number(self.clock.now().as_secs_f64())),
+            Sort => {
+                let sorted = match ctx.stack.back_mut() {
+                    Some(Value::List(l)) => l,
+                    None => {return Err(Error::StackUnderflow(ctx.ip - 1))}
+                    _ => {return Err(Error::Type("Only lists can be sorted".into()))}
+                };
+                if sorted.iter().all(Value::is_num) {
+                    sorted.sort_by(|a, b| match (a, b) {
+                        (Value::Number(n), Value::Number(m)) => n.total_cmp(m),
+                        _ => unreachable!(),
+                    });
+                } else if sorted.iter().all(Value::is_str) {
+                    sorted.sort_by(|a, b| match (a, b) {
+                        (Value::String(s), Value::String(t)) => s.cmp(t),
+                        _ => unreachable!(),
+                    });
+                } else {
+                    return Err(Error::Type("Lists can only be sorted if they're all numbers or all strings".into()));
+                }
+            }
+            MakeList(n) => {
+                let start = ctx.stack.len().checked_sub(*n).ok_or(Error::StackUnderflow(ctx.ip - 1))?;
+                let list = ctx.stack.drain(start..).collect();
+                ctx.stack.push_back(Value::List(list));
+            }
+            NormalizeAngle(range) => {
+                match ctx.stack.back_mut() {
+                    Some(Value::Number(n)) => {*n = range.normalize(*n);},
+                    None => {return Err(Error::StackUnderflow(ctx.ip - 1))}
+                    _ => {return Err(Error::Type("Only numbers can be normalized as angles".into()))}
+                }
+            }
+            Format(format) => {
+                match pop!(ctx)? {
+                    Value::Number(n) => ctx.stack.push_back(Value::String(format.format(n))),
+                    _ => {return Err(Error::Type("Only numbers can be formatted".into()))}
+                }
+            }
             And => {logicop!(ctx, &&);}
             Or => {logicop!(ctx, ||);}
             Not => {
@@ -518,10 +1541,10 @@ impl Interpreter {
                 let b = pop!(ctx)?;
                 ctx.stack.push_back(Value::Bool(a != b));
             }
-            Lt => {binop!(ctx, Value::Bool, <);}
-            Le => {binop!(ctx, Value::Bool, <=);}
-            Gt => {binop!(ctx, Value::Bool, >);}
-            Ge => {binop!(ctx, Value::Bool, >=);}
+            Lt => {cmpop!(ctx, <);}
+            Le => {cmpop!(ctx, <=);}
+            Gt => {cmpop!(ctx, >);}
+            Ge => {cmpop!(ctx, >=);}
 
             Jump(a) => {ctx.ip = ctx.ip.wrapping_add_signed(*a - 1);}
             JumpUnless(a) => {
@@ -532,21 +1555,86 @@ impl Interpreter {
                 let cond = pop!(ctx)?;
                 if cond.truthy() {ctx.ip = ctx.ip.wrapping_add_signed(*a - 1);}
             }
+            JumpUnlessEq(a) => {
+                let r = pop!(ctx)?;
+                let l = pop!(ctx)?;
+                if l != r {ctx.ip = ctx.ip.wrapping_add_signed(*a - 1);}
+            }
+            JumpUnlessNe(a) => {
+                let r = pop!(ctx)?;
+                let l = pop!(ctx)?;
+                if l == r {ctx.ip = ctx.ip.wrapping_add_signed(*a - 1);}
+            }
+            JumpUnlessLt(a) => {cmpjump!(ctx, *a, <);}
+            JumpUnlessLe(a) => {cmpjump!(ctx, *a, <=);}
+            JumpUnlessGt(a) => {cmpjump!(ctx, *a, >);}
+            JumpUnlessGe(a) => {cmpjump!(ctx, *a, >=);}
 
             Label(_name) => {
-                // No-op. Artefact of group identification.
+                // Never reached, since labels are skipped above.
+            }
+            Call(name, arity) | CallValue(name, arity) if self.dry_run.is_some() && self.callables.contains_key(name) => {
+                let start = ctx.stack.len().checked_sub(*arity).ok_or(Error::StackUnderflow(ctx.ip - 1))?;
+                let args: Vec<Value> = ctx.stack.drain(start..).collect();
+                if let Some(signature) = self.callables[name].signature() {
+                    signature.check_values(name, &args)?;
+                }
+                if let CallValue(_, _) = op {
+                    ctx.stack.push_back(Value::Nil);
+                }
+                if let Some(actions) = self.dry_run.as_mut() {
+                    actions.push(DryRunAction::Call(name.clone(), args));
+                }
+                return Ok(ExecutionState::CallEnd);
             }
-            Call(name, arity) => {
-                if let Some(gener) = self.callables.get_mut(name) {
+            Call(name, arity) | CallValue(name, arity)
+                    if self.trace.replaying() && (self.callables.contains_key(name) || !self.groups.contains_key(name)) => {
+                let entry = self.trace.next_call(ctx.ip - 1)?;
+                if let TraceEvent::Wait(_) = entry.event {
+                    // The arguments stay on the stack until the call finishes
+                    ctx.ip -= 1;
+                    return Ok(ExecutionState::Yield);
+                }
+                for _ in 0..*arity {
+                    pop!(ctx)?;
+                }
+                if let CallValue(_, _) = op {
+                    ctx.stack.push_back(entry.value);
+                }
+                return Ok(ExecutionState::CallEnd);
+            }
+            Call(name, arity) | CallValue(name, arity) => {
+                let keep_result = matches!(op, CallValue(_, _));
+                if self.callables.contains_key(name) {
                     if ctx.current_callable.is_none() {
                         let mut args = Vec::new();
                         for _ in 0..*arity {
                             args.push(pop!(ctx)?);
                         }
                         args.reverse();
-                        
+                        let mut target = name.clone();
+                        for middleware in self.call_middleware.iter_mut() {
+                            target = middleware(&target, &args).ok_or_else(|| Error::CallRefused(ctx.ip - 1, target.clone()))?;
+                        }
+                        let Some(gener) = self.callables.get_mut(&target) else {
+                            return Err(Error::UnregisteredCallable(ctx.ip - 1, target, None));
+                        };
+                        if let Some(signature) = gener.signature() {
+                            signature.check_values(&target, &args)?;
+                        }
+                        if let Some(max) = self.max_total_wait {
+                            let known: Vec<_> = args.iter().cloned().map(Some).collect();
+                            let total = self.total_wait + gener.estimated_duration(&known).unwrap_or_default();
+                            if total > max {
+                                return Err(Error::WaitBudgetExceeded(ctx.ip - 1));
+                            }
+                            self.total_wait = total;
+                        }
+
                         let new_callable = gener.generate(args)?;
                         ctx.current_callable = Some(self.callable_index);
+                        ctx.called = target;
+                        ctx.polls = 0;
                         self.active_callables.insert(self.callable_index, new_callable);
                         self.callable_index += 1;
                     };
@@ -554,22 +1642,46 @@ impl Interpreter {
 
 
                     if !callable.call()? {
+                        ctx.polls += 1;
+                        if let Some(limit) = self.stall_limit && ctx.polls > limit {
+                            let keep_waiting = self.stall_hook.as_mut().is_some_and(|hook| hook(&ctx.called, ctx.polls));
+                            if !keep_waiting {
+                                return Err(Error::CallableStalled(ctx.ip - 1, ctx.called.clone()));
+                            }
+                        }
+                        self.trace.record(ctx.ip - 1, TraceEvent::Wait(ctx.called.clone()), &Value::Nil);
                         ctx.ip -= 1;
                         return Ok(ExecutionState::Yield);
                     } else {
+                        let result = if keep_result {callable.result()} else {Value::Nil};
+                        self.trace.record(ctx.ip - 1, TraceEvent::Call(ctx.called.clone()), &result);
+                        if keep_result {
+                            ctx.stack.push_back(result);
+                        }
                         let Some(id) = ctx.current_callable.take() else {unreachable!()};
                         self.active_callables.remove(&id);
                         return Ok(ExecutionState::CallEnd);
                     }
                 } else {
                     let Some(addr) = self.groups.get(name) else {
-                        return Err(Error::UnregisteredCallable(ctx.ip - 1, name.into()));
+                        return Err(Error::UnregisteredCallable(ctx.ip - 1, name.into(), self.closest_callable(name)));
                     };
+                    // Groups don't have a result to keep
+                    if keep_result {
+                        return Err(Error::InvalidCall(ctx.ip - 1));
+                    }
+                    let region = self.check_calls.then(|| self.group_region(*addr));
+                    let deadline = self.timeouts.get(name).map(|t| self.clock.now() + *t);
                     ctx.call_stack.push(StackFrame {
                         return_addr: ctx.ip,
                         stack_offset: ctx.stack.len() - arity,
+                        group: *addr,
+                        region,
+                        deadline,
+                        checkpoint: None,
                     });
                     ctx.ip = *addr;
+                    self.group_event(ctx.ip, true);
                 }
             }
 
@@ -586,6 +1698,12 @@ impl Interpreter {
                         if name.starts_with("#") {
                             sub_ctx.set_native_proxy();
                         }
+                        if self.check_calls {
+                            sub_ctx.base_region = Some(self.group_region(*addr));
+                        }
+                        sub_ctx.deadline = self.timeouts.get(name).map(|t| self.clock.now() + *t);
+                        sub_ctx.group = Some(*addr);
+                        fire_group_event(&mut self.group_hook, &self.program, *addr, true);
                         ctx.add_dependency(sub_ctx);
                     } else {
                         return Err(Error::InvalidCall(ctx.ip - 1));
@@ -610,6 +1728,12 @@ impl Interpreter {
                         if name.starts_with("#") {
                             sub_ctx.set_native_proxy();
                         }
+                        if self.check_calls {
+                            sub_ctx.base_region = Some(self.group_region(*addr));
+                        }
+                        sub_ctx.deadline = self.timeouts.get(name).map(|t| self.clock.now() + *t);
+                        sub_ctx.group = Some(*addr);
+                        fire_group_event(&mut self.group_hook, &self.program, *addr, true);
                         ctx.add_dependency(sub_ctx);
                     } else {
                         return Err(Error::InvalidCall(ctx.ip - 1));
@@ -620,14 +1744,35 @@ impl Interpreter {
                     return Ok(ExecutionState::ThreadsAdded);
                 }
             }
+            Halt => return Ok(ExecutionState::Halt),
+            Spawn(name, arity) => {
+                let Some(addr) = self.groups.get(name) else {
+                    return Err(Error::InvalidCall(ctx.ip - 1));
+                };
+                let mut task = ExecutionContext::new(*addr);
+                for _ in 0..*arity {
+                    task.stack.push_back(pop!(ctx)?);
+                }
+                if self.check_calls {
+                    task.base_region = Some(self.group_region(*addr));
+                }
+                task.deadline = self.timeouts.get(name).map(|t| self.clock.now() + *t);
+                task.group = Some(*addr);
+                fire_group_event(&mut self.group_hook, &self.program, *addr, true);
+                self.background.push(Box::new(task));
+            }
             Return => {
                 // This interpretation opens up the possibiilty of a naked return, but I don't know
                 // that's a problem?
                 match ctx.call_stack.pop() {
                     Some(frame) => {
                         ctx.ip = frame.return_addr;
+                        self.group_event(frame.group, false);
                     }
                     None => {
+                        if let Some(group) = ctx.group {
+                            self.group_event(group, false);
+                        }
                         return Ok(ExecutionState::Stop);
                     }
                 }
@@ -635,32 +1780,50 @@ impl Interpreter {
             Yield => {
                 return Ok(ExecutionState::Yield);
             }
+            Checkpoint(a) => {
+                let checkpoint = Some((ctx.ip.wrapping_add_signed(*a - 1), ctx.stack.len()));
+                match ctx.call_stack.last_mut() {
+                    Some(frame) => frame.checkpoint = checkpoint,
+                    None => ctx.base_checkpoint = checkpoint,
+                }
+            }
+            Statement => {
+                if self.yield_per_statement {
+                    return Ok(ExecutionState::Yield);
+                }
+            }
+            Line(_) => {}
 
             // _ => todo!()
         }
+        if check_result && let Some(Value::Number(n)) = ctx.stack.back() && !n.is_finite() {
+            return Err(Error::NumericError(ctx.ip - 1));
+        }
         Ok(ExecutionState::Continue)
     }
 
+    // What an unregistered name was probably meant to be, for error messages
+    fn closest_callable(&self, name: &str) -> Option<String> {
+        closest_name(name, self.callables.keys().chain(self.groups.keys()).map(String::as_str))
+    }
 
-    fn scan_groups(program: &[Op]) -> HashMap<String, usize> {
-        let mut groups = HashMap::new();
-        for (i, op) in program.iter().enumerate() {
-            if let Op::Label(name) = op {
-                groups.insert(name.clone(), i);
-            }
-        }
-        groups
+    fn closest_property(&self, name: &str) -> Option<String> {
+        closest_name(name, self.props.keys().chain(self.prop_defaults.keys()).map(String::as_str))
     }
 
     fn verify_externals(&self) -> Result<(), Vec<Error>> {
+        // Replays never touch properties or callables
+        if self.trace.replaying() {
+            return Ok(());
+        }
         let mut errors = Vec::new();
         let mut seen = HashSet::new();
         for (i, op) in self.program.iter().enumerate() {
             match op {
                 Op::Get(name) => {
-                    if !seen.contains(name) && !self.props.contains_key(name) {
+                    if !seen.contains(name) && !self.props.contains_key(name) && !self.prop_defaults.contains_key(name) {
                         seen.insert(name.clone());
-                        errors.push(Error::UnregisteredProperty(i, name.into()));
+                        errors.push(Error::UnregisteredProperty(i, name.into(), self.closest_property(name)));
                     }
                 }
                 Op::Set(name) => {
@@ -680,14 +1843,14 @@ impl Interpreter {
                     } else {
                         if !seen.contains(name) {
                             seen.insert(name.clone());
-                            errors.push(Error::UnregisteredProperty(i, name.into()));
+                            errors.push(Error::UnregisteredProperty(i, name.into(), self.closest_property(name)));
                         }
                     }
                 }
-                Op::Call(name, _) => {
+                Op::Call(name, _) | Op::CallValue(name, _) | Op::Spawn(name, _) => {
                     if !(self.callables.contains_key(name) || self.groups.contains_key(name)) && !seen.contains(name) {
                         seen.insert(name.clone());
-                        errors.push(Error::UnregisteredCallable(i, name.into()));
+                        errors.push(Error::UnregisteredCallable(i, name.into(), self.closest_callable(name)));
                     }
                 }
                 Op::CallParallel(calls) | Op::CallRace(calls) => {
@@ -695,7 +1858,7 @@ impl Interpreter {
                         let name = &call.0;
                         if !(self.callables.contains_key(name) || self.groups.contains_key(name)) && !seen.contains(name) {
                             seen.insert(name.clone());
-                            errors.push(Error::UnregisteredCallable(i, name.into()));
+                            errors.push(Error::UnregisteredCallable(i, name.into(), self.closest_callable(name)));
                         }
                     }
                 }
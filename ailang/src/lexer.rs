@@ -1,14 +1,15 @@
-use std::collections::{VecDeque};
+use std::collections::{VecDeque, HashMap};
+use std::borrow::Cow;
 
 use unicode_segmentation::{UnicodeSegmentation, GraphemeIndices};
 
-use crate::token::{Token, TokenType, Literal};
+use crate::token::{Token, TokenType, Literal, position};
 use crate::error::{Error};
 
 // assumes input is a grapheme cluster
 fn is_digit(g: &str) -> bool {
-    let g = g.as_bytes();
-    g[0] >= b'0' && g[0] <= b'9'
+    // A digit with combining marks is still a single grapheme, but isn't a digit
+    g.len() == 1 && g.as_bytes()[0].is_ascii_digit()
 }
 
 fn is_whitespace(g: &str) -> bool {
@@ -16,13 +17,99 @@ fn is_whitespace(g: &str) -> bool {
 }
 
 fn is_reserved_char(g: &str) -> bool {
-    is_whitespace(g) || (&["(", ")", "|", "{", "}", "-", "+", "*", "/", "%", "^", "!", "=", "<", ">", "#", "$", "\"", "'", ";"][..]).contains(&g)
+    is_whitespace(g) || (&["(", ")", "|", "{", "}", "[", "]", "-", "+", "*", "/", "%", "^", "!", "=", "<", ">", "#", "$", "\"", "'", ";"][..]).contains(&g)
+}
+
+// `%` is also an operator, so it only starts an identifier when a name follows it directly
+const SIGILS: &[char] = &['$', '%', '@', '&', '~', '?'];
+
+fn is_ident_char(g: &str) -> bool {
+    !is_reserved_char(g) && g.chars().all(|c| c.is_alphabetic() || c.is_numeric() || c == '_')
+}
+
+// The byte index of the ')' closing the '(' that `text` starts with, skipping over any in strings
+fn closing_paren(text: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ')' => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+// Decodes the escapes in a string literal's text. Errors say where in `text` the bad escape's
+// backslash is, in bytes.
+pub(crate) fn unescape(text: &str) -> Result<Cow<'_, str>, (usize, String)> {
+    if !text.contains('\\') {
+        return Ok(Cow::Borrowed(text));
+    }
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('\\') {
+        decoded.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let at = text.len() - rest.len();
+        let error = |msg: String| (at, msg);
+        let (c, len) = if let Some(hex) = rest.strip_prefix("\\x") {
+            let digits = hex.get(..2).filter(|d| d.chars().all(|c| c.is_ascii_hexdigit()))
+                .ok_or_else(|| error("Expect two hex digits after '\\x'".into()))?;
+            let n = u8::from_str_radix(digits, 16).unwrap();
+            // Like Rust, so that it's never unclear whether it's a byte or a character
+            if n > 0x7f {
+                return Err(error(format!("'\\x{}' is out of range. Use '\\u{{{}}}' for characters past 7F", digits, digits)));
+            }
+            (n as char, 4)
+        } else if let Some(hex) = rest.strip_prefix("\\u{") {
+            let end = hex.find('}').ok_or_else(|| error("Unterminated '\\u{' escape".into()))?;
+            let digits = &hex[..end];
+            if digits.is_empty() || digits.len() > 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(error("Expect 1 to 6 hex digits in '\\u{...}'".into()));
+            }
+            let c = u32::from_str_radix(digits, 16).ok().and_then(char::from_u32)
+                .ok_or_else(|| error(format!("'\\u{{{}}}' is not a valid character", digits)))?;
+            (c, end + 4)
+        } else if let Some(hex) = rest.strip_prefix("\\u") {
+            let digits = hex.get(..4).filter(|d| d.chars().all(|c| c.is_ascii_hexdigit()))
+                .ok_or_else(|| error("Expect four hex digits or '{' after '\\u'".into()))?;
+            let c = char::from_u32(u32::from_str_radix(digits, 16).unwrap())
+                .ok_or_else(|| error(format!("'\\u{}' is not a valid character", digits)))?;
+            (c, 6)
+        } else {
+            let c = match rest[1..].chars().next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '\'' | '"')) => c,
+                Some(c) => return Err(error(format!("Unknown escape '\\{}'", c))),
+                None => return Err(error("Expect an escape after '\\'".into())),
+            };
+            (c, 2)
+        };
+        decoded.push(c);
+        rest = &rest[len..];
+    }
+    decoded.push_str(rest);
+    Ok(Cow::Owned(decoded))
 }
 
 fn check_for(word: &str, sord: &str, ty: TokenType) -> TokenType {
     if word == sord {ty} else {TokenType::Word}
 }
 
+/// Whether `word` lexes as a keyword, like `true` or `while`, rather than a plain word.
+pub(crate) fn is_keyword(word: &str) -> bool {
+    !word.is_empty() && word_type(word) != TokenType::Word
+}
+
 fn word_type(word: &str) -> TokenType {
     let bord = word.as_bytes();
     match bord[0] {
@@ -35,16 +122,23 @@ fn word_type(word: &str) -> TokenType {
         }
         b'g' => check_for(word, "group", TokenType::Group),
         b'p' => check_for(word, "parallel", TokenType::Parallel),
-        b'r' => if let Some(b'e') = bord.get(1) {
-            check_for(word, "return", TokenType::Return)
-        } else {
-            check_for(word, "race", TokenType::Race)
+        b'r' => {
+            if word == "return" {TokenType::Return}
+            else if word == "repeat" {TokenType::Repeat}
+            else {check_for(word, "race", TokenType::Race)}
+        }
+        b'd' => {
+            if word == "do" {TokenType::Do}
+            else {check_for(word, "deadline", TokenType::Deadline)}
+        }
+        b'f' => {
+            if word == "for" {TokenType::For}
+            else {check_for(word, "false", TokenType::False)}
         }
-        b'd' => check_for(word, "deadline", TokenType::Deadline),
-        b'f' => check_for(word, "false", TokenType::False),
         b't' => check_for(word, "true", TokenType::True),
         b'w' => check_for(word, "while", TokenType::While),
         b'b' => check_for(word, "break", TokenType::Break),
+        b'c' => check_for(word, "continue", TokenType::Continue),
         b'u' => {
             if word == "use" {TokenType::Use}
             else if word == "until" {TokenType::Until}
@@ -53,8 +147,12 @@ fn word_type(word: &str) -> TokenType {
         }
         b'i' => check_for(word, "if", TokenType::If),
         b'e' => check_for(word, "else", TokenType::Else),
-        b's' => check_for(word, "sequence", TokenType::Sequence),
+        b's' => {
+            if word == "spawn" {TokenType::Spawn}
+            else {check_for(word, "sequence", TokenType::Sequence)}
+        }
         b'y' => check_for(word, "yield", TokenType::Yield),
+        b'h' => check_for(word, "halt", TokenType::Halt),
         _ => TokenType::Word
     }
 }
@@ -77,6 +175,12 @@ pub struct Lexer<'a> {
     errors: Vec<Error>,
 
     peek_token: Option<Token<'a>>,
+    last_ty: Option<TokenType>,
+    depth: usize, // open parentheses and brackets. ',' only separates things inside them.
+    case_insensitive_keywords: bool,
+    sigil: char,
+    pending_docs: Vec<Token<'a>>,
+    docs: HashMap<usize, Vec<Token<'a>>>, // start of the token they're attached to -> doc comments
 }
 
 impl<'a> Lexer<'a> {
@@ -89,9 +193,34 @@ impl<'a> Lexer<'a> {
             current: 0,
             errors: Vec::new(),
             peek_token: None,
+            last_ty: None,
+            depth: 0,
+            case_insensitive_keywords: false,
+            sigil: '$',
+            pending_docs: Vec::new(),
+            docs: HashMap::new(),
         }
     }
 
+    /// Makes keywords match regardless of case, so `Sequence` and `SEQUENCE` both lex as
+    /// `sequence`. Identifiers and words stay case-sensitive. Off by default.
+    pub fn set_case_insensitive_keywords(&mut self, enabled: bool) {
+        self.case_insensitive_keywords = enabled;
+    }
+
+    /// Changes the character that starts variable and property names from `$` to another one
+    /// of `%`, `@`, `&`, `~`, or `?`. With `%`, a name has to follow it directly and can't start
+    /// with a digit, so `%a % %b` and `%a%2` are still remainders.
+    pub fn set_variable_sigil(&mut self, sigil: char) -> Result<(), Error> {
+        Self::check_sigil(sigil)?;
+        self.sigil = sigil;
+        Ok(())
+    }
+
+    pub(crate) fn check_sigil(sigil: char) -> Result<(), Error> {
+        if SIGILS.contains(&sigil) {Ok(())} else {Err(Error::InvalidSigil(sigil))}
+    }
+
     /// Note for future self: This function has nothing to do with lexing. This peeks a whole
     /// token. The other peek-y functions only do graphemes.
     pub fn peek(&mut self) -> Option<&Token<'a>> {
@@ -106,6 +235,13 @@ impl<'a> Lexer<'a> {
         self.source
     }
 
+    /// The `///` doc comments directly before the token starting at `start`, one per line. Doc
+    /// comments are only kept when they're followed by a group declaration or its attributes, and
+    /// are skipped like any other comment everywhere else.
+    pub fn doc_comments(&self, start: usize) -> &[Token<'a>] {
+        self.docs.get(&start).map(|d| &d[..]).unwrap_or(&[])
+    }
+
     #[allow(dead_code)]
     fn _scan(&mut self) -> Option<Token<'a>> {
         if self.peek_token.is_some() {
@@ -127,13 +263,21 @@ impl<'a> Lexer<'a> {
         // self.start = self.current;
         let g = self.advance()?;
 
+        if self.starts_ident(g) {
+            self.advance_while(is_ident_char);
+            let name = &self.source[self.start+g.len()..(self.current)];
+            return self.make_token(Ident, Some(Literal::Ident(name)));
+        }
+
         match g {
             "(" => self.make_token(LeftParen, None),
             ")" => self.make_token(RightParen, None),
             "|" => self.make_token(Bar, None),
             "{" => self.make_token(LeftBrace, None),
             "}" => self.make_token(RightBrace, None),
-            "-" => if is_digit(self._peek()?) {
+            "[" => self.make_token(LeftBracket, None),
+            "]" => self.make_token(RightBracket, None),
+            "-" => if self.starts_negative_number() {
                 self.advance()?;
                 self.number()
             } else {
@@ -141,6 +285,17 @@ impl<'a> Lexer<'a> {
             }
             "+" => compound_op!(self, Plus, PlusEqual),
             "*" => compound_op!(self, Star, StarEqual),
+            "/" if self._peek() == Some("/") && self.peekn(2) == Some("/") => {
+                self.advance_while(|g| g != "\n");
+                let text = self.source[self.start+3..self.current].strip_prefix(' ');
+                let text = text.unwrap_or(&self.source[self.start+3..self.current]).trim_end();
+                self.make_token(DocComment, Some(Literal::String(text)))
+            }
+            "/" if self._peek() == Some("/") => {
+                self.advance_while(|g| g != "\n");
+                self.make_token(Comment, None)
+            }
+            "/" if self._peek() == Some("*") => self.block_comment(),
             "/" => compound_op!(self, Slash, SlashEqual),
             "%" => compound_op!(self, Percent, PercentEqual),
             "^" => compound_op!(self, Caret, CaretEqual),
@@ -148,58 +303,196 @@ impl<'a> Lexer<'a> {
             "=" => compound_op!(self, Equal, EqualEqual),
             "<" => compound_op!(self, Less, LessEqual),
             ">" => compound_op!(self, Greater, GreaterEqual),
+            "#" if self.starts_attribute() => {
+                self.advance();
+                self.make_token(HashBracket, None)
+            }
+            "#" if let Some(ty) = self.directive() => self.make_token(ty, None),
             "#" => {
                 self.advance_while(|g| g != "\n");
-                self.pass();
-                return self._scan();
+                self.make_token(Comment, None)
             }
             ";" => self.make_token(Semicolon, None),
+            ":" if self.last_ty == Some(Word) && self.label_colon(self.start) => self.make_token(Colon, None),
+            "," if self.depth > 0 => self.make_token(Comma, None),
+            // a range only follows an operand, so `cd ..` still passes the word ".."
+            "." if self._peek() == Some(".") && self.after_operand() => {
+                self.advance();
+                self.make_token(DotDot, None)
+            }
+            // only a method call, like `$a.abs()`. Elsewhere a '.' is just part of a word.
+            "." if self._peek().is_some_and(|g| is_ident_char(g) && !is_digit(g)) => self.make_token(Dot, None),
             // single and double quotes are supported
             "\"" | "'" => self.string(g),
-            _ if is_digit(g) => self.number(),
-            
-            "$" => {
-                self.advance_while(|g| !is_reserved_char(g) && (g.chars().all(|c| {
-                    c.is_alphabetic() || c.is_numeric() || c == '_'
-                })));
-                let name = &self.source[self.start+1..(self.current)];
-                self.make_token(Ident, Some(Literal::Ident(name)))
+            // raw strings, which never treat '\' specially
+            "r" if matches!(self._peek(), Some("\"" | "'")) => {
+                let quote = self.advance()?;
+                self.string(quote)
             }
+            _ if is_digit(g) => self.number(),
+
             _ => {
-                self.advance_while(|g| !is_reserved_char(g));
-                let word = &self.source[self.start..self.current];
-                let ty = word_type(word);
+                // only a word that starts a statement can be a loop's label
+                let statement = matches!(self.last_ty, None | Some(Semicolon | LeftBrace | RightBrace));
+                let bracketed = self.depth > 0;
+                loop {
+                    self.advance_while(|g| !is_reserved_char(g) && g != ":" && !(bracketed && g == ","));
+                    if self._peek() != Some(":") || statement && self.label_colon(self.current) {
+                        break;
+                    }
+                    self.advance();
+                }
+                let ty = self.word_type(&self.source[self.start..self.current]);
                 self.make_token(ty, None)
             }
         }
     }
 
+    fn word_type(&self, word: &str) -> TokenType {
+        if word.is_empty() {
+            TokenType::Word
+        } else if self.case_insensitive_keywords {
+            word_type(&word.to_lowercase())
+        } else {
+            word_type(word)
+        }
+    }
+
+    // A ':' is only a label's colon when a loop follows it, like `outer: while`, so words like
+    // `a:b` stay whole
+    fn label_colon(&self, colon: usize) -> bool {
+        let rest = self.source[colon + 1..].trim_start();
+        let len = rest.graphemes(true).take_while(|g| !is_reserved_char(g)).map(str::len).sum();
+        use TokenType::*;
+        matches!(self.word_type(&rest[..len]), While | Until | Repeat | Do | For)
+    }
+
+    fn starts_ident(&mut self, g: &str) -> bool {
+        let mut chars = g.chars();
+        if chars.next() != Some(self.sigil) || chars.next().is_some() {
+            return false;
+        }
+        self.sigil != '%' || self._peek().is_some_and(|g| is_ident_char(g) && !is_digit(g))
+    }
+
+    // Called just after a '-'. After an operand, it's only a sign if it's set apart like a separate
+    // argument, so `move $x -1` passes two arguments, but `$x-1` and `$x - 1` are subtractions.
+    fn starts_negative_number(&mut self) -> bool {
+        if !self._peek().map(is_digit).unwrap_or(false) {
+            return false;
+        }
+        if !self.after_operand() {
+            return true;
+        }
+        self.source[..self.start].chars().next_back().map(char::is_whitespace).unwrap_or(false)
+    }
+
+    fn after_operand(&self) -> bool {
+        use TokenType::*;
+        matches!(self.last_ty, Some(Ident | Num | Str | RightParen | RightBracket | True | False | Nil))
+    }
+
     fn number(&mut self) -> Option<Token<'a>> {
+        let text = &self.source[self.start..self.current];
+        if text.ends_with('0') && matches!(self._peek(), Some("x" | "X")) {
+            return self.hex_number();
+        }
         // don't care if hit end
         let _ = self.advance_while(|g| is_digit(g));
-        if let Some(".") = self._peek() {
+        // a letter after the "." means it's a method call on the number, like `2.5.round()`
+        // and a second "." means it's a range, like `0..5`
+        if let Some(".") = self._peek() && !self.peekn(2).is_some_and(|g| g == "." || is_ident_char(g) && !is_digit(g)) {
             // trailing "." after number is valid 
             let _ = self.advance();
             self.advance_while(|g| is_digit(g));
         }
+        // An `e` that doesn't start an exponent is left for the next token, like in `2em`
+        if let Some("e" | "E") = self._peek() {
+            let sign = matches!(self.peekn(2), Some("+" | "-"));
+            let digit = self.peekn(if sign {3} else {2}).is_some_and(is_digit);
+            let word = !sign && self.peekn(2).is_some_and(is_ident_char);
+            if digit || !word {
+                self.advance();
+                if sign {
+                    self.advance();
+                }
+                if !digit {
+                    self.error("Expect digits in the exponent");
+                    return self.make_token(TokenType::Error, None);
+                }
+                self.advance_while(is_digit);
+            }
+        }
         let num = self.source[self.start..(self.current)].parse().unwrap();
         self.make_token(TokenType::Num, Some(Literal::Number(num)))
     }
 
+    // Called with the "0" of a "0x" taken
+    fn hex_number(&mut self) -> Option<Token<'a>> {
+        self.advance();
+        let digits = self.current;
+        self.advance_while(|g| g.len() == 1 && g.as_bytes()[0].is_ascii_hexdigit());
+        let negative = self.source[self.start..].starts_with('-');
+        let Ok(n) = u64::from_str_radix(&self.source[digits..self.current], 16) else {
+            let msg = if digits == self.current {"Expect hex digits after '0x'"} else {"Hex number is too large"};
+            self.error(msg);
+            return self.make_token(TokenType::Error, None);
+        };
+        let num = if negative {-(n as f64)} else {n as f64};
+        self.make_token(TokenType::Num, Some(Literal::Number(num)))
+    }
+
     fn string(&mut self, quote: &str) -> Option<Token<'a>> {
-        // escaping is currently not supported. If it is necessary, that might get complicated
-        let hit_end = self.advance_while(|g| g != quote);
-        if hit_end {
-            self.error("Unterminated string");
-            return None;
+        let raw = self.source[self.start..].starts_with('r');
+        loop {
+            match self.advance() {
+                Some(g) if g == quote => break,
+                // An escaped quote doesn't end the string
+                Some("\\") if !raw => {
+                    self.advance();
+                }
+                Some(_) => {}
+                None => {
+                    self.error("Unterminated string");
+                    return self.make_token(TokenType::Error, None);
+                }
+            }
         }
 
-        self.advance(); // closing quote
-
         // the +/- 1 is perfectly safe, since those will always be quotes (even if expanded to
-        // single or double quotes. Will never be Unicode).
-        let value = &self.source[(self.start+1)..(self.current-1)];
-        self.make_token(TokenType::Str, Some(Literal::String(value)))
+        // single or double quotes. Will never be Unicode). Raw strings start with an 'r' too.
+        let value = &self.source[(self.start + if raw {2} else {1})..(self.current-1)];
+        if raw {
+            return self.make_token(TokenType::Str, Some(Literal::String(value)));
+        }
+        // Decoded again when the value's needed, since tokens can only borrow the source
+        match unescape(value) {
+            Ok(decoded) if decoded != value => self.make_token(TokenType::Str, Some(Literal::EscapedString(value))),
+            Ok(_) => self.make_token(TokenType::Str, Some(Literal::String(value))),
+            Err((at, msg)) => {
+                self.error_at(self.start + 1 + at, &msg);
+                self.make_token(TokenType::Error, None)
+            }
+        }
+    }
+
+    // Called just after the '/' of a `/*`. Block comments nest, so `/* a /* b */ c */` is all one
+    // comment.
+    fn block_comment(&mut self) -> Option<Token<'a>> {
+        self.advance();
+        let mut depth = 1;
+        while depth > 0 {
+            match self.advance() {
+                Some("*") if self.matches("/") => depth -= 1,
+                Some("/") if self.matches("*") => depth += 1,
+                Some(_) => {}
+                None => {
+                    self.error("Unterminated block comment");
+                    return self.make_token(TokenType::Error, None);
+                }
+            }
+        }
+        self.make_token(TokenType::Comment, None)
     }
 
     // This can't fail. The Option is for ergonomics in scan
@@ -214,6 +507,53 @@ impl<'a> Lexer<'a> {
         })
     }
 
+    // Called just after a '#'. The directive has to end where its word does, so a comment like
+    // `#iffy` is still a comment.
+    fn directive(&mut self) -> Option<TokenType> {
+        use TokenType::*;
+        let rest = &self.source[self.current..];
+        let (word, ty) = [("if", HashIf), ("else", HashElse), ("endif", HashEndif)].into_iter()
+            .find(|(word, _)| rest.starts_with(word))?;
+        if rest[word.len()..].chars().next().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            return None;
+        }
+        for _ in 0..word.len() {
+            self.advance();
+        }
+        Some(ty)
+    }
+
+    // Called just after a '#'. A `#[` only starts an attribute when a name, any arguments, and a
+    // `]` follow, and then more attributes or a group declaration, so a comment like
+    // `#[not an attribute]` is still a comment.
+    fn starts_attribute(&self) -> bool {
+        let mut rest = &self.source[self.start..];
+        while let Some(inner) = rest.strip_prefix("#[") {
+            let inner = inner.trim_start();
+            let name = inner.len() - inner.trim_start_matches(|c: char| c.is_alphanumeric() || c == '_').len();
+            if name == 0 {
+                return false;
+            }
+            let mut after = inner[name..].trim_start();
+            if after.starts_with('(') {
+                let Some(end) = closing_paren(after) else {
+                    return false;
+                };
+                after = after[end + 1..].trim_start();
+            }
+            let Some(after) = after.strip_prefix(']') else {
+                return false;
+            };
+            rest = after.trim_start();
+            // doc comments and ordinary ones can sit between attributes and the group
+            while rest.starts_with("//") {
+                rest = rest.find('\n').map_or("", |end| rest[end..].trim_start());
+            }
+        }
+        let len = rest.graphemes(true).take_while(|g| !is_reserved_char(g)).map(str::len).sum();
+        self.word_type(&rest[..len]) == TokenType::Group
+    }
+
     fn _peek(&mut self) -> Option<&'a str> {
         self.peekn(1)
     }
@@ -267,14 +607,14 @@ impl<'a> Lexer<'a> {
     }
 
     fn error(&mut self, msg: &str) {
-        let mut line = 1;
-        for c in self.source.bytes() {
-            if c == b'\n' {
-                line += 1;
-            }
-        }
+        self.error_at(self.start, msg);
+    }
+
+    fn error_at(&mut self, offset: usize, msg: &str) {
+        let (line, column) = position(self.source, offset);
         self.errors.push(Error::Lex{
             line,
+            column,
             msg: msg.to_string(),
         });
     } 
@@ -284,14 +624,34 @@ impl<'a> Lexer<'a> {
     }
 }
 
+/// Yields the tokens of the source in order, with their spans, skipping whitespace and comments.
+/// Anything that can't be lexed is yielded as a `TokenType::Error` token, and the error is kept in
+/// `errors`.
 impl<'a> Iterator for Lexer<'a> {
     type Item = Token<'a>;
     fn next(&mut self) -> Option<Token<'a>> {
-        let tok = self._scan();
-        if tok.as_ref().map(|t| t.ty == TokenType::Comment).unwrap_or(false) {
-            self._scan()
-        } else {
-            tok
+        let mut tok = self._scan();
+        while let Some(t) = tok.as_ref() && matches!(t.ty, TokenType::Comment | TokenType::DocComment) {
+            // Doc comments have to be directly above what they document
+            if t.ty == TokenType::DocComment {
+                self.pending_docs.push(t.clone());
+            } else {
+                self.pending_docs.clear();
+            }
+            tok = self._scan();
+        }
+        if let Some(tok) = &tok {
+            self.last_ty = Some(tok.ty);
+            match tok.ty {
+                TokenType::LeftParen | TokenType::LeftBracket | TokenType::HashBracket => self.depth += 1,
+                TokenType::RightParen | TokenType::RightBracket => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+            let docs = std::mem::take(&mut self.pending_docs);
+            if !docs.is_empty() && matches!(tok.ty, TokenType::Group | TokenType::HashBracket) {
+                self.docs.insert(tok.start, docs);
+            }
         }
+        tok
     }
 }
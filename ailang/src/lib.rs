@@ -1,21 +1,40 @@
 mod lexer;
 mod token;
 mod parser;
-mod ast;
+pub mod ast;
 mod error;
 mod compiler;
 mod interpreter;
+mod bytecode;
+mod cost;
+mod equivalence;
+mod dot;
+mod units;
+
+use std::collections::{HashMap, HashSet};
 
 pub use crate::lexer::{Lexer};
-pub use crate::parser::{Parser};
-pub use crate::error::{Error, Result};
-pub use crate::compiler::{Compiler, Callable, CallableGenerator, Prop, Arg, Value, Program, Op};
-pub use crate::interpreter::{Interpreter as AiInterpreter, InterpreterState};
+pub use crate::token::{Token, TokenType, Literal, OwnedToken, OwnedLiteral};
+pub use crate::parser::{Parser, Precedence};
+pub use crate::error::{Error, ErrorDetail, Result, Warning, Severity, Diagnostic, Diagnostics};
+pub use crate::compiler::{Compiler, Callable, CallableGenerator, Prop, Arg, Value, Program, Op, Signature, ComputedProp, Param, AngleRange, NumberFormat, OpCategory, DebugSymbols, GroupInfo};
+pub use crate::bytecode::{BYTECODE_VERSION};
+pub use crate::cost::{CostEstimate};
+pub use crate::units::{Unit, Dimension};
+pub use crate::interpreter::{Interpreter as AiInterpreter, InterpreterState, Clock, SystemClock, GroupEvent, TraceEntry, TraceEvent, DryRunAction, Coverage};
 
 
 
 pub struct AiCompiler {
     compiler: Option<Compiler>,
+    warnings: Vec<Warning>,
+    errors: Vec<Error>,
+    case_insensitive_keywords: bool,
+    variable_sigil: char,
+    allowed_ops: Option<HashSet<OpCategory>>,
+    max_expression_depth: Option<usize>,
+    error_detail: ErrorDetail,
+    operators: HashMap<String, Precedence>,
 }
 
 impl AiCompiler {
@@ -23,56 +42,233 @@ impl AiCompiler {
     pub fn new() -> AiCompiler {
         AiCompiler {
             compiler: None,
+            warnings: Vec::new(),
+            errors: Vec::new(),
+            case_insensitive_keywords: false,
+            variable_sigil: '$',
+            allowed_ops: None,
+            max_expression_depth: None,
+            error_detail: ErrorDetail::Full,
+            operators: HashMap::new(),
+        }
+    }
+
+    /// See `Lexer::set_case_insensitive_keywords`.
+    pub fn set_case_insensitive_keywords(&mut self, enabled: bool) {
+        self.case_insensitive_keywords = enabled;
+    }
+
+    /// See `Lexer::set_variable_sigil`.
+    pub fn set_variable_sigil(&mut self, sigil: char) -> Result<()> {
+        Lexer::check_sigil(sigil)?;
+        self.variable_sigil = sigil;
+        Ok(())
+    }
+
+    /// Restricts compiled programs to operations in the `allowed` categories. Programs using
+    /// anything else fail to compile with `Error::ForbiddenOperation`. Everything is allowed by
+    /// default.
+    pub fn set_allowed_ops(&mut self, allowed: HashSet<OpCategory>) {
+        self.allowed_ops = Some(allowed);
+    }
+
+    /// See `Parser::set_max_expression_depth`.
+    pub fn set_max_expression_depth(&mut self, depth: usize) {
+        self.max_expression_depth = Some(depth);
+    }
+
+    /// Sets how many errors compiling reports when one mistake causes several. Everything is
+    /// reported by default.
+    pub fn set_error_detail(&mut self, detail: ErrorDetail) {
+        self.error_detail = detail;
+    }
+
+    fn parser<'a>(&self, source: &'a str) -> Parser<'a> {
+        let mut lexer = Lexer::new(source);
+        lexer.set_case_insensitive_keywords(self.case_insensitive_keywords);
+        // checked when it was set
+        let _ = lexer.set_variable_sigil(self.variable_sigil);
+        let mut parser = Parser::new(lexer);
+        if let Some(depth) = self.max_expression_depth {
+            parser.set_max_expression_depth(depth);
+        }
+        for (name, precedence) in self.operators.iter() {
+            parser.register_operator(name, *precedence);
         }
+        parser
     }
     
     pub fn register_callable<C: CallableGenerator + 'static>(&mut self, name: &str, callable: C) -> Result<()> {
-        if self.compiler.is_none() {
-            self.compiler = Some(Compiler::new());
-        }
-        self.compiler.as_mut().unwrap().register_callable(name, Box::new(callable))
+        self.compiler.get_or_insert_with(Compiler::new).register_callable(name, Box::new(callable))
     }
     
     pub fn register_property<P: Prop + 'static>(&mut self, name: &str, prop: P) -> Result<()> {
-        if self.compiler.is_none() {
-            self.compiler = Some(Compiler::new());
+        self.compiler.get_or_insert_with(Compiler::new).register_property(name, Box::new(prop))
+    }
+
+    /// See `Compiler::set_implicit_main`.
+    pub fn set_implicit_main(&mut self, enabled: bool) -> Result<()> {
+        self.compiler.get_or_insert_with(Compiler::new).set_implicit_main(enabled)
+    }
+
+    /// See `Compiler::set_merge_identical_groups`.
+    pub fn set_merge_identical_groups(&mut self, enabled: bool) -> Result<()> {
+        self.compiler.get_or_insert_with(Compiler::new).set_merge_identical_groups(enabled)
+    }
+
+    /// See `Compiler::set_tail_calls`.
+    pub fn set_tail_calls(&mut self, enabled: bool) -> Result<()> {
+        self.compiler.get_or_insert_with(Compiler::new).set_tail_calls(enabled)
+    }
+
+    /// See `Compiler::set_branchless_selects`.
+    pub fn set_branchless_selects(&mut self, enabled: bool) -> Result<()> {
+        self.compiler.get_or_insert_with(Compiler::new).set_branchless_selects(enabled)
+    }
+
+    /// See `Compiler::set_statement_markers`.
+    pub fn set_statement_markers(&mut self, enabled: bool) -> Result<()> {
+        self.compiler.get_or_insert_with(Compiler::new).set_statement_markers(enabled)
+    }
+
+    /// See `Compiler::define_flag`.
+    pub fn define_flag(&mut self, name: &str) -> Result<()> {
+        self.compiler.get_or_insert_with(Compiler::new).define_flag(name)
+    }
+
+    pub fn set_angle_range(&mut self, range: AngleRange) -> Result<()> {
+        self.compiler.get_or_insert_with(Compiler::new).set_angle_range(range)
+    }
+
+    pub fn set_number_format(&mut self, format: NumberFormat) -> Result<()> {
+        self.compiler.get_or_insert_with(Compiler::new).set_number_format(format)
+    }
+
+    /// Adds an infix operator that calls `callable` with its two operands, binding like the
+    /// built-in operators at `precedence`. The name has to be a plain word, like `dist`, that isn't
+    /// a keyword.
+    pub fn register_operator(&mut self, name: &str, precedence: Precedence, callable: &str) -> Result<()> {
+        let mut lexer = Lexer::new(name);
+        lexer.set_case_insensitive_keywords(self.case_insensitive_keywords);
+        let word = matches!(lexer.next(), Some(tok) if tok.ty == TokenType::Word && tok.lexeme == name);
+        if !word || lexer.next().is_some() {
+            return Err(Error::InvalidOperator(name.to_string()));
         }
-        self.compiler.as_mut().unwrap().register_property(name, Box::new(prop))
+        self.compiler.get_or_insert_with(Compiler::new).register_operator(name, callable)?;
+        self.operators.insert(name.to_string(), precedence);
+        Ok(())
     }
 
     pub fn compile(&mut self, source: &str) -> std::result::Result<Program, Vec<Error>> {
-        let lexer = Lexer::new(source);
-        
-        let mut parser = Parser::new(lexer);
-        let ast = parser.parse();
-        if ast.is_none() {
-            return Err(parser.errors);
+        let res = self.compile_program(source);
+        self.record_errors(res)
+    }
+
+    fn compile_program(&mut self, source: &str) -> std::result::Result<Program, Vec<Error>> {
+        self.warnings.clear();
+        let mut parser = self.parser(source);
+        let ast = parser.parse_safe()?;
+        let mut compiler = self.compiler.take().unwrap_or_else(Compiler::new);
+        compiler.set_source(source).map_err(|e| vec![e])?;
+        let code = compiler.compile_nonconsuming(ast);
+        self.warnings = compiler.warnings().to_vec();
+        let code = code?;
+        self.check_ops(&code)?;
+        Ok(compiler.package_program(code))
+    }
+
+    /// Like `compile`, but parses and compiles the script a statement at a time, so it never holds
+    /// more than one top-level statement's AST. Meant for very large generated scripts. A `use`
+    /// statement only covers the statements after it, rather than the whole script.
+    pub fn compile_streaming(&mut self, source: &str) -> std::result::Result<Program, Vec<Error>> {
+        let res = self.compile_streaming_program(source);
+        self.record_errors(res)
+    }
+
+    fn compile_streaming_program(&mut self, source: &str) -> std::result::Result<Program, Vec<Error>> {
+        self.warnings.clear();
+        let mut parser = self.parser(source);
+        let mut compiler = self.compiler.take().unwrap_or_else(Compiler::new);
+        compiler.set_source(source).map_err(|e| vec![e])?;
+        let code = compiler.compile_stream(parser.statements());
+        self.warnings = compiler.warnings().to_vec();
+        // Parse errors come first, as they would if the script were parsed up front
+        let parse_errors = parser.take_errors();
+        if !parse_errors.is_empty() {
+            return Err(parse_errors);
         }
-        let ast = ast.unwrap();
-        let compiler = self.compiler.take().unwrap_or_else(|| Compiler::new());
-        compiler.compile(ast)
+        let code = code?;
+        self.check_ops(&code)?;
+        Ok(compiler.package_program(code))
     }
 
     pub fn compile_nonconsuming(&mut self, source: &str) -> std::result::Result<Vec<Op>, Vec<Error>> {
-        let lexer = Lexer::new(source);
-        
-        let mut parser = Parser::new(lexer);
-        let ast = parser.parse();
-        if ast.is_none() {
-            return Err(parser.errors);
-        }
-        let ast = ast.unwrap();
-        if self.compiler.is_none() {
-            self.compiler = Some(Compiler::new());
+        let res = self.compile_code(source);
+        self.record_errors(res)
+    }
+
+    fn compile_code(&mut self, source: &str) -> std::result::Result<Vec<Op>, Vec<Error>> {
+        self.warnings.clear();
+        let mut parser = self.parser(source);
+        let ast = parser.parse_safe()?;
+        let compiler = self.compiler.get_or_insert_with(Compiler::new);
+        compiler.set_source(source).map_err(|e| vec![e])?;
+        let code = compiler.compile_nonconsuming(ast);
+        self.warnings = compiler.warnings().to_vec();
+        let code = code?;
+        self.check_ops(&code)?;
+        Ok(code)
+    }
+
+    fn check_ops(&self, code: &[Op]) -> std::result::Result<(), Vec<Error>> {
+        match &self.allowed_ops {
+            Some(allowed) => compiler::check_ops(code, allowed).map_err(|e| vec![e]),
+            None => Ok(()),
         }
-        self.compiler.as_mut().unwrap().compile_nonconsuming(ast)
+    }
+
+    fn record_errors<T>(&mut self, res: std::result::Result<T, Vec<Error>>) -> std::result::Result<T, Vec<Error>> {
+        let res = res.map_err(|errors| self.error_detail.apply(errors));
+        self.errors = res.as_ref().err().cloned().unwrap_or_default();
+        res
+    }
+
+    /// Warnings from the most recent compilation, whether or not it succeeded.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// The errors and warnings from the most recent compilation.
+    pub fn diagnostics(&self) -> Diagnostics {
+        Diagnostics::new(self.errors.clone(), self.warnings.clone())
+    }
+
+    /// Checks `source` without keeping the code, and returns everything wrong with it. Like
+    /// `compile_nonconsuming`, groups it declares stay declared.
+    pub fn lint(&mut self, source: &str) -> Diagnostics {
+        let _ = self.compile_nonconsuming(source);
+        self.diagnostics()
     }
 
     pub fn package_program(&mut self, code: Vec<Op>) -> Program {
-        if self.compiler.is_none() {
-            self.compiler = Some(Compiler::new());
-        }
-        self.compiler.as_mut().unwrap().package_program(code)
+        self.compiler.get_or_insert_with(Compiler::new).package_program(code)
+    }
+
+    /// Evaluates a single expression, such as `$speed * 2 + 1`, against the registered
+    /// properties.
+    pub fn eval(&mut self, source: &str) -> std::result::Result<Value, Vec<Error>> {
+        let mut parser = self.parser(source);
+        let Some(expr) = parser.parse_expression() else {
+            return Err(parser.errors);
+        };
+        let compiler = self.compiler.get_or_insert_with(Compiler::new);
+        let code = compiler.compile_expression(&expr)?;
+        let program = compiler.package_program(code);
+
+        let mut interpreter = AiInterpreter::from_program(program);
+        let res = interpreter.evaluate();
+        compiler.unpackage_program(interpreter.into_program());
+        res.map_err(|e| vec![e])
     }
 
     pub fn convert(&mut self, source: &str) -> std::result::Result<AiInterpreter, Vec<Error>> {
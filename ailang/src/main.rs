@@ -1,19 +1,8 @@
-mod lexer;
-mod token;
-mod parser;
-mod ast;
-mod error;
-mod compiler;
-mod interpreter;
-
 use itertools::Itertools;
 
-use crate::lexer::{Lexer};
-use crate::ast::{ExprVisitor, StmtVisitorMut, Arg as AstArg};
-use crate::parser::{Parser};
-use crate::compiler::{Compiler, Callable, CallableGenerator, Prop, Arg, Value};
-use crate::error::Error;
-use crate::interpreter::{Interpreter, InterpreterState};
+use ailang::{Lexer, Parser, Compiler, Callable, CallableGenerator, Prop, Arg, Value, Error, InterpreterState};
+use ailang::AiInterpreter as Interpreter;
+use ailang::ast::{self, ExprVisitor, StmtVisitorMut, Arg as AstArg};
 
 pub struct AstPrinter {
     indent: usize,
@@ -71,6 +60,20 @@ impl<'a> ExprVisitor<'a, String> for AstPrinter {
     fn visit_variable_expr(&self, expr: &ast::Variable<'a>) -> String {
         format!("{}", expr.name.lexeme)
     }
+
+    fn visit_call_expr(&self, expr: &ast::Call<'a>) -> String {
+        let args: Vec<_> = expr.args.iter().collect();
+        self.parenthesize(expr.callee.lexeme, &args)
+    }
+
+    fn visit_list_expr(&self, expr: &ast::List<'a>) -> String {
+        let elements: Vec<_> = expr.elements.iter().collect();
+        self.parenthesize("list", &elements)
+    }
+
+    fn visit_index_expr(&self, expr: &ast::Index<'a>) -> String {
+        self.parenthesize("index", &[&expr.target, &expr.index])
+    }
 }
 // pub trait StmtVisitorMut<'a, R> {
 //     fn visit_group_stmt(&mut self, stmt: &Group<'a>) -> R;
@@ -82,13 +85,18 @@ impl<'a> ExprVisitor<'a, String> for AstPrinter {
 // }      
 impl<'a> StmtVisitorMut<'a, String> for AstPrinter {
     fn visit_group_stmt(&mut self, stmt: &ast::Group<'a>) -> String {
-        let param_list = stmt.params.iter().map(|t| t.lexeme).join(" ");
+        let param_list = stmt.params.iter().map(|p| {
+            if p.inout {format!("inout {}", p.name.lexeme)} else {p.name.lexeme.to_string()}
+        }).join(" ");
         self.indent += 1;
         let body = stmt.statements.iter().map(|s| {
             format!("{}{}", self.indent(), s.accept_mut(self))
         }).join("\n");
         self.indent -= 1;
-        format!("(group {} {}\n{})", stmt.name.lexeme, param_list, body)
+        let attributes = stmt.attributes.iter().map(|a| {
+            format!("#[{}({})] ", a.name.lexeme, a.args.iter().map(|t| t.lexeme).join(" "))
+        }).join("");
+        format!("({}group {} {}\n{})", attributes, stmt.name.lexeme, param_list, body)
     }
 
     fn visit_use_stmt(&mut self, stmt: &ast::Use<'a>) -> String {
@@ -114,6 +122,17 @@ impl<'a> StmtVisitorMut<'a, String> for AstPrinter {
             format!("({} {} \n{}\n{})", keyword, condition, then_body, else_body)
         }
     }
+    fn visit_conditional_stmt(&mut self, stmt: &ast::Conditional<'a>) -> String {
+        self.indent += 1;
+        let then_body = stmt.then_branch.iter().map(|s| {
+            format!("{}{}", self.indent(), s.accept_mut(self))
+        }).join("\n");
+        let else_body = stmt.else_branch.iter().map(|s| {
+            format!("{}{}", self.indent(), s.accept_mut(self))
+        }).join("\n");
+        self.indent -= 1;
+        format!("(#if {}\n{}\n{})", stmt.flag.lexeme, then_body, else_body)
+    }
     fn visit_while_stmt(&mut self, stmt: &ast::While<'a>) -> String {
         let keyword = if stmt.invert {"until"} else {"while"};
         let condition = stmt.condition.accept(self);
@@ -123,7 +142,74 @@ impl<'a> StmtVisitorMut<'a, String> for AstPrinter {
         }).join("\n");
         self.indent -= 1;
 
-        format!("({} {}\n{})", keyword, condition, body)
+        let looped = match &stmt.label {
+            Some(label) => format!("({}: {} {}\n{})", label.lexeme, keyword, condition, body),
+            None => format!("({} {}\n{})", keyword, condition, body),
+        };
+        let Some(timeout) = &stmt.timeout else {
+            return looped;
+        };
+        let duration = timeout.duration.iter().map(|t| t.lexeme).join("");
+        self.indent += 1;
+        let else_body = timeout.else_body.iter().map(|s| {
+            format!("{}{}", self.indent(), s.accept_mut(self))
+        }).join("\n");
+        self.indent -= 1;
+        format!("(timeout {} {}\n{})", duration, looped, else_body)
+    }
+    fn visit_repeat_stmt(&mut self, stmt: &ast::Repeat<'a>) -> String {
+        let count = stmt.count.accept(self);
+        self.indent += 1;
+        let body = stmt.body.iter().map(|s| {
+            format!("{}{}", self.indent(), s.accept_mut(self))
+        }).join("\n");
+        self.indent -= 1;
+
+        match &stmt.label {
+            Some(label) => format!("({}: repeat {}\n{})", label.lexeme, count, body),
+            None => format!("(repeat {}\n{})", count, body),
+        }
+    }
+    fn visit_foreach_stmt(&mut self, stmt: &ast::ForEach<'a>) -> String {
+        let iterable = stmt.iterable.accept(self);
+        self.indent += 1;
+        let body = stmt.body.iter().map(|s| {
+            format!("{}{}", self.indent(), s.accept_mut(self))
+        }).join("\n");
+        self.indent -= 1;
+
+        match &stmt.label {
+            Some(label) => format!("({}: for {} {}\n{})", label.lexeme, stmt.var.lexeme, iterable, body),
+            None => format!("(for {} {}\n{})", stmt.var.lexeme, iterable, body),
+        }
+    }
+    fn visit_forrange_stmt(&mut self, stmt: &ast::ForRange<'a>) -> String {
+        let start = stmt.start.accept(self);
+        let end = stmt.end.accept(self);
+        self.indent += 1;
+        let body = stmt.body.iter().map(|s| {
+            format!("{}{}", self.indent(), s.accept_mut(self))
+        }).join("\n");
+        self.indent -= 1;
+
+        match &stmt.label {
+            Some(label) => format!("({}: for {} {}..{}\n{})", label.lexeme, stmt.var.lexeme, start, end, body),
+            None => format!("(for {} {}..{}\n{})", stmt.var.lexeme, start, end, body),
+        }
+    }
+    fn visit_dowhile_stmt(&mut self, stmt: &ast::DoWhile<'a>) -> String {
+        self.indent += 1;
+        let body = stmt.body.iter().map(|s| {
+            format!("{}{}", self.indent(), s.accept_mut(self))
+        }).join("\n");
+        self.indent -= 1;
+
+        let keyword = if stmt.invert {"do-until"} else {"do-while"};
+        let condition = stmt.condition.accept(self);
+        match &stmt.label {
+            Some(label) => format!("({}: {} {}\n{})", label.lexeme, keyword, condition, body),
+            None => format!("({} {}\n{})", keyword, condition, body),
+        }
     }
     fn visit_exec_stmt(&mut self, stmt: &ast::Exec<'a>) -> String {
         let arg_list = stmt.args.iter().map(|arg| {
@@ -137,6 +223,9 @@ impl<'a> StmtVisitorMut<'a, String> for AstPrinter {
     fn visit_var_stmt(&mut self, stmt: &ast::Var<'a>) -> String {
         format!("(set {} {})", stmt.name.lexeme, stmt.value.accept(self))
     }
+    fn visit_capture_stmt(&mut self, stmt: &ast::Capture<'a>) -> String {
+        format!("(set {} {})", stmt.name.lexeme, self.visit_exec_stmt(&stmt.call))
+    }
 
     fn visit_parallel_stmt(&mut self, stmt: &ast::Parallel<'a>) -> String {
         let calls = stmt.calls.iter().map(|call| {
@@ -147,16 +236,34 @@ impl<'a> StmtVisitorMut<'a, String> for AstPrinter {
         format!("({} {})", kind, calls)
     }
 
+    fn visit_spawn_stmt(&mut self, stmt: &ast::Spawn<'a>) -> String {
+        format!("(spawn {})", self.visit_exec_stmt(&stmt.call))
+    }
+
     fn visit_return_stmt(&mut self, _stmt: &ast::Return<'a>) -> String {
         format!("(return)")
     }
     
+    fn visit_halt_stmt(&mut self, _stmt: &ast::Halt<'a>) -> String {
+        "(halt)".to_string()
+    }
+
     fn visit_yield_stmt(&mut self, _stmt: &ast::Yield<'a>) -> String {
         format!("(yield)")
     }
     
-    fn visit_break_stmt(&mut self, _stmt: &ast::Break<'a>) -> String {
-        format!("(break)")
+    fn visit_break_stmt(&mut self, stmt: &ast::Break<'a>) -> String {
+        match &stmt.label {
+            Some(label) => format!("(break {})", label.lexeme),
+            None => format!("(break)"),
+        }
+    }
+
+    fn visit_continue_stmt(&mut self, stmt: &ast::Continue<'a>) -> String {
+        match &stmt.label {
+            Some(label) => format!("(continue {})", label.lexeme),
+            None => "(continue)".to_string(),
+        }
     }
 }
 
@@ -3,29 +3,72 @@ use crate::token::{Token, TokenType, OwnedToken, Literal};
 use crate::ast::{*};
 use crate::error::{Error, Result};
 
+use std::collections::HashMap;
+
 // use std::collections::{VecDeque};
 
 type ExprResult<'a> = Result<Box<Expr<'a>>>;
 type StmtResult<'a> = Result<Stmt<'a>>;
 
+// Functions that can be called from inside a call's argument list, where any other word would be
+// taken as a word argument.
+const FUNCTIONS: &[&str] = &["normalize_angle", "sort", "format"];
+
+const DEFAULT_MAX_EXPRESSION_DEPTH: usize = 64;
+
+/// The built-in operator levels, loosest first. A custom operator binds as tightly as the built-in
+/// operators at the level it's registered with, and is left-associative like them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Precedence {
+    /// `or`
+    Or,
+    /// `xor`
+    Xor,
+    /// `and`
+    And,
+    /// `==` and `!=`
+    Equality,
+    /// `<`, `<=`, `>` and `>=`
+    Comparison,
+    /// `+` and `-`
+    Term,
+    /// `*`, `/` and `%`
+    Factor,
+    /// `^`
+    Exponent,
+}
+
 pub struct Parser<'a> {
     tokens: Lexer<'a>,
     pub errors: Vec<Error>,
     in_loop: bool,
+    last_end: usize, // where the last token taken ends, in bytes
+    in_call_args: bool, // directly in a call statement's arguments, outside any brackets
+    expression_depth: usize,
+    max_expression_depth: usize,
+    operators: HashMap<String, Precedence>, // custom infix operators, which are words
 }
 
 macro_rules! binary_expr {
-    ($lt:lifetime, $name:ident, $next_level:ident, $kind:ident, [$($ty:ident),+]) => {
+    ($lt:lifetime, $name:ident, $next_level:ident, $kind:ident, $level:ident, [$($ty:ident),+]) => {
         fn $name(&mut self) -> ExprResult<'a> {
             // println!("{}", stringify!($name));
+            let depth = self.expression_depth;
             let mut expr = self.$next_level()?;
 
-            while $(self.check(TokenType::$ty))||+ {
+            while $(self.check(TokenType::$ty))||+ || self.check_operator(Precedence::$level) {
+                self.deeper()?;
                 let op = self.advance();
                 let right = self.$next_level()?;
-                expr = Expr::$kind(expr, op, right);
+                // Custom operators are calls, so they never short circuit
+                expr = if op.ty == TokenType::Word {
+                    Expr::binary(expr, op, right)
+                } else {
+                    Expr::$kind(expr, op, right)
+                };
             }
 
+            self.expression_depth = depth;
             Ok(expr)
         }
     }
@@ -47,9 +90,28 @@ impl<'a> Parser<'a> {
             tokens: lexer,
             errors: Vec::new(),
             in_loop: false,
+            last_end: 0,
+            in_call_args: false,
+            expression_depth: 0,
+            max_expression_depth: DEFAULT_MAX_EXPRESSION_DEPTH,
+            operators: HashMap::new(),
         }
     }
 
+    /// Limits how deeply expressions can nest, so that untrusted scripts can't overflow the stack.
+    /// Brackets and prefix operators like `-` each count as a level, as does each operator in a
+    /// chain like `1 + 2 + 3`. Deeper expressions fail with `Error::ExpressionTooDeep`. The default
+    /// is 64.
+    pub fn set_max_expression_depth(&mut self, depth: usize) {
+        self.max_expression_depth = depth;
+    }
+
+    /// Makes `name` an infix operator, so that `$a name $b` is parsed like the built-in operators
+    /// at `precedence`. The compiler decides what it does.
+    pub fn register_operator(&mut self, name: &str, precedence: Precedence) {
+        self.operators.insert(name.to_string(), precedence);
+    }
+
     pub fn parse(&mut self) -> Option<Vec<Stmt<'a>>> {
         let mut statements = Vec::new();
         while !self.is_finished() {
@@ -63,26 +125,136 @@ impl<'a> Parser<'a> {
         // statements
     }
 
+    /// Parses one top-level statement at a time, so a long script never has to be held as a whole
+    /// AST. Statements that don't parse are skipped, and their errors are kept for `take_errors`,
+    /// along with the lexer's once the input runs out.
+    pub fn statements(&mut self) -> impl Iterator<Item = Stmt<'a>> + '_ {
+        std::iter::from_fn(move || {
+            while !self.is_finished() {
+                if let Some(stmt) = self.declaration() {
+                    return Some(stmt);
+                }
+            }
+            self.errors.extend_from_slice(self.tokens.errors());
+            None
+        }).fuse()
+    }
+
+    /// The errors found so far, leaving none behind.
+    pub fn take_errors(&mut self) -> Vec<Error> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Parses a lone expression, which must make up the whole input.
+    pub fn parse_expression(&mut self) -> Option<Box<Expr<'a>>> {
+        let expr = self.standalone_expression();
+        self.errors.extend_from_slice(self.tokens.errors());
+        match expr {
+            Ok(expr) => self.errors.is_empty().then_some(expr),
+            Err(e) => {
+                self.errors.push(e);
+                None
+            }
+        }
+    }
+
+    fn standalone_expression(&mut self) -> ExprResult<'a> {
+        let expr = self.expression()?;
+        if !self.is_finished() {
+            default_error!(self, "Expect end of input after expression");
+        }
+        Ok(expr)
+    }
+
+    /// Like `parse`, but reports failure with the errors that caused it. This never panics, no
+    /// matter the input, so it's suitable for untrusted scripts.
+    pub fn parse_safe(&mut self) -> std::result::Result<Vec<Stmt<'a>>, Vec<Error>> {
+        match self.parse() {
+            Some(statements) => Ok(statements),
+            None => Err(std::mem::take(&mut self.errors)),
+        }
+    }
+
     fn declaration(&mut self) -> Option<Stmt<'a>> {
         // println!("declaration");
-        use TokenType::*;
-        let out = if self.check(Group) || self.check(Parallel) || self.check(Race) {
-            self.group_declaration()
-        } else if self.matches(Use) {
-            self.use_statement()
-        } else {
-            self.statement()
-        };
-        match out {
+        match self.top_level_statement() {
             Ok(s) => Some(s),
             Err(e) => {
                 self.errors.push(e);
+                self.expression_depth = 0;
+                self.in_loop = false;
+                self.in_call_args = false;
                 self.synchronize();
                 None
             }
         }
     }
 
+    // The statements in a `{ ... }` body, up to its closing brace. A statement that doesn't parse
+    // is skipped and its error kept, so the rest of the body still gets checked.
+    fn body(&mut self) -> Result<Vec<Stmt<'a>>> {
+        self.body_with(Self::statement)
+    }
+
+    fn body_with(&mut self, mut statement: impl FnMut(&mut Self) -> StmtResult<'a>) -> Result<Vec<Stmt<'a>>> {
+        let mut body = Vec::new();
+        while !self.check(TokenType::RightBrace) {
+            let (in_loop, in_call_args) = (self.in_loop, self.in_call_args);
+            match statement(self) {
+                Ok(stmt) => body.push(stmt),
+                // Without a closing brace, the enclosing statement is broken too
+                Err(e) if self.is_finished() => return Err(e),
+                Err(e) => {
+                    self.errors.push(e);
+                    self.expression_depth = 0;
+                    self.in_loop = in_loop;
+                    self.in_call_args = in_call_args;
+                    if !self.check(TokenType::RightBrace) {
+                        self.synchronize();
+                    }
+                }
+            }
+        }
+        Ok(body)
+    }
+
+    fn top_level_statement(&mut self) -> StmtResult<'a> {
+        use TokenType::*;
+        if self.check(HashIf) {
+            self.conditional(true)
+        } else if self.check(HashBracket) {
+            self.attributed_declaration()
+        } else if self.check(Group) || self.check(Parallel) || self.check(Race) {
+            self.group_declaration(Vec::new())
+        } else if self.matches(Use) {
+            self.use_statement()
+        } else {
+            self.statement()
+        }
+    }
+
+    // `#if FLAG { ... } #else { ... } #endif`. Both branches are parsed, whichever one ends up
+    // being compiled. At the top level, they can declare groups too.
+    fn conditional(&mut self, top_level: bool) -> StmtResult<'a> {
+        let _ = self.advance();
+        let flag = self.consume(TokenType::Word, "Expect flag name after '#if'")?;
+        let then_branch = self.conditional_branch(top_level)?;
+        let else_branch = if self.matches(TokenType::HashElse) {
+            self.conditional_branch(top_level)?
+        } else {
+            Vec::new()
+        };
+        let _ = self.consume(TokenType::HashEndif, "Expect '#endif' after conditional block")?;
+        Ok(Stmt::conditional(flag, then_branch, else_branch))
+    }
+
+    fn conditional_branch(&mut self, top_level: bool) -> Result<Vec<Stmt<'a>>> {
+        let _ = self.consume(TokenType::LeftBrace, "Expect '{' after '#if' flag or '#else'")?;
+        let body = if top_level {self.body_with(Self::top_level_statement)?} else {self.body()?};
+        let _ = self.consume(TokenType::RightBrace, "Expect '}' after conditional block")?;
+        Ok(body)
+    }
+
     fn use_statement(&mut self) -> StmtResult<'a> {
         // println!("use_statement");
         if !self.check(TokenType::Ident) {
@@ -94,18 +266,62 @@ impl<'a> Parser<'a> {
         Ok(Stmt::r#use(name))
     }
     
-    fn group_declaration(&mut self) -> StmtResult<'a> {
+    // `///` comments before a group turn into a `doc` attribute, the same as `#[doc("...")]`. The
+    // name token spans the comments, since there's no `doc` in the source to point at.
+    fn doc_attribute(&self, start: usize) -> Option<Attribute<'a>> {
+        let docs = self.tokens.doc_comments(start);
+        let first = docs.first()?;
+        let name = Token {ty: TokenType::Word, start: first.start, len: first.len, lexeme: "doc", literal: None};
+        Some(Attribute {name, args: docs.to_vec()})
+    }
+
+    fn attributed_declaration(&mut self) -> StmtResult<'a> {
+        let start = self.peek().map(|t| t.start).unwrap_or(0);
+        let mut attributes: Vec<_> = self.doc_attribute(start).into_iter().collect();
+        while self.matches(TokenType::HashBracket) {
+            let name = self.consume(TokenType::Word, "Expect attribute name after '#['")?;
+            let mut args = Vec::new();
+            if self.matches(TokenType::LeftParen) {
+                while !self.check(TokenType::RightParen) {
+                    if self.is_finished() {
+                        default_error!(self, "Expect ')' after attribute arguments");
+                    }
+                    args.push(self.advance());
+                }
+                self.advance();
+            }
+            self.consume(TokenType::RightBracket, "Expect ']' after attribute")?;
+            attributes.push(Attribute {name, args});
+        }
+
+        if !self.check(TokenType::Group) {
+            default_error!(self, "Attributes can only be applied to group declarations");
+        }
+        self.group_declaration(attributes)
+    }
+
+    fn group_declaration(&mut self, mut attributes: Vec<Attribute<'a>>) -> StmtResult<'a> {
         let kind = self.advance();
         let is_race = kind.ty == TokenType::Race;
         if kind.ty != TokenType::Group {
             return self.parallel_block(is_race);
         }
         let name = self.consume(TokenType::Word, "Expect group name after keyword 'group'")?;
+        if let Some(doc) = self.doc_attribute(kind.start) {
+            attributes.push(doc);
+        }
 
         let mut params = Vec::new();
         while !self.check(TokenType::LeftBrace) {
-            if self.check(TokenType::Word) || self.check(TokenType::Ident) {
-                params.push(self.advance());
+            if self.check(TokenType::Word) {
+                let word = self.advance();
+                if word.lexeme == "inout" && self.check(TokenType::Ident) {
+                    params.push(GroupParam {name: self.advance(), inout: true});
+                } else {
+                    params.push(GroupParam {name: word, inout: false});
+                }
+            } else if self.check(TokenType::Ident) {
+                params.push(GroupParam {name: self.advance(), inout: false});
             } else {
                 default_error!(self, "Group parameters can only be identifiers or words");
             }
@@ -114,31 +330,33 @@ impl<'a> Parser<'a> {
         if kind.ty == TokenType::Group {
             let _ = self.consume(TokenType::LeftBrace, "Expect '{' after argument list")?;
 
-            let mut body = Vec::new();
-            while !self.check(TokenType::RightBrace) {
-                let stmt = self.statement()?;
-                body.push(stmt);
-            }
+            let body = self.body()?;
 
             let _ = self.consume(TokenType::RightBrace, "Expect '}' after group declaration")?;
             
-            Ok(Stmt::group(name, params, body))
+            Ok(Stmt::group(name, params, body, attributes))
         } else {
             let body = self.parallel_block(is_race)?;
 
-            Ok(Stmt::group(name, params, vec![body]))
+            Ok(Stmt::group(name, params, vec![body], attributes))
         }
     }
 
     fn statement(&mut self) -> StmtResult<'a> {
         // println!("statement");
         use TokenType::*;
-        if self.check(If) || self.check(Unless) {
+        if self.check(HashIf) {
+            self.conditional(false)
+        } else if self.check(If) || self.check(Unless) {
             self.if_statement()
-        } else if self.check(While) || self.check(Until) {
+        } else if self.check(While) || self.check(Until) || self.check(Repeat) || self.check(Do) || self.check(For) {
             self.while_statement()
         } else if self.check(Parallel) || self.check(Race) {
             self.parallel_statement()
+        } else if self.check(Spawn) {
+            let tok = self.advance();
+            let Stmt::Exec(call) = self.exec_statement()? else {unreachable!()};
+            Ok(Stmt::spawn(tok, call))
         } else if self.check(Ident) {
             self.var_statement()
         }else if self.check(Yield) {
@@ -149,16 +367,35 @@ impl<'a> Parser<'a> {
             let stmt = Stmt::r#return(self.advance());
             self.consume(TokenType::Semicolon, "Expect ';' after 'return'")?;
             Ok(stmt)
+        } else if self.check(Halt) {
+            let stmt = Stmt::halt(self.advance());
+            self.consume(TokenType::Semicolon, "Expect ';' after 'halt'")?;
+            Ok(stmt)
         } else if self.check(Word) {
-            self.exec_statement()
+            let name = self.advance();
+            if self.matches(Colon) {
+                self.labeled_statement(name)
+            } else {
+                self.call_statement(name)
+            }
         } else if self.check(Break) {
             if self.in_loop {
-                let stmt = Stmt::r#break(self.advance());
+                let tok = self.advance();
+                let label = if self.check(Word) {Some(self.advance())} else {None};
                 self.consume(TokenType::Semicolon, "Expect ';' after 'break'")?;
-                Ok(stmt)
+                Ok(Stmt::r#break(tok, label))
             } else {
                 default_error!(self, "'break' is not allowed outside of loops");
             }
+        } else if self.check(Continue) {
+            if self.in_loop {
+                let tok = self.advance();
+                let label = if self.check(Word) {Some(self.advance())} else {None};
+                self.consume(TokenType::Semicolon, "Expect ';' after 'continue'")?;
+                Ok(Stmt::r#continue(tok, label))
+            } else {
+                default_error!(self, "'continue' is not allowed outside of loops");
+            }
         } else {
             default_error!(self, "Statements must be conditionals, loops, assignments, or calls");
         }
@@ -166,15 +403,13 @@ impl<'a> Parser<'a> {
 
     fn if_statement(&mut self) -> StmtResult<'a> {
         // println!("if_statement");
-        let invert = self.advance().ty == TokenType::Unless;
+        let keyword = self.advance();
+        let invert = keyword.ty == TokenType::Unless;
 
         let condition = self.expression()?;
 
         let _ = self.consume(TokenType::LeftBrace, "Expect '{' after condition")?;
-        let mut then_body = Vec::new();
-        while !self.check(TokenType::RightBrace) {
-            then_body.push(self.statement()?);
-        }
+        let then_body = self.body()?;
 
         let _ = self.consume(TokenType::RightBrace, "Expect '}' after conditional body");
         
@@ -184,35 +419,106 @@ impl<'a> Parser<'a> {
                 let stmt = self.if_statement()?;
                 else_body.push(stmt);
             } else if self.matches(TokenType::LeftBrace) {
-                while !self.check(TokenType::RightBrace) {
-                    else_body.push(self.statement()?);
-                }
+                else_body = self.body()?;
                 let _ = self.consume(TokenType::RightBrace, "Expect '}' after else body");
             } else {
                 default_error!(self, "Expect '{' or another 'if' statement after 'else'");
             }
         }
 
-        Ok(Stmt::r#if(condition, invert, then_body, else_body))
+        Ok(Stmt::r#if(keyword, condition, invert, then_body, else_body))
     }
     
+    fn labeled_statement(&mut self, label: Token<'a>) -> StmtResult<'a> {
+        if self.check(TokenType::While) || self.check(TokenType::Until) || self.check(TokenType::Repeat) || self.check(TokenType::Do) || self.check(TokenType::For) {
+            self.loop_statement(Some(label))
+        } else {
+            default_error!(self, "Only loops can be labeled");
+        }
+    }
+
     fn while_statement(&mut self) -> StmtResult<'a> {
+        self.loop_statement(None)
+    }
+
+    fn loop_statement(&mut self, label: Option<Token<'a>>) -> StmtResult<'a> {
         // println!("while_statement");
-        self.in_loop = true;
-        let invert = self.advance().ty == TokenType::Until;
+        let was_in_loop = std::mem::replace(&mut self.in_loop, true);
+        let tok = self.advance();
+        let keyword = tok.ty;
+        if keyword == TokenType::Do {
+            let _ = self.consume(TokenType::LeftBrace, "Expect '{' after 'do'")?;
+            let body = self.body()?;
+            let _ = self.consume(TokenType::RightBrace, "Expect '}' after loop body")?;
+            self.in_loop = was_in_loop;
+            let invert = self.matches(TokenType::Until);
+            if !invert {
+                let _ = self.consume(TokenType::While, "Expect 'while' or 'until' after 'do' loop body")?;
+            }
+            let condition = self.expression()?;
+            let _ = self.consume(TokenType::Semicolon, "Expect ';' after loop condition")?;
+            return Ok(Stmt::do_while(label, body, condition, invert));
+        }
+        if keyword == TokenType::For {
+            let var = self.consume(TokenType::Ident, "Expect loop variable after 'for'")?;
+            // `in` isn't a keyword, so it's still usable as a word argument
+            if !self.peek().is_some_and(|t| t.ty == TokenType::Word && t.lexeme == "in") {
+                default_error!(self, "Expect 'in' after loop variable");
+            }
+            self.advance();
+            let iterable = self.expression()?;
+            let end = if self.matches(TokenType::DotDot) {Some(self.expression()?)} else {None};
+            let _ = self.consume(TokenType::LeftBrace, "Expect '{' after the list or range to loop over")?;
+            let body = self.body()?;
+            let _ = self.consume(TokenType::RightBrace, "Expect '}' after loop body")?;
+            self.in_loop = was_in_loop;
+            return Ok(match end {
+                Some(end) => Stmt::for_range(label, var, iterable, end, body),
+                None => Stmt::for_each(label, var, iterable, body),
+            });
+        }
+        let invert = keyword == TokenType::Until;
 
         let condition = self.expression()?;
+        if keyword == TokenType::Repeat {
+            let _ = self.consume(TokenType::LeftBrace, "Expect '{' after repeat count")?;
+            let body = self.body()?;
+            let _ = self.consume(TokenType::RightBrace, "Expect '}' after loop body")?;
+            self.in_loop = was_in_loop;
+            return Ok(Stmt::repeat(label, condition, body));
+        }
 
-        let _ = self.consume(TokenType::LeftBrace, "Expect '{' after condition")?;
-        let mut body = Vec::new();
-        while !self.check(TokenType::RightBrace) {
-            body.push(self.statement()?);
+        // `timeout` isn't a keyword, so it's still usable as a callable name everywhere else
+        let timed = self.peek().map(|t| t.ty == TokenType::Word && t.lexeme == "timeout").unwrap_or(false);
+        let mut duration = Vec::new();
+        if timed {
+            self.advance();
+            while !self.check(TokenType::LeftBrace) {
+                if self.is_finished() {
+                    default_error!(self, "Expect '{' after loop timeout");
+                }
+                duration.push(self.advance());
+            }
         }
 
+        let _ = self.consume(TokenType::LeftBrace, "Expect '{' after condition")?;
+        let body = self.body()?;
+
         let _ = self.consume(TokenType::RightBrace, "Expect '}' after loop body")?;
+        self.in_loop = was_in_loop;
+
+        let mut timeout = None;
+        if timed {
+            let mut else_body = Vec::new();
+            if self.matches(TokenType::Else) {
+                let _ = self.consume(TokenType::LeftBrace, "Expect '{' after 'else'")?;
+                else_body = self.body()?;
+                let _ = self.consume(TokenType::RightBrace, "Expect '}' after else body")?;
+            }
+            timeout = Some(LoopTimeout {duration, else_body});
+        }
 
-        self.in_loop = false;
-        Ok(Stmt::r#while(condition, invert, body))
+        Ok(Stmt::r#while(label, tok, condition, invert, body, timeout))
     }
     
     fn var_statement(&mut self) -> StmtResult<'a> {
@@ -226,6 +532,12 @@ impl<'a> Parser<'a> {
         }
         let op = self.advance();
         if op.ty == TokenType::Equal {
+            // A bare word can't start an expression, so it's a call whose result is assigned
+            if self.check(Word) && !self.check_function() {
+                let callee = self.advance();
+                let Stmt::Exec(call) = self.call_statement(callee)? else {unreachable!()};
+                return Ok(Stmt::capture(name, call));
+            }
 
             let value = self.expression()?;
             let _ = self.consume(TokenType::Semicolon, "Expect ';' after assignment");
@@ -256,15 +568,24 @@ impl<'a> Parser<'a> {
     
     fn exec_statement(&mut self) -> StmtResult<'a> {
         // println!("exec_statement");
-        let name = self.advance();
-        
+        let name = self.consume(TokenType::Word, "Expect callable name")?;
+        self.call_statement(name)
+    }
+
+    // Arguments are words or expressions. An expression argument is as long as it can be, so it
+    // only ends at a token that can't continue it: `move 1 + 2` passes 3, while `move (1) (2)` and
+    // `move $x $y` pass two arguments. A '-' set apart by whitespace before a number is a sign
+    // rather than subtraction (see the lexer), so `move 1 -2` passes two arguments too.
+    fn call_statement(&mut self, name: Token<'a>) -> StmtResult<'a> {
         let mut args = Vec::new();
         while !self.check(TokenType::Semicolon) {
-            if self.check(TokenType::Word) {
+            if self.check(TokenType::Word) && !self.check_function() {
                 args.push(self.advance().into());
             } else {
-                let exp = self.expression()?;
-                args.push(exp.into());
+                let was_in_call_args = std::mem::replace(&mut self.in_call_args, true);
+                let exp = self.expression();
+                self.in_call_args = was_in_call_args;
+                args.push(exp?.into());
             }
         }
         let _ = self.consume(TokenType::Semicolon, "Expect ';' after call")?;
@@ -294,26 +615,87 @@ impl<'a> Parser<'a> {
 
     fn expression(&mut self) -> ExprResult<'a> {
         // println!("expression");
-        self.or()
+        self.deeper()?;
+        let expr = self.or()?;
+        self.expression_depth -= 1;
+        Ok(expr)
     }
 
-    binary_expr!('a, or, xor, logical, [Or]);
-    binary_expr!('a, xor, and, logical, [Xor]);
-    binary_expr!('a, and, equality, logical, [And]);
-    binary_expr!('a, equality, comparison, binary, [BangEqual, EqualEqual]);
-    binary_expr!('a, comparison, term, binary, [Greater, GreaterEqual, Less, LessEqual]);
-    binary_expr!('a, term, factor, binary, [Minus, Plus]);
-    binary_expr!('a, factor, exp, binary, [Slash, Star, Percent]);
-    binary_expr!('a, exp, unary, binary, [Caret]);
+    // Goes one level deeper into an expression, failing if that's too deep. Chains like
+    // `1 + 2 + 3` go a level deeper for each link, since they nest as deeply as brackets would
+    // once they're an AST. Errors leave the depth where it was, to be reset by `declaration`.
+    fn deeper(&mut self) -> Result<()> {
+        if self.expression_depth >= self.max_expression_depth {
+            let source = self.tokens.source();
+            let tok = self.peek().map(|tok| tok.to_owned(source));
+            return Err(match tok {
+                Some(tok) => Error::ExpressionTooDeep{tok, limit: self.max_expression_depth},
+                None => Error::EndOfStream{msg: "Expression is nested too deeply".into()},
+            });
+        }
+        self.expression_depth += 1;
+        Ok(())
+    }
+
+    binary_expr!('a, or, xor, logical, Or, [Or]);
+    binary_expr!('a, xor, and, logical, Xor, [Xor]);
+    binary_expr!('a, and, equality, logical, And, [And]);
+    binary_expr!('a, equality, comparison, binary, Equality, [BangEqual, EqualEqual]);
+    binary_expr!('a, comparison, term, binary, Comparison, [Greater, GreaterEqual, Less, LessEqual]);
+    binary_expr!('a, term, factor, binary, Term, [Minus, Plus]);
+    binary_expr!('a, factor, exp, binary, Factor, [Slash, Star, Percent]);
+    binary_expr!('a, exp, unary, binary, Exponent, [Caret]);
+
+    // Whether the next token is a custom operator at this level
+    fn check_operator(&mut self, level: Precedence) -> bool {
+        if self.operators.is_empty() {
+            return false;
+        }
+        match self.peek() {
+            Some(tok) if tok.ty == TokenType::Word => {
+                let name = tok.lexeme;
+                self.operators.get(name) == Some(&level)
+            }
+            _ => false,
+        }
+    }
 
     fn unary(&mut self) -> ExprResult<'a> {
         // println!("unary");
         if self.check(TokenType::Not) || self.check(TokenType::Minus) {
             let op = self.advance();
+            self.deeper()?;
             let right = self.unary()?;
+            self.expression_depth -= 1;
             Ok(Expr::unary(op, right))
         } else {
-            self.primary()
+            self.method_call()
+        }
+    }
+
+    // Methods and indexing bind tighter than any operator, so `-$a.abs()` negates the absolute
+    // value. An index has to follow directly, since `go $x [1, 2]` passes a list as a second
+    // argument.
+    fn method_call(&mut self) -> ExprResult<'a> {
+        let depth = self.expression_depth;
+        let mut expr = self.primary()?;
+        loop {
+            if self.check(TokenType::Dot) || self.check(TokenType::LeftBracket) {
+                self.deeper()?;
+            }
+            if self.matches(TokenType::Dot) {
+                let method = self.consume(TokenType::Word, "Expect method name after '.'")?;
+                let args = self.call_args()?;
+                expr = Expr::method_call(method, *expr, args);
+            } else if let last_end = self.last_end && self.peek().is_some_and(|t| t.ty == TokenType::LeftBracket && t.start == last_end) {
+                let bracket = self.advance();
+                let index = self.bracketed(Self::expression)?;
+                let _ = self.consume(TokenType::RightBracket, "Expect ']' after index")?;
+                expr = Expr::index(expr, bracket, index);
+            } else {
+                self.expression_depth = depth;
+                return Ok(expr);
+            }
         }
     }
 
@@ -326,24 +708,87 @@ impl<'a> Parser<'a> {
             Expr::literal(Literal::Bool(false))
         } else if self.matches(Nil) {
             Expr::literal(Literal::Nil)
-        } else if self.check(Num) || self.check(Str) {
+        } else if self.check(Num) {
             Expr::literal(self.advance().literal.unwrap())
+        } else if self.check(Str) {
+            // Adjacent strings are joined, except in call arguments, where they're separate
+            // arguments unless they're bracketed, like `say ('a' 'b')`
+            let depth = self.expression_depth;
+            let mut exp = Expr::literal(self.advance().literal.unwrap());
+            while !self.in_call_args && self.check(Str) {
+                self.deeper()?;
+                let next = self.advance();
+                let plus = Token {ty: Plus, start: next.start, len: 0, lexeme: "+", literal: None};
+                exp = Expr::binary(exp, plus, Expr::literal(next.literal.unwrap()));
+            }
+            self.expression_depth = depth;
+            exp
         } else if self.check(Ident) {
             Expr::variable(self.advance())
+        } else if self.check(Word) {
+            let callee = self.advance();
+            self.call_expression(callee)?
         } else if self.matches(LeftParen) {
-            let exp = self.expression()?;
+            let exp = self.bracketed(Self::expression)?;
             let _ = self.consume(RightParen, "Expect ')' after expression")?;
             Expr::grouping(exp, false)
         } else if self.matches(Bar) {
-            let exp = self.expression()?;
+            let exp = self.bracketed(Self::expression)?;
             let _ = self.consume(Bar, "Expect closing '|' in absolute value expression")?;
             Expr::grouping(exp, true)
+        } else if self.check(LeftBracket) {
+            let bracket = self.advance();
+            let elements = self.bracketed(|this| {
+                let mut elements = Vec::new();
+                if !this.check(RightBracket) {
+                    elements.push(*this.expression()?);
+                    while this.matches(Comma) {
+                        elements.push(*this.expression()?);
+                    }
+                }
+                Ok(elements)
+            })?;
+            let _ = self.consume(RightBracket, "Expect ']' after list elements")?;
+            Expr::list(bracket, elements)
         } else {
             default_error!(self, "Expect expression");
         })
     }
 
 
+    fn call_expression(&mut self, callee: Token<'a>) -> ExprResult<'a> {
+        let args = self.call_args()?;
+        Ok(Expr::call(callee, args))
+    }
+
+    fn call_args(&mut self) -> Result<Vec<Expr<'a>>> {
+        let _ = self.consume(TokenType::LeftParen, "Expect '(' after function name")?;
+        let args = self.bracketed(|this| {
+            let mut args = Vec::new();
+            if !this.check(TokenType::RightParen) {
+                args.push(*this.expression()?);
+                while this.matches(TokenType::Comma) {
+                    args.push(*this.expression()?);
+                }
+            }
+            Ok(args)
+        })?;
+        let _ = self.consume(TokenType::RightParen, "Expect ')' after function arguments")?;
+        Ok(args)
+    }
+
+    // Parses something inside brackets, where a call statement's arguments have no effect
+    fn bracketed<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let was_in_call_args = std::mem::replace(&mut self.in_call_args, false);
+        let result = f(self);
+        self.in_call_args = was_in_call_args;
+        result
+    }
+
+    fn check_function(&mut self) -> bool {
+        self.peek().map(|tok| tok.ty == TokenType::Word && FUNCTIONS.contains(&tok.lexeme)).unwrap_or(false)
+    }
+
     fn matches(&mut self, ty: TokenType) -> bool {
         if self.check(ty) {
             self.advance();
@@ -375,7 +820,9 @@ impl<'a> Parser<'a> {
     }
 
     fn advance(&mut self) -> Token<'a> {
+        self.peek();
         let next = self.tokens.next().unwrap();
+        self.last_end = next.start + next.len;
         // println!("{}", next);
         next
     }
@@ -385,6 +832,10 @@ impl<'a> Parser<'a> {
     }
 
     fn peek(&mut self) -> Option<&Token<'a>> {
+        // The lexer reports its own errors
+        while self.tokens.peek().is_some_and(|tok| tok.ty == TokenType::Error) {
+            self.tokens.next();
+        }
         let out = self.tokens.peek();
         out
     }
@@ -407,7 +858,7 @@ impl<'a> Parser<'a> {
         while let Some(tok) = self.peek() {
             if last_tok.ty == Semicolon {return;}
             match tok.ty {
-                Use | Group | Race | Parallel | Sequence | If | Unless | While | Until => return,
+                RightBrace | Use | Group | Race | Parallel | Sequence | Spawn | If | Unless | While | Until | Repeat | Do | For | HashIf => return,
                 _ => {
                     last_tok = self.advance();
                 }
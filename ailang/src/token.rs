@@ -1,9 +1,24 @@
 use std::fmt::{self, Display};
+use std::ops::Range;
+use std::borrow::Cow;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::lexer::unescape;
+
+/// The line and column of the byte at `offset` in `source`, both counted from 1. Columns are
+/// counted in graphemes, like the lexer reads the source.
+pub(crate) fn position(source: &str, offset: usize) -> (usize, usize) {
+    let before = source.get(..offset).unwrap_or(source);
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (before.matches('\n').count() + 1, before[line_start..].graphemes(true).count() + 1)
+}
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Literal<'a> {
     Ident(&'a str),
     String(&'a str),
+    /// A string with escapes in it, as it's written in the source. See `Literal::string`.
+    EscapedString(&'a str),
     Number(f64),
     Bool(bool),
     Nil, 
@@ -13,7 +28,7 @@ impl<'a> Display for Literal<'a> {
         use Literal::*;
         match self {
             Ident(name) => write!(f, "{}", name),
-            String(value) => write!(f, "\"{}\"", value),
+            String(value) | EscapedString(value) => write!(f, "\"{}\"", value),
             Number(value) => write!(f, "{}", value),
             Bool(value) => write!(f, "{}", value),
             Nil => write!(f, "nil")
@@ -22,10 +37,20 @@ impl<'a> Display for Literal<'a> {
 }
 
 impl<'a> Literal<'a> {
+    /// The text of a string literal, with any escapes decoded.
+    pub fn string(&self) -> Option<Cow<'a, str>> {
+        match *self {
+            Literal::String(s) => Some(Cow::Borrowed(s)),
+            // The lexer only makes these from escapes that decode
+            Literal::EscapedString(s) => Some(unescape(s).unwrap_or(Cow::Borrowed(s))),
+            _ => None,
+        }
+    }
+
     pub fn to_owned(&self) -> OwnedLiteral {
         match self {
             Literal::Ident(s) => OwnedLiteral::Ident(s.to_string()),
-            Literal::String(s) => OwnedLiteral::String(s.to_string()),
+            Literal::String(_) | Literal::EscapedString(_) => OwnedLiteral::String(self.string().unwrap_or_default().into_owned()),
             Literal::Number(n) => OwnedLiteral::Number(*n),
             Literal::Bool(b) => OwnedLiteral::Bool(*b),
             Literal::Nil => OwnedLiteral::Nil,
@@ -60,13 +85,17 @@ impl Display for OwnedLiteral {
 pub enum TokenType {
     // delimiters
     LeftBrace, RightBrace,
+    LeftBracket, RightBracket,
+    HashBracket, // starts an attribute
+    HashIf, HashElse, HashEndif, // conditional compilation
     Bar,
     // arithmetic
     LeftParen, RightParen,
     Minus, Plus, Slash, Star, Percent, Caret,
     MinusEqual, PlusEqual, SlashEqual, StarEqual, PercentEqual, CaretEqual,
     // Dollar, //?
-    Semicolon,
+    Semicolon, Colon, Comma, Dot,
+    DotDot, // a range, like `0..$n`
 
     // comparison
     // Bang, 
@@ -81,16 +110,18 @@ pub enum TokenType {
     Str,
     Num,
     Comment,
+    DocComment,
+    Error, // something that couldn't be lexed, which the lexer has already reported
 
     // Keywords
     And, Or, Not, Xor,
-    Group, Parallel, Race, Sequence,
+    Group, Parallel, Race, Sequence, Spawn,
     Deadline, // may not use, but reserve for later use
     False, True, Nil,
-    While, Until,
-    Break, //Continue, // break is hard to imitate, continue less so
+    While, Until, Repeat, Do, For,
+    Break, Continue,
     If, Else, Unless, // may not use unless, but reserving
-    Yield, Return,
+    Yield, Return, Halt,
     Use,
     // EOF,
 }
@@ -120,20 +151,22 @@ impl<'a> Display for Token<'a> {
 }
 
 impl<'a> Token<'a> {
-    pub fn to_owned(&self, source: &'a str) -> OwnedToken {
-        let mut line = 1;
-        for (i, b) in source.bytes().enumerate() {
-            if i >= self.start {
-                break;
-            }
-            if b == b'\n' {
-                line += 1;
-            }
-        }
+    /// The byte range of the token in the source.
+    pub fn span(&self) -> Range<usize> {
+        self.start..self.start + self.len
+    }
 
+    /// The line and column the token starts at in `source`, both counted from 1.
+    pub fn position(&self, source: &str) -> (usize, usize) {
+        position(source, self.start)
+    }
+
+    pub fn to_owned(&self, source: &'a str) -> OwnedToken {
+        let (line, column) = self.position(source);
         OwnedToken {
             ty: self.ty,
             line,
+            column,
             lexeme: self.lexeme.to_owned(),
             literal: self.literal.map(|l| l.to_owned()),
         }
@@ -155,6 +188,7 @@ impl<'a> Token<'a> {
 pub struct OwnedToken {
     pub ty: TokenType,
     pub line: usize,
+    pub column: usize,
     pub lexeme: String,
     pub literal: Option<OwnedLiteral>,
 }
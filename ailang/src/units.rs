@@ -0,0 +1,85 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// What a `Unit` measures. Only units of the same dimension can be converted between.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Dimension {
+    Length,
+    Time,
+    Angle,
+}
+
+/// A unit that a property or callable parameter can declare its numbers to be in. The compiler
+/// converts between units of the same dimension, and rejects mixing different dimensions, like
+/// adding meters to seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Unit {
+    Meters,
+    Centimeters,
+    Millimeters,
+    Kilometers,
+    Feet,
+    Inches,
+    Seconds,
+    Milliseconds,
+    Minutes,
+    Radians,
+    Degrees,
+}
+
+impl Unit {
+    pub fn dimension(self) -> Dimension {
+        use Unit::*;
+        match self {
+            Meters | Centimeters | Millimeters | Kilometers | Feet | Inches => Dimension::Length,
+            Seconds | Milliseconds | Minutes => Dimension::Time,
+            Radians | Degrees => Dimension::Angle,
+        }
+    }
+
+    // The size of the unit, relative to others of the same dimension. Whole numbers where
+    // possible, so that conversions like feet to inches come out exact.
+    fn scale(self) -> f64 {
+        use Unit::*;
+        match self {
+            // in tenths of a millimeter
+            Meters => 10_000.0,
+            Centimeters => 100.0,
+            Millimeters => 10.0,
+            Kilometers => 10_000_000.0,
+            Feet => 3048.0,
+            Inches => 254.0,
+            // in milliseconds
+            Seconds => 1000.0,
+            Milliseconds => 1.0,
+            Minutes => 60_000.0,
+            Radians => 1.0,
+            Degrees => std::f64::consts::PI / 180.0,
+        }
+    }
+
+    /// What to multiply a number in this unit by to get it in `other`, if they measure the same
+    /// thing.
+    pub fn factor_to(self, other: Unit) -> Option<f64> {
+        (self.dimension() == other.dimension()).then(|| self.scale() / other.scale())
+    }
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        use Unit::*;
+        let name = match self {
+            Meters => "meters",
+            Centimeters => "centimeters",
+            Millimeters => "millimeters",
+            Kilometers => "kilometers",
+            Feet => "feet",
+            Inches => "inches",
+            Seconds => "seconds",
+            Milliseconds => "milliseconds",
+            Minutes => "minutes",
+            Radians => "radians",
+            Degrees => "degrees",
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,43 @@
+use ailang::*;
+use ailang::ast::*;
+
+fn parse(source: &str) -> Vec<Stmt<'_>> {
+    Parser::new(Lexer::new(source)).parse_safe().unwrap_or_else(|e| panic!("{:?}", e))
+}
+
+fn assigned<'s, 'a>(stmt: &'s Stmt<'a>) -> &'s Expr<'a> {
+    let Stmt::Var(Var {value, ..}) = stmt else {panic!("expected an assignment")};
+    value
+}
+
+#[test]
+fn double_negations_are_folded() {
+    let program = rewrite(parse("$y = -(-$x); $z = - -$x;"), &mut FoldDoubleNegation);
+    for stmt in program.iter() {
+        let Expr::Variable(Variable {name}) = assigned(stmt) else {panic!("expected a variable")};
+        assert_eq!(name.lexeme, "$x");
+    }
+}
+
+#[test]
+fn single_negations_are_left_alone() {
+    let program = rewrite(parse("$y = -$x; $z = -(-(-$x));"), &mut FoldDoubleNegation);
+    for stmt in program.iter() {
+        let Expr::Unary(Unary {op, right}) = assigned(stmt) else {panic!("expected a negation")};
+        assert_eq!(op.ty, TokenType::Minus);
+        let mut right = &**right;
+        while let Expr::Grouping(Grouping {expression, ..}) = right {
+            right = expression;
+        }
+        assert!(matches!(right, Expr::Variable(_)));
+    }
+}
+
+#[test]
+fn expressions_inside_statements_are_rewritten() {
+    let program = rewrite(parse("if -(-$x) > 1 { $y = -(-$y); }"), &mut FoldDoubleNegation);
+    let Stmt::If(If {condition, then_branch, ..}) = &program[0] else {panic!("expected an if")};
+    let Expr::Binary(Binary {left, ..}) = &**condition else {panic!("expected a comparison")};
+    assert!(matches!(&**left, Expr::Variable(_)));
+    assert!(matches!(assigned(&then_branch[0]), Expr::Variable(_)));
+}
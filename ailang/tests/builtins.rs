@@ -0,0 +1,184 @@
+mod common;
+
+use ailang::*;
+use common::*;
+
+fn eval(source: &str, range: AngleRange) -> Value {
+    let mut compiler = AiCompiler::new();
+    compiler.set_angle_range(range).unwrap();
+    compiler.eval(source).unwrap_or_else(|e| panic!("{:?}", e))
+}
+
+fn angle(source: &str) -> Value {
+    eval(source, AngleRange::SignedDegrees)
+}
+
+#[test]
+fn normalize_angle_wraps_at_the_boundaries() {
+    assert_eq!(angle("normalize_angle(180)"), Value::Number(-180.0));
+    assert_eq!(angle("normalize_angle(-180)"), Value::Number(-180.0));
+    assert_eq!(angle("normalize_angle(190)"), Value::Number(-170.0));
+    assert_eq!(angle("normalize_angle(720)"), Value::Number(0.0));
+    assert_eq!(eval("normalize_angle(360)", AngleRange::UnsignedDegrees), Value::Number(0.0));
+    assert_eq!(eval("normalize_angle(359)", AngleRange::UnsignedDegrees), Value::Number(359.0));
+}
+
+#[test]
+fn normalize_angle_handles_negative_angles() {
+    assert_eq!(angle("normalize_angle(-190)"), Value::Number(170.0));
+    assert_eq!(angle("normalize_angle(-540)"), Value::Number(-180.0));
+    assert_eq!(eval("normalize_angle(-90)", AngleRange::UnsignedDegrees), Value::Number(270.0));
+    // tiny negative angles mustn't round up to the top of the range
+    assert_eq!(AngleRange::UnsignedDegrees.normalize(-1e-20), 0.0);
+}
+
+#[test]
+fn normalize_angle_works_in_radians() {
+    use std::f64::consts::PI;
+    assert_eq!(AngleRange::SignedRadians.normalize(PI), -PI);
+    assert_eq!(AngleRange::UnsignedRadians.normalize(-PI / 2.0), 1.5 * PI);
+}
+
+#[test]
+fn commas_only_separate_arguments_inside_brackets() {
+    assert_eq!(angle("normalize_angle(normalize_angle(370))"), Value::Number(10.0));
+    assert_eq!(angle("[1, 2][1]"), Value::Number(2.0));
+
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("out x,y", Record(log.clone())).unwrap();
+    let program = compiler.compile("out x,y 1;").unwrap();
+    AiInterpreter::from_program(program).interpret().unwrap();
+    assert_eq!(entries(&log), ["1"]);
+}
+
+fn list(values: &[f64]) -> Value {
+    Value::List(values.iter().map(|n| Value::Number(*n)).collect())
+}
+
+#[test]
+fn sort_orders_numbers_ascending() {
+    assert_eq!(angle("sort([3, -1, 2.5, 0])"), list(&[-1.0, 0.0, 2.5, 3.0]));
+    assert_eq!(angle("[2, 1].sort()"), list(&[1.0, 2.0]));
+    assert_eq!(angle("sort([])"), list(&[]));
+}
+
+#[test]
+fn sort_orders_strings_lexicographically() {
+    let words = |w: &[&str]| Value::List(w.iter().map(|s| Value::String(s.to_string())).collect());
+    assert_eq!(angle("sort(['pear', 'apple', 'Zebra', 'apricot'])"), words(&["Zebra", "apple", "apricot", "pear"]));
+}
+
+#[test]
+fn sort_returns_a_new_list() {
+    assert_eq!(run("$a = [2, 1];\n$b = sort($a);\nprint $a $b;"), ["[2, 1] [1, 2]"]);
+}
+
+#[test]
+fn lists_of_mixed_types_cannot_be_sorted() {
+    let errors = AiCompiler::new().eval("sort([1, 'a'])").unwrap_err();
+    assert!(errors[0].to_string().contains("all numbers or all strings"), "{:?}", errors);
+    assert!(AiCompiler::new().eval("sort([true, false])").is_err());
+    assert!(AiCompiler::new().eval("sort(1)").is_err());
+}
+
+#[test]
+fn methods_chain_left_to_right() {
+    assert_eq!(run("$x = -2.6;\nprint $x.abs().round();\nprint -$x.abs();"), ["3", "-2.6"]);
+    assert_eq!(angle("(-190).normalize_angle().abs()"), Value::Number(170.0));
+    assert_eq!(run("$l = [3, 1, 2];\nprint $l.sort()[0];"), ["1"]);
+}
+
+#[test]
+fn unknown_methods_are_compile_errors() {
+    let errors = compile_errors("$x = 2;\nprint $x.abs().floor();");
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("Unknown method 'floor'"), "{:?}", errors);
+}
+
+#[test]
+fn strings_index_and_iterate_by_character() {
+    assert_eq!(run("$s = 'héllo';\nprint $s[1];\nprint $s[2];\nprint $s.len();"), ["\"é\"", "\"l\"", "5"]);
+    assert_eq!(run("for $c in 'aé😀' { print $c; }"), ["\"a\"", "\"é\"", "\"😀\""]);
+    assert_eq!(angle("'héllo'[4]"), Value::String("o".into()));
+
+    let log = log();
+    let program = compiler(&log).compile("$s = 'hé';\nprint $s[2];").unwrap();
+    let error = AiInterpreter::from_program(program).interpret().unwrap_err();
+    assert!(matches!(error, Error::IndexOutOfBounds(_)), "{:?}", error);
+}
+
+#[test]
+fn adjacent_strings_are_joined_outside_call_arguments() {
+    assert_eq!(angle("'Obstacle ahead, '\n    'turning around'"), Value::String("Obstacle ahead, turning around".into()));
+    assert_eq!(angle(r#""it's" ' "here"'"#), Value::String(r#"it's "here""#.into()));
+    assert_eq!(run("$m = 'a' 'b';\nprint $m;\nprint 'a' 'b';\nprint ('a' 'b');"), ["\"ab\"", "\"a\" \"b\"", "\"ab\""]);
+}
+
+#[test]
+fn numbers_format_in_each_mode() {
+    let cases = [
+        (NumberFormat::Fixed, ["100000000000000000000", "0.000000001", "12.5"]),
+        (NumberFormat::Scientific, ["1e20", "1e-9", "1.25e1"]),
+        (NumberFormat::Auto, ["1e20", "1e-9", "12.5"]),
+    ];
+    for (format, expected) in cases {
+        let formatted = [1e20, 1e-9, 12.5].map(|n| format.format(n));
+        assert_eq!(formatted, expected, "{}", format);
+        let source = format!("format(1e20, '{}')", format);
+        assert_eq!(angle(&source), Value::String(expected[0].into()));
+    }
+
+    assert_eq!(run("$n = 1e20;\nprint format($n, 'auto');\nprint format($n);"), ["\"1e20\"", "\"100000000000000000000\""]);
+
+    // the compiler's format is the default
+    let mut compiler = AiCompiler::new();
+    compiler.set_number_format(NumberFormat::Scientific).unwrap();
+    assert_eq!(compiler.eval("format(0.000000001)").unwrap(), Value::String("1e-9".into()));
+    assert_eq!(angle("format(0.000000001)"), Value::String("0.000000001".into()));
+}
+
+fn with_dist(precedence: Precedence, source: &str) -> Vec<String> {
+    let log = log();
+    let mut compiler = compiler(&log);
+    let distance = Returning(Signature::new(vec![Param::Number, Param::Number]), 1, |args: &[Value]| match args {
+        [Value::Number(a), Value::Number(b)] => Value::Number((a - b).abs()),
+        _ => Value::Nil,
+    });
+    compiler.register_callable("distance", distance).unwrap();
+    compiler.register_operator("dist", precedence, "distance").unwrap();
+    let program = compiler.compile(source).unwrap_or_else(|e| panic!("{:?}", e));
+    AiInterpreter::from_program(program).interpret().unwrap();
+    entries(&log)
+}
+
+#[test]
+fn custom_operators_bind_at_their_precedence() {
+    let source = "$a = 3;\nprint 1 + $a dist 7 * 2;\nprint 1 dist 5 * 2;\nprint 10 dist 4 dist 1;";
+    assert_eq!(with_dist(Precedence::Factor, source), ["9", "8", "5"]);
+    // 1 + 3 is 4, and 7 * 2 is 14
+    assert_eq!(with_dist(Precedence::Term, source), ["10", "9", "5"]);
+
+    let mut compiler = AiCompiler::new();
+    for bad in ["+", "while", "two words", ""] {
+        assert!(compiler.register_operator(bad, Precedence::Term, "distance").is_err(), "{:?} registered", bad);
+    }
+}
+
+#[test]
+fn strings_and_numbers_order_among_themselves() {
+    assert_eq!(run("print (1 < 2) (2 <= 2) (3 > 4) (-1 >= 0);"), ["true true false false"]);
+    assert_eq!(run("print ('apple' < 'banana') ('b' <= 'a') ('Z' < 'a') ('abc' >= 'abc');"), ["true false true true"]);
+    let log = log();
+    let program = compiler(&log).compile("$x = 'one';\n$two = 2;\nprint $x < $two;").unwrap();
+    let error = AiInterpreter::from_program(program).interpret().unwrap_err();
+    assert!(matches!(error, Error::Type(_)), "{:?}", error);
+    assert!(error.to_string().contains("Can't order a string and a number"), "{}", error);
+}
+
+#[test]
+fn hex_and_scientific_literals_are_numbers() {
+    assert_eq!(run("print 0xFF 0X10 1.5e3 2E-2 1e+2 10;"), ["255 16 1500 0.02 100 10"]);
+    assert!(!compile_errors("print 1e;").is_empty());
+    assert!(!compile_errors("print 0x;").is_empty());
+}
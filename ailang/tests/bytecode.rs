@@ -0,0 +1,59 @@
+mod common;
+
+use ailang::*;
+use common::*;
+
+const SCRIPT: &str = "
+    #[timeout(2s)]
+    group go $d { print $d 'far'; }
+    $x = [1, 'two', true, nil];
+    for $v in $x { print $v; }
+    repeat 2 { go 1.5; }
+    print 'far';
+";
+
+#[test]
+fn programs_round_trip_through_bytes() {
+    let log = log();
+    let program = compiler(&log).compile(SCRIPT).unwrap();
+    let bytes = program.to_bytes();
+    let read = Program::from_bytes(&bytes).unwrap();
+    assert!(read.code == program.code);
+    assert_eq!(read.constants, program.constants);
+    assert_eq!(read.timeouts, program.timeouts);
+    assert_eq!(read.to_bytes(), bytes);
+
+    let mut interpreter = AiInterpreter::from_program(read);
+    interpreter.register_callable("print", Box::new(Record(log.clone()))).unwrap();
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log).len(), 7);
+}
+
+#[test]
+fn other_bytecode_versions_are_rejected() {
+    let mut bytes = compiler(&log()).compile(SCRIPT).unwrap().to_bytes();
+    bytes[4..6].copy_from_slice(&(BYTECODE_VERSION + 1).to_le_bytes());
+    assert!(matches!(Program::from_bytes(&bytes), Err(Error::UnsupportedBytecodeVersion(v)) if v == BYTECODE_VERSION + 1));
+}
+
+#[test]
+fn damaged_bytecode_is_rejected() {
+    let bytes = compiler(&log()).compile(SCRIPT).unwrap().to_bytes();
+    assert!(matches!(Program::from_bytes(b"nope"), Err(Error::InvalidBytecode(_))));
+    assert!(matches!(Program::from_bytes(&bytes[..bytes.len() - 1]), Err(Error::InvalidBytecode(_))));
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(matches!(Program::from_bytes(&trailing), Err(Error::InvalidBytecode(_))));
+}
+
+#[test]
+fn source_lines_round_trip_through_bytes() {
+    let program = compiler(&log()).compile("print 1;\n\nprint [2][5];").unwrap();
+    let read = Program::from_bytes(&program.to_bytes()).unwrap();
+    assert_eq!(read.lines, program.lines);
+
+    let mut interpreter = AiInterpreter::from_program(read);
+    interpreter.register_callable("print", Box::new(Record(log()))).unwrap();
+    let Err(Error::IndexOutOfBounds(addr)) = interpreter.interpret() else {panic!("expected an index error")};
+    assert_eq!(interpreter.source_line(addr), Some(3));
+}
@@ -0,0 +1,190 @@
+// Mock callables and properties shared by the integration tests. Not every test file uses all of
+// them.
+#![allow(dead_code)]
+
+use ailang::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub type Log = Arc<Mutex<Vec<String>>>;
+
+pub fn log() -> Log {
+    Arc::new(Mutex::new(Vec::new()))
+}
+
+pub fn entries(log: &Log) -> Vec<String> {
+    log.lock().unwrap().clone()
+}
+
+/// Records its arguments, space separated, and finishes straight away.
+pub struct Record(pub Log);
+
+struct Recording(Log, Vec<Value>);
+
+impl CallableGenerator for Record {
+    fn generate(&mut self, args: Vec<Value>) -> Result<Box<dyn Callable>> {
+        Ok(Box::new(Recording(self.0.clone(), args)))
+    }
+}
+
+impl Callable for Recording {
+    fn call(&mut self) -> Result<bool> {
+        self.0.lock().unwrap().push(self.1.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(" "));
+        Ok(true)
+    }
+}
+
+/// Like `Record`, but only accepts the arguments in its signature.
+pub struct Typed(pub Log, pub Signature);
+
+impl CallableGenerator for Typed {
+    fn generate(&mut self, args: Vec<Value>) -> Result<Box<dyn Callable>> {
+        Ok(Box::new(Recording(self.0.clone(), args)))
+    }
+    fn signature(&self) -> Option<Signature> {
+        Some(self.1.clone())
+    }
+}
+
+/// Like `Record`, but estimates that it takes as many seconds as its first argument says.
+pub struct Sleep(pub Log);
+
+impl CallableGenerator for Sleep {
+    fn generate(&mut self, args: Vec<Value>) -> Result<Box<dyn Callable>> {
+        Ok(Box::new(Recording(self.0.clone(), args)))
+    }
+    fn estimated_duration(&self, args: &[Option<Value>]) -> Option<Duration> {
+        match args.first() {
+            Some(Some(Value::Number(n))) => Some(Duration::from_secs_f64(*n)),
+            _ => None,
+        }
+    }
+}
+
+/// Returns `f(args)` once it's been polled `polls` times.
+pub struct Returning<F: Fn(&[Value]) -> Value + Send + Sync>(pub Signature, pub u32, pub F);
+
+struct Pending(Value, u32);
+
+impl<F: Fn(&[Value]) -> Value + Send + Sync> CallableGenerator for Returning<F> {
+    fn generate(&mut self, args: Vec<Value>) -> Result<Box<dyn Callable>> {
+        Ok(Box::new(Pending((self.2)(&args), self.1)))
+    }
+    fn signature(&self) -> Option<Signature> {
+        Some(self.0.clone().returning())
+    }
+}
+
+impl Callable for Pending {
+    fn call(&mut self) -> Result<bool> {
+        self.1 = self.1.saturating_sub(1);
+        Ok(self.1 == 0)
+    }
+    fn result(&mut self) -> Value {
+        self.0.clone()
+    }
+}
+
+/// Never finishes on its own, and logs "terminated" when it's stopped.
+pub struct Forever(pub Log);
+
+struct Spinning(Log);
+
+impl CallableGenerator for Forever {
+    fn generate(&mut self, _args: Vec<Value>) -> Result<Box<dyn Callable>> {
+        Ok(Box::new(Spinning(self.0.clone())))
+    }
+}
+
+impl Callable for Spinning {
+    fn call(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+    fn terminate(&mut self) -> Result<()> {
+        self.0.lock().unwrap().push("terminated".into());
+        Ok(())
+    }
+}
+
+/// Always fails when it's called.
+pub struct Failing;
+
+struct Failure;
+
+impl CallableGenerator for Failing {
+    fn generate(&mut self, _args: Vec<Value>) -> Result<Box<dyn Callable>> {
+        Ok(Box::new(Failure))
+    }
+}
+
+impl Callable for Failure {
+    fn call(&mut self) -> Result<bool> {
+        Err(Error::Type("failed on purpose".into()))
+    }
+}
+
+/// A read-only property with a fixed value.
+pub struct Fixed(pub Value);
+
+impl Prop for Fixed {
+    fn get(&self) -> Result<Value> {
+        Ok(self.0.clone())
+    }
+}
+
+/// A settable property that logs every write.
+pub struct Shared(pub Arc<Mutex<Value>>, pub Log);
+
+impl Prop for Shared {
+    fn get(&self) -> Result<Value> {
+        Ok(self.0.lock().unwrap().clone())
+    }
+    fn set(&mut self, val: Value) -> Result<()> {
+        self.1.lock().unwrap().push(format!("set {}", val));
+        *self.0.lock().unwrap() = val;
+        Ok(())
+    }
+    fn settable(&self) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// A clock the test moves by hand.
+#[derive(Clone, Default)]
+pub struct FakeClock(pub Arc<Mutex<Duration>>);
+
+impl FakeClock {
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Duration {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// A compiler with `print` registered, recording into `log`.
+pub fn compiler(log: &Log) -> AiCompiler {
+    let mut compiler = AiCompiler::new();
+    compiler.register_callable("print", Record(log.clone())).unwrap();
+    compiler
+}
+
+/// Compiles and runs `source` with `print` registered, returning what it printed.
+pub fn run(source: &str) -> Vec<String> {
+    let log = log();
+    let program = compiler(&log).compile(source).unwrap_or_else(|e| panic!("{:?}", e));
+    AiInterpreter::from_program(program).interpret().unwrap();
+    entries(&log)
+}
+
+/// The errors compiling `source` gives, as they're displayed.
+pub fn compile_errors(source: &str) -> Vec<String> {
+    let log = log();
+    match compiler(&log).compile(source) {
+        Ok(_) => Vec::new(),
+        Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+    }
+}
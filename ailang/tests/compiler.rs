@@ -0,0 +1,616 @@
+mod common;
+
+use ailang::*;
+use common::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn warnings(source: &str) -> Vec<String> {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.compile(source).unwrap_or_else(|e| panic!("{:?}", e));
+    compiler.warnings().iter().map(|w| w.to_string()).collect()
+}
+
+#[test]
+fn constant_conditions_are_warned_about_on_their_line() {
+    assert_eq!(warnings("print 1;\nif true { print 2; }"), ["[line 2] Condition is always true, so the branch is always taken"]);
+    assert_eq!(warnings("if 1 > 2 { print 2; }"), ["[line 1] Condition is always false, so the branch is never taken"]);
+    assert_eq!(warnings("unless false { print 2; }"), ["[line 1] Condition is always true, so the branch is always taken"]);
+    assert_eq!(warnings("$x = 1;\n\nwhile false { print 2; }"), ["[line 3] Loop condition is always false, so the loop body is dead code"]);
+    assert_eq!(warnings("until true { print 2; }"), ["[line 1] Loop condition is always false, so the loop body is dead code"]);
+    assert_eq!(warnings("if 'a' == 'a' {\n    print 1;\n} else {\n    if nil { print 2; }\n}"), [
+        "[line 1] Condition is always true, so the branch is always taken",
+        "[line 4] Condition is always false, so the branch is never taken",
+    ]);
+}
+
+#[test]
+fn conditions_that_can_change_or_loop_forever_are_not_warned_about() {
+    assert!(warnings("$x = 1; if $x > 2 { print 2; }").is_empty());
+    assert!(warnings("while true { break; }").is_empty());
+}
+
+#[test]
+fn parameters_that_shadow_outer_names_are_warned_about() {
+    assert_eq!(warnings("$speed = 1;\n\ngroup go $speed { print $speed; }\ngo 2;"), [
+        "[line 3] Parameter 'speed' has the same name as a variable outside its group",
+    ]);
+    assert!(warnings("$speed = 1;\ngroup go $distance { print $distance; }\ngo 2;").is_empty());
+
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_property("heading", Fixed(Value::Number(0.0))).unwrap();
+    compiler.compile("use $heading;\ngroup turn $heading { print $heading; }").unwrap();
+    let warnings: Vec<_> = compiler.warnings().iter().map(|w| w.to_string()).collect();
+    assert_eq!(warnings, ["[line 2] Parameter 'heading' has the same name as a property, which is what it refers to"]);
+}
+
+#[test]
+fn variables_read_before_they_might_be_assigned_are_warned_about() {
+    assert_eq!(warnings("$go = 1;\nif $go > 0 {\n    $x = 1;\n}\nprint $x;"), [
+        "[line 5] Variable 'x' might be read before it's assigned",
+    ]);
+    assert_eq!(warnings("group g {\n    print 1;\n    if true { $y = 2; } else { print $y; }\n}"), [
+        "[line 3] Condition is always true, so the branch is always taken",
+        "[line 3] Variable 'y' might be read before it's assigned",
+    ]);
+    assert!(warnings("$go = 1;\n$x = 0;\nif $go > 0 { $x = 1; }\nprint $x;").is_empty());
+    assert!(warnings("$go = 1;\nif $go > 0 { $x = 1; } else { $x = 2; }\nprint $x;").is_empty());
+}
+
+fn calls(compiler: &mut AiCompiler, source: &str) -> std::result::Result<(), Vec<String>> {
+    compiler.compile(source).map(|_| ()).map_err(|errors| errors.iter().map(|e| e.to_string()).collect())
+}
+
+#[test]
+fn multi_word_callables_take_the_longest_registered_name() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("go", Record(log.clone())).unwrap();
+    compiler.register_callable("go left", Typed(log.clone(), Signature::new(vec![Param::Number]))).unwrap();
+    let program = compiler.compile("go left 10; go right 5;").unwrap();
+    AiInterpreter::from_program(program).interpret().unwrap();
+    assert_eq!(entries(&log), ["10", "5"]);
+}
+
+#[test]
+fn multi_word_callables_are_ambiguous_only_when_the_shorter_signature_fits() {
+    let log = log();
+    let mut overlapping = compiler(&log);
+    overlapping.register_callable("go", Typed(log.clone(), Signature::new(vec![Param::Word("left".into()), Param::Number]))).unwrap();
+    overlapping.register_callable("go left", Record(log.clone())).unwrap();
+    assert!(calls(&mut overlapping, "go left 10;").unwrap_err()[0].contains("ambiguous between 'go' and 'go left'"));
+
+    let mut distinct = compiler(&log);
+    distinct.register_callable("go", Typed(log.clone(), Signature::new(vec![Param::Word("right".into()), Param::Number]))).unwrap();
+    distinct.register_callable("go left", Record(log.clone())).unwrap();
+    assert!(calls(&mut distinct, "go left 10; go right 10;").is_ok());
+}
+
+#[test]
+fn linked_programs_keep_their_source_lines() {
+    let log = log();
+    let first = compiler(&log).compile("print 1;\ngroup g {\n    print [1][5];\n}").unwrap();
+    let second = compiler(&log).compile("print 2;\n\nprint [2][5];").unwrap();
+    let linked = Program::link(vec![first, second]).unwrap();
+
+    let mut interpreter = AiInterpreter::from_program(linked);
+    let Err(Error::IndexOutOfBounds(addr)) = interpreter.interpret() else {panic!("expected an index error")};
+    assert_eq!(interpreter.source_line(addr), Some(3));
+    assert_eq!(entries(&log), ["1", "2"]);
+
+    let first = compiler(&log).compile("group g {\n    print [1][5];\n}").unwrap();
+    // calling another program's group needs a stand-in to compile
+    let mut calling = compiler(&log);
+    calling.register_callable("g", Record(log.clone())).unwrap();
+    let second = calling.compile("g;").unwrap();
+    let mut interpreter = AiInterpreter::from_program(Program::link(vec![first, second]).unwrap());
+    let Err(Error::IndexOutOfBounds(addr)) = interpreter.interpret() else {panic!("expected an index error")};
+    assert_eq!(interpreter.source_line(addr), Some(2));
+}
+
+fn typed_compiler(log: &Log) -> AiCompiler {
+    let mut compiler = compiler(log);
+    let signature = Signature::new(vec![Param::Number, Param::Optional(Box::new(Param::String))]);
+    compiler.register_callable("forward", Typed(log.clone(), signature)).unwrap();
+    compiler
+}
+
+#[test]
+fn signatures_catch_arity_errors() {
+    let log = log();
+    let errors = calls(&mut typed_compiler(&log), "forward;").unwrap_err();
+    assert!(errors[0].contains("Call to 'forward' is missing an argument"), "{:?}", errors);
+    let errors = calls(&mut typed_compiler(&log), "forward 1 'a' 2;").unwrap_err();
+    assert!(errors[0].contains("Call to 'forward' has too many arguments"), "{:?}", errors);
+    assert!(calls(&mut typed_compiler(&log), "forward 1; forward 1 'fast';").is_ok());
+}
+
+#[test]
+fn signatures_catch_type_errors() {
+    let log = log();
+    let errors = calls(&mut typed_compiler(&log), "forward 'far';").unwrap_err();
+    assert!(errors[0].contains("Argument at position 0 of 'forward' should be"), "{:?}", errors);
+    let errors = calls(&mut typed_compiler(&log), "forward fast;").unwrap_err();
+    assert!(errors[0].contains("should be"), "{:?}", errors);
+    // values that aren't known until runtime get the benefit of the doubt
+    assert!(calls(&mut typed_compiler(&log), "$d = 'far'; forward $d;").is_ok());
+}
+
+#[test]
+fn groups_take_computed_arguments() {
+    let source = "
+        group go $d { print $d; }
+        group both $a $b { print $a $b; }
+        $x = 2;
+        go $x * 3 + 1;
+        go $x-1;
+        both ($x) ($x + 1);
+        both $x -1;
+    ";
+    assert_eq!(run(source), ["7", "1", "2 3", "2 -1"]);
+}
+
+#[test]
+fn duplicate_string_literals_share_a_constant() {
+    let log = log();
+    let program = compiler(&log).compile("print 'hi'; print 'there'; print 'hi'; $x = 'hi';").unwrap();
+    assert_eq!(program.constants, [Value::String("hi".into()), Value::String("there".into())]);
+    let slots: Vec<_> = program.code.iter().filter_map(|op| match op {
+        Op::Const(c) => Some(*c),
+        _ => None,
+    }).collect();
+    assert_eq!(slots, [0, 1, 0, 0]);
+    AiInterpreter::from_program(program).interpret().unwrap();
+    assert_eq!(entries(&log), ["\"hi\"", "\"there\"", "\"hi\""]);
+}
+
+#[test]
+fn inout_parameters_write_back_to_the_caller() {
+    let source = "
+        group bump inout $n { $n = $n + 1; }
+        $count = 0;
+        bump $count;
+        bump $count;
+        repeat 3 { bump $count; }
+        print $count;
+    ";
+    assert_eq!(run(source), ["5"]);
+    assert!(!compile_errors("group bump inout $n { $n = $n + 1; }\nbump 1;").is_empty());
+}
+
+#[test]
+fn plain_parameters_do_not_write_back() {
+    assert_eq!(run("group bump $n { $n = $n + 1; }\n$count = 0;\nbump $count;\nprint $count;"), ["0"]);
+}
+
+#[test]
+fn group_bodies_read_their_parameters_alongside_locals() {
+    let source = "
+        group go_right $unit {
+            $turned = 90;
+            $distance = $unit * 2;
+            print $unit $turned $distance;
+        }
+        $unit = 5;
+        $other = 1;
+        go_right $unit;
+        go_right 3;
+        print $unit $other;
+    ";
+    assert_eq!(run(source), ["5 90 10", "3 90 6", "5 1"]);
+
+    let nested = "
+        group inner $x { $y = $x * 10; print $x $y; }
+        group outer $a { $b = $a + 1; inner $b; print $a $b; }
+        outer 1;
+    ";
+    assert_eq!(run(nested), ["2 20", "1 2"]);
+}
+
+#[test]
+fn literals_lists_numbers_and_strings() {
+    let log = log();
+    let program = compiler(&log).compile("print 10 'fast';\n$x = 2.5;\nprint 'fast' true nil $x;").unwrap();
+    let literals: Vec<_> = program.literals().into_iter().cloned().collect();
+    assert_eq!(literals, [
+        Value::Number(10.0), Value::String("fast".into()), Value::Number(2.5), Value::String("fast".into()),
+    ]);
+}
+
+// A property that logs every read
+struct Watched(Log, bool);
+
+impl Prop for Watched {
+    fn get(&self) -> Result<Value> {
+        self.0.lock().unwrap().push("read".into());
+        Ok(Value::Number(2.0))
+    }
+    fn pure(&self) -> bool {
+        self.1
+    }
+}
+
+fn reads(pure: bool, source: &str) -> usize {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_property("angle", Watched(log.clone(), pure)).unwrap();
+    AiInterpreter::from_program(compiler.compile(source).unwrap()).interpret().unwrap();
+    entries(&log).iter().filter(|e| *e == "read").count()
+}
+
+#[test]
+fn pure_properties_are_read_once_per_statement() {
+    assert_eq!(reads(true, "use $angle; $x = $angle * $angle + $angle;"), 1);
+    assert_eq!(reads(true, "use $angle; $x = $angle * $angle; $y = $angle + $angle;"), 2);
+}
+
+#[test]
+fn impure_properties_are_read_every_time() {
+    assert_eq!(reads(false, "use $angle; $x = $angle * $angle + $angle;"), 3);
+}
+
+fn read_only(log: &Log) -> AiCompiler {
+    let mut compiler = compiler(log);
+    compiler.register_property("angle", Shared(Arc::new(Mutex::new(Value::Number(0.0))), log.clone())).unwrap();
+    let mut allowed = OpCategory::all();
+    allowed.remove(&OpCategory::PropertyWrite);
+    compiler.set_allowed_ops(allowed);
+    compiler
+}
+
+#[test]
+fn policies_can_forbid_property_writes() {
+    let log = log();
+    let Err(errors) = read_only(&log).compile("use $angle;\nprint 1;\n$angle = 5;") else {panic!("compiled")};
+    assert!(matches!(errors[..], [Error::ForbiddenOperation(_, OpCategory::PropertyWrite)]), "{:?}", errors);
+    let Err(errors) = read_only(&log).compile("use $angle;\ngroup g { $angle += 1; }") else {panic!("compiled")};
+    assert!(matches!(errors[..], [Error::ForbiddenOperation(_, OpCategory::PropertyWrite)]), "{:?}", errors);
+
+    let program = read_only(&log).compile("use $angle;\n$x = $angle + 1;\nprint $x;").unwrap();
+    assert!(program.check_ops(&[OpCategory::Stack, OpCategory::Arithmetic].into()).is_err());
+    AiInterpreter::from_program(program).interpret().unwrap();
+    assert_eq!(entries(&log), ["1"]);
+}
+
+fn with_angle(source: &str) -> std::result::Result<Program, Vec<Error>> {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_property("angle", Shared(Arc::new(Mutex::new(Value::Number(0.0))), log.clone())).unwrap();
+    compiler.compile(source)
+}
+
+#[test]
+fn declared_properties_can_be_used() {
+    assert!(with_angle("use $angle;\n$x = $angle + 90;\n$angle = $x;").is_ok());
+    assert!(with_angle("use $angle;\ngroup turn { $angle += 90; }\nturn;").is_ok());
+    // names that aren't properties are just variables
+    assert!(with_angle("$heading = 1;\nprint $heading;").is_ok());
+    // a `use` covers the whole script, unless it's compiled a statement at a time
+    assert!(with_angle("$x = $angle;\nuse $angle;").is_ok());
+    let log = log();
+    let mut streaming = compiler(&log);
+    streaming.register_property("angle", Fixed(Value::Number(0.0))).unwrap();
+    assert!(streaming.compile_streaming("$x = $angle;\nuse $angle;").is_err());
+}
+
+#[test]
+fn properties_have_to_be_declared_before_use() {
+    for source in ["$x = $angle + 90;", "$angle = 1;", "print $angle;", "group g { $angle += 1; }"] {
+        let Err(errors) = with_angle(source) else {panic!("{:?} compiled", source)};
+        assert!(errors.iter().any(|e| matches!(e, Error::UndeclaredProperty(name) if name == "angle")), "{:?}", errors);
+    }
+}
+
+#[test]
+fn linked_programs_can_call_each_others_groups() {
+    let log = log();
+    let first = compiler(&log).compile("$a = 1;\ngroup shout $n { print $n; }\nprint $a;").unwrap();
+    let mut calling = compiler(&log);
+    calling.register_callable("shout", Record(log.clone())).unwrap();
+    let second = calling.compile("$b = 2;\nshout $b * 10;\nprint $b;").unwrap();
+
+    let mut interpreter = AiInterpreter::from_program(Program::link(vec![first, second]).unwrap());
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["1", "20", "2"]);
+    assert_eq!(interpreter.global("a"), Some(Value::Number(1.0)));
+    assert_eq!(interpreter.global("b"), Some(Value::Number(2.0)));
+}
+
+#[test]
+fn linked_programs_cannot_share_group_names() {
+    let log = log();
+    let first = compiler(&log).compile("group g { print 1; }").unwrap();
+    let second = compiler(&log).compile("group g { print 2; }").unwrap();
+    let Err(errors) = Program::link(vec![first, second]) else {panic!("linked")};
+    assert!(matches!(&errors[..], [Error::DuplicateGroup(name)] if name == "g"));
+}
+
+#[test]
+fn doc_comments_describe_the_group_below_them() {
+    let source = "
+        /// Drives to the right
+        /// by N units
+        group right $n { print $n; }
+
+        group left $n { print $n; }
+
+        /// Not attached to anything
+        print 1;
+
+        /// Stops everything
+        #[best_effort]
+        group stop { print 0; }
+
+        #[doc(\"Waits a bit\")]
+        group wait { yield; }
+    ";
+    let program = compiler(&log()).compile(source).unwrap();
+    let description = |name: &str| program.group_info[name].description.clone();
+    assert_eq!(description("right").as_deref(), Some("Drives to the right\nby N units"));
+    assert_eq!(description("left"), None);
+    assert_eq!(description("stop").as_deref(), Some("Stops everything"));
+    assert_eq!(description("wait").as_deref(), Some("Waits a bit"));
+    assert_eq!(program.group_info["right"].params, [Arg::Value]);
+}
+
+#[test]
+fn diagnostics_count_errors_and_warnings() {
+    let log = log();
+    let mut failing = compiler(&log);
+    let source = "if true { print 1; }\njump;\nwhile false { print 2; }\nfly 3;";
+    assert!(failing.compile(source).is_err());
+    let diagnostics = failing.diagnostics();
+    assert_eq!((diagnostics.error_count(), diagnostics.warning_count()), (2, 2));
+    assert!(diagnostics.any_at_least(Severity::Error));
+    let lines: Vec<_> = diagnostics.iter().map(|d| (d.line(), d.severity())).collect();
+    assert_eq!(lines[0], (Some(1), Severity::Warning));
+
+    let mut passing = compiler(&log);
+    passing.compile("if true { print 1; }").unwrap();
+    let diagnostics = passing.diagnostics();
+    assert_eq!((diagnostics.error_count(), diagnostics.warning_count()), (0, 1));
+    assert!(!diagnostics.any_at_least(Severity::Error));
+    assert!(diagnostics.any_at_least(Severity::Warning));
+}
+
+#[test]
+fn compile_errors_are_on_their_statements_line() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    let source = "$a = $x;\nif true { print 1; }\n\n$b = 'x'.wobble();\nwhile false {\n    print $y;\n}";
+    assert!(compiler.compile(source).is_err());
+    let diagnostics = compiler.diagnostics();
+    let lines: Vec<_> = diagnostics.iter().map(|d| (d.line(), d.severity())).collect();
+    assert_eq!(lines, [
+        (Some(1), Severity::Error),
+        (Some(2), Severity::Warning),
+        (Some(4), Severity::Error),
+        (Some(5), Severity::Warning),
+        (Some(6), Severity::Error),
+    ]);
+}
+
+#[test]
+fn identical_groups_are_merged() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.set_merge_identical_groups(true).unwrap();
+    let source = "group b $x { print $x + 1; }\ngroup a $y { print $y + 1; }\ngroup c $z { print $z + 2; }\nb 1;\na 2;\nc 3;";
+    let program = compiler.compile(source).unwrap();
+    let mut names = program.group_names();
+    names.sort();
+    assert_eq!(names, ["a", "c"]);
+    let labels = program.code.iter().filter(|op| matches!(op, Op::Label(_))).count();
+    assert_eq!(labels, 2);
+    let called: Vec<_> = program.code.iter().filter_map(|op| match op {
+        Op::Call(name, _) if name != "print" => Some(name.as_str()),
+        _ => None,
+    }).collect();
+    assert_eq!(called, ["a", "a", "c"]);
+
+    AiInterpreter::from_program(program).interpret().unwrap();
+    assert_eq!(entries(&log), ["2", "3", "5"]);
+}
+
+fn cost(source: &str) -> CostEstimate {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("sleep", Sleep(log.clone())).unwrap();
+    compiler.compile(source).unwrap().estimated_cost()
+}
+
+#[test]
+fn estimated_cost_adds_up_fixed_waits() {
+    let estimate = cost("$x = 1;\nsleep 2;\nrepeat 3 { sleep 1; }\nif $x > 0 { sleep 4; } else { sleep 1; }\nprint 1;");
+    assert_eq!(estimate.known, Duration::from_secs(9));
+    assert_eq!(estimate.unknown_calls, 1);
+    assert!(!estimate.unbounded);
+}
+
+#[test]
+fn estimated_cost_flags_unbounded_loops() {
+    let estimate = cost("$i = 0;\nwhile $i < 10 { sleep 1; $i = $i + 1; }\nsleep 2;");
+    assert_eq!(estimate.known, Duration::from_secs(3));
+    assert!(estimate.unbounded);
+    assert!(!cost("repeat 2 { sleep 1; }").unbounded);
+}
+
+// The groups and literals compiled with `flags` defined, and what the program prints
+fn with_flags(flags: &[&str]) -> (Vec<String>, Vec<Value>, Vec<String>) {
+    let log = log();
+    let mut compiler = compiler(&log);
+    for flag in flags {
+        compiler.define_flag(flag).unwrap();
+    }
+    let source = "#iffy comment\n#if PRACTICE {\n    group warmup { print 1; }\n    warmup;\n} #else {\n    print 2;\n} #endif\n#if LOUD { print 3; } #endif";
+    let program = compiler.compile(source).unwrap();
+    let groups = program.group_names().into_iter().map(String::from).collect();
+    let literals = program.literals().into_iter().cloned().collect();
+    AiInterpreter::from_program(program).interpret().unwrap();
+    (groups, literals, entries(&log))
+}
+
+#[test]
+fn flags_pick_branches_at_compile_time() {
+    let (groups, literals, printed) = with_flags(&[]);
+    assert_eq!(printed, ["2"]);
+    assert!(groups.is_empty());
+    assert!(literals.contains(&Value::Number(2.0)) && !literals.contains(&Value::Number(1.0)));
+
+    let (groups, literals, printed) = with_flags(&["PRACTICE", "LOUD"]);
+    assert_eq!(printed, ["1", "3"]);
+    assert_eq!(groups, ["warmup"]);
+    assert!(literals.contains(&Value::Number(1.0)) && !literals.contains(&Value::Number(2.0)));
+}
+
+fn program(source: &str) -> Program {
+    compiler(&log()).compile(source).unwrap_or_else(|e| panic!("{:?}", e))
+}
+
+#[test]
+fn programs_differing_only_in_group_names_are_structurally_equal() {
+    let a = program("group go $n { print $n; }\ngroup stop { print 0; }\ngo 1;\nstop;");
+    let b = program("group walk $m { print $m; }\ngroup halt_now { print 0; }\nwalk 1;\nhalt_now;");
+    // laid out in a different order
+    let c = program("group halt_now { print 0; }\ngroup walk $m { print $m; }\nwalk 1;\nhalt_now;");
+    assert!(a.structurally_eq(&b));
+    assert!(b.structurally_eq(&a));
+    assert!(a.structurally_eq(&c));
+
+    assert!(!a.structurally_eq(&program("group go $n { print $n; }\ngroup stop { print 0; }\ngo 2;\nstop;")));
+    assert!(!a.structurally_eq(&program("group go $n { print $n; }\ngroup stop { print 0; }\nstop;\ngo 1;")));
+    // the renaming has to be consistent
+    assert!(!a.structurally_eq(&program("group go $n { print $n; }\ngroup stop { print 0; }\ngo 1;\ngo 1;")));
+}
+
+#[test]
+fn streaming_compiles_a_large_script_like_batch() {
+    let mut source = String::from("use $heading;\n");
+    for i in 0..2000 {
+        source += &format!("group g{} $n {{ if $n > {} {{ print $n; }} else {{ print $heading; }} }}\n", i, i % 7);
+        source += &format!("$x{} = {} * 2;\nwhile $x{} > 0 {{ g{} $x{}; $x{} = $x{} - 1; }}\n", i, i % 5, i, i, i, i, i);
+    }
+    let batch = |log: &Log| {
+        let mut compiler = compiler(log);
+        compiler.register_property("heading", Fixed(Value::Number(90.0))).unwrap();
+        compiler
+    };
+    let (log, streamed_log) = (log(), log());
+    let program = batch(&log).compile(&source).unwrap();
+    let streamed = batch(&streamed_log).compile_streaming(&source).unwrap();
+    // groups can be laid out in a different order
+    assert!(program.structurally_eq(&streamed));
+
+    AiInterpreter::from_program(program).interpret().unwrap();
+    AiInterpreter::from_program(streamed).interpret().unwrap();
+    assert_eq!(entries(&log), entries(&streamed_log));
+    assert_eq!(entries(&log).len(), 8000);
+}
+
+fn variadic_compiler(log: &Log) -> AiCompiler {
+    let mut compiler = compiler(log);
+    compiler.register_callable("log", Typed(log.clone(), Signature::variadic(Param::Number))).unwrap();
+    compiler
+}
+
+#[test]
+fn variadic_callables_take_any_number_of_arguments() {
+    let log = log();
+    let program = variadic_compiler(&log).compile("log;\nlog 1;\nlog 1 2 3 (2 + 2);").unwrap();
+    AiInterpreter::from_program(program).interpret().unwrap();
+    assert_eq!(entries(&log), ["", "1", "1 2 3 4"]);
+
+    let errors = calls(&mut variadic_compiler(&log), "log 1 2 'three';").unwrap_err();
+    assert!(errors[0].contains("too many arguments"), "{:?}", errors);
+}
+
+// Whether `source` compiles to a `Select` and whether it has any conditional jumps, and what it
+// prints
+fn selects(pure: bool, source: &str) -> (bool, bool, Vec<String>) {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.set_branchless_selects(true).unwrap();
+    compiler.register_property("angle", Watched(log.clone(), pure)).unwrap();
+    let program = compiler.compile(source).unwrap();
+    let select = program.code.iter().any(|op| matches!(op, Op::Select));
+    let branches = program.code.iter().any(|op| matches!(op,
+        Op::JumpUnless(_) | Op::JumpIf(_) | Op::JumpUnlessEq(_) | Op::JumpUnlessNe(_) |
+        Op::JumpUnlessLt(_) | Op::JumpUnlessLe(_) | Op::JumpUnlessGt(_) | Op::JumpUnlessGe(_)));
+    AiInterpreter::from_program(program).interpret().unwrap();
+    let printed = entries(&log).into_iter().filter(|e| e != "read").collect();
+    (select, branches, printed)
+}
+
+#[test]
+fn pure_ifs_compile_to_selects() {
+    let source = "use $angle;\n$fast = 1 < 2;\nif $fast { $speed = $angle; } else { $speed = 2 * 5; }\nprint $speed;";
+    assert_eq!(selects(true, source), (true, false, vec!["2".to_string()]));
+    // reading an impure property could have side effects, so it's only done if it's picked
+    assert_eq!(selects(false, source), (false, true, vec!["2".to_string()]));
+    let calls = "$fast = 1 < 2;\nif $fast { $speed = 1; print 0; } else { $speed = 2; }\nprint $speed;";
+    assert_eq!(selects(true, calls), (false, true, vec!["0".to_string(), "1".to_string()]));
+}
+
+#[test]
+fn groups_can_be_tested_on_their_own() {
+    let mut compiler = compiler(&log());
+    compiler.register_property("speed", Fixed(Value::Number(1.0))).unwrap();
+    let source = "use $speed;\ngroup drive $d { print $d * $speed; }\ngroup other { print 0; }\ndrive 10;\nother;";
+    let program = compiler.compile(source).unwrap();
+
+    let mock = log();
+    let mut props: HashMap<String, Box<dyn Prop>> = HashMap::new();
+    props.insert("speed".into(), Box::new(Fixed(Value::Number(3.0))));
+    let mut callables: HashMap<String, Box<dyn CallableGenerator>> = HashMap::new();
+    callables.insert("print".into(), Box::new(Record(mock.clone())));
+    program.test_group("drive", vec![Value::Number(2.0)], props, callables).unwrap();
+    // only the group ran, against the mocks
+    assert_eq!(entries(&mock), ["6"]);
+
+    let error = program.test_group("missing", vec![], HashMap::new(), HashMap::new()).unwrap_err();
+    assert!(matches!(error, Error::UnknownCallable(_)), "{:?}", error);
+}
+
+#[test]
+fn dot_graphs_show_loops_branches_and_calls() {
+    let source = "group go $n { print $n; }\n$i = 0;\nwhile $i < 3 { if $i == 1 { go $i; } else { print 0; } $i = $i + 1; }";
+    let dot = program(source).to_dot();
+    assert!(dot.starts_with("digraph program {\n") && dot.ends_with("}\n"), "{}", dot);
+    let lines: Vec<_> = dot.lines().map(str::trim).collect();
+    for expected in [
+        // the group and the main flow head their first blocks
+        r#"b1 [label="group go\n2: load 0\l3: call \"print\" 1\l4: pop\l5: return\l"];"#,
+        r#"b6 [label="main\n6: push nil\l7: push 0\l8: store 0\l"];"#,
+        // the loop's condition leaves it, and the end of its body jumps back
+        r#"b9 -> end [label="taken"];"#,
+        "b9 -> b12;",
+        "b20 -> b9;",
+        // the `if` either falls through or takes the jump to its `else`, and both meet up after
+        r#"b12 -> b18 [label="taken"];"#,
+        "b12 -> b15;",
+        "b15 -> b20;",
+        "b18 -> b20;",
+        r#"b15 -> b1 [style=dashed, label="call"];"#,
+    ] {
+        assert!(lines.contains(&expected), "missing {}\n{}", expected, dot);
+    }
+}
+
+#[test]
+fn set_ops_display_as_set() {
+    assert_eq!(Op::Set("speed".into()).to_string(), "set \"speed\"");
+    assert_eq!(Op::Get("speed".into()).to_string(), "get \"speed\"");
+}
+
+#[test]
+fn ops_parse_back_from_their_text() {
+    for text in ["call \"drive forward\" 2", "set \"speed\"", "jump 3", "pop"] {
+        let op: Op = text.parse().unwrap();
+        assert_eq!(op.to_string(), text);
+    }
+    let spaced: Op = "call  \"drive forward\"   2".parse().unwrap();
+    assert_eq!(spaced.to_string(), "call \"drive forward\" 2");
+}
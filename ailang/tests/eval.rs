@@ -0,0 +1,32 @@
+mod common;
+
+use ailang::*;
+use common::*;
+
+fn eval(source: &str) -> Value {
+    AiCompiler::new().eval(source).unwrap_or_else(|e| panic!("{:?}", e))
+}
+
+#[test]
+fn arithmetic_expressions_evaluate_standalone() {
+    assert_eq!(eval("1 + 2 * 3"), Value::Number(7.0));
+    assert_eq!(eval("(1 + 2) * 3"), Value::Number(9.0));
+    assert_eq!(eval("-4 / 2 + 10 % 4"), Value::Number(0.0));
+    assert_eq!(eval("1 < 2 and not false"), Value::Bool(true));
+    assert_eq!(eval("'a' + 'b'"), Value::String("ab".into()));
+}
+
+#[test]
+fn expressions_can_read_properties() {
+    let mut compiler = AiCompiler::new();
+    compiler.register_property("speed", Fixed(Value::Number(3.0))).unwrap();
+    assert_eq!(compiler.eval("$speed * 2 + 1").unwrap(), Value::Number(7.0));
+    // the compiler can be used again afterwards
+    assert_eq!(compiler.eval("$speed").unwrap(), Value::Number(3.0));
+}
+
+#[test]
+fn malformed_expressions_give_errors() {
+    assert!(AiCompiler::new().eval("1 +").is_err());
+    assert!(AiCompiler::new().eval("(1").is_err());
+}
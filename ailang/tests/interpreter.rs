@@ -0,0 +1,1043 @@
+mod common;
+
+use ailang::*;
+use common::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[test]
+fn falling_out_of_a_group_is_unbalanced() {
+    // `g` is missing its return, so it runs on into `h`
+    let code = vec![
+        Op::Jump(6),
+        Op::Label("g".into()),
+        Op::Push(Value::Number(1.0)),
+        Op::Pop,
+        Op::Label("h".into()),
+        Op::Return,
+        Op::Call("g".into(), 0),
+    ];
+    let mut interpreter = AiInterpreter::new(code);
+    interpreter.set_call_checking(true);
+    assert!(matches!(interpreter.interpret(), Err(Error::UnbalancedCall(4))));
+}
+
+#[test]
+fn balanced_groups_pass_call_checking() {
+    let code = vec![
+        Op::Jump(6),
+        Op::Label("g".into()),
+        Op::Call("h".into(), 0),
+        Op::Return,
+        Op::Label("h".into()),
+        Op::Return,
+        Op::Call("g".into(), 0),
+    ];
+    let mut interpreter = AiInterpreter::new(code);
+    interpreter.set_call_checking(true);
+    interpreter.interpret().unwrap();
+}
+
+#[test]
+fn call_checking_survives_a_leading_jump_past_the_end() {
+    let code = vec![
+        Op::Jump(10),
+        Op::Label("g".into()),
+        Op::Call("h".into(), 0),
+        Op::Return,
+        Op::Label("h".into()),
+        Op::Return,
+    ];
+    let mut interpreter = AiInterpreter::new(code);
+    interpreter.set_call_checking(true);
+    interpreter.call_group("g", vec![]).unwrap();
+    interpreter.interpret().unwrap();
+}
+
+#[test]
+fn groups_past_their_timeout_are_abandoned() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("spin", Forever(log.clone())).unwrap();
+    let program = compiler.compile("#[timeout(2s)]\ngroup slow { spin; print 1; }\nslow;\nprint 2;").unwrap();
+    let mut interpreter = AiInterpreter::from_program(program);
+    let clock = FakeClock::default();
+    interpreter.set_clock(Box::new(clock.clone()));
+
+    interpreter.step().unwrap();
+    clock.advance(Duration::from_secs(1));
+    interpreter.step().unwrap();
+    assert!(entries(&log).is_empty());
+
+    clock.advance(Duration::from_secs(1));
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["terminated", "2"]);
+}
+
+#[test]
+fn brackets_after_a_hash_are_only_an_attribute_before_a_group() {
+    assert_eq!(run("#[not an attribute] comment\nprint 1;"), ["1"]);
+    assert_eq!(run("#[timeout(2s)] but no group\nprint 1;"), ["1"]);
+    assert_eq!(run("#[best_effort]\n// a comment\ngroup g { print 1; }\ng;"), ["1"]);
+}
+
+#[test]
+fn cached_group_addresses_are_used_instead_of_scanning() {
+    let log = log();
+    let mut program = compiler(&log).compile("group g { print 1; }\ngroup h { print 2; }\ng;").unwrap();
+    let groups = program.groups.as_mut().unwrap();
+    let (g, h) = (groups["g"], groups["h"]);
+    groups.insert("g".into(), h);
+    groups.insert("h".into(), g);
+    AiInterpreter::from_program(program).interpret().unwrap();
+    // a scan would have found `g` where it really is
+    assert_eq!(entries(&log), ["2"]);
+}
+
+fn steps_to_finish(interpreter: &mut AiInterpreter) -> usize {
+    let mut steps = 1;
+    while interpreter.step().unwrap() == InterpreterState::Yield {
+        steps += 1;
+    }
+    steps
+}
+
+#[test]
+fn labels_between_ops_do_not_take_steps() {
+    let mut code = vec![Op::Push(Value::Number(1.0)), Op::Pop, Op::Yield];
+    code.extend((0..5).map(|i| Op::Label(format!("g{}", i))));
+    code.extend([Op::Yield, Op::Push(Value::Number(2.0)), Op::Pop]);
+    let mut interpreter = AiInterpreter::new(code);
+    assert_eq!(steps_to_finish(&mut interpreter), 3);
+
+    let mut interpreter = AiInterpreter::new(vec![Op::Yield, Op::Label("a".into()), Op::Label("b".into())]);
+    assert_eq!(steps_to_finish(&mut interpreter), 2);
+}
+
+#[test]
+fn callables_can_be_replaced_between_steps() {
+    let (old, new) = (log(), log());
+    let program = compiler(&old).compile("print 1; yield; print 2;").unwrap();
+    let mut interpreter = AiInterpreter::from_program(program);
+    assert_eq!(interpreter.step().unwrap(), InterpreterState::Yield);
+    interpreter.replace_callable("print", Box::new(Record(new.clone()))).unwrap();
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&old), ["1"]);
+    assert_eq!(entries(&new), ["2"]);
+}
+
+#[test]
+fn callables_in_progress_cannot_be_replaced() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("spin", Forever(log.clone())).unwrap();
+    let mut interpreter = AiInterpreter::from_program(compiler.compile("spin;").unwrap());
+    interpreter.step().unwrap();
+    assert!(matches!(interpreter.replace_callable("spin", Box::new(Failing)), Err(Error::CallableInUse(_))));
+    assert!(matches!(interpreter.replace_callable("walk", Box::new(Failing)), Err(Error::UnknownCallable(_))));
+    interpreter.replace_callable("print", Box::new(Failing)).unwrap();
+}
+
+// Runs `left <op> right` through a conditional jump, returning which way it went
+fn branch(left: &Value, right: &Value, compare: Vec<Op>) -> Result<Value> {
+    let out = Arc::new(Mutex::new(Value::Nil));
+    let mut code = vec![Op::Push(left.clone()), Op::Push(right.clone())];
+    code.extend(compare);
+    code.extend([Op::Push(Value::Bool(true)), Op::Set("out".into()), Op::Jump(3), Op::Push(Value::Bool(false)), Op::Set("out".into())]);
+    let mut interpreter = AiInterpreter::new(code);
+    interpreter.register_property("out", Box::new(Shared(out.clone(), log()))).unwrap();
+    interpreter.interpret()?;
+    let value = out.lock().unwrap().clone();
+    Ok(value)
+}
+
+#[test]
+fn fused_compare_jumps_match_the_unfused_ops() {
+    let pairs = [
+        (Op::Eq, Op::JumpUnlessEq(4)), (Op::Ne, Op::JumpUnlessNe(4)), (Op::Lt, Op::JumpUnlessLt(4)),
+        (Op::Le, Op::JumpUnlessLe(4)), (Op::Gt, Op::JumpUnlessGt(4)), (Op::Ge, Op::JumpUnlessGe(4)),
+    ];
+    let values = [
+        Value::Number(1.0), Value::Number(2.0), Value::String("a".into()), Value::String("b".into()),
+        Value::Bool(false), Value::Bool(true), Value::Nil,
+    ];
+    for (compare, fused) in pairs {
+        for left in values.iter() {
+            for right in values.iter() {
+                let unfused = branch(left, right, vec![compare.clone(), Op::JumpUnless(4)]);
+                let fused = branch(left, right, vec![fused.clone()]);
+                match (unfused, fused) {
+                    (Ok(a), Ok(b)) => assert_eq!(a, b, "{} {} {}", left, compare, right),
+                    (Err(_), Err(_)) => {}
+                    (a, b) => panic!("{} {} {}: {:?} unfused, {:?} fused", left, compare, right, a, b),
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn comparisons_before_branches_are_fused() {
+    let program = compiler(&log()).compile("$x = 1; if $x < 2 { print 1; } while $x != 3 { $x += 1; }").unwrap();
+    assert!(program.code.iter().any(|op| matches!(op, Op::JumpUnlessLt(_))));
+    assert!(program.code.iter().any(|op| matches!(op, Op::JumpUnlessNe(_))));
+    assert!(!program.code.iter().any(|op| matches!(op, Op::Lt | Op::Ne)));
+}
+
+fn group_events(source: &str) -> Vec<String> {
+    let events = log();
+    let program = compiler(&log()).compile(source).unwrap();
+    let mut interpreter = AiInterpreter::from_program(program);
+    let recorder = events.clone();
+    interpreter.set_group_hook(Box::new(move |event| {
+        let entry = match event {
+            GroupEvent::Enter {name} => format!("enter {}", name),
+            GroupEvent::Exit {name} => format!("exit {}", name),
+        };
+        recorder.lock().unwrap().push(entry);
+    }));
+    interpreter.interpret().unwrap();
+    entries(&events)
+}
+
+#[test]
+fn group_hooks_see_nested_calls_in_order() {
+    let source = "
+        group inner { print 1; }
+        group outer { inner; print 2; inner; }
+        outer;
+        inner;
+    ";
+    assert_eq!(group_events(source), [
+        "enter outer", "enter inner", "exit inner", "enter inner", "exit inner", "exit outer",
+        "enter inner", "exit inner",
+    ]);
+}
+
+#[test]
+fn group_hooks_see_groups_cut_short() {
+    let source = "group inner { return; print 1; }\ngroup outer { inner; halt; }\nouter;";
+    assert_eq!(group_events(source), ["enter outer", "enter inner", "exit inner", "exit outer"]);
+}
+
+fn without_props(source: &str, log: &Log) -> AiInterpreter {
+    let mut compiler = compiler(log);
+    compiler.register_property("angle", Shared(Arc::new(Mutex::new(Value::Nil)), log.clone())).unwrap();
+    compiler.register_property("speed", Fixed(Value::Number(0.0))).unwrap();
+    let mut program = compiler.compile(source).unwrap();
+    program.props.clear();
+    AiInterpreter::from_program(program)
+}
+
+#[test]
+fn scripts_run_against_property_defaults() {
+    let log = log();
+    let mut interpreter = without_props("use $angle; use $speed;\nprint $angle + 1 $speed;", &log);
+    interpreter.set_property_default("angle", Value::Number(90.0));
+    interpreter.set_property_default("speed", Value::String("fast".into()));
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["91 \"fast\""]);
+}
+
+#[test]
+fn properties_without_defaults_are_still_errors() {
+    let log = log();
+    let mut interpreter = without_props("use $angle; use $speed;\nprint $angle $speed;", &log);
+    interpreter.set_property_default("angle", Value::Number(90.0));
+    assert!(matches!(interpreter.interpret(), Err(Error::UnregisteredProperty(_, name, _)) if name == "speed"));
+
+    let mut interpreter = without_props("use $angle;\n$angle = 1;", &log);
+    interpreter.set_property_default("angle", Value::Number(90.0));
+    assert!(matches!(interpreter.interpret(), Err(Error::UnregisteredProperty(_, name, _)) if name == "angle"));
+}
+
+#[test]
+fn yield_statements_yield_once_and_carry_on() {
+    let log = log();
+    let program = compiler(&log).compile("print 1; yield; print 2;").unwrap();
+    let mut interpreter = AiInterpreter::from_program(program);
+    assert_eq!(interpreter.step().unwrap(), InterpreterState::Yield);
+    assert_eq!(entries(&log), ["1"]);
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["1", "2"]);
+
+    let steps = |source: &str| steps_to_finish(&mut AiInterpreter::from_program(compiler(&log).compile(source).unwrap()));
+    assert_eq!(steps("print 1; yield; print 2;"), steps("print 1; print 2;") + 1);
+    assert_eq!(steps("repeat 3 { yield; }"), steps("repeat 3 { }") + 3);
+}
+
+#[test]
+fn locals_report_names_and_values_mid_run() {
+    let log = log();
+    let source = "
+        group go $d { $half = $d / 2; yield; print $half; }
+        $x = 1;
+        $name = 'ai';
+        yield;
+        go 10;
+        $x = 2;
+        yield;
+    ";
+    let mut interpreter = AiInterpreter::from_program(compiler(&log).compile(source).unwrap());
+    interpreter.step().unwrap();
+    assert_eq!(interpreter.locals(), [("x".to_string(), Value::Number(1.0)), ("name".to_string(), Value::String("ai".into()))]);
+
+    interpreter.step().unwrap();
+    assert_eq!(interpreter.locals(), [("d".to_string(), Value::Number(10.0)), ("half".to_string(), Value::Number(5.0))]);
+
+    interpreter.interpret().unwrap();
+    assert_eq!(interpreter.global("x"), Some(Value::Number(2.0)));
+    assert_eq!(interpreter.global("half"), None);
+}
+
+fn timed_loop(log: &Log, at_target: &Arc<Mutex<Value>>, clock: &FakeClock) -> AiInterpreter {
+    let mut compiler = compiler(log);
+    compiler.register_property("at_target", Shared(at_target.clone(), log.clone())).unwrap();
+    let source = "use $at_target;\nuntil $at_target timeout 3s { yield; } else { print 0; }\nprint 1;";
+    let mut interpreter = AiInterpreter::from_program(compiler.compile(source).unwrap());
+    interpreter.set_clock(Box::new(clock.clone()));
+    interpreter
+}
+
+#[test]
+fn timed_loops_finish_when_their_condition_is_met() {
+    let (log, at_target, clock) = (log(), Arc::new(Mutex::new(Value::Bool(false))), FakeClock::default());
+    let mut interpreter = timed_loop(&log, &at_target, &clock);
+    for _ in 0..5 {
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(interpreter.step().unwrap(), InterpreterState::Yield);
+    }
+    *at_target.lock().unwrap() = Value::Bool(true);
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["1"]);
+}
+
+#[test]
+fn timed_loops_run_their_else_body_when_time_runs_out() {
+    let (log, at_target, clock) = (log(), Arc::new(Mutex::new(Value::Bool(false))), FakeClock::default());
+    let mut interpreter = timed_loop(&log, &at_target, &clock);
+    interpreter.step().unwrap();
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(interpreter.step().unwrap(), InterpreterState::Yield);
+    clock.advance(Duration::from_secs(1));
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["0", "1"]);
+}
+
+#[test]
+fn callables_reject_property_values_of_the_wrong_type() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("forward", Typed(log.clone(), Signature::new(vec![Param::Number]))).unwrap();
+    compiler.register_property("distance", Fixed(Value::String("far".into()))).unwrap();
+
+    let program = compiler.compile("use $distance;\nforward $distance;").unwrap();
+    let error = AiInterpreter::from_program(program).interpret().unwrap_err();
+    assert!(error.to_string().contains("Value 0 passed to 'forward' should be"), "{}", error);
+    assert!(entries(&log).is_empty());
+}
+
+#[test]
+fn callables_accept_property_values_of_the_right_type() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("forward", Typed(log.clone(), Signature::new(vec![Param::Number]))).unwrap();
+    compiler.register_property("steps", Fixed(Value::Number(3.0))).unwrap();
+    let program = compiler.compile("use $steps;\nforward $steps;").unwrap();
+    AiInterpreter::from_program(program).interpret().unwrap();
+    assert_eq!(entries(&log), ["3"]);
+}
+
+#[test]
+fn the_implicit_main_group_can_be_called_by_name() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.set_implicit_main(true).unwrap();
+    let program = compiler.compile("print 1;\nprint 2;").unwrap();
+    let mut interpreter = AiInterpreter::from_program(program);
+    interpreter.interpret().unwrap();
+    interpreter.call_group("__main", vec![]).unwrap();
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["1", "2", "1", "2"]);
+}
+
+fn checked(source: &str, enabled: bool) -> Result<Vec<String>> {
+    let log = log();
+    let mut interpreter = AiInterpreter::from_program(compiler(&log).compile(source).unwrap());
+    interpreter.set_checked_arithmetic(enabled);
+    interpreter.interpret()?;
+    Ok(entries(&log))
+}
+
+#[test]
+fn checked_arithmetic_rejects_infinity_and_nan() {
+    let overflow = "$base = 10;\nprint $base ^ 1000;";
+    let undefined = "$zero = 0;\nprint $zero / $zero;";
+    assert!(matches!(checked(overflow, true), Err(Error::NumericError(_))));
+    assert!(matches!(checked(undefined, true), Err(Error::NumericError(_))));
+    assert_eq!(checked(overflow, false).unwrap(), ["inf"]);
+    assert_eq!(checked(undefined, false).unwrap(), ["NaN"]);
+    assert_eq!(checked("$base = 10;\nprint $base ^ 2;", true).unwrap(), ["100"]);
+}
+
+#[test]
+fn assignments_capture_a_callables_result() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    let measure = Returning(Signature::new(vec![Param::Number]), 2, |args: &[Value]| match args[0] {
+        Value::Number(n) => Value::Number(n * 2.0),
+        _ => Value::Nil,
+    });
+    compiler.register_callable("measure distance", measure).unwrap();
+    let program = compiler.compile("$result = measure distance 4;\nprint $result + 1;").unwrap();
+    AiInterpreter::from_program(program).interpret().unwrap();
+    assert_eq!(entries(&log), ["9"]);
+}
+
+#[test]
+fn assignments_cannot_capture_void_callables() {
+    let errors = compile_errors("$result = print 1;");
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("'print' doesn't return a value"), "{:?}", errors);
+}
+
+fn sensing(log: &Log, distance: f64) -> Program {
+    let mut compiler = compiler(log);
+    compiler.register_property("distance", Fixed(Value::Number(distance))).unwrap();
+    compiler.register_callable("wait", Returning(Signature::new(vec![]), 3, |_: &[Value]| Value::Nil)).unwrap();
+    compiler.compile("use $distance;\nwait;\nif $distance > 2 { print 1; } else { print 2; }").unwrap()
+}
+
+#[test]
+fn replaying_a_trace_repeats_the_recorded_run() {
+    let (log, replay_log) = (log(), log());
+    let mut recorder = AiInterpreter::from_program(sensing(&log, 3.0));
+    recorder.record();
+    recorder.interpret().unwrap();
+    let trace = recorder.take_trace();
+    assert_eq!(entries(&log), ["1"]);
+    assert!(trace.iter().any(|e| e.event == TraceEvent::Get("distance".into()) && e.value == Value::Number(3.0)));
+    assert_eq!(trace.iter().filter(|e| e.event == TraceEvent::Wait("wait".into())).count(), 2);
+
+    // The live property now reads differently, and the callables don't run, but the trace
+    // drives the same path through the program.
+    let mut replayer = AiInterpreter::from_program(sensing(&replay_log, 0.0));
+    replayer.replay(trace.clone());
+    replayer.interpret().unwrap();
+    assert!(entries(&replay_log).is_empty());
+
+    let mut short = AiInterpreter::from_program(sensing(&replay_log, 0.0));
+    short.replay(trace[..trace.len() - 1].to_vec());
+    assert!(matches!(short.interpret(), Err(Error::TraceMismatch(..))));
+}
+
+#[test]
+fn booleans_order_false_before_true() {
+    assert_eq!(run("$f = false;\n$t = true;\nprint $f < $t;\nprint $t <= $f;\nprint $t >= $t;"), ["true", "false", "true"]);
+    assert_eq!(AiCompiler::new().eval("false < true").unwrap(), Value::Bool(true));
+
+    let log = log();
+    let program = compiler(&log).compile("$t = true;\n$one = 1;\nprint $t < $one;").unwrap();
+    let error = AiInterpreter::from_program(program).interpret().unwrap_err();
+    assert!(error.to_string().contains("Can't order a boolean and a number"), "{}", error);
+}
+
+#[test]
+fn dry_runs_take_the_real_branches_without_side_effects() {
+    let log = log();
+    let speed = Arc::new(Mutex::new(Value::Number(1.0)));
+    let mut compiler = compiler(&log);
+    compiler.register_property("distance", Fixed(Value::Number(3.0))).unwrap();
+    compiler.register_property("speed", Shared(speed.clone(), log.clone())).unwrap();
+    let source = "
+        use $distance;
+        use $speed;
+        if $distance > 2 {
+            $speed = 5;
+            print 'far' $distance;
+        } else {
+            $speed = 0;
+            print 'near';
+        }
+    ";
+    let mut interpreter = AiInterpreter::from_program(compiler.compile(source).unwrap());
+    let actions = interpreter.run_dry().unwrap();
+    assert_eq!(actions, [
+        DryRunAction::Set("speed".into(), Value::Number(5.0)),
+        DryRunAction::Call("print".into(), vec![Value::String("far".into()), Value::Number(3.0)]),
+    ]);
+    assert!(entries(&log).is_empty());
+    assert_eq!(*speed.lock().unwrap(), Value::Number(1.0));
+}
+
+fn stepping_over(log: &Log, markers: bool) -> AiInterpreter {
+    let mut compiler = compiler(log);
+    compiler.set_statement_markers(markers).unwrap();
+    compiler.register_property("p", Shared(Arc::new(Mutex::new(Value::Nil)), log.clone())).unwrap();
+    let source = "use $p;\ngroup g { $p = 1; $p = 2; }\n$x = 1;\ng;\n$x = 2;";
+    AiInterpreter::from_program(compiler.compile(source).unwrap())
+}
+
+#[test]
+fn stepping_over_a_group_runs_it_in_one_step() {
+    let (log, stepped) = (log(), log());
+    let mut interpreter = stepping_over(&log, false);
+    let mut seen = Vec::new();
+    loop {
+        let state = interpreter.step_over_group().unwrap();
+        seen.push((entries(&log).len(), interpreter.global("x")));
+        if state != InterpreterState::Yield {
+            break;
+        }
+    }
+    // the call runs both writes at once, then stops in the top level, before `$x = 2`
+    let call = seen.iter().position(|(writes, _)| *writes > 0).unwrap();
+    assert_eq!(seen[call], (2, Some(Value::Number(1.0))));
+    assert_eq!(seen[call - 1], (0, Some(Value::Number(1.0))));
+    assert_eq!(seen.last(), Some(&(2, Some(Value::Number(2.0)))));
+
+    // stepping a statement at a time stops between the group's writes
+    let mut interpreter = stepping_over(&stepped, true);
+    interpreter.set_yield_per_statement(true);
+    let mut seen = Vec::new();
+    while interpreter.step().unwrap() == InterpreterState::Yield {
+        seen.push(entries(&stepped).len());
+    }
+    assert!(seen.contains(&1), "{:?}", seen);
+}
+
+fn parallel(source: &str) -> (Result<()>, Vec<String>) {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("forever", Forever(log.clone())).unwrap();
+    compiler.register_callable("fail", Failing).unwrap();
+    let program = compiler.compile(source).unwrap_or_else(|e| panic!("{:?}", e));
+    let result = AiInterpreter::from_program(program).interpret();
+    (result, entries(&log))
+}
+
+#[test]
+fn return_only_ends_its_own_parallel_branch() {
+    let source = "group a { print 1; return; print 2; }\ngroup b { yield; yield; print 3; }\nparallel { a; b; }\nprint 4;";
+    let (result, log) = parallel(source);
+    result.unwrap();
+    assert_eq!(log, ["1", "3", "4"]);
+}
+
+#[test]
+fn halt_in_a_parallel_branch_cancels_its_siblings() {
+    let (result, log) = parallel("group a { yield; halt; }\ngroup b { forever; }\nparallel { a; b; }\nprint 4;");
+    result.unwrap();
+    assert_eq!(log, ["terminated"]);
+}
+
+#[test]
+fn errors_in_a_parallel_branch_cancel_its_siblings() {
+    let (result, log) = parallel("group a { yield; fail; }\ngroup b { forever; }\nparallel { a; b; }\nprint 4;");
+    assert!(result.unwrap_err().to_string().contains("failed on purpose"));
+    assert_eq!(log, ["terminated"]);
+}
+
+#[test]
+fn spawned_groups_outlive_the_main_flow() {
+    let log = log();
+    let program = compiler(&log).compile("group blink $n { repeat $n { yield; print 0; } }\nspawn blink 3;\nprint 1;").unwrap();
+    let mut interpreter = AiInterpreter::from_program(program);
+    interpreter.step().unwrap();
+    assert_eq!(entries(&log), ["1"]);
+    assert_eq!(interpreter.background_task_count(), 1);
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["1", "0", "0", "0"]);
+    assert_eq!(interpreter.background_task_count(), 0);
+}
+
+#[test]
+fn empty_sources_stop_straight_away() {
+    for source in ["", "  \n\t\n", "// nothing\n# to see here\n", "/// no group\n"] {
+        let log = log();
+        let program = compiler(&log).compile(source).unwrap_or_else(|e| panic!("{:?}: {:?}", source, e));
+        assert!(program.group_names().is_empty());
+        let mut interpreter = AiInterpreter::from_program(program);
+        assert_eq!(interpreter.step().unwrap(), InterpreterState::Stop, "{:?}", source);
+        assert!(entries(&log).is_empty());
+    }
+}
+
+// The deepest the countdown group gets nested, going by the group hook
+fn countdown_depth(n: u32, tail_calls: bool) -> usize {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.set_tail_calls(tail_calls).unwrap();
+    let source = format!("group countdown $n {{\n if $n <= 0 {{ print 0; return; }}\n countdown ($n - 1);\n}}\ncountdown {};", n);
+    let mut interpreter = AiInterpreter::from_program(compiler.compile(&source).unwrap());
+    let depth = Arc::new(Mutex::new((0, 0)));
+    let tracker = depth.clone();
+    interpreter.set_group_hook(Box::new(move |event| {
+        let mut depth = tracker.lock().unwrap();
+        match event {
+            GroupEvent::Enter {..} => depth.0 += 1,
+            GroupEvent::Exit {..} => depth.0 -= 1,
+        }
+        depth.1 = depth.1.max(depth.0);
+    }));
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["0"]);
+    let (current, deepest) = *depth.lock().unwrap();
+    assert_eq!(current, 0);
+    deepest
+}
+
+#[test]
+fn tail_calls_keep_the_call_stack_flat() {
+    assert_eq!(countdown_depth(50, false), 51);
+    assert_eq!(countdown_depth(50, true), 1);
+    assert_eq!(countdown_depth(100_000, true), 1);
+}
+
+#[test]
+fn registering_in_bulk_reports_only_the_duplicates() {
+    let log = log();
+    let program = compiler(&log).compile("group g { print 1; }\ng;").unwrap();
+    let mut interpreter = AiInterpreter::from_program(program);
+    let mut callables: HashMap<String, Box<dyn CallableGenerator>> = HashMap::new();
+    callables.insert("beep".into(), Box::new(Record(log.clone())));
+    callables.insert("print".into(), Box::new(Record(log.clone())));
+    callables.insert("flash".into(), Box::new(Record(log.clone())));
+    let errors = interpreter.register_callables(callables).unwrap_err();
+    assert!(matches!(&errors[..], [Error::DuplicateCallable(name)] if name == "print"), "{:?}", errors);
+    for name in ["beep", "flash"] {
+        let again = interpreter.register_callable(name, Box::new(Record(log.clone())));
+        assert!(matches!(again, Err(Error::DuplicateCallable(_))), "{} wasn't registered", name);
+    }
+
+    let mut props: HashMap<String, Box<dyn Prop>> = HashMap::new();
+    props.insert("speed".into(), Box::new(Fixed(Value::Number(1.0))));
+    props.insert("heading".into(), Box::new(Fixed(Value::Number(0.0))));
+    interpreter.register_properties(props).unwrap();
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["1"]);
+}
+
+// The coverage of a run, and the source line of each op that has one, with its address
+fn covered_lines(x: f64) -> (Coverage, Vec<(usize, usize)>) {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_property("x", Fixed(Value::Number(x))).unwrap();
+    let program = compiler.compile("use $x;\nif $x > 0 {\n    print 1;\n} else {\n    print 2;\n}").unwrap();
+    let mut interpreter = AiInterpreter::from_program(program);
+    interpreter.set_coverage(true);
+    interpreter.interpret().unwrap();
+    let coverage = interpreter.coverage();
+    let lines = (0..coverage.executed.len())
+        .filter_map(|addr| Some((addr, interpreter.source_line(addr)?)))
+        .collect();
+    (coverage, lines)
+}
+
+#[test]
+fn coverage_reports_an_untaken_else_branch() {
+    let (coverage, lines) = covered_lines(1.0);
+    let on_line = |n| lines.iter().filter(move |&&(_, line)| line == n).map(|&(addr, _)| addr);
+    assert!(on_line(5).count() > 0);
+    assert!(on_line(5).all(|addr| !coverage.covered(addr)));
+    assert!(on_line(3).all(|addr| coverage.covered(addr)));
+    assert!(coverage.ratio() < 1.0);
+
+    let (other, _) = covered_lines(-1.0);
+    let mut both = coverage.clone();
+    both.merge(&other);
+    assert!(both.uncovered().len() < coverage.uncovered().len());
+    assert!(on_line(5).all(|addr| both.covered(addr)));
+}
+
+#[test]
+fn unregistered_names_suggest_a_close_registered_one() {
+    let log = log();
+    let mut interpreter = AiInterpreter::new(vec![Op::Get("sped".into()), Op::Pop]);
+    interpreter.register_property("speed", Box::new(Fixed(Value::Number(1.0)))).unwrap();
+    let error = interpreter.step().unwrap_err();
+    assert!(matches!(&error, Error::UnregisteredProperty(0, name, Some(hint)) if name == "sped" && hint == "speed"), "{:?}", error);
+
+    let mut interpreter = AiInterpreter::new(vec![Op::Call("fowrard".into(), 0)]);
+    interpreter.register_callable("forward", Box::new(Record(log.clone()))).unwrap();
+    let error = interpreter.step().unwrap_err();
+    assert_eq!(error.to_string(), "[address 0] 'fowrard' is not a registered callable. Did you mean 'forward'?");
+
+    let mut interpreter = AiInterpreter::new(vec![Op::Call("xyzzy".into(), 0)]);
+    interpreter.register_callable("forward", Box::new(Record(log.clone()))).unwrap();
+    assert!(matches!(interpreter.step(), Err(Error::UnregisteredCallable(0, _, None))));
+}
+
+#[test]
+fn waiting_past_the_budget_is_an_error() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("sleep", Sleep(log.clone())).unwrap();
+    let program = compiler.compile("repeat 10 { sleep 1; print 0; }").unwrap();
+    let mut interpreter = AiInterpreter::from_program(program);
+    interpreter.set_max_total_wait(Duration::from_millis(3500));
+    let error = interpreter.interpret().unwrap_err();
+    assert!(matches!(error, Error::WaitBudgetExceeded(_)), "{:?}", error);
+    // print doesn't say how long it takes, so it doesn't count
+    assert_eq!(entries(&log), ["1", "0", "1", "0", "1", "0"]);
+}
+
+fn stalling(log: &Log) -> AiInterpreter {
+    let mut compiler = compiler(log);
+    compiler.register_callable("stuck", Forever(log.clone())).unwrap();
+    compiler.register_callable("slow", Returning(Signature::new(vec![]), 6, |_: &[Value]| Value::Nil)).unwrap();
+    let mut interpreter = AiInterpreter::from_program(compiler.compile("slow;\nprint 1;\nstuck;").unwrap());
+    interpreter.set_stall_limit(5);
+    interpreter
+}
+
+#[test]
+fn the_watchdog_stops_a_stalled_callable() {
+    let log = log();
+    let mut interpreter = stalling(&log);
+    // `slow` yields five times, which is within the limit
+    let mut yields = 0;
+    let error = loop {
+        match interpreter.step() {
+            Ok(_) => yields += 1,
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(&error, Error::CallableStalled(_, name) if name == "stuck"), "{:?}", error);
+    assert_eq!(entries(&log), ["1"]);
+    // six from `slow`, counting the one when it finishes, one from `print`, and five from `stuck`
+    assert_eq!(yields, 12);
+}
+
+#[test]
+fn the_stall_hook_can_keep_waiting() {
+    let log = log();
+    let mut interpreter = stalling(&log);
+    let seen = log.clone();
+    interpreter.set_stall_hook(Box::new(move |name, polls| {
+        seen.lock().unwrap().push(format!("{} {}", name, polls));
+        polls < 7
+    }));
+    assert!(matches!(interpreter.interpret(), Err(Error::CallableStalled(..))));
+    assert_eq!(entries(&log), ["1", "stuck 6", "stuck 7"]);
+}
+
+#[test]
+fn the_host_can_read_results_after_a_run() {
+    let log = log();
+    let source = "$result = 0;\n$i = 1;\nwhile $i <= 4 { $result = $result + $i; $i = $i + 1; }\n$label = 'sum';";
+    let mut interpreter = AiInterpreter::from_program(compiler(&log).compile(source).unwrap());
+    interpreter.interpret().unwrap();
+    assert_eq!(interpreter.global("result"), Some(Value::Number(10.0)));
+    assert_eq!(interpreter.global("label"), Some(Value::String("sum".into())));
+    assert_eq!(interpreter.global("missing"), None);
+
+    // in an implicit main group, they're gone once it returns
+    let mut compiler = compiler(&log);
+    compiler.set_implicit_main(true).unwrap();
+    let mut interpreter = AiInterpreter::from_program(compiler.compile(source).unwrap());
+    interpreter.interpret().unwrap();
+    assert_eq!(interpreter.global("result"), None);
+}
+
+#[test]
+fn closures_can_back_a_property() {
+    let (log, speed) = (log(), Arc::new(Mutex::new(2.0)));
+    let mut compiler = compiler(&log);
+    let (read, written) = (speed.clone(), speed.clone());
+    let prop = ComputedProp::new(
+        move || Value::Number(*read.lock().unwrap()),
+        move |v| if let Value::Number(n) = v { *written.lock().unwrap() = n; },
+    );
+    compiler.register_property("speed", prop).unwrap();
+    let program = compiler.compile("use $speed;\nprint $speed;\n$speed = $speed * 3;\nprint $speed;").unwrap();
+    AiInterpreter::from_program(program).interpret().unwrap();
+    assert_eq!(entries(&log), ["2", "6"]);
+    assert_eq!(*speed.lock().unwrap(), 6.0);
+
+    let (read, written) = (speed.clone(), speed.clone());
+    let mut interpreter = AiInterpreter::new(vec![Op::Get("speed".into()), Op::Push(Value::Number(1.0)), Op::Add, Op::Set("speed".into())]);
+    interpreter.register_computed_property(
+        "speed",
+        move || Value::Number(*read.lock().unwrap()),
+        move |v| if let Value::Number(n) = v { *written.lock().unwrap() = n; },
+    ).unwrap();
+    interpreter.interpret().unwrap();
+    assert_eq!(*speed.lock().unwrap(), 7.0);
+}
+
+#[test]
+fn stepping_back_restores_variables_and_properties() {
+    let (log, p) = (log(), Arc::new(Mutex::new(Value::Number(0.0))));
+    let mut compiler = compiler(&log);
+    compiler.register_property("p", Shared(p.clone(), log.clone())).unwrap();
+    let source = "use $p;\n$x = 1;\nyield;\n$x = 2;\n$p = 5;\nyield;\n$x = 3;\nyield;";
+    let mut interpreter = AiInterpreter::from_program(compiler.compile(source).unwrap());
+    interpreter.set_step_history(10);
+    let x = |interpreter: &AiInterpreter| interpreter.global("x");
+
+    for _ in 0..3 {
+        interpreter.step().unwrap();
+    }
+    assert_eq!(x(&interpreter), Some(Value::Number(3.0)));
+    assert!(interpreter.step_back().unwrap());
+    assert_eq!(x(&interpreter), Some(Value::Number(2.0)));
+    assert_eq!(*p.lock().unwrap(), Value::Number(5.0));
+    assert!(interpreter.step_back().unwrap());
+    assert_eq!(x(&interpreter), Some(Value::Number(1.0)));
+    assert_eq!(*p.lock().unwrap(), Value::Number(0.0));
+
+    // and forwards again the same way
+    interpreter.step().unwrap();
+    assert_eq!(x(&interpreter), Some(Value::Number(2.0)));
+    assert_eq!(*p.lock().unwrap(), Value::Number(5.0));
+    assert_eq!(entries(&log), ["set 5", "set 0", "set 5"]);
+
+    assert!(interpreter.step_back().unwrap());
+    assert!(interpreter.step_back().unwrap());
+    assert!(!interpreter.step_back().unwrap());
+}
+
+// A property that can be set but not read, like a motor's target speed
+struct WriteOnly(Log);
+
+impl Prop for WriteOnly {
+    fn get(&self) -> Result<Value> {
+        Err(Error::Type("the motor can't be read".into()))
+    }
+    fn set(&mut self, val: Value) -> Result<()> {
+        self.0.lock().unwrap().push(format!("set {}", val));
+        Ok(())
+    }
+    fn settable(&self) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+#[test]
+fn step_history_still_writes_properties_it_cant_read() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_property("motor", WriteOnly(log.clone())).unwrap();
+    let mut interpreter = AiInterpreter::from_program(compiler.compile("use $motor;\n$motor = 3;\nyield;\nprint 1;").unwrap());
+    interpreter.set_step_history(10);
+    interpreter.step().unwrap();
+    assert_eq!(entries(&log), ["set 3"]);
+    // there's nothing to put the motor back to, but the rest of the step is still undone
+    assert!(interpreter.step_back().unwrap());
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["set 3", "set 3", "1"]);
+}
+
+fn statement_yields(source: &str) -> usize {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.set_statement_markers(true).unwrap();
+    let mut interpreter = AiInterpreter::from_program(compiler.compile(source).unwrap());
+    interpreter.set_yield_per_statement(true);
+    steps_to_finish(&mut interpreter) - 1
+}
+
+#[test]
+fn yielding_per_statement_yields_once_per_executed_statement() {
+    assert_eq!(statement_yields("$a = 1;\n$b = 2;\n$c = $a + $b;"), 3);
+    // the loop itself, then its body three times
+    assert_eq!(statement_yields("$i = 0;\nwhile $i < 3 { $i = $i + 1; }"), 5);
+    // the untaken branch doesn't count
+    assert_eq!(statement_yields("$i = 0;\nif $i > 0 { $i = 1; $i = 2; } else { $i = 3; }"), 3);
+}
+
+fn with_middleware(slow: &Log, fast: &Log) -> AiInterpreter {
+    let mut compiler = compiler(fast);
+    compiler.register_callable("forward", Record(fast.clone())).unwrap();
+    compiler.register_callable("forward_slow", Record(slow.clone())).unwrap();
+    compiler.register_callable("fire", Record(fast.clone())).unwrap();
+    AiInterpreter::from_program(compiler.compile("forward 1;\nprint 2;\nfire;\nforward 3;").unwrap())
+}
+
+#[test]
+fn middleware_can_redirect_calls() {
+    let (slow, fast) = (log(), log());
+    let mut interpreter = with_middleware(&slow, &fast);
+    interpreter.add_call_middleware(Box::new(|name, _| Some(if name == "forward" {"forward_slow".into()} else {name.into()})));
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&slow), ["1", "3"]);
+    assert_eq!(entries(&fast), ["2", ""]);
+}
+
+#[test]
+fn middleware_can_veto_calls() {
+    let (slow, fast) = (log(), log());
+    let mut interpreter = with_middleware(&slow, &fast);
+    let seen = slow.clone();
+    interpreter.add_call_middleware(Box::new(move |name, args| {
+        seen.lock().unwrap().push(format!("{} {:?}", name, args));
+        Some(name.into())
+    }));
+    interpreter.add_call_middleware(Box::new(|name, _| (name != "fire").then(|| name.into())));
+    let error = interpreter.interpret().unwrap_err();
+    assert!(matches!(&error, Error::CallRefused(_, name) if name == "fire"), "{:?}", error);
+    assert_eq!(entries(&fast), ["1", "2"]);
+    assert_eq!(entries(&slow), ["forward [Number(1.0)]", "print [Number(2.0)]", "fire []"]);
+}
+
+fn redirected(log: &Log, middleware: bool) -> AiInterpreter {
+    let mut compiler = compiler(log);
+    compiler.register_callable("forward", Record(log.clone())).unwrap();
+    compiler.register_callable("forward_slow", Returning(Signature::new(vec![Param::Number]), 3, |_: &[Value]| Value::Nil)).unwrap();
+    let mut interpreter = AiInterpreter::from_program(compiler.compile("forward 1;\nprint 2;").unwrap());
+    if middleware {
+        interpreter.add_call_middleware(Box::new(|name, _| Some(if name == "forward" {"forward_slow".into()} else {name.into()})));
+    }
+    interpreter
+}
+
+#[test]
+fn redirected_calls_are_watched_and_recorded_as_the_callable_they_went_to() {
+    let (log, replay_log) = (log(), log());
+    let mut interpreter = redirected(&log, true);
+    let stalled = log.clone();
+    interpreter.set_stall_limit(1);
+    interpreter.set_stall_hook(Box::new(move |name, polls| {
+        stalled.lock().unwrap().push(format!("{} stalled {}", name, polls));
+        true
+    }));
+    interpreter.record();
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["forward_slow stalled 2", "2"]);
+    let trace = interpreter.take_trace();
+    assert!(trace.iter().any(|e| e.event == TraceEvent::Call("forward_slow".into())));
+    assert!(!trace.iter().any(|e| e.event == TraceEvent::Call("forward".into())));
+
+    // replaying doesn't need the middleware to follow the redirected call
+    let mut replayer = redirected(&replay_log, false);
+    replayer.replay(trace);
+    replayer.interpret().unwrap();
+    assert!(entries(&replay_log).is_empty());
+
+    let mut interpreter = redirected(&log, true);
+    interpreter.set_stall_limit(1);
+    let error = interpreter.interpret().unwrap_err();
+    assert!(matches!(&error, Error::CallableStalled(_, name) if name == "forward_slow"), "{:?}", error);
+}
+
+fn tidy_up(best_effort: bool) -> (Result<()>, Vec<String>) {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("close gripper", Failing).unwrap();
+    let attribute = if best_effort {"#[best_effort]\n"} else {""};
+    let source = format!("{}group tidy_up {{\n    print 1;\n    close gripper;\n    print 3;\n}}\ntidy_up;\nprint 4;", attribute);
+    let mut interpreter = AiInterpreter::from_program(compiler.compile(&source).unwrap());
+    let recovered = log.clone();
+    interpreter.set_recovery_hook(Box::new(move |error| recovered.lock().unwrap().push(format!("recovered: {}", error))));
+    let result = interpreter.interpret();
+    (result, entries(&log))
+}
+
+#[test]
+fn best_effort_groups_carry_on_past_a_failing_command() {
+    let (result, log) = tidy_up(true);
+    result.unwrap();
+    assert_eq!(log, ["1", "recovered: failed on purpose", "3", "4"]);
+
+    let (result, log) = tidy_up(false);
+    assert!(result.is_err());
+    assert_eq!(log, ["1"]);
+}
+
+/// Moves the clock on by its argument, in milliseconds, each time it's called.
+struct Ticking(FakeClock);
+
+struct Tick(FakeClock, Duration);
+
+impl CallableGenerator for Ticking {
+    fn generate(&mut self, args: Vec<Value>) -> Result<Box<dyn Callable>> {
+        let Some(Value::Number(ms)) = args.first() else {
+            return Err(Error::Type("tick takes milliseconds".into()));
+        };
+        Ok(Box::new(Tick(self.0.clone(), Duration::from_millis(*ms as u64))))
+    }
+}
+
+impl Callable for Tick {
+    fn call(&mut self) -> Result<bool> {
+        self.0.advance(self.1);
+        Ok(true)
+    }
+}
+
+#[test]
+fn step_timing_measures_each_step_with_the_clock() {
+    let clock = FakeClock::default();
+    let mut compiler = compiler(&log());
+    compiler.register_callable("tick", Ticking(clock.clone())).unwrap();
+    let mut interpreter = AiInterpreter::from_program(compiler.compile("tick 5;\ntick 20;\n$x = 1;").unwrap());
+    interpreter.set_clock(Box::new(clock.clone()));
+    assert_eq!(interpreter.last_step_duration(), Duration::ZERO);
+
+    interpreter.set_step_timing(true);
+    let mut durations = Vec::new();
+    while interpreter.step().unwrap() == InterpreterState::Yield {
+        durations.push(interpreter.last_step_duration());
+    }
+    durations.push(interpreter.last_step_duration());
+    assert_eq!(durations, [5, 20, 0].map(Duration::from_millis));
+}
+
+/// Logs each poll, and finishes on the fourth.
+struct Polled(Log);
+
+struct Polling(Log, u32);
+
+impl CallableGenerator for Polled {
+    fn generate(&mut self, _args: Vec<Value>) -> Result<Box<dyn Callable>> {
+        Ok(Box::new(Polling(self.0.clone(), 0)))
+    }
+}
+
+impl Callable for Polling {
+    fn call(&mut self) -> Result<bool> {
+        self.1 += 1;
+        self.0.lock().unwrap().push(format!("poll {}", self.1));
+        Ok(self.1 == 4)
+    }
+}
+
+#[test]
+fn unfinished_callables_are_polled_again_on_the_next_step() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("drive to point", Polled(log.clone())).unwrap();
+    let mut interpreter = AiInterpreter::from_program(compiler.compile("drive to point;\nprint 1;").unwrap());
+    // the fourth step is the one that finishes it
+    for polls in 1..=4 {
+        assert_eq!(interpreter.step().unwrap(), InterpreterState::Yield);
+        assert_eq!(entries(&log).len(), polls);
+    }
+    interpreter.interpret().unwrap();
+    assert_eq!(entries(&log), ["poll 1", "poll 2", "poll 3", "poll 4", "1"]);
+}
+
+#[test]
+fn else_branches_run_when_the_condition_is_false() {
+    let source = "
+        $n = 1;
+        if $n > 2 { print 1; } else { print 2; }
+        if $n < 2 { print 3; } else { print 4; }
+        print 5;
+    ";
+    assert_eq!(run(source), ["2", "3", "5"]);
+}
+
+#[test]
+fn and_and_or_short_circuit_on_the_left_operand() {
+    let source = "
+        $t = true;
+        $f = false;
+        print ($t or $f) ($f or $t) ($f or $f) ($t and $f) ($f and $t) ($t and $t);
+        while $f or $f { print 0; }
+        print 9;
+    ";
+    assert_eq!(run(source), ["true true false false false true", "9"]);
+}
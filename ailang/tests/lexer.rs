@@ -0,0 +1,102 @@
+use ailang::*;
+
+fn types(lexer: Lexer) -> Vec<TokenType> {
+    lexer.map(|t| t.ty).collect()
+}
+
+#[test]
+fn keywords_are_case_sensitive_by_default() {
+    use TokenType::*;
+    assert_eq!(types(Lexer::new("Sequence WHILE while")), [Word, Word, While]);
+}
+
+#[test]
+fn keywords_can_match_regardless_of_case() {
+    use TokenType::*;
+    let mut lexer = Lexer::new("Sequence WHILE wHiLe True $While Go");
+    lexer.set_case_insensitive_keywords(true);
+    let tokens: Vec<_> = lexer.collect();
+    assert_eq!(tokens.iter().map(|t| t.ty).collect::<Vec<_>>(), [Sequence, While, While, True, Ident, Word]);
+    // identifiers and words keep their case
+    assert_eq!(tokens[4].lexeme, "$While");
+    assert_eq!(tokens[5].lexeme, "Go");
+
+    let mut compiler = AiCompiler::new();
+    compiler.set_case_insensitive_keywords(true);
+    assert!(compiler.compile("$x = 0; WHILE $x < 2 { $x = $x + 1; }").is_ok());
+    assert!(AiCompiler::new().compile("$x = 0; WHILE $x < 2 { $x = $x + 1; }").is_err());
+}
+
+#[test]
+fn the_same_script_lexes_with_another_sigil() {
+    use TokenType::*;
+    let expected = [Ident, Equal, Ident, Percent, Ident, Semicolon];
+    assert_eq!(types(Lexer::new("$a = $b % $c;")), expected);
+
+    let mut lexer = Lexer::new("%a = %b % %c;");
+    lexer.set_variable_sigil('%').unwrap();
+    assert_eq!(types(lexer), expected);
+
+    let mut lexer = Lexer::new("@a = @b % 2;");
+    lexer.set_variable_sigil('@').unwrap();
+    let names: Vec<_> = lexer.filter(|t| t.ty == Ident).map(|t| t.lexeme).collect();
+    assert_eq!(names, ["@a", "@b"]);
+}
+
+#[test]
+fn with_the_percent_sigil_remainders_still_work() {
+    use TokenType::*;
+    let mut lexer = Lexer::new("%a%2 %3");
+    lexer.set_variable_sigil('%').unwrap();
+    assert_eq!(types(lexer), [Ident, Percent, Num, Percent, Num]);
+}
+
+#[test]
+fn only_some_characters_can_be_sigils() {
+    assert!(matches!(Lexer::new("").set_variable_sigil('x'), Err(Error::InvalidSigil('x'))));
+    assert!(AiCompiler::new().set_variable_sigil('#').is_err());
+}
+
+#[test]
+fn raw_strings_keep_backslashes() {
+    let eval = |source: &str| AiCompiler::new().eval(source).unwrap();
+    assert_eq!(eval(r"r'C:\path\no\escapes'"), Value::String(r"C:\path\no\escapes".into()));
+    assert_eq!(eval(r#"r"\x41\n""#), Value::String(r"\x41\n".into()));
+    // a backslash doesn't escape the closing quote either
+    assert_eq!(eval(r"r'ends\' + 'b'"), Value::String(r"ends\b".into()));
+    assert_eq!(eval(r"'\x41'"), Value::String("A".into()));
+}
+
+#[test]
+fn the_token_stream_can_be_collected() {
+    use TokenType::*;
+    let source = "$x = 2; // note\nprint 'hi' $x;";
+    let tokens: Vec<_> = Lexer::new(source).collect();
+    assert_eq!(tokens.iter().map(|t| t.ty).collect::<Vec<_>>(), [Ident, Equal, Num, Semicolon, Word, Str, Ident, Semicolon]);
+    assert_eq!(tokens[2].literal, Some(Literal::Number(2.0)));
+    assert_eq!(tokens[5].literal, Some(Literal::String("hi")));
+    for token in &tokens {
+        assert_eq!(&source[token.span()], token.lexeme);
+    }
+
+    let mut lexer = Lexer::new("print 'oops");
+    assert_eq!(lexer.by_ref().map(|t| t.ty).collect::<Vec<_>>(), [Word, Error]);
+    assert_eq!(lexer.errors().len(), 1);
+}
+
+#[test]
+fn escapes_can_spell_out_characters() {
+    let eval = |source: &str| AiCompiler::new().eval(source).unwrap();
+    assert_eq!(eval(r"'\x41\x7F'"), Value::String("A\u{7F}".into()));
+    assert_eq!(eval(r"'\u{1F600} \u00e9 \u{41}'"), Value::String("😀 é A".into()));
+    assert_eq!(eval(r"'tab\there\n\'\\'"), Value::String("tab\there\n'\\".into()));
+
+    for bad in [r"'\u{110000}'", r"'\u{D800}'", r"'\x80'", r"'\x4'", r"'\xZZ'", r"'\u{}'", r"'\u{1234567}'", r"'\u12'", r"'\q'"] {
+        let mut lexer = Lexer::new(bad);
+        lexer.by_ref().for_each(drop);
+        assert_eq!(lexer.errors().len(), 1, "{} lexed", bad);
+        // pointing at the backslash
+        assert!(lexer.errors()[0].to_string().contains("column 2"), "{}: {}", bad, lexer.errors()[0]);
+        assert!(AiCompiler::new().eval(bad).is_err(), "{} evaluated", bad);
+    }
+}
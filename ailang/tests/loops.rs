@@ -0,0 +1,122 @@
+mod common;
+
+use ailang::*;
+use common::*;
+
+#[test]
+fn labeled_break_leaves_both_loops() {
+    let source = "
+        $i = 0;
+        outer: while $i < 3 {
+            $j = 0;
+            while $j < 3 {
+                if $j == 1 { break outer; }
+                print $i $j;
+                $j = $j + 1;
+            }
+            $i = $i + 1;
+        }
+        print 9;
+    ";
+    assert_eq!(run(source), ["0 0", "9"]);
+}
+
+#[test]
+fn plain_break_only_leaves_the_inner_loop() {
+    let source = "
+        $i = 0;
+        outer: while $i < 2 {
+            repeat 3 { break; }
+            print $i;
+            $i = $i + 1;
+        }
+    ";
+    assert_eq!(run(source), ["0", "1"]);
+}
+
+#[test]
+fn a_label_has_to_name_an_enclosing_loop() {
+    assert!(!compile_errors("inner: while true { break; }\nwhile true { break inner; }").is_empty());
+    assert!(!compile_errors("while true { break nowhere; }").is_empty());
+}
+
+#[test]
+fn colons_outside_labels_stay_in_words() {
+    let log = log();
+    let mut compiler = compiler(&log);
+    compiler.register_callable("out a:b", Record(log.clone())).unwrap();
+    let program = compiler.compile("out a:b 1; outer : repeat 2 { out a:b 2; break outer; }").unwrap();
+    AiInterpreter::from_program(program).interpret().unwrap();
+    assert_eq!(entries(&log), ["1", "2"]);
+}
+
+#[test]
+fn repeat_runs_as_often_as_the_equivalent_while_loop() {
+    for count in ["0", "1", "2", "2.9", "-1", "7", "$n", "$n * 2"] {
+        let counted = run(&format!("$n = 3; $i = 0; repeat {} {{ $i += 1; }} print $i;", count));
+        let generic = run(&format!("$n = 3; $i = 0; $left = {}; while $left >= 1 {{ $i += 1; $left -= 1; }} print $i;", count));
+        assert_eq!(counted, generic, "repeat {}", count);
+    }
+}
+
+#[test]
+fn repeat_uses_the_counter_ops() {
+    let program = compiler(&log()).compile("repeat 3 { repeat 2 { print 1; } }").unwrap();
+    assert_eq!(program.code.iter().filter(|op| matches!(op, Op::InitCounter(_))).count(), 2);
+    assert_eq!(program.code.iter().filter(|op| matches!(op, Op::DecCounterJumpNonZero(..))).count(), 2);
+    assert_eq!(run("repeat 3 { repeat 2 { print 1; } }").len(), 6);
+}
+
+#[test]
+fn do_loops_run_their_body_once_when_the_condition_starts_false() {
+    assert_eq!(run("$i = 5;\ndo { print $i; $i = $i + 1; } while $i < 3;\nprint 9;"), ["5", "9"]);
+    assert_eq!(run("$i = 5;\ndo { print $i; } until $i > 0;\nprint 9;"), ["5", "9"]);
+    assert_eq!(run("$i = 0;\ndo { print $i; $i = $i + 1; } while $i < 3;"), ["0", "1", "2"]);
+    assert_eq!(run("$i = 0;\nwhile $i > 0 { print $i; }\nprint 9;"), ["9"]);
+}
+
+#[test]
+fn continue_inside_an_if_skips_the_rest_of_the_body() {
+    let source = "
+        $i = 0;
+        while $i < 5 {
+            $i = $i + 1;
+            if $i == 2 or $i == 4 { continue; }
+            print $i;
+        }
+    ";
+    assert_eq!(run(source), ["1", "3", "5"]);
+    assert_eq!(run("$i = 0;\ndo { $i = $i + 1; if $i == 2 { continue; } print $i; } while $i < 3;"), ["1", "3"]);
+    assert_eq!(run("for $i in 0..4 { if $i == 1 { continue; } print $i; }"), ["0", "2", "3"]);
+    assert_eq!(run("$n = 0;\nrepeat 3 { $n = $n + 1; if $n == 2 { continue; } print $n; }"), ["1", "3"]);
+}
+
+#[test]
+fn labeled_continue_moves_on_to_the_next_outer_iteration() {
+    let source = "
+        outer: for $i in 0..3 {
+            for $j in 0..3 {
+                if $j == 1 { continue outer; }
+                print $i $j;
+            }
+            print 9;
+        }
+    ";
+    assert_eq!(run(source), ["0 0", "1 0", "2 0"]);
+}
+
+#[test]
+fn break_and_continue_outside_loops_are_errors() {
+    assert_eq!(compile_errors("if true { break; }").len(), 1);
+    assert!(compile_errors("continue;")[0].contains("'continue' is not allowed outside of loops"));
+    assert!(compile_errors("while true { continue nowhere; }")[0].contains("does not refer to an enclosing loop"));
+}
+
+#[test]
+fn for_loops_count_up_to_but_not_including_the_end() {
+    assert_eq!(run("for $i in 0..3 { print $i; }"), ["0", "1", "2"]);
+    assert_eq!(run("$n = 2;\nfor $i in 1..$n + 1 { print $i; }"), ["1", "2"]);
+    assert_eq!(run("for $i in 3..3 { print $i; }\nprint 9;"), ["9"]);
+    // the end is only evaluated once, and assigning to the variable doesn't change the count
+    assert_eq!(run("$n = 2;\nfor $i in 0..$n { $n = 10; $i = 5; print $i; }"), ["5", "5"]);
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use ailang::*;
+use common::*;
+
+fn parse(source: &str) -> std::result::Result<usize, Vec<Error>> {
+    Parser::new(Lexer::new(source)).parse_safe().map(|statements| statements.len())
+}
+
+// Inputs that used to panic, found by the fuzz target
+#[test]
+fn digits_with_combining_marks_are_not_numbers() {
+    assert!(parse("move 1\u{301};").is_ok());
+    assert!(parse("\u{0661}\u{301}").is_err());
+    assert!(parse("-").is_err());
+    assert!(parse("move -").is_err());
+}
+
+#[test]
+fn runs_of_comments_are_all_skipped() {
+    assert_eq!(parse("# one\n# two\n// three\n/* four */ # five\nmove;").unwrap(), 1);
+    assert_eq!(parse("# only comments\n# and nothing else").unwrap(), 0);
+}
+
+#[test]
+fn malformed_scripts_give_errors_rather_than_panics() {
+    for source in ["move", "if", "group", "#[best_effort] group", "#[timeout(1s)] group g", "$", "$a =", "{", "}", "move (1;", "\"", "/*", "0x"] {
+        assert!(parse(source).is_err(), "{:?} parsed", source);
+        assert!(AiCompiler::new().compile(source).is_err(), "{:?} compiled", source);
+    }
+}
+
+fn arg_count(source: &str) -> usize {
+    let statements = Parser::new(Lexer::new(source)).parse_safe().unwrap_or_else(|e| panic!("{:?}", e));
+    let Some(ast::Stmt::Exec(call)) = statements.first() else {panic!("expected a call")};
+    call.args.len()
+}
+
+#[test]
+fn expression_arguments_extend_as_far_as_they_can() {
+    assert_eq!(arg_count("move 1 + 2;"), 1);
+    assert_eq!(arg_count("move $unit * 2;"), 1);
+    assert_eq!(arg_count("move 1 - 2;"), 1);
+    assert_eq!(arg_count("move 1-2;"), 1);
+    assert_eq!(run("print 1 + 2; print 1 - 2; print 2 * 3 + 1;"), ["3", "-1", "7"]);
+}
+
+#[test]
+fn parentheses_and_spacing_separate_arguments() {
+    assert_eq!(arg_count("move (1) (2);"), 2);
+    assert_eq!(arg_count("move $x $y;"), 2);
+    assert_eq!(arg_count("move 1 -2;"), 2);
+    assert_eq!(arg_count("move left 1 + 2 fast;"), 3);
+    assert_eq!(run("print (1) (2); print 1 -2; $x = 3; print $x -1; print $x-1;"), ["1 2", "1 -2", "3 -1", "2"]);
+}
+
+fn too_deep(source: &str, limit: usize) -> bool {
+    let mut parser = Parser::new(Lexer::new(source));
+    parser.set_max_expression_depth(limit);
+    match parser.parse_safe() {
+        Err(errors) => matches!(errors[..], [Error::ExpressionTooDeep{limit: l, ..}, ..] if l == limit),
+        Ok(_) => false,
+    }
+}
+
+fn nested(depth: usize) -> String {
+    format!("$x = {}1{};", "(".repeat(depth), ")".repeat(depth))
+}
+
+#[test]
+fn deeply_nested_expressions_give_a_clean_error() {
+    let n = 100_000;
+    assert!(too_deep(&nested(n), 64));
+    assert!(too_deep(&format!("$x = {}1;", "-".repeat(n)), 64));
+    assert!(too_deep(&format!("$x = {}1{};", "[".repeat(n), "]".repeat(n)), 64));
+    assert!(too_deep(&format!("$x = 1{};", " + 1".repeat(n)), 64));
+    assert!(too_deep(&format!("move {}1{};", "(".repeat(n), ")".repeat(n)), 64));
+    assert!(matches!(parse(&nested(n)).unwrap_err()[..], [Error::ExpressionTooDeep{limit: 64, ..}, ..]));
+    assert!(parse(&nested(30)).is_ok());
+
+    assert!(too_deep(&nested(20), 10));
+    let mut compiler = AiCompiler::new();
+    compiler.set_max_expression_depth(10);
+    assert!(compiler.compile(&nested(20)).is_err());
+    assert!(compiler.compile(&nested(8)).is_ok());
+}
+
+fn errors(detail: ErrorDetail, source: &str) -> Vec<String> {
+    let mut compiler = AiCompiler::new();
+    compiler.set_error_detail(detail);
+    match compiler.compile(source) {
+        Ok(_) => Vec::new(),
+        Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+    }
+}
+
+#[test]
+fn summarized_errors_keep_one_per_malformed_line() {
+    let source = "$a = (1 + ] ) } 'oops;\n$b = 2;";
+    let full = errors(ErrorDetail::Full, source);
+    assert!(full.len() > 1, "{:?}", full);
+    // the lexer's error is the likeliest cause of the rest
+    assert_eq!(errors(ErrorDetail::Summarized, source), ["[line 1, column 17] Unterminated string"]);
+
+    let source = "$a = (1 + ] ) };
+$b = 2;";
+    let full = errors(ErrorDetail::Full, source);
+    assert!(full.len() > 1, "{:?}", full);
+    assert_eq!(errors(ErrorDetail::Summarized, source), full[..1]);
+}
+
+#[test]
+fn summarized_errors_keep_unrelated_compile_errors() {
+    let source = "$a = $x;\n$b = $y;\n$c = $z;\n";
+    let full = errors(ErrorDetail::Full, source);
+    assert_eq!(full.len(), 3, "{:?}", full);
+    assert_eq!(errors(ErrorDetail::Summarized, source), full);
+}
+
+#[test]
+fn each_malformed_statement_gets_its_own_error() {
+    let source = "move (1;\n$ok = 1;\n$a = ;\ngroup g { turn; }\n$b = 2 +;\nmove 2;";
+    let errors = parse(source).unwrap_err();
+    let lines: Vec<_> = errors.iter().map(|e| e.line()).collect();
+    assert_eq!(lines, [Some(1), Some(3), Some(5)], "{:?}", errors);
+    assert_eq!(compile_errors(source).len(), 3);
+}
+
+#[test]
+fn booleans_are_literals() {
+    assert_eq!(AiCompiler::new().eval("true").unwrap(), Value::Bool(true));
+    assert_eq!(AiCompiler::new().eval("not false").unwrap(), Value::Bool(true));
+    assert_eq!(run("$x = true;\nprint $x false;"), ["true false"]);
+}
+
+#[test]
+fn booleans_cannot_name_properties_or_groups() {
+    let log = log();
+    for name in ["true", "false"] {
+        let mut compiler = AiCompiler::new();
+        assert!(matches!(compiler.register_property(name, Fixed(Value::Nil)), Err(Error::ReservedName(_))));
+        assert!(matches!(compiler.register_callable(name, Record(log.clone())), Err(Error::ReservedName(_))));
+        let mut interpreter = AiInterpreter::new(Vec::new());
+        assert!(matches!(interpreter.register_property(name, Box::new(Fixed(Value::Nil))), Err(Error::ReservedName(_))));
+
+        assert!(parse(&format!("group {} {{ move; }}", name)).is_err(), "group {} parsed", name);
+        assert!(parse(&format!("{};", name)).is_err(), "{} parsed as a call", name);
+    }
+}
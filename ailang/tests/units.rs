@@ -0,0 +1,96 @@
+mod common;
+
+use ailang::*;
+use common::*;
+
+/// A read-only property whose number is in `unit`.
+struct Measured(f64, Unit);
+
+impl Prop for Measured {
+    fn get(&self) -> Result<Value> {
+        Ok(Value::Number(self.0))
+    }
+    fn unit(&self) -> Option<Unit> {
+        Some(self.1)
+    }
+}
+
+fn measured(log: &Log) -> AiCompiler {
+    let mut compiler = compiler(log);
+    compiler.register_property("height", Measured(2.0, Unit::Meters)).unwrap();
+    compiler.register_property("elapsed", Measured(3.0, Unit::Seconds)).unwrap();
+    compiler.register_property("delay", Measured(500.0, Unit::Milliseconds)).unwrap();
+    compiler.register_callable("raise", Typed(log.clone(), Signature::new(vec![Param::Quantity(Unit::Feet)]))).unwrap();
+    compiler.register_callable("pause", Typed(log.clone(), Signature::new(vec![Param::Quantity(Unit::Milliseconds)]))).unwrap();
+    compiler
+}
+
+fn run_measured(source: &str) -> Vec<String> {
+    let log = log();
+    let program = measured(&log).compile(source).unwrap_or_else(|e| panic!("{:?}", e));
+    AiInterpreter::from_program(program).interpret().unwrap();
+    entries(&log)
+}
+
+fn measured_errors(source: &str) -> Vec<String> {
+    match measured(&log()).compile(source) {
+        Ok(_) => Vec::new(),
+        Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+    }
+}
+
+fn feet(printed: &str) -> f64 {
+    printed.parse().unwrap()
+}
+
+#[test]
+fn meters_convert_to_feet() {
+    let printed = run_measured("use $height;\nraise $height;\nraise $height * 2;\nraise 3;");
+    assert!((feet(&printed[0]) - 6.5617).abs() < 1e-3, "{:?}", printed);
+    assert!((feet(&printed[1]) - 13.1234).abs() < 1e-3, "{:?}", printed);
+    // plain numbers are taken to be in feet already
+    assert_eq!(printed[2], "3");
+}
+
+#[test]
+fn meters_and_seconds_do_not_mix() {
+    let errors = measured_errors("use $height;\nuse $elapsed;\n$x = $height + $elapsed;");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert!(errors[0].contains("meters") && errors[0].contains("seconds"), "{:?}", errors);
+    assert_eq!(measured_errors("use $elapsed;\nraise $elapsed;").len(), 1);
+}
+
+const DURATIONS: &str = "use $height;\nuse $elapsed;\nuse $delay;\n";
+
+#[test]
+fn durations_scale_divide_and_compare() {
+    let printed = run_measured(&format!("{}\
+        print $elapsed / $delay;\n\
+        print $delay / $elapsed;\n\
+        pause $elapsed * 2;\n\
+        pause 2 * $elapsed;\n\
+        pause $elapsed / 2;\n\
+        pause $elapsed + $delay;\n\
+        print $delay < $elapsed;\n\
+        print $elapsed <= $delay;\n\
+        print $elapsed == $elapsed;", DURATIONS));
+    assert_eq!(printed, ["6", "0.16666666666666666", "6000", "6000", "1500", "3500", "true", "false", "true"]);
+}
+
+#[test]
+fn durations_reject_nonsensical_arithmetic() {
+    for (expr, msg) in [
+        ("$elapsed * $delay", "Can't multiply seconds by milliseconds"),
+        ("2 / $elapsed", "Can't divide a number by seconds"),
+        ("$elapsed / $height", "Can't divide seconds by meters"),
+        ("$height / $elapsed", "Can't divide meters by seconds"),
+        ("$elapsed ^ 2", "Durations can't be raised to powers"),
+        ("2 ^ $delay", "Durations can't be raised to powers"),
+        ("$elapsed - $height", "Can't combine seconds and meters"),
+        ("$elapsed < $height", "Can't combine seconds and meters"),
+    ] {
+        let errors = measured_errors(&format!("{}$x = {};", DURATIONS, expr));
+        assert_eq!(errors.len(), 1, "{}: {:?}", expr, errors);
+        assert!(errors[0].contains(msg), "{}: {:?}", expr, errors);
+    }
+}